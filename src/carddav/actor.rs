@@ -0,0 +1,837 @@
+//! Contains the actor that synchronizes the address book with a CardDAV server
+//!
+//! Every synchronization first pushes local changes, then pulls remote ones.
+//! Conflicts are resolved in favour of the server: a card that changed on both
+//! sides is downloaded again instead of overwriting the server's copy.
+
+use std::{collections::HashMap, fmt::Write, slice, time::Duration};
+
+use actix::{dev::ToEnvelope, prelude::*};
+use reqwest::{StatusCode, Url};
+
+use super::{
+    client::{Client, Errors, Response},
+    xml::{self, CALENDAR_SERVER, CARDDAV, DAV},
+};
+use crate::{
+    config::CardDavAccount,
+    database::{
+        self,
+        address_book::CardRecord,
+        card_sync::{
+            AddressBookRecord, AddressBookStateMessage,
+            ApplyRemoteChangesMessage, CardPushedMessage,
+            DeletionPushedMessage, PendingChangesMessage, RemoteEtagsMessage,
+            SaveAddressBookStateMessage,
+        },
+        vcard::{self, Version},
+        DatabaseActor,
+    },
+};
+
+/// Properties asked for while discovering address books
+const DISCOVERY_REQUEST: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:" xmlns:card="urn:ietf:params:xml:ns:carddav"
+    xmlns:cs="http://calendarserver.org/ns/">
+  <d:prop>
+    <d:resourcetype/>
+    <d:displayname/>
+    <d:current-user-principal/>
+    <card:addressbook-home-set/>
+    <cs:getctag/>
+    <d:sync-token/>
+    <d:supported-report-set/>
+  </d:prop>
+</d:propfind>"#;
+
+/// Lists the ETag of every card in an address book
+const ETAGS_REQUEST: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:getetag/>
+  </d:prop>
+</d:propfind>"#;
+
+/// How many cards to download with a single `addressbook-multiget` report
+const MULTIGET_CHUNK_SIZE: usize = 100;
+
+/// An address book found on the server
+#[derive(Debug)]
+struct AddressBook {
+    /// URL of the address book collection
+    url: Url,
+    /// The display name of the address book
+    name: Option<String>,
+    /// The current `getctag`, if the server supports it
+    ctag: Option<String>,
+    /// Whether the server supports `sync-collection` reports on it
+    supports_sync: bool,
+}
+
+impl AddressBook {
+    /// Reads an address book from a discovery response, if the resource is
+    /// one
+    fn from_response(response: &Response) -> Option<Self> {
+        let is_address_book = response
+            .property(DAV, "resourcetype")?
+            .contains(CARDDAV, "addressbook");
+        is_address_book.then(|| {
+            let mut url = response.url.clone();
+            // Card URLs are made by joining onto the collection URL
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+            Self {
+                url,
+                name: response.property_text(DAV, "displayname"),
+                ctag: response.property_text(CALENDAR_SERVER, "getctag"),
+                supports_sync: response
+                    .property(DAV, "supported-report-set")
+                    .is_some_and(|reports| {
+                        reports.contains(DAV, "sync-collection")
+                    }),
+            }
+        })
+    }
+
+    /// The URL to upload a card that isn't on the server yet to
+    fn new_card_url(&self, uid: &str) -> Url {
+        let name: String = uid
+            .chars()
+            .map(|character| {
+                if character.is_ascii_alphanumeric()
+                    || "-_.".contains(character)
+                {
+                    character
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        self.url
+            .join(&format!("{name}.vcf"))
+            .expect("A sanitized file name is a valid relative URL")
+    }
+}
+
+/// Sends a message to the database actor, logging any failure
+async fn store<M, T>(
+    address: &Addr<DatabaseActor>,
+    message: M,
+) -> Result<T, Errors>
+where
+    M: Message<Result = Result<T, database::Errors>> + Send + 'static,
+    T: Send + 'static,
+    DatabaseActor: Handler<M>,
+    <DatabaseActor as Actor>::Context: ToEnvelope<DatabaseActor, M>,
+{
+    match address.send(message).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            log::warn!("Failed to update the address book: {e}");
+            Err(Errors::Store(e))
+        }
+        Err(e) => {
+            log::warn!("Failed to reach the database: {e}");
+            Err(Errors::Unreachable(e))
+        }
+    }
+}
+
+/// Finds the address books below a URL
+///
+/// The URL can point at an address book, an address book home set or any
+/// resource that reports the current user's principal, such as the server
+/// root.
+async fn discover(
+    client: &Client,
+    url: &Url,
+) -> Result<Vec<AddressBook>, Errors> {
+    let propfind = |url: Url, depth| async move {
+        client
+            .multistatus(
+                "PROPFIND",
+                &url,
+                Some(depth),
+                DISCOVERY_REQUEST.to_owned(),
+            )
+            .await
+    };
+    let mut home = url.clone();
+    let found = propfind(url.clone(), "0").await?;
+    if let Some(response) = found.responses.first() {
+        if let Some(address_book) = AddressBook::from_response(response) {
+            return Ok(vec![address_book]);
+        }
+        if let Some(home_set) =
+            response.property_url(CARDDAV, "addressbook-home-set")
+        {
+            home = home_set;
+        } else if let Some(principal) =
+            response.property_url(DAV, "current-user-principal")
+        {
+            let principal = propfind(principal, "0").await?;
+            if let Some(home_set) =
+                principal.responses.first().and_then(|response| {
+                    response.property_url(CARDDAV, "addressbook-home-set")
+                })
+            {
+                home = home_set;
+            }
+        }
+    }
+    let address_books: Vec<AddressBook> = propfind(home, "1")
+        .await?
+        .responses
+        .iter()
+        .filter_map(AddressBook::from_response)
+        .collect();
+    if address_books.is_empty() {
+        return Err(Errors::NoAddressBooks);
+    }
+    Ok(address_books)
+}
+
+/// Pushes local changes to an address book
+async fn push(
+    client: &Client,
+    database: &Addr<DatabaseActor>,
+    address_book: &AddressBook,
+    include_unsynced: bool,
+) -> Result<(), Errors> {
+    let pending = store(
+        database,
+        PendingChangesMessage {
+            url: address_book.url.to_string(),
+            include_unsynced,
+        },
+    )
+    .await?;
+    for deletion in pending.deletions {
+        let url = Url::parse(&deletion.href)
+            .map_err(|_e| Errors::Url(deletion.href.clone()))?;
+        match client.delete(&url, deletion.etag.as_deref()).await {
+            Ok(()) => {}
+            Err(e) if e.is_conflict() => {
+                log::warn!("{url} changed on the server, keeping its version");
+                let cards = download(
+                    client,
+                    address_book,
+                    slice::from_ref(&deletion.href),
+                )
+                .await?;
+                store(
+                    database,
+                    ApplyRemoteChangesMessage {
+                        url: address_book.url.to_string(),
+                        cards,
+                        removed: Vec::new(),
+                    },
+                )
+                .await?;
+            }
+            Err(e) => return Err(e),
+        }
+        store(
+            database,
+            DeletionPushedMessage {
+                href: deletion.href,
+            },
+        )
+        .await?;
+    }
+    for card in pending.cards {
+        let url = match card.href.as_deref().map(Url::parse) {
+            Some(Ok(url)) => url,
+            _ => address_book.new_card_url(&card.uid),
+        };
+        let etag = match client
+            .put(&url, vcard::write(&card, Version::V3), card.etag.as_deref())
+            .await
+        {
+            Ok(etag) => etag,
+            Err(e) if e.is_conflict() => {
+                log::warn!("{url} changed on the server, keeping its version");
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        store(
+            database,
+            CardPushedMessage {
+                uid: card.uid,
+                address_book: address_book.url.to_string(),
+                href: url.to_string(),
+                etag,
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Asks for the cards that changed since a sync token, or for every card if
+/// there is no token
+///
+/// Responds with the ETags of changed cards, the URLs of removed cards and the
+/// new sync token.
+async fn sync_collection(
+    client: &Client,
+    address_book: &AddressBook,
+    sync_token: Option<&str>,
+) -> Result<
+    (HashMap<String, Option<String>>, Vec<String>, Option<String>),
+    Errors,
+> {
+    let request = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<d:sync-collection xmlns:d="DAV:">
+  <d:sync-token>{}</d:sync-token>
+  <d:sync-level>1</d:sync-level>
+  <d:prop>
+    <d:getetag/>
+  </d:prop>
+</d:sync-collection>"#,
+        xml::escape(sync_token.unwrap_or_default())
+    );
+    let found =
+        client.multistatus("REPORT", &address_book.url, None, request).await?;
+    let mut changed = HashMap::new();
+    let mut removed = Vec::new();
+    for response in found.responses {
+        if response.url == address_book.url {
+            continue;
+        }
+        if response.status == Some(404) {
+            removed.push(response.url.to_string());
+        } else {
+            let etag = response.property_text(DAV, "getetag");
+            changed.insert(response.url.to_string(), etag);
+        }
+    }
+    Ok((changed, removed, found.sync_token))
+}
+
+/// Lists the ETag of every card in an address book
+async fn etags(
+    client: &Client,
+    address_book: &AddressBook,
+) -> Result<HashMap<String, Option<String>>, Errors> {
+    Ok(client
+        .multistatus(
+            "PROPFIND",
+            &address_book.url,
+            Some("1"),
+            ETAGS_REQUEST.to_owned(),
+        )
+        .await?
+        .responses
+        .into_iter()
+        .filter(|response| response.url != address_book.url)
+        .map(|response| {
+            let etag = response.property_text(DAV, "getetag");
+            (response.url.to_string(), etag)
+        })
+        .collect())
+}
+
+/// Downloads cards from an address book
+async fn download(
+    client: &Client,
+    address_book: &AddressBook,
+    urls: &[String],
+) -> Result<Vec<CardRecord>, Errors> {
+    let mut cards = Vec::new();
+    for chunk in urls.chunks(MULTIGET_CHUNK_SIZE) {
+        let hrefs = chunk.iter().filter_map(|url| Url::parse(url).ok()).fold(
+            String::new(),
+            |mut hrefs, url| {
+                writeln!(
+                    hrefs,
+                    "  <d:href>{}</d:href>",
+                    xml::escape(url.path())
+                )
+                .expect("Writing to a String can't fail");
+                hrefs
+            },
+        );
+        let request = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<card:addressbook-multiget xmlns:d="DAV:"
+    xmlns:card="urn:ietf:params:xml:ns:carddav">
+  <d:prop>
+    <d:getetag/>
+    <card:address-data/>
+  </d:prop>
+{hrefs}</card:addressbook-multiget>"#
+        );
+        let found = client
+            .multistatus("REPORT", &address_book.url, Some("1"), request)
+            .await?;
+        for response in found.responses {
+            let Some(data) = response.property(CARDDAV, "address-data") else {
+                continue;
+            };
+            let mut card = match vcard::parse(&data.text) {
+                Ok(parsed) => match parsed.into_iter().next() {
+                    Some(card) => card,
+                    None => continue,
+                },
+                Err(e) => {
+                    log::warn!(
+                        "Skipping invalid vCard at {}: {e}",
+                        response.url
+                    );
+                    continue;
+                }
+            };
+            if card.uid.is_empty() {
+                card.uid = response.url.to_string();
+            }
+            card.address_book = Some(address_book.url.to_string());
+            card.href = Some(response.url.to_string());
+            card.etag = response.property_text(DAV, "getetag");
+            card.modified = false;
+            cards.push(card);
+        }
+    }
+    Ok(cards)
+}
+
+/// Pulls remote changes from an address book
+///
+/// `sync-collection` is used where the server supports it. Otherwise the
+/// `getctag` tells whether anything changed, and the ETags of all cards tell
+/// what.
+async fn pull(
+    client: &Client,
+    database: &Addr<DatabaseActor>,
+    address_book: &AddressBook,
+) -> Result<(), Errors> {
+    let url = address_book.url.to_string();
+    let state = store(
+        database,
+        AddressBookStateMessage {
+            url: url.clone(),
+        },
+    )
+    .await?;
+    let mut sync_token =
+        state.as_ref().and_then(|state| state.sync_token.clone());
+    let unchanged = state.as_ref().is_some_and(|state| {
+        address_book.ctag.is_some() && state.ctag == address_book.ctag
+    });
+    if unchanged {
+        log::trace!("Address book {url} is unchanged");
+        return Ok(());
+    }
+    let local = store(
+        database,
+        RemoteEtagsMessage {
+            url: url.clone(),
+        },
+    )
+    .await?;
+    let (remote, removed) = if address_book.supports_sync {
+        let mut full_sync = sync_token.is_none();
+        let report =
+            match sync_collection(client, address_book, sync_token.as_deref())
+                .await
+            {
+                Err(Errors::Status {
+                    status,
+                    ..
+                }) if !full_sync
+                    && (status == StatusCode::FORBIDDEN
+                        || status == StatusCode::CONFLICT) =>
+                {
+                    log::info!(
+                        "Sync token for {url} expired, syncing everything"
+                    );
+                    full_sync = true;
+                    sync_collection(client, address_book, None).await?
+                }
+                report => report?,
+            };
+        let (changed, mut removed, next_token) = report;
+        sync_token = next_token;
+        if full_sync {
+            // A full sync lists every card, so anything else is stale
+            removed.extend(
+                local
+                    .keys()
+                    .filter(|href| !changed.contains_key(*href))
+                    .cloned(),
+            );
+        }
+        (changed, removed)
+    } else {
+        let remote = etags(client, address_book).await?;
+        let removed = local
+            .keys()
+            .filter(|href| !remote.contains_key(*href))
+            .cloned()
+            .collect();
+        (remote, removed)
+    };
+    let outdated: Vec<String> = remote
+        .into_iter()
+        .filter(|(href, etag)| etag.is_none() || local.get(href) != Some(etag))
+        .map(|(href, _)| href)
+        .collect();
+    let cards = download(client, address_book, &outdated).await?;
+    log::info!(
+        "Pulled {} changed and {} removed cards from {url}",
+        cards.len(),
+        removed.len()
+    );
+    store(
+        database,
+        ApplyRemoteChangesMessage {
+            url: url.clone(),
+            cards,
+            removed,
+        },
+    )
+    .await?;
+    store(
+        database,
+        SaveAddressBookStateMessage {
+            state: AddressBookRecord {
+                url,
+                name: address_book.name.clone(),
+                ctag: address_book.ctag.clone(),
+                sync_token,
+            },
+        },
+    )
+    .await
+}
+
+/// An actor that synchronizes the address book with a CardDAV account
+pub(crate) struct CardDavActor {
+    /// The account this actor synchronizes with
+    account: CardDavAccount,
+    /// Client for the account's server
+    client: Client,
+    /// Address of the database actor for inter-actor communication
+    db_address: Addr<DatabaseActor>,
+}
+
+impl CardDavActor {
+    /// Creates a new actor for a given account
+    pub(crate) fn new(
+        account: CardDavAccount,
+        db_address: Addr<DatabaseActor>,
+    ) -> Self {
+        Self {
+            client: Client::new(&account),
+            account,
+            db_address,
+        }
+    }
+}
+
+impl Actor for CardDavActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::trace!("Started CardDAV actor for {}", self.account.url);
+        ctx.notify(SyncAddressBooksMessage);
+        ctx.run_interval(
+            Duration::from_secs(self.account.sync_interval),
+            |_actor, ctx| ctx.notify(SyncAddressBooksMessage),
+        );
+    }
+}
+
+/// A message to synchronize every address book of the account this actor
+/// represents
+///
+/// The actor sends it to itself when it starts and then at the account's
+/// sync interval. Synchronizations don't overlap. Address books are
+/// synchronized one after another. A failure is logged and doesn't stop the
+/// others; the first one is returned.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct SyncAddressBooksMessage;
+
+impl Handler<SyncAddressBooksMessage> for CardDavActor {
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: SyncAddressBooksMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.url);
+        let client = self.client.clone();
+        let database = self.db_address.clone();
+        let account = self.account.clone();
+        AtomicResponse::new(Box::pin(
+            async move {
+                let url = Url::parse(&account.url)
+                    .map_err(|_e| Errors::Url(account.url.clone()))?;
+                let address_books = discover(&client, &url).await?;
+                let mut result = Ok(());
+                for (index, address_book) in address_books.iter().enumerate() {
+                    // Cards created locally go to the first address book
+                    let include_unsynced =
+                        account.upload_local_cards && index == 0;
+                    let synced = async {
+                        push(
+                            &client,
+                            &database,
+                            address_book,
+                            include_unsynced,
+                        )
+                        .await?;
+                        pull(&client, &database, address_book).await
+                    }
+                    .await;
+                    if let Err(e) = synced {
+                        log::warn!(
+                            "Failed to synchronize {}: {e}",
+                            address_book.url
+                        );
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+                    }
+                }
+                result
+            }
+            .into_actor(self),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+
+    use super::{
+        super::stand_in::StandIn, CardDavActor, SyncAddressBooksMessage,
+    };
+    use crate::{
+        config::CardDavAccount,
+        database::{
+            address_book::{
+                CardRecord, CardsMessage, DeleteCardMessage, SaveCardMessage,
+            },
+            card_sync::AddressBookStateMessage,
+            testing::{self, TestDirectory},
+            DatabaseActor,
+        },
+    };
+
+    /// A card as another client would upload it
+    const JANE: &str = "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:jane\r\nFN:Jane \
+                        Doe\r\nEMAIL:jane@example.com\r\nEND:VCARD\r\n";
+
+    /// `JANE` after another client renamed her
+    const JANE_RENAMED: &str = "BEGIN:VCARD\r\nVERSION:3.0\r\nUID:jane\r\nFN:\
+                                Jane Smith\r\nEMAIL:jane@example.com\r\nEND:\
+                                VCARD\r\n";
+
+    /// Starts a database in a directory of its own, a stand-in server and an
+    /// actor synchronizing the two
+    ///
+    /// The directory must be kept until the test ends.
+    async fn start(
+        test: &str,
+    ) -> (TestDirectory, StandIn, Addr<DatabaseActor>, Addr<CardDavActor>) {
+        let (directory, database, _) =
+            testing::start(&format!("carddav-{test}")).await;
+        let stand_in = StandIn::start();
+        let account = CardDavAccount {
+            url: stand_in.url().to_owned(),
+            username: "test".to_owned(),
+            password: "test".to_owned(),
+            upload_local_cards: true,
+            sync_interval: 3600,
+        };
+        let actor = CardDavActor::new(account, database.clone()).start();
+        (directory, stand_in, database, actor)
+    }
+
+    /// Synchronizes once more
+    async fn sync(actor: &Addr<CardDavActor>) {
+        actor
+            .send(SyncAddressBooksMessage)
+            .await
+            .expect("The actor should be running")
+            .expect("The synchronization should succeed");
+    }
+
+    /// Lists the cards in the address book
+    async fn cards(database: &Addr<DatabaseActor>) -> Vec<CardRecord> {
+        database
+            .send(CardsMessage {
+                group: None,
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The cards should be listed")
+    }
+
+    /// Saves a card in the address book
+    async fn save(database: &Addr<DatabaseActor>, card: CardRecord) {
+        database
+            .send(SaveCardMessage {
+                card,
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The card should be saved");
+    }
+
+    /// Deletes a card from the address book
+    async fn delete(database: &Addr<DatabaseActor>, uid: &str) {
+        database
+            .send(DeleteCardMessage {
+                uid: uid.to_owned(),
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The card should be deleted");
+    }
+
+    #[actix_rt::test]
+    async fn pulls_cards_from_the_server() {
+        let (_directory, stand_in, database, actor) = start("pull").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        let cards = cards(&database).await;
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].name, "Jane Doe");
+        assert_eq!(cards[0].emails[0].address, "jane@example.com");
+        assert_eq!(
+            cards[0].href.as_deref(),
+            Some(format!("{}jane.vcf", stand_in.url()).as_str())
+        );
+        assert!(!cards[0].modified);
+    }
+
+    #[actix_rt::test]
+    async fn remembers_address_books_across_restarts() {
+        let (directory, stand_in, database, actor) = start("restart").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        let seen = database
+            .send(AddressBookStateMessage {
+                url: stand_in.url().to_owned(),
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The state should be looked up")
+            .expect("The address book should have been seen");
+        let restarted = DatabaseActor::new(
+            &directory.path().join("blobs"),
+            &directory.path().join("state"),
+        )
+        .await
+        .expect("The database should restart")
+        .start();
+        let remembered = restarted
+            .send(AddressBookStateMessage {
+                url: stand_in.url().to_owned(),
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The state should be looked up")
+            .expect("The address book should be remembered");
+        assert!(seen.ctag.is_some());
+        assert_eq!(remembered.ctag, seen.ctag);
+        assert_eq!(remembered.sync_token, seen.sync_token);
+        assert_eq!(cards(&restarted).await[0].name, "Jane Doe");
+    }
+
+    #[actix_rt::test]
+    async fn pulls_cards_changed_on_the_server() {
+        let (_directory, stand_in, database, actor) =
+            start("pull-changes").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        stand_in.put("jane.vcf", JANE_RENAMED);
+        sync(&actor).await;
+        assert_eq!(cards(&database).await[0].name, "Jane Smith");
+    }
+
+    #[actix_rt::test]
+    async fn removes_cards_deleted_on_the_server() {
+        let (_directory, stand_in, database, actor) =
+            start("pull-deletion").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        stand_in.remove("jane.vcf");
+        sync(&actor).await;
+        assert!(cards(&database).await.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn pushes_local_changes() {
+        let (_directory, stand_in, database, actor) = start("push").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        let mut jane = cards(&database).await.remove(0);
+        jane.name = "Jane Roe".to_owned();
+        save(&database, jane).await;
+        save(
+            &database,
+            CardRecord {
+                name: "John Doe".to_owned(),
+                ..CardRecord::default()
+            },
+        )
+        .await;
+        sync(&actor).await;
+        let pushed = stand_in.card("jane.vcf").expect("Jane should be kept");
+        assert!(pushed.contains("FN:Jane Roe"));
+        assert_eq!(stand_in.len(), 2);
+        assert!(cards(&database).await.iter().all(|card| !card.modified));
+    }
+
+    #[actix_rt::test]
+    async fn pushes_local_deletions() {
+        let (_directory, stand_in, database, actor) =
+            start("push-deletion").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        delete(&database, "jane").await;
+        sync(&actor).await;
+        assert_eq!(stand_in.len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn keeps_the_server_version_of_cards_changed_on_both_sides() {
+        let (_directory, stand_in, database, actor) = start("conflict").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        stand_in.put("jane.vcf", JANE_RENAMED);
+        let mut jane = cards(&database).await.remove(0);
+        jane.name = "Jane Roe".to_owned();
+        save(&database, jane).await;
+        sync(&actor).await;
+        let kept = stand_in.card("jane.vcf").expect("Jane should be kept");
+        assert!(kept.contains("FN:Jane Smith"));
+        assert_eq!(cards(&database).await[0].name, "Jane Smith");
+    }
+
+    #[actix_rt::test]
+    async fn pulls_cards_changed_on_the_server_instead_of_deleting_them() {
+        let (_directory, stand_in, database, actor) =
+            start("delete-conflict").await;
+        stand_in.put("jane.vcf", JANE);
+        sync(&actor).await;
+        stand_in.put("jane.vcf", JANE_RENAMED);
+        delete(&database, "jane").await;
+        sync(&actor).await;
+        let kept = stand_in.card("jane.vcf").expect("Jane should be kept");
+        assert!(kept.contains("FN:Jane Smith"));
+        let cards = cards(&database).await;
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].name, "Jane Smith");
+        assert!(!cards[0].modified);
+    }
+}
@@ -0,0 +1,288 @@
+//! The WebDAV requests CardDAV synchronization is built from
+
+use std::fmt::Display;
+
+use reqwest::{header, Method, StatusCode, Url};
+
+use super::xml::{self, Element, DAV};
+use crate::config::CardDavAccount;
+
+/// Errors that can occur while talking to a CardDAV server
+#[derive(Debug)]
+pub(crate) enum Errors {
+    /// The configured URL is not a valid URL
+    Url(String),
+    /// The request could not be sent or the response could not be read
+    Request(reqwest::Error),
+    /// The server answered with an unexpected status
+    Status {
+        /// The requested URL
+        url: Url,
+        /// The status the server answered with
+        status: StatusCode,
+    },
+    /// The server sent XML that could not be read
+    Xml(xml::Errors),
+    /// No address books were found below the configured URL
+    NoAddressBooks,
+    /// The address book could not be read from or written to the database
+    Store(crate::database::Errors),
+    /// The database actor stopped before answering
+    Unreachable(actix::MailboxError),
+}
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errors::Url(url) => write!(f, "invalid URL \"{url}\""),
+            Errors::Request(e) => write!(f, "request failed: {e}"),
+            Errors::Status {
+                url,
+                status,
+            } => {
+                write!(f, "{url} answered with {status}")
+            }
+            Errors::Xml(e) => write!(f, "invalid response: {e}"),
+            Errors::NoAddressBooks => write!(f, "no address books found"),
+            Errors::Store(e) => {
+                write!(f, "failed to update the address book: {e}")
+            }
+            Errors::Unreachable(e) => {
+                write!(f, "failed to reach the database: {e}")
+            }
+        }
+    }
+}
+
+impl Errors {
+    /// Whether the server rejected a write because the resource changed
+    /// since it was last read
+    pub(crate) fn is_conflict(&self) -> bool {
+        matches!(
+            self,
+            Errors::Status { status, .. }
+                if *status == StatusCode::PRECONDITION_FAILED
+        )
+    }
+}
+
+/// One resource in a multistatus response
+#[derive(Debug)]
+pub(crate) struct Response {
+    /// Absolute URL of the resource
+    pub(crate) url: Url,
+    /// Status of the resource itself, only given when it has no properties,
+    /// e.g. 404 for members removed since the last sync
+    pub(crate) status: Option<u16>,
+    /// The properties the server returned with a 200 status
+    pub(crate) properties: Vec<Element>,
+}
+
+impl Response {
+    /// The property with the given namespace and local name
+    pub(crate) fn property(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Option<&Element> {
+        self.properties.iter().find(|property| property.is(namespace, name))
+    }
+
+    /// The trimmed text of a property
+    pub(crate) fn property_text(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Option<String> {
+        self.property(namespace, name)
+            .map(|property| property.text.trim().to_owned())
+            .filter(|text| !text.is_empty())
+    }
+
+    /// The absolute URL in the `href` child of a property, as used by
+    /// `current-user-principal` and `addressbook-home-set`
+    pub(crate) fn property_url(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Option<Url> {
+        let href = self.property(namespace, name)?.child_text(DAV, "href")?;
+        self.url.join(href).ok()
+    }
+}
+
+/// A parsed `207 Multi-Status` response
+#[derive(Debug)]
+pub(crate) struct Multistatus {
+    /// Every resource in the response
+    pub(crate) responses: Vec<Response>,
+    /// The new token returned by a `sync-collection` report
+    pub(crate) sync_token: Option<String>,
+}
+
+/// Parses the status code out of a status line like `HTTP/1.1 200 OK`
+fn status_code(line: &str) -> Option<u16> {
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Reads a multistatus body, resolving every `href` against the request URL
+fn parse_multistatus(base: &Url, body: &str) -> Result<Multistatus, Errors> {
+    let root = xml::parse(body).map_err(Errors::Xml)?;
+    let responses = root
+        .children(DAV, "response")
+        .filter_map(|response| {
+            let href = response.child_text(DAV, "href")?;
+            let url = base.join(href).ok()?;
+            let properties = response
+                .children(DAV, "propstat")
+                .filter(|propstat| {
+                    propstat
+                        .child_text(DAV, "status")
+                        .and_then(status_code)
+                        .is_some_and(|status| status == 200)
+                })
+                .filter_map(|propstat| propstat.child(DAV, "prop"))
+                .flat_map(|prop| prop.children.iter().cloned())
+                .collect();
+            Some(Response {
+                url,
+                status: response
+                    .child_text(DAV, "status")
+                    .and_then(status_code),
+                properties,
+            })
+        })
+        .collect();
+    Ok(Multistatus {
+        responses,
+        sync_token: root.child_text(DAV, "sync-token").map(str::to_owned),
+    })
+}
+
+/// A client for a single CardDAV account
+#[derive(Clone)]
+pub(crate) struct Client {
+    /// The underlying HTTP client, which pools connections
+    http: reqwest::Client,
+    /// The user to authenticate as
+    username: String,
+    /// The password to authenticate with
+    password: String,
+}
+
+impl Client {
+    /// Creates a client for a configured account
+    pub(crate) fn new(account: &CardDavAccount) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            username: account.username.clone(),
+            password: account.password.clone(),
+        }
+    }
+
+    /// Sends an authenticated request
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, Errors> {
+        request
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await
+            .map_err(Errors::Request)
+    }
+
+    /// Sends a `PROPFIND` or `REPORT` request and reads the multistatus
+    /// response
+    ///
+    /// The `Depth` header is left out when no depth is given, as
+    /// `sync-collection` reports require.
+    pub(crate) async fn multistatus(
+        &self,
+        method: &str,
+        url: &Url,
+        depth: Option<&str>,
+        body: String,
+    ) -> Result<Multistatus, Errors> {
+        let method = Method::from_bytes(method.as_bytes())
+            .expect("WebDAV method names are valid HTTP methods");
+        let mut request = self
+            .http
+            .request(method, url.clone())
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(body);
+        if let Some(depth) = depth {
+            request = request.header("Depth", depth);
+        }
+        let response = self.send(request).await?;
+        if response.status() != StatusCode::MULTI_STATUS {
+            return Err(Errors::Status {
+                url: url.clone(),
+                status: response.status(),
+            });
+        }
+        let url = response.url().clone();
+        let body = response.text().await.map_err(Errors::Request)?;
+        parse_multistatus(&url, &body)
+    }
+
+    /// Uploads a vCard and responds with its new ETag, if the server sent one
+    ///
+    /// Existing cards are only replaced if they still have the given ETag.
+    /// Without an ETag, the card is only created if nothing exists at the URL.
+    pub(crate) async fn put(
+        &self,
+        url: &Url,
+        vcard: String,
+        etag: Option<&str>,
+    ) -> Result<Option<String>, Errors> {
+        let precondition = match etag {
+            Some(etag) => (header::IF_MATCH, etag),
+            None => (header::IF_NONE_MATCH, "*"),
+        };
+        let response = self
+            .send(
+                self.http
+                    .put(url.clone())
+                    .header(precondition.0, precondition.1)
+                    .header(header::CONTENT_TYPE, "text/vcard; charset=utf-8")
+                    .body(vcard),
+            )
+            .await?;
+        if !response.status().is_success() {
+            return Err(Errors::Status {
+                url: url.clone(),
+                status: response.status(),
+            });
+        }
+        Ok(response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_owned))
+    }
+
+    /// Deletes a vCard if it still has the given ETag
+    ///
+    /// Cards that are already gone count as deleted.
+    pub(crate) async fn delete(
+        &self,
+        url: &Url,
+        etag: Option<&str>,
+    ) -> Result<(), Errors> {
+        let mut request = self.http.delete(url.clone());
+        if let Some(etag) = etag {
+            request = request.header(header::IF_MATCH, etag);
+        }
+        let response = self.send(request).await?;
+        let status = response.status();
+        if status.is_success() || status == StatusCode::NOT_FOUND {
+            Ok(())
+        } else {
+            Err(Errors::Status {
+                url: url.clone(),
+                status,
+            })
+        }
+    }
+}
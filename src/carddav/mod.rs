@@ -0,0 +1,9 @@
+//! Contains and re-exports all CardDAV-related functionality
+
+mod actor;
+mod client;
+#[cfg(test)]
+mod stand_in;
+mod xml;
+
+pub(crate) use actor::*;
@@ -0,0 +1,301 @@
+//! A CardDAV server holding a single address book in memory, for tests
+//!
+//! It answers just the requests the `CardDavActor` sends, and only supports
+//! `getctag` based synchronization. Every change gets a new ETag and a new
+//! `getctag` from a shared revision counter. Each request is answered on a
+//! connection of its own.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+use super::xml;
+
+/// Path of the address book on the server
+const BOOK_PATH: &str = "/contacts/";
+
+/// A card stored on the server
+struct Card {
+    /// The quoted ETag
+    etag: String,
+    /// The vCard
+    data: String,
+}
+
+/// What the server holds
+#[derive(Default)]
+struct Book {
+    /// Cards by path
+    cards: BTreeMap<String, Card>,
+    /// Counter the ETags and the `getctag` are taken from
+    revision: u64,
+}
+
+/// A response to send back
+struct Reply {
+    /// The status line after the protocol, e.g. `200 OK`
+    status: &'static str,
+    /// The ETag header, if any
+    etag: Option<String>,
+    /// The body
+    body: String,
+}
+
+impl Reply {
+    /// A response without a body
+    fn empty(status: &'static str) -> Self {
+        Self {
+            status,
+            etag: None,
+            body: String::new(),
+        }
+    }
+
+    /// A `207 Multi-Status` response with the given `response` elements
+    fn multistatus(responses: &str) -> Self {
+        Self {
+            status: "207 Multi-Status",
+            etag: None,
+            body: format!(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:" xmlns:card="urn:ietf:params:xml:ns:carddav"
+    xmlns:cs="http://calendarserver.org/ns/">
+{responses}</d:multistatus>"#
+            ),
+        }
+    }
+}
+
+/// A `response` element with the given properties
+fn response(href: &str, properties: &str) -> String {
+    format!(
+        "<d:response><d:href>{href}</d:href><d:propstat><d:prop>{properties}</\
+         d:prop><d:status>HTTP/1.1 200 \
+         OK</d:status></d:propstat></d:response>\n"
+    )
+}
+
+impl Book {
+    /// Takes the next ETag
+    fn next_etag(&mut self) -> String {
+        self.revision += 1;
+        format!("\"{}\"", self.revision)
+    }
+
+    /// Answers a request
+    fn respond(
+        &mut self,
+        method: &str,
+        path: &str,
+        headers: &HashMap<String, String>,
+        body: &str,
+    ) -> Reply {
+        let header = |name: &str| headers.get(name).map(String::as_str);
+        match (method, path) {
+            ("PROPFIND", BOOK_PATH) if header("depth") == Some("0") => {
+                Reply::multistatus(&response(
+                    BOOK_PATH,
+                    &format!(
+                        "<d:resourcetype><d:collection/><card:addressbook/></\
+                         d:resourcetype><d:displayname>Contacts</d:\
+                         displayname><cs:getctag>{}</cs:getctag>",
+                        self.revision
+                    ),
+                ))
+            }
+            ("PROPFIND", BOOK_PATH) => {
+                let mut responses = response(BOOK_PATH, "");
+                for (path, card) in &self.cards {
+                    responses.push_str(&response(
+                        path,
+                        &format!("<d:getetag>{}</d:getetag>", card.etag),
+                    ));
+                }
+                Reply::multistatus(&responses)
+            }
+            ("REPORT", BOOK_PATH) => {
+                let mut responses = String::new();
+                let hrefs = body.split("<d:href>").skip(1).filter_map(|rest| {
+                    rest.split_once("</d:href>").map(|(href, _)| href)
+                });
+                for href in hrefs {
+                    let Some(card) = self.cards.get(href) else {
+                        continue;
+                    };
+                    responses.push_str(&response(
+                        href,
+                        &format!(
+                            "<d:getetag>{}</d:getetag><card:address-data>{}</\
+                             card:address-data>",
+                            card.etag,
+                            xml::escape(&card.data)
+                        ),
+                    ));
+                }
+                Reply::multistatus(&responses)
+            }
+            ("PUT", _) if path.starts_with(BOOK_PATH) => {
+                let existing = self.cards.get(path).map(|card| &card.etag);
+                let allowed =
+                    match (header("if-match"), header("if-none-match")) {
+                        (Some(etag), _) => existing.is_some_and(|e| e == etag),
+                        (None, Some("*")) => existing.is_none(),
+                        (None, _) => true,
+                    };
+                if !allowed {
+                    return Reply::empty("412 Precondition Failed");
+                }
+                let etag = self.next_etag();
+                self.cards.insert(
+                    path.to_owned(),
+                    Card {
+                        etag: etag.clone(),
+                        data: body.to_owned(),
+                    },
+                );
+                Reply {
+                    status: "201 Created",
+                    etag: Some(etag),
+                    body: String::new(),
+                }
+            }
+            ("DELETE", _) => {
+                let Some(card) = self.cards.get(path) else {
+                    return Reply::empty("404 Not Found");
+                };
+                if header("if-match").is_some_and(|etag| etag != card.etag) {
+                    return Reply::empty("412 Precondition Failed");
+                }
+                self.cards.remove(path);
+                self.revision += 1;
+                Reply::empty("204 No Content")
+            }
+            _ => Reply::empty("405 Method Not Allowed"),
+        }
+    }
+}
+
+/// Reads a request from a connection and answers it
+fn serve(stream: TcpStream, book: &Mutex<Book>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let Some((name, value)) = line.split_once(':') else {
+            break;
+        };
+        headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or_default();
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let reply = book
+        .lock()
+        .expect("The stand-in never panics while holding the lock")
+        .respond(&method, &path, &headers, &String::from_utf8_lossy(&body));
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        reply.status,
+        reply.body.len()
+    );
+    if let Some(etag) = reply.etag {
+        write!(head, "ETag: {etag}\r\n")
+            .expect("Writing to a String can't fail");
+    }
+    let mut stream = stream;
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(b"\r\n")?;
+    stream.write_all(reply.body.as_bytes())
+}
+
+/// A running stand-in server
+pub(super) struct StandIn {
+    /// The URL of the address book
+    url: String,
+    /// What the server holds, shared with the thread serving it
+    book: Arc<Mutex<Book>>,
+}
+
+impl StandIn {
+    /// Starts a server on a free local port
+    pub(super) fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .expect("A local port should be free");
+        let url = format!(
+            "http://{}{BOOK_PATH}",
+            listener.local_addr().expect("The listener is bound")
+        );
+        let book = Arc::new(Mutex::new(Book::default()));
+        let served = Arc::clone(&book);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = serve(stream, &served) {
+                    log::warn!("CardDAV stand-in failed to answer: {e}");
+                }
+            }
+        });
+        Self {
+            url,
+            book,
+        }
+    }
+
+    /// The URL of the address book
+    pub(super) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Locks what the server holds
+    fn book(&self) -> MutexGuard<'_, Book> {
+        self.book
+            .lock()
+            .expect("The stand-in never panics while holding the lock")
+    }
+
+    /// Creates or replaces a card, as another client would
+    pub(super) fn put(&self, name: &str, vcard: &str) {
+        let mut book = self.book();
+        let etag = book.next_etag();
+        book.cards.insert(
+            format!("{BOOK_PATH}{name}"),
+            Card {
+                etag,
+                data: vcard.to_owned(),
+            },
+        );
+    }
+
+    /// Deletes a card, as another client would
+    pub(super) fn remove(&self, name: &str) {
+        let mut book = self.book();
+        book.cards.remove(&format!("{BOOK_PATH}{name}"));
+        book.revision += 1;
+    }
+
+    /// The vCard stored under a name, if any
+    pub(super) fn card(&self, name: &str) -> Option<String> {
+        self.book()
+            .cards
+            .get(&format!("{BOOK_PATH}{name}"))
+            .map(|card| card.data.clone())
+    }
+
+    /// How many cards the server holds
+    pub(super) fn len(&self) -> usize {
+        self.book().cards.len()
+    }
+}
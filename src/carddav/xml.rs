@@ -0,0 +1,295 @@
+//! A small namespace-aware XML reader for WebDAV responses
+//!
+//! WebDAV servers are free to pick their own namespace prefixes, so elements
+//! are identified by namespace URI and local name rather than by how they are
+//! spelled in the document. Only what multistatus responses need is
+//! supported: elements, attributes, text, CDATA and the predefined and numeric
+//! entities. Comments, processing instructions and doctypes are skipped.
+
+use std::{collections::HashMap, fmt::Display};
+
+/// The `DAV:` namespace defined by RFC 4918
+pub(crate) const DAV: &str = "DAV:";
+/// The CardDAV namespace defined by RFC 6352
+pub(crate) const CARDDAV: &str = "urn:ietf:params:xml:ns:carddav";
+/// The namespace of the `getctag` extension
+pub(crate) const CALENDAR_SERVER: &str = "http://calendarserver.org/ns/";
+
+/// Errors that can occur while reading an XML document
+#[derive(Debug)]
+pub(crate) enum Errors {
+    /// The document ended inside a tag or before the root element was closed
+    UnexpectedEnd,
+    /// A closing tag didn't match the element it closed
+    MismatchedTag(String),
+    /// A tag or entity is malformed
+    Malformed(String),
+    /// An element used a namespace prefix that was never declared
+    UnknownPrefix(String),
+}
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errors::UnexpectedEnd => write!(f, "document ended unexpectedly"),
+            Errors::MismatchedTag(tag) => {
+                write!(f, "closing tag \"{tag}\" doesn't match")
+            }
+            Errors::Malformed(text) => write!(f, "malformed \"{text}\""),
+            Errors::UnknownPrefix(prefix) => {
+                write!(f, "undeclared namespace prefix \"{prefix}\"")
+            }
+        }
+    }
+}
+
+/// An element with its namespace resolved
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Element {
+    /// The namespace URI, empty if the element has none
+    pub(crate) namespace: String,
+    /// The local name, without prefix
+    pub(crate) name: String,
+    /// Child elements in document order
+    pub(crate) children: Vec<Element>,
+    /// All text directly inside the element, with entities resolved
+    pub(crate) text: String,
+}
+
+impl Element {
+    /// Whether the element has the given namespace and local name
+    pub(crate) fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+
+    /// The first child with the given namespace and local name
+    pub(crate) fn child(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(namespace, name))
+    }
+
+    /// Every child with the given namespace and local name
+    pub(crate) fn children<'a>(
+        &'a self,
+        namespace: &'a str,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Element> {
+        self.children.iter().filter(move |child| child.is(namespace, name))
+    }
+
+    /// Whether the element or any element below it has the given namespace
+    /// and local name
+    pub(crate) fn contains(&self, namespace: &str, name: &str) -> bool {
+        self.is(namespace, name)
+            || self.children.iter().any(|child| child.contains(namespace, name))
+    }
+
+    /// The trimmed text of the first child with the given namespace and local
+    /// name
+    pub(crate) fn child_text(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> Option<&str> {
+        self.child(namespace, name).map(|child| child.text.trim())
+    }
+}
+
+/// An element that has been opened but not closed yet
+struct OpenElement {
+    /// The tag as written, to match it against the closing tag
+    tag: String,
+    /// Namespace prefixes declared on this element
+    namespaces: HashMap<String, String>,
+    /// The element being built
+    element: Element,
+}
+
+/// Resolves the predefined and numeric entities in text
+fn decode_entities(text: &str) -> Result<String, Errors> {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((before, after)) = rest.split_once('&') {
+        decoded.push_str(before);
+        let (entity, after) = after.split_once(';').ok_or_else(|| {
+            Errors::Malformed(after.chars().take(10).collect())
+        })?;
+        let character = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| {
+                    entity
+                        .strip_prefix('#')
+                        .and_then(|decimal| decimal.parse().ok())
+                })
+                .and_then(char::from_u32),
+        };
+        decoded.push(
+            character
+                .ok_or_else(|| Errors::Malformed(format!("&{entity};")))?,
+        );
+        rest = after;
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+/// Escapes text so it can be embedded in a request body
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A tag name and its attributes with entities resolved
+type Tag<'a> = (&'a str, Vec<(&'a str, String)>);
+
+/// Splits the inside of a start tag into its name and attributes
+fn parse_tag(inside: &str) -> Result<Tag<'_>, Errors> {
+    let inside = inside.trim();
+    let name_end = inside
+        .find(|character: char| character.is_whitespace())
+        .unwrap_or(inside.len());
+    let (name, mut rest) = inside.split_at(name_end);
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (attribute, after) = rest
+            .split_once('=')
+            .ok_or_else(|| Errors::Malformed(rest.to_owned()))?;
+        let after = after.trim_start();
+        let quote = after
+            .chars()
+            .next()
+            .filter(|quote| *quote == '"' || *quote == '\'')
+            .ok_or_else(|| Errors::Malformed(after.to_owned()))?;
+        let (value, after) = after
+            .get(1..)
+            .and_then(|value| value.split_once(quote))
+            .ok_or_else(|| Errors::Malformed(after.to_owned()))?;
+        attributes.push((attribute.trim(), decode_entities(value)?));
+        rest = after;
+    }
+    Ok((name, attributes))
+}
+
+/// Looks up the namespace bound to a prefix, innermost declaration first
+fn resolve(
+    stack: &[OpenElement],
+    declared: &HashMap<String, String>,
+    prefix: &str,
+) -> Option<String> {
+    declared
+        .get(prefix)
+        .or_else(|| {
+            stack.iter().rev().find_map(|open| open.namespaces.get(prefix))
+        })
+        .cloned()
+}
+
+/// Opens an element, resolving its namespace
+fn open(stack: &[OpenElement], inside: &str) -> Result<OpenElement, Errors> {
+    let (tag, attributes) = parse_tag(inside)?;
+    let mut namespaces = HashMap::new();
+    for (attribute, value) in attributes {
+        if attribute == "xmlns" {
+            namespaces.insert(String::new(), value);
+        } else if let Some(prefix) = attribute.strip_prefix("xmlns:") {
+            namespaces.insert(prefix.to_owned(), value);
+        }
+    }
+    let (prefix, name) = tag.split_once(':').unwrap_or(("", tag));
+    let namespace = match resolve(stack, &namespaces, prefix) {
+        Some(namespace) => namespace,
+        None if prefix.is_empty() => String::new(),
+        None => return Err(Errors::UnknownPrefix(prefix.to_owned())),
+    };
+    Ok(OpenElement {
+        tag: tag.to_owned(),
+        namespaces,
+        element: Element {
+            namespace,
+            name: name.to_owned(),
+            ..Element::default()
+        },
+    })
+}
+
+/// Closes the innermost element, attaching it to its parent
+///
+/// Returns the element if it was the root.
+fn close(
+    stack: &mut Vec<OpenElement>,
+    tag: &str,
+) -> Result<Option<Element>, Errors> {
+    let open =
+        stack.pop().ok_or_else(|| Errors::MismatchedTag(tag.to_owned()))?;
+    if open.tag != tag {
+        return Err(Errors::MismatchedTag(tag.to_owned()));
+    }
+    match stack.last_mut() {
+        Some(parent) => {
+            parent.element.children.push(open.element);
+            Ok(None)
+        }
+        None => Ok(Some(open.element)),
+    }
+}
+
+/// Reads an XML document into its root element
+pub(crate) fn parse(document: &str) -> Result<Element, Errors> {
+    let mut stack: Vec<OpenElement> = Vec::new();
+    let mut rest = document;
+    loop {
+        let (text, after) =
+            rest.split_once('<').ok_or(Errors::UnexpectedEnd)?;
+        if let Some(open) = stack.last_mut() {
+            open.element.text.push_str(&decode_entities(text)?);
+        }
+        if let Some(after) = after.strip_prefix("!--") {
+            rest = after.split_once("-->").ok_or(Errors::UnexpectedEnd)?.1;
+        } else if let Some(after) = after.strip_prefix("![CDATA[") {
+            let (data, after) =
+                after.split_once("]]>").ok_or(Errors::UnexpectedEnd)?;
+            if let Some(open) = stack.last_mut() {
+                open.element.text.push_str(data);
+            }
+            rest = after;
+        } else if after.starts_with('?') || after.starts_with('!') {
+            rest = after.split_once('>').ok_or(Errors::UnexpectedEnd)?.1;
+        } else {
+            let (inside, after) =
+                after.split_once('>').ok_or(Errors::UnexpectedEnd)?;
+            rest = after;
+            let root = if let Some(tag) = inside.strip_prefix('/') {
+                close(&mut stack, tag.trim())?
+            } else if let Some(inside) = inside.strip_suffix('/') {
+                let element = open(&stack, inside)?;
+                let tag = element.tag.clone();
+                stack.push(element);
+                close(&mut stack, &tag)?
+            } else {
+                let element = open(&stack, inside)?;
+                stack.push(element);
+                None
+            };
+            if let Some(root) = root {
+                return Ok(root);
+            }
+        }
+    }
+}
@@ -4,6 +4,8 @@
 //! as the static value `GLOBAL_CONFIG`, which serves as a thread-safe single
 //! source of truth for program configuration.
 
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
 use figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment,
@@ -50,15 +52,181 @@ pub(crate) struct Account {
     pub(crate) imap_password: String,
     /// Port to use for the IMAP server
     pub(crate) imap_port: u16,
+    /// Seconds between synchronizations of folders without an interval of
+    /// their own
+    #[serde(default = "default_sync_interval")]
+    pub(crate) sync_interval: u64,
+    /// Mailboxes to keep in sync. By default only `INBOX` is, every minute
+    /// and with IDLE.
+    #[serde(default = "default_folders")]
+    pub(crate) folders: Vec<Folder>,
+    /// What is copied locally from folders without a policy of their own.
+    /// Everything is by default.
+    #[serde(default)]
+    pub(crate) sync_policy: SyncPolicy,
+    /// Mailbox emails are moved to when they are archived
+    #[serde(default = "default_archive_mailbox")]
+    pub(crate) archive_mailbox: String,
+}
+
+impl Account {
+    /// How long to wait between synchronizations of one of the account's
+    /// folders
+    pub(crate) fn sync_interval(&self, folder: &Folder) -> Duration {
+        Duration::from_secs(folder.sync_interval.unwrap_or(self.sync_interval))
+    }
+
+    /// What is copied locally from one of the account's mailboxes
+    pub(crate) fn sync_policy(&self, mailbox: &str) -> &SyncPolicy {
+        self.folders
+            .iter()
+            .find(|folder| folder.mailbox == mailbox)
+            .and_then(|folder| folder.sync_policy.as_ref())
+            .unwrap_or(&self.sync_policy)
+    }
+}
+
+/// The default number of seconds between synchronizations of a folder
+fn default_sync_interval() -> u64 {
+    15 * 60
+}
+
+/// The mailbox emails are archived to by default
+fn default_archive_mailbox() -> String {
+    "Archive".to_owned()
+}
+
+/// The folders of an account kept in sync by default
+fn default_folders() -> Vec<Folder> {
+    vec![Folder {
+        mailbox: "INBOX".to_owned(),
+        sync_interval: Some(60),
+        idle: true,
+        sync_policy: None,
+    }]
+}
+
+/// Represents a mailbox to keep in sync and how often to synchronize it
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Folder {
+    /// Name of the mailbox, e.g. `INBOX` or `Archive/2024`
+    pub(crate) mailbox: String,
+    /// Seconds between synchronizations, overriding the account's
+    /// `sync_interval`
+    #[serde(default)]
+    pub(crate) sync_interval: Option<u64>,
+    /// Whether to watch the mailbox with IDLE. Polling is paused while the
+    /// server reports changes on its own.
+    #[serde(default)]
+    pub(crate) idle: bool,
+    /// What is copied locally, overriding the account's `sync_policy`
+    #[serde(default)]
+    pub(crate) sync_policy: Option<SyncPolicy>,
+}
+
+/// Limits on what of a mailbox is copied locally
+///
+/// Headers are always synchronized. Bodies outside the limits are fetched on
+/// demand when a message is opened.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct SyncPolicy {
+    /// Only download headers
+    #[serde(default)]
+    pub(crate) headers_only: bool,
+    /// Only download the bodies of messages from the last this many days
+    #[serde(default)]
+    pub(crate) body_days: Option<u32>,
+    /// Of messages larger than this many bytes, only download the text and
+    /// the attachments that fit, leaving out the largest attachments
+    #[serde(default)]
+    pub(crate) max_message_size: Option<u32>,
+}
+
+impl SyncPolicy {
+    /// Whether every message is downloaded in full
+    pub(crate) fn is_complete(&self) -> bool {
+        !self.headers_only
+            && self.body_days.is_none()
+            && self.max_message_size.is_none()
+    }
+}
+
+/// Represents a CardDAV account to synchronize the address book with
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct CardDavAccount {
+    /// URL of the server. This can be the server root, an address book home
+    /// set or a single address book. Plain `http` works for local servers.
+    pub(crate) url: String,
+    /// User to log in as
+    pub(crate) username: String,
+    /// Password to log in with
+    pub(crate) password: String,
+    /// Whether cards created locally are uploaded to the first address book
+    /// of this account
+    #[serde(default)]
+    pub(crate) upload_local_cards: bool,
+    /// Seconds between synchronizations
+    #[serde(default = "default_sync_interval")]
+    pub(crate) sync_interval: u64,
+}
+
+/// The built-in sets of keyboard shortcuts of the GUI
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Keymap {
+    /// Single keys like `j`, `k` and `/`, as in vim and mutt
+    #[default]
+    Vim,
+    /// Shortcuts with modifiers like `ctrl+r`, as in most desktop mail
+    /// clients
+    Conventional,
+}
+
+/// The keyboard shortcuts of the GUI
+///
+/// Keys are written like `j`, `J`, `ctrl+shift+p`, `f5` or `arrowdown`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct KeyBindings {
+    /// The built-in shortcuts to start from
+    #[serde(default)]
+    pub(crate) keymap: Keymap,
+    /// Shortcuts added to or replacing those of the keymap, from key to
+    /// action, e.g. `"ctrl+d" = "delete"`. Binding a key to `"none"` removes
+    /// its shortcut.
+    #[serde(default)]
+    pub(crate) bindings: BTreeMap<String, String>,
 }
 
 /// Data structure that represents the global program configuration.
 ///
 /// Do not derive Debug for this struct. It contains sensitive information!
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct Config {
     /// Accounts for the MailAgent to manage
     accounts: Vec<Account>,
+    /// Directory raw messages and attachments are stored in
+    blob_directory: PathBuf,
+    /// Directory what the user decided, such as saved searches, is kept in
+    /// across restarts
+    state_directory: PathBuf,
+    /// CardDAV accounts to synchronize the address book with
+    carddav_accounts: Vec<CardDavAccount>,
+    /// Keyboard shortcuts of the GUI
+    keybindings: KeyBindings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            accounts: Vec::new(),
+            blob_directory: PathBuf::from("blobs"),
+            state_directory: PathBuf::from("state"),
+            carddav_accounts: Vec::new(),
+            keybindings: KeyBindings::default(),
+        }
+    }
 }
 
 impl Config {
@@ -66,4 +234,24 @@ impl Config {
     pub(crate) fn get_accounts(&self) -> &Vec<Account> {
         &self.accounts
     }
+
+    /// Gets the directory raw messages and attachments are stored in
+    pub(crate) fn get_blob_directory(&self) -> &PathBuf {
+        &self.blob_directory
+    }
+
+    /// Gets the directory what the user decided is kept in across restarts
+    pub(crate) fn get_state_directory(&self) -> &PathBuf {
+        &self.state_directory
+    }
+
+    /// Gets the CardDAV accounts to synchronize the address book with
+    pub(crate) fn get_carddav_accounts(&self) -> &Vec<CardDavAccount> {
+        &self.carddav_accounts
+    }
+
+    /// Gets the keyboard shortcuts of the GUI
+    pub(crate) fn get_keybindings(&self) -> &KeyBindings {
+        &self.keybindings
+    }
 }
@@ -0,0 +1,329 @@
+//! The queue of server-side operations on emails
+//!
+//! Flagging, moving, copying and deleting emails is applied to the local copy
+//! of a mailbox at once and queued here. The `MailActor` of the account runs
+//! the queue in order whenever it can reach the server, so operations made
+//! while offline are replayed once the connection is back. Each queued action
+//! keeps the records it changed locally, so it can be undone if the server
+//! rejects it. The queue is kept in the state store, so actions still waiting
+//! for the server survive restarts.
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use surrealdb::{engine::local::Db, Surreal};
+
+use super::{
+    state_store::StateStore, structures::EmailRecord, DatabaseActor, Errors,
+};
+
+/// Name of the state store table the queue is kept in
+const STATE_TABLE: &str = "actions";
+
+/// Appends an action to the queue of an account
+///
+/// Actions are numbered per account so they can be replayed in the order they
+/// were queued.
+const QUEUE_ACTION_QUERY: &str = "
+    BEGIN TRANSACTION;
+    LET $last = (
+        SELECT VALUE sequence FROM action
+        WHERE account = $account
+        ORDER BY sequence DESC
+        LIMIT 1
+    )[0];
+    LET $sequence = IF $last = NONE { 1 } ELSE { $last + 1 };
+    CREATE type::thing('action', [$account, $sequence]) CONTENT {
+        account: $account,
+        sequence: $sequence,
+        action: $action,
+        before: $before,
+    };
+    COMMIT TRANSACTION;
+";
+
+/// Finds the oldest action queued for an account
+const NEXT_ACTION_QUERY: &str = "
+    SELECT * FROM action WHERE account = $account ORDER BY sequence LIMIT 1;
+";
+
+/// Removes an action from the queue
+const DEQUEUE_ACTION_QUERY: &str = "
+    DELETE type::thing('action', [$account, $sequence]);
+";
+
+/// Selects every queued action in order
+const ALL_ACTIONS_QUERY: &str = "
+    SELECT account, sequence, action, before FROM action
+    ORDER BY account, sequence;
+";
+
+/// Puts back an action kept in the state store
+const RESTORE_ACTION_QUERY: &str = "
+    CREATE type::thing('action', [$stored.account, $stored.sequence])
+        CONTENT $stored;
+";
+
+/// An operation that has to be run on the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Action {
+    /// Add flags to or remove flags from emails
+    Flag {
+        /// The mailbox the emails are in
+        mailbox: String,
+        /// UIDs of the emails
+        uids: Vec<u32>,
+        /// The flags to add or remove, e.g. `\Seen`
+        flags: Vec<String>,
+        /// Whether to add the flags instead of removing them
+        add: bool,
+    },
+    /// Move emails to another mailbox of the same account
+    Move {
+        /// The mailbox the emails are in
+        mailbox: String,
+        /// UIDs of the emails
+        uids: Vec<u32>,
+        /// The mailbox to move the emails to
+        destination: String,
+    },
+    /// Copy emails to another mailbox of the same account
+    Copy {
+        /// The mailbox the emails are in
+        mailbox: String,
+        /// UIDs of the emails
+        uids: Vec<u32>,
+        /// The mailbox to copy the emails to
+        destination: String,
+    },
+    /// Delete emails, moving them to the trash unless `permanently` is set
+    Delete {
+        /// The mailbox the emails are in
+        mailbox: String,
+        /// UIDs of the emails
+        uids: Vec<u32>,
+        /// Whether to expunge the emails instead of moving them to the trash
+        permanently: bool,
+    },
+}
+
+impl Action {
+    /// The mailbox the action applies to
+    pub(crate) fn mailbox(&self) -> &str {
+        match self {
+            Action::Flag {
+                mailbox,
+                ..
+            }
+            | Action::Move {
+                mailbox,
+                ..
+            }
+            | Action::Copy {
+                mailbox,
+                ..
+            }
+            | Action::Delete {
+                mailbox,
+                ..
+            } => mailbox,
+        }
+    }
+
+    /// UIDs of the emails the action applies to
+    pub(crate) fn uids(&self) -> &[u32] {
+        match self {
+            Action::Flag {
+                uids,
+                ..
+            }
+            | Action::Move {
+                uids,
+                ..
+            }
+            | Action::Copy {
+                uids,
+                ..
+            }
+            | Action::Delete {
+                uids,
+                ..
+            } => uids,
+        }
+    }
+}
+
+/// An action waiting in the queue
+#[derive(Deserialize, Debug)]
+pub(crate) struct QueuedAction {
+    /// Position of the action in the queue of its account
+    pub(crate) sequence: u64,
+    /// The operation to run on the server
+    pub(crate) action: Action,
+    /// The local records the action changed, as they were before
+    pub(crate) before: Vec<EmailRecord>,
+}
+
+/// An action waiting in the queue, as kept in the state store
+#[derive(Serialize, Deserialize)]
+struct StoredAction {
+    /// The account to run the action on
+    account: String,
+    /// Position of the action in the queue of its account
+    sequence: u64,
+    /// The operation to run on the server
+    action: Action,
+    /// The local records the action changed, as they were before
+    before: Vec<EmailRecord>,
+}
+
+/// Adds the actions kept in the state store to a new database
+pub(super) async fn restore(
+    database: &Surreal<Db>,
+    state: &StateStore,
+) -> Result<(), Errors> {
+    let actions: Vec<StoredAction> =
+        state.load(STATE_TABLE).map_err(Errors::State)?;
+    for stored in actions {
+        database
+            .query(RESTORE_ACTION_QUERY)
+            .bind(("stored", stored))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(Errors::query)?;
+    }
+    Ok(())
+}
+
+/// Message requesting that an action is appended to the queue of an account
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct QueueActionMessage {
+    /// The account to run the action on
+    pub(crate) account: String,
+    /// The operation to run on the server
+    pub(crate) action: Action,
+    /// The local records the action changed, as they were before
+    pub(crate) before: Vec<EmailRecord>,
+}
+
+impl Handler<QueueActionMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: QueueActionMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received {:?} to queue for {}",
+            msg.action,
+            msg.account
+        );
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(QUEUE_ACTION_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("action", msg.action))
+                    .bind(("before", msg.before))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                state
+                    .persist::<StoredAction>(
+                        &database,
+                        STATE_TABLE,
+                        ALL_ACTIONS_QUERY,
+                    )
+                    .await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("queue action", result)),
+        )
+    }
+}
+
+/// Message requesting the oldest action queued for an account
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Option<QueuedAction>, Errors>")]
+pub(crate) struct NextActionMessage {
+    /// The account whose queue to look at
+    pub(crate) account: String,
+}
+
+impl Handler<NextActionMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Option<QueuedAction>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: NextActionMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let actions: Vec<QueuedAction> = database
+                    .query(NEXT_ACTION_QUERY)
+                    .bind(("account", msg.account))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)?;
+                Ok(actions.into_iter().next())
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("read action queue", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting that an action is removed from the queue once it has
+/// been dealt with
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct DequeueActionMessage {
+    /// The account the action belongs to
+    pub(crate) account: String,
+    /// Position of the action in the queue of its account
+    pub(crate) sequence: u64,
+}
+
+impl Handler<DequeueActionMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: DequeueActionMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(DEQUEUE_ACTION_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("sequence", msg.sequence))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                state
+                    .persist::<StoredAction>(
+                        &database,
+                        STATE_TABLE,
+                        ALL_ACTIONS_QUERY,
+                    )
+                    .await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("dequeue action", result)),
+        )
+    }
+}
@@ -1,85 +1,427 @@
+use std::{collections::HashSet, fmt::Display, io, path::Path, time::Duration};
+
 use actix::prelude::*;
 use surrealdb::{
     engine::local::{Db, Mem},
+    sql::Thing,
     Surreal,
 };
 
-use crate::{database::structures::EmailRecord, mail::ImapEmail};
+use super::{
+    action_queue, address_book,
+    blob_store::BlobStore,
+    card_sync,
+    contacts::RefreshContactsMessage,
+    notifications::EmailsChangedMessage,
+    query::ParseError,
+    remote_content, saved_searches,
+    search::SEARCH_SCHEMA,
+    state_store::StateStore,
+    structures::{AttachmentRecord, EmailRecord},
+    threading::RethreadMessage,
+    vcard,
+};
+use crate::mail::{mime, ImapEmail};
+
+/// How many emails the mail actors should batch into a single
+/// `InsertEmailsMessage`
+pub(crate) const INSERT_CHUNK_SIZE: usize = 500;
+
+/// Inserts or updates a batch of emails in a single transaction
+///
+/// Records are keyed by account, mailbox and UID so that syncing the same
+/// mailbox twice updates the existing records instead of duplicating them.
+/// Messages from Gmail are keyed by account and Gmail's message ID instead,
+/// so a message that appears in several label folders has a single record.
+/// Its locations in other mailboxes are kept, while those in the mailboxes
+/// being written are replaced, as the UIDs there may have changed. Bodies and
+/// attachments stored earlier are kept if a record comes without them, so a
+/// sync that only fetched headers doesn't drop bodies downloaded earlier.
+pub(super) const INSERT_EMAILS_QUERY: &str = "
+    BEGIN TRANSACTION;
+    FOR $email IN $emails {
+        LET $key = IF $email.gmail_message_id != NONE {
+            [$email.account, $email.gmail_message_id]
+        } ELSE {
+            [$email.account, $email.locations[0].mailbox,
+                $email.locations[0].uid]
+        };
+        LET $previous = (
+            SELECT attachments, locations FROM type::thing('mail', $key)
+        )[0];
+        UPDATE type::thing('mail', $key) MERGE $email;
+        UPDATE type::thing('mail', $key) SET locations = array::concat(
+            ($previous.locations ?? [])[
+                WHERE mailbox NOTINSIDE $email.locations.mailbox
+            ],
+            $email.locations
+        );
+        IF $email.raw = NONE AND $previous != NONE {
+            UPDATE type::thing('mail', $key)
+                SET attachments = $previous.attachments;
+        };
+    };
+    COMMIT TRANSACTION;
+";
+
+/// Selects the hashes of every blob referenced by an email record
+const REFERENCED_BLOBS_QUERY: &str = "
+    SELECT VALUE raw FROM mail WHERE raw != NONE;
+    SELECT VALUE attachments.blob FROM mail;
+";
+
+/// How long an unreferenced blob is kept before garbage collection may delete
+/// it. This protects blobs whose records haven't been committed yet.
+const BLOB_GRACE_PERIOD: Duration = Duration::from_hours(1);
+
+/// How often blobs no longer referenced by any record are deleted
+const GARBAGE_COLLECTION_INTERVAL: Duration = Duration::from_hours(6);
+
+/// Errors that can occur while interacting with the database
+#[derive(Debug)]
+pub(crate) enum Errors {
+    /// The in-memory database could not be created
+    Create(Box<surrealdb::Error>),
+    /// The blob store could not be read or written
+    Blob(io::Error),
+    /// A file of the state directory could not be read or written
+    State(io::Error),
+    /// The database could not switch to the mail namespace and database
+    Namespace(Box<surrealdb::Error>),
+    /// The tables, analyzers or indexes could not be defined
+    Schema(Box<surrealdb::Error>),
+    /// A query failed to execute or one of its statements returned an error
+    Query(Box<surrealdb::Error>),
+    /// A search query could not be parsed
+    Parse(ParseError),
+    /// A vCard file could not be parsed
+    VCard(vcard::ParseError),
+}
+
+impl Errors {
+    /// Wraps an error returned by a query
+    pub(super) fn query(error: surrealdb::Error) -> Self {
+        Self::Query(Box::new(error))
+    }
+}
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errors::Create(e) => write!(f, "failed to create database: {e}"),
+            Errors::Blob(e) => write!(f, "blob store failed: {e}"),
+            Errors::State(e) => write!(f, "state store failed: {e}"),
+            Errors::Namespace(e) => {
+                write!(f, "failed to select mail database: {e}")
+            }
+            Errors::Schema(e) => write!(f, "failed to define schema: {e}"),
+            Errors::Query(e) => write!(f, "query failed: {e}"),
+            Errors::Parse(e) => write!(f, "invalid search query: {e}"),
+            Errors::VCard(e) => write!(f, "invalid vCard file: {e}"),
+        }
+    }
+}
+
+/// The health of the database as last observed by the `DatabaseActor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Health {
+    /// The last database operation succeeded
+    Healthy,
+    /// One or more database operations in a row have failed
+    Degraded {
+        /// How many operations have failed since the last success
+        failures: u32,
+        /// Description of the most recent failure
+        last_error: String,
+    },
+}
 
 /// An actor that handles all transactions for a database
 pub(crate) struct DatabaseActor {
     /// Connection to the in-memory database
     pub(crate) database: Surreal<Db>,
+    /// Store for raw messages and attachments referenced by records
+    pub(super) blobs: BlobStore,
+    /// Store for the tables that are kept across restarts
+    pub(super) state: StateStore,
+    /// Health of the database, updated after every operation
+    health: Health,
+    /// Actors to notify when emails change
+    pub(super) subscribers: Vec<Recipient<EmailsChangedMessage>>,
+    /// Addresses of the accounts emails were inserted for, in lowercase.
+    /// They are left out of the contacts.
+    pub(super) accounts: HashSet<String>,
 }
 
 impl DatabaseActor {
-    /// Creates a new database
-    pub(crate) async fn new() -> Self {
+    /// Creates a new database that keeps its blobs in `blob_directory`, and
+    /// what the user decided in `state_directory`
+    ///
+    /// What was kept in `state_directory` is restored.
+    pub(crate) async fn new(
+        blob_directory: &Path,
+        state_directory: &Path,
+    ) -> Result<Self, Errors> {
+        let blobs = BlobStore::new(blob_directory).map_err(Errors::Blob)?;
+        let state = StateStore::new(state_directory).map_err(Errors::State)?;
         let db = Surreal::new::<Mem>(())
             .await
-            .expect("Failed to create in-memory surreal database");
-        Self {
+            .map_err(|e| Errors::Create(Box::new(e)))?;
+        db.use_ns("weasel")
+            .use_db("mail")
+            .await
+            .map_err(|e| Errors::Namespace(Box::new(e)))?;
+        db.query(SEARCH_SCHEMA)
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(|e| Errors::Schema(Box::new(e)))?;
+        saved_searches::restore(&db, &state).await?;
+        remote_content::restore(&db, &state).await?;
+        action_queue::restore(&db, &state).await?;
+        address_book::restore(&db, &state).await?;
+        card_sync::restore(&db, &state).await?;
+        Ok(Self {
             database: db,
+            blobs,
+            state,
+            health: Health::Healthy,
+            subscribers: Vec::new(),
+            accounts: HashSet::new(),
+        })
+    }
+
+    /// Logs the result of a database operation and updates the health status
+    /// accordingly
+    pub(super) fn track<T>(
+        &mut self,
+        operation: &str,
+        result: Result<T, Errors>,
+    ) -> Result<T, Errors> {
+        match &result {
+            Ok(_) => {
+                if self.health != Health::Healthy {
+                    log::info!("Database recovered after {operation}");
+                }
+                self.health = Health::Healthy;
+            }
+            Err(e) => {
+                log::error!("Database failed to {operation}: {e}");
+                let failures = match &self.health {
+                    Health::Healthy => 1,
+                    Health::Degraded {
+                        failures,
+                        ..
+                    } => failures.saturating_add(1),
+                };
+                self.health = Health::Degraded {
+                    failures,
+                    last_error: format!("Failed to {operation}: {e}"),
+                };
+            }
         }
+        result
     }
 }
 
+/// Runs blob store operations on a blocking thread, so the database actor
+/// keeps answering while files are read and written
+pub(super) async fn unblock<T, F>(operation: F) -> Result<T, Errors>
+where
+    F: FnOnce() -> Result<T, Errors> + Send + 'static,
+    T: Send + 'static,
+{
+    actix_rt::task::spawn_blocking(operation)
+        .await
+        .map_err(|e| Errors::Blob(io::Error::other(e)))?
+}
+
+/// Writes the raw source and attachments of an email to the blob store
+///
+/// Returns the hash of the raw source and references to the attachments.
+fn store_blobs(
+    blobs: &BlobStore,
+    email: &ImapEmail,
+) -> Result<(Option<String>, Vec<AttachmentRecord>), Errors> {
+    let Some(raw) = &email.raw else {
+        return Ok((None, Vec::new()));
+    };
+    let raw_hash = blobs.put(raw).map_err(Errors::Blob)?;
+    let mut attachments = Vec::new();
+    for attachment in mime::attachments(raw) {
+        attachments.push(AttachmentRecord {
+            blob: blobs.put(&attachment.data).map_err(Errors::Blob)?,
+            filename: attachment.filename,
+            mime_type: attachment.mime_type,
+            size: attachment.data.len(),
+        });
+    }
+    Ok((Some(raw_hash), attachments))
+}
+
 impl Actor for DatabaseActor {
     type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
+    fn started(&mut self, ctx: &mut Self::Context) {
         log::trace!("Database actor started");
+        ctx.run_interval(GARBAGE_COLLECTION_INTERVAL, |_actor, ctx| {
+            ctx.notify(CollectGarbageMessage);
+        });
     }
 }
 
-/// Message containing a new email to insert into the database
+/// Message containing a batch of emails fetched from a single mailbox
+///
+/// The whole batch is written in one transaction. The response is only sent
+/// once the transaction has finished, so senders that await it before sending
+/// the next batch never have more than one batch queued. The conversations of
+/// the emails are threaded again afterwards, and the contacts updated with
+/// their addresses.
 #[derive(Message, Debug)]
-#[rtype(result = "()")]
-pub(crate) struct NewEmailMessage {
-    /// The new email to insert into the database
-    pub(crate) email: ImapEmail,
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct InsertEmailsMessage {
+    /// The account the emails were fetched from
+    pub(crate) account: String,
+    /// The mailbox the emails were fetched from
+    pub(crate) mailbox: String,
+    /// The new emails to insert into the database
+    pub(crate) emails: Vec<ImapEmail>,
 }
 
-impl Handler<NewEmailMessage> for DatabaseActor {
-    type Result = ();
+impl Handler<InsertEmailsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
 
     fn handle(
         &mut self,
-        msg: NewEmailMessage,
+        msg: InsertEmailsMessage,
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
-        log::trace!("Database actor received {msg:?}");
-        // Create the email record from IMAP response
-        let email = msg.email;
-        let email_record = EmailRecord::from(email);
+        log::trace!(
+            "Database actor received {} emails from {} for {}",
+            msg.emails.len(),
+            msg.mailbox,
+            msg.account
+        );
+        let operation = format!(
+            "insert {} emails from {} for {}",
+            msg.emails.len(),
+            msg.mailbox,
+            msg.account
+        );
+        self.accounts.insert(msg.account.to_lowercase());
+        let database = self.database.clone();
+        let blobs = self.blobs.clone();
+        let account = msg.account.clone();
+        let mailbox = msg.mailbox.clone();
+        let emails = msg.emails;
+        Box::pin(
+            async move {
+                let records = unblock(move || {
+                    emails
+                        .into_iter()
+                        .map(|email| {
+                            let (raw, attachments) =
+                                store_blobs(&blobs, &email)?;
+                            Ok(EmailRecord::new(
+                                &account,
+                                &mailbox,
+                                email,
+                                raw,
+                                attachments,
+                            ))
+                        })
+                        .collect::<Result<Vec<EmailRecord>, Errors>>()
+                })
+                .await?;
+                let ids: Vec<Thing> =
+                    records.iter().map(EmailRecord::id).collect();
+                database
+                    .query(INSERT_EMAILS_QUERY)
+                    .bind(("emails", records))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map(|_| ids)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(move |result, actor, ctx| {
+                let result = result.map(|emails| {
+                    actor.notify(&msg.account, &msg.mailbox);
+                    ctx.notify(RefreshContactsMessage {
+                        emails: emails.clone(),
+                    });
+                    ctx.notify(RethreadMessage {
+                        account: msg.account.clone(),
+                        emails,
+                    });
+                });
+                actor.track(&operation, result)
+            }),
+        )
+    }
+}
+
+/// Message requesting that blobs no longer referenced by any record are
+/// deleted
+///
+/// Responds with the number of deleted blobs.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<usize, Errors>")]
+pub(crate) struct CollectGarbageMessage;
 
-        // Run the async database operations
+impl Handler<CollectGarbageMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<usize, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: CollectGarbageMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
         let database = self.database.clone();
-        actix::spawn(async move {
-            database.use_ns("weasel").use_db("mail").await.expect(
-                "Failed to change to mail database. A malfunctioning database \
-                 is not recoverable.",
-            );
-            let _: Vec<EmailRecord> =
-                database.create("mail").content(email_record).await.expect("");
-        });
-        log::trace!("Added new email");
+        let blobs = self.blobs.clone();
+        Box::pin(
+            async move {
+                let mut response = database
+                    .query(REFERENCED_BLOBS_QUERY)
+                    .await
+                    .map_err(Errors::query)?;
+                let raw: Vec<String> =
+                    response.take(0).map_err(Errors::query)?;
+                let attachments: Vec<Vec<String>> =
+                    response.take(1).map_err(Errors::query)?;
+                let referenced: HashSet<String> = raw
+                    .into_iter()
+                    .chain(attachments.into_iter().flatten())
+                    .collect();
+                let removed = unblock(move || {
+                    blobs
+                        .collect_garbage(&referenced, BLOB_GRACE_PERIOD)
+                        .map_err(Errors::Blob)
+                })
+                .await?;
+                log::info!("Removed {removed} unreferenced blobs");
+                Ok(removed)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("collect garbage", result)),
+        )
     }
 }
 
-/// Test
+/// Message requesting the current health of the database
 #[derive(Message, Debug)]
-#[rtype(result = "()")]
-pub(crate) struct GuiMessage;
+#[rtype(result = "Health")]
+pub(crate) struct HealthMessage;
 
-impl Handler<GuiMessage> for DatabaseActor {
-    type Result = ();
+impl Handler<HealthMessage> for DatabaseActor {
+    type Result = MessageResult<HealthMessage>;
 
     fn handle(
         &mut self,
-        msg: GuiMessage,
+        msg: HealthMessage,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
         log::trace!("DatabaseActor received {msg:?}");
+        MessageResult(self.health.clone())
     }
 }
@@ -0,0 +1,549 @@
+//! The address book the user maintains, as opposed to the contacts harvested
+//! from emails
+//!
+//! Cards are keyed by their vCard `UID`, so importing the same file twice
+//! updates the existing cards instead of duplicating them. The address book
+//! is kept in the state store, along with the deletions still to be pushed to
+//! CardDAV servers.
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use surrealdb::{
+    engine::local::Db,
+    sql::{Thing, Uuid},
+    Surreal,
+};
+use time::OffsetDateTime;
+
+use super::{
+    state_store::StateStore,
+    structures::ContactRecord,
+    vcard::{self, Version},
+    DatabaseActor, Errors,
+};
+
+/// Name of the state store table the cards are kept in
+const CARDS_TABLE: &str = "cards";
+
+/// Name of the state store table the deletions still to be pushed are kept in
+const DELETIONS_TABLE: &str = "card_deletions";
+
+/// Writes cards in a single transaction, replacing the contents of existing
+/// cards with the same UID
+///
+/// The cards are merged so that existing cards keep their place on the
+/// CardDAV server.
+const UPSERT_CARDS_QUERY: &str = "
+    BEGIN TRANSACTION;
+    FOR $card IN $cards {
+        UPDATE type::thing('card', $card.uid) MERGE $card;
+    };
+    COMMIT TRANSACTION;
+";
+
+/// Writes a single card and returns it as stored
+const SAVE_CARD_QUERY: &str = "
+    UPDATE type::thing('card', $card.uid) CONTENT $card RETURN AFTER;
+";
+
+/// Deletes a card by UID
+///
+/// Cards that came from a CardDAV server leave a `card_deletion` record
+/// behind so the deletion can be pushed to the server.
+const DELETE_CARD_QUERY: &str = "
+    BEGIN TRANSACTION;
+    LET $card = (
+        SELECT address_book, href, etag FROM type::thing('card', $uid)
+    )[0];
+    IF $card.href != NONE {
+        CREATE card_deletion CONTENT $card;
+    };
+    DELETE type::thing('card', $uid);
+    COMMIT TRANSACTION;
+";
+
+/// Selects every card as kept in the state store
+const ALL_CARDS_QUERY: &str = "SELECT * OMIT id FROM card";
+
+/// Selects every deletion still to be pushed
+const ALL_DELETIONS_QUERY: &str =
+    "SELECT address_book, href, etag FROM card_deletion";
+
+/// Writes back a card kept in the state store
+const RESTORE_CARD_QUERY: &str = "
+    UPDATE type::thing('card', $card.uid) CONTENT $card;
+";
+
+/// Writes back a deletion kept in the state store
+const RESTORE_DELETION_QUERY: &str = "
+    CREATE card_deletion CONTENT $deletion;
+";
+
+/// Lists every card by name
+const LIST_CARDS_QUERY: &str = "
+    SELECT * FROM card ORDER BY name COLLATE;
+";
+
+/// Lists the cards in a group by name
+const LIST_GROUP_QUERY: &str = "
+    SELECT * FROM card WHERE $group INSIDE groups ORDER BY name COLLATE;
+";
+
+/// An email address on a card
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CardEmail {
+    /// The email address
+    pub(crate) address: String,
+    /// Lowercase vCard types such as `work` or `home`
+    pub(crate) kinds: Vec<String>,
+    /// Whether this is the address to use by default
+    pub(crate) preferred: bool,
+}
+
+/// A phone number on a card
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CardPhone {
+    /// The phone number as entered
+    pub(crate) number: String,
+    /// Lowercase vCard types such as `cell` or `work`
+    pub(crate) kinds: Vec<String>,
+    /// Whether this is the number to use by default
+    pub(crate) preferred: bool,
+}
+
+/// A person or organization in the address book
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct CardRecord {
+    /// ID of the card record. Only set when read from the database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<Thing>,
+    /// The vCard `UID`. Left empty for new cards, which get one when saved.
+    pub(crate) uid: String,
+    /// The name to display
+    pub(crate) name: String,
+    /// Family name, from the structured `N` property
+    pub(crate) family_name: Option<String>,
+    /// Given name, from the structured `N` property
+    pub(crate) given_name: Option<String>,
+    /// The organization the contact belongs to
+    pub(crate) organization: Option<String>,
+    /// Email addresses
+    pub(crate) emails: Vec<CardEmail>,
+    /// Phone numbers
+    pub(crate) phones: Vec<CardPhone>,
+    /// Groups the contact belongs to, stored as vCard `CATEGORIES`
+    pub(crate) groups: Vec<String>,
+    /// Free-form notes
+    pub(crate) note: Option<String>,
+    /// When the card was last changed
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub(crate) revision: Option<OffsetDateTime>,
+    /// Unfolded content lines of imported properties that aren't modelled
+    /// above, kept so they can be exported again
+    pub(crate) extra: Vec<String>,
+    /// The vCard version the `extra` lines were imported from, if any
+    pub(crate) extra_version: Option<Version>,
+    /// URL of the CardDAV address book the card is synchronized with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) address_book: Option<String>,
+    /// URL of the card on the CardDAV server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) href: Option<String>,
+    /// ETag of the card on the CardDAV server when it was last synchronized
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) etag: Option<String>,
+    /// Whether the card was changed locally since it was last synchronized
+    pub(crate) modified: bool,
+}
+
+/// A card deleted locally that still has to be deleted on its CardDAV
+/// server, as kept in the state store
+#[derive(Serialize, Deserialize)]
+struct CardDeletion {
+    /// URL of the address book collection the card was in
+    address_book: Option<String>,
+    /// URL of the card on the server
+    href: String,
+    /// ETag of the card when it was last synchronized
+    etag: Option<String>,
+}
+
+impl CardRecord {
+    /// A name for cards that don't have a display name
+    pub(crate) fn fallback_name(&self) -> String {
+        let structured: Vec<&str> = [&self.given_name, &self.family_name]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        if !structured.is_empty() {
+            return structured.join(" ");
+        }
+        self.organization
+            .clone()
+            .or_else(|| self.emails.first().map(|email| email.address.clone()))
+            .unwrap_or_default()
+    }
+
+    /// Gets a locally changed card ready to be written to the database
+    ///
+    /// New cards get a UID and unnamed cards a fallback name. The card is
+    /// marked as modified so it is pushed on the next synchronization.
+    fn prepare(mut self) -> Self {
+        self.id = None;
+        self.modified = true;
+        if self.uid.trim().is_empty() {
+            self.uid = format!("urn:uuid:{}", Uuid::new_v4().to_raw());
+        }
+        if self.name.trim().is_empty() {
+            self.name = self.fallback_name();
+        }
+        self
+    }
+}
+
+impl From<ContactRecord> for CardRecord {
+    fn from(contact: ContactRecord) -> Self {
+        Self {
+            name: contact.name.unwrap_or_default(),
+            emails: vec![CardEmail {
+                address: contact.address,
+                kinds: Vec::new(),
+                preferred: true,
+            }],
+            ..Self::default()
+        }
+    }
+}
+
+/// Writes every card to the state store
+pub(super) async fn persist_cards(
+    database: &Surreal<Db>,
+    state: &StateStore,
+) -> Result<(), Errors> {
+    state.persist::<CardRecord>(database, CARDS_TABLE, ALL_CARDS_QUERY).await
+}
+
+/// Writes every deletion still to be pushed to the state store
+pub(super) async fn persist_deletions(
+    database: &Surreal<Db>,
+    state: &StateStore,
+) -> Result<(), Errors> {
+    state
+        .persist::<CardDeletion>(database, DELETIONS_TABLE, ALL_DELETIONS_QUERY)
+        .await
+}
+
+/// Adds the cards and deletions kept in the state store to a new database
+pub(super) async fn restore(
+    database: &Surreal<Db>,
+    state: &StateStore,
+) -> Result<(), Errors> {
+    let cards: Vec<CardRecord> =
+        state.load(CARDS_TABLE).map_err(Errors::State)?;
+    for card in cards {
+        database
+            .query(RESTORE_CARD_QUERY)
+            .bind(("card", card))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(Errors::query)?;
+    }
+    let deletions: Vec<CardDeletion> =
+        state.load(DELETIONS_TABLE).map_err(Errors::State)?;
+    for deletion in deletions {
+        database
+            .query(RESTORE_DELETION_QUERY)
+            .bind(("deletion", deletion))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(Errors::query)?;
+    }
+    Ok(())
+}
+
+/// Message requesting the cards in the address book, by name
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<CardRecord>, Errors>")]
+pub(crate) struct CardsMessage {
+    /// Only list the cards in this group
+    pub(crate) group: Option<String>,
+}
+
+impl Handler<CardsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Vec<CardRecord>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: CardsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let statement = match msg.group {
+                    Some(group) => {
+                        database.query(LIST_GROUP_QUERY).bind(("group", group))
+                    }
+                    None => database.query(LIST_CARDS_QUERY),
+                };
+                statement
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("list cards", result)),
+        )
+    }
+}
+
+/// Message requesting that a card is created or updated
+///
+/// The revision is set to the current time. Responds with the card as stored.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Option<CardRecord>, Errors>")]
+pub(crate) struct SaveCardMessage {
+    /// The card to save. Cards with an empty UID are created.
+    pub(crate) card: CardRecord,
+}
+
+impl Handler<SaveCardMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Option<CardRecord>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: SaveCardMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let mut card = msg.card.prepare();
+        card.revision = Some(OffsetDateTime::now_utc());
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                let card = database
+                    .query(SAVE_CARD_QUERY)
+                    .bind(("card", card))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)?;
+                persist_cards(&database, &state).await?;
+                Ok(card)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("save card", result)),
+        )
+    }
+}
+
+/// Message requesting that a card is removed from the address book
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct DeleteCardMessage {
+    /// UID of the card to delete
+    pub(crate) uid: String,
+}
+
+impl Handler<DeleteCardMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: DeleteCardMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(DELETE_CARD_QUERY)
+                    .bind(("uid", msg.uid))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                persist_cards(&database, &state).await?;
+                persist_deletions(&database, &state).await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("delete card", result)),
+        )
+    }
+}
+
+/// Message requesting that the cards in a `.vcf` file are imported
+///
+/// Cards whose UID is already in the address book replace the existing card.
+/// Responds with the number of imported cards.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<usize, Errors>")]
+pub(crate) struct ImportVCardsMessage {
+    /// Contents of the `.vcf` file
+    pub(crate) vcf: String,
+}
+
+impl Handler<ImportVCardsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<usize, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ImportVCardsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received {} bytes of vCards to import",
+            msg.vcf.len()
+        );
+        let cards: Vec<CardRecord> = match vcard::parse(&msg.vcf) {
+            Ok(cards) => cards.into_iter().map(CardRecord::prepare).collect(),
+            Err(e) => return Box::pin(fut::ready(Err(Errors::VCard(e)))),
+        };
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                let count = cards.len();
+                database
+                    .query(UPSERT_CARDS_QUERY)
+                    .bind(("cards", cards))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                persist_cards(&database, &state).await?;
+                Ok(count)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("import vCards", result)),
+        )
+    }
+}
+
+/// Message requesting the address book as a `.vcf` file
+#[derive(Message, Debug)]
+#[rtype(result = "Result<String, Errors>")]
+pub(crate) struct ExportVCardsMessage {
+    /// The vCard version to write
+    pub(crate) version: Version,
+    /// Only export the cards in this group
+    pub(crate) group: Option<String>,
+}
+
+impl Handler<ExportVCardsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<String, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ExportVCardsMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let version = msg.version;
+        Box::pin(
+            self.handle(
+                CardsMessage {
+                    group: msg.group,
+                },
+                ctx,
+            )
+            .map(move |result, _actor, _ctx| {
+                result.map(|cards| {
+                    cards
+                        .iter()
+                        .map(|card| vcard::write(card, version))
+                        .collect()
+                })
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+
+    use super::{CardRecord, CardsMessage, DeleteCardMessage, SaveCardMessage};
+    use crate::database::{
+        card_sync::PendingChangesMessage, testing::start, DatabaseActor,
+    };
+
+    /// Saves a card in the address book
+    async fn save(database: &Addr<DatabaseActor>, card: CardRecord) {
+        database
+            .send(SaveCardMessage {
+                card,
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The card should be saved");
+    }
+
+    /// Cards, and deletions not pushed yet, are still there after a restart
+    #[actix_rt::test]
+    async fn keeps_cards_across_restarts() {
+        let (directory, database, _) = start("address-book-restart").await;
+        save(
+            &database,
+            CardRecord {
+                name: "Jane Doe".to_owned(),
+                ..CardRecord::default()
+            },
+        )
+        .await;
+        save(
+            &database,
+            CardRecord {
+                uid: "john".to_owned(),
+                name: "John Doe".to_owned(),
+                address_book: Some("https://dav.example.com/book/".to_owned()),
+                href: Some("https://dav.example.com/book/john.vcf".to_owned()),
+                ..CardRecord::default()
+            },
+        )
+        .await;
+        database
+            .send(DeleteCardMessage {
+                uid: "john".to_owned(),
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The card should be deleted");
+
+        let restarted = DatabaseActor::new(
+            &directory.path().join("blobs"),
+            &directory.path().join("state"),
+        )
+        .await
+        .expect("The database should restart")
+        .start();
+        let cards = restarted
+            .send(CardsMessage {
+                group: None,
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The cards should be listed");
+        let names: Vec<&str> =
+            cards.iter().map(|card| card.name.as_str()).collect();
+        assert_eq!(names, vec!["Jane Doe"]);
+        let pending = restarted
+            .send(PendingChangesMessage {
+                url: "https://dav.example.com/book/".to_owned(),
+                include_unsynced: false,
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The changes should be listed");
+        let deleted: Vec<&str> = pending
+            .deletions
+            .iter()
+            .map(|deletion| deletion.href.as_str())
+            .collect();
+        assert_eq!(deleted, vec!["https://dav.example.com/book/john.vcf"]);
+    }
+}
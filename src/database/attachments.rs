@@ -0,0 +1,39 @@
+//! Copies attachments out of the blob store into files the user can open
+
+use std::{fs, path::PathBuf};
+
+use actix::prelude::*;
+
+use super::{actor::unblock, DatabaseActor, Errors};
+
+/// Message writing an attachment to a file
+///
+/// Directories missing on the way to the file are created.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct ExportAttachmentMessage {
+    /// Hash of the attachment in the blob store
+    pub(crate) blob: String,
+    /// Where to write the attachment
+    pub(crate) path: PathBuf,
+}
+
+impl Handler<ExportAttachmentMessage> for DatabaseActor {
+    type Result = ResponseFuture<Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ExportAttachmentMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let blobs = self.blobs.clone();
+        Box::pin(unblock(move || {
+            let data = blobs.get(&msg.blob).map_err(Errors::Blob)?;
+            if let Some(parent) = msg.path.parent() {
+                fs::create_dir_all(parent).map_err(Errors::Blob)?;
+            }
+            fs::write(&msg.path, data).map_err(Errors::Blob)
+        }))
+    }
+}
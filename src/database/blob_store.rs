@@ -0,0 +1,147 @@
+//! A content-addressed store for large binary data
+//!
+//! Raw messages and attachments are too large to keep inside database records,
+//! so they are written to files named after the SHA-256 hash of their
+//! contents. Identical data is only ever stored once, no matter how many
+//! records reference it.
+
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+
+/// A directory of blobs keyed by the hex encoded SHA-256 of their contents
+///
+/// Every method reads or writes files, so async callers should run them on a
+/// blocking thread.
+#[derive(Clone)]
+pub(crate) struct BlobStore {
+    /// Directory the blobs are stored in
+    root: PathBuf,
+}
+
+impl BlobStore {
+    /// Opens the blob store in the given directory, creating it if necessary
+    pub(crate) fn new(root: &Path) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_owned(),
+        })
+    }
+
+    /// Gets the path a blob is stored at
+    ///
+    /// Blobs are split into subdirectories by the first two characters of
+    /// their hash to keep directory sizes manageable.
+    fn path(&self, hash: &str) -> PathBuf {
+        let (prefix, rest) = hash.split_at(2.min(hash.len()));
+        self.root.join(prefix).join(rest)
+    }
+
+    /// Stores a blob and returns its hash
+    ///
+    /// If a blob with the same contents already exists, only its modification
+    /// time is refreshed, so garbage collection gives the new reference the
+    /// same grace period as a new blob.
+    pub(crate) fn put(&self, data: &[u8]) -> io::Result<String> {
+        let hash = Sha256::digest(data).iter().fold(
+            String::with_capacity(64),
+            |mut hash, byte| {
+                write!(hash, "{byte:02x}")
+                    .expect("Writing to a String can't fail");
+                hash
+            },
+        );
+        let path = self.path(&hash);
+        match fs::File::options().append(true).open(&path) {
+            Ok(blob) => {
+                blob.set_modified(SystemTime::now())?;
+                return Ok(hash);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so a crash never leaves a truncated
+        // blob under a valid hash
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &path)?;
+        Ok(hash)
+    }
+
+    /// Reads the blob with the given hash
+    pub(crate) fn get(&self, hash: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(hash))
+    }
+
+    /// Deletes every blob whose hash is not in `referenced`
+    ///
+    /// Blobs modified within `grace_period` are kept even if unreferenced,
+    /// since they may belong to records that are still being written.
+    /// Returns how many blobs were deleted.
+    pub(crate) fn collect_garbage(
+        &self,
+        referenced: &HashSet<String>,
+        grace_period: Duration,
+    ) -> io::Result<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for prefix in fs::read_dir(&self.root)? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            let prefix_name = prefix.file_name().to_string_lossy().into_owned();
+            for blob in fs::read_dir(prefix.path())? {
+                let blob = blob?;
+                let hash = format!(
+                    "{prefix_name}{}",
+                    blob.file_name().to_string_lossy()
+                );
+                if referenced.contains(&hash) {
+                    continue;
+                }
+                let age = now
+                    .duration_since(blob.metadata()?.modified()?)
+                    .unwrap_or_default();
+                if age < grace_period {
+                    continue;
+                }
+                fs::remove_file(blob.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, time::Duration};
+
+    use super::{super::testing::TestDirectory, BlobStore};
+
+    /// A blob stored again is kept for the grace period, however old the
+    /// first copy was
+    #[test]
+    fn refreshes_stored_blobs() {
+        let directory = TestDirectory::new("blobs");
+        let store =
+            BlobStore::new(directory.path()).expect("Should open the store");
+        let hash = store.put(b"raw message").expect("Should store the blob");
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.put(b"raw message").ok(), Some(hash));
+        let removed = store
+            .collect_garbage(&HashSet::new(), Duration::from_millis(10))
+            .expect("Should collect garbage");
+        assert_eq!(removed, 0);
+    }
+}
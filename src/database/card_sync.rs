@@ -0,0 +1,447 @@
+//! Bookkeeping for synchronizing the address book with CardDAV servers
+//!
+//! The `CardDavActor` does the talking to servers. These messages let it find
+//! out what changed locally since the last synchronization and store what
+//! changed remotely. What was seen of each address book is kept in the state
+//! store, so a restart picks up where the last synchronization left off.
+
+use std::collections::HashMap;
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use surrealdb::{engine::local::Db, Surreal};
+
+use super::{
+    address_book::{self, CardRecord},
+    state_store::StateStore,
+    DatabaseActor, Errors,
+};
+
+/// Name of the state store table the address book states are kept in
+const STATE_TABLE: &str = "address_books";
+
+/// Looks up what was last seen of an address book
+const ADDRESS_BOOK_STATE_QUERY: &str = "
+    SELECT * FROM type::thing('address_book', $url);
+";
+
+/// Stores what was seen of an address book
+const SAVE_ADDRESS_BOOK_STATE_QUERY: &str = "
+    UPDATE type::thing('address_book', $state.url) CONTENT $state;
+";
+
+/// Selects what was seen of every address book
+const ALL_ADDRESS_BOOK_STATES_QUERY: &str =
+    "SELECT url, name, ctag, sync_token FROM address_book";
+
+/// Finds the cards that have to be uploaded to an address book
+const MODIFIED_CARDS_QUERY: &str = "
+    SELECT * FROM card
+    WHERE modified = true
+        AND (address_book = $url
+            OR ($include_unsynced AND address_book = NONE));
+";
+
+/// Finds the deletions that have to be pushed to an address book
+const DELETIONS_QUERY: &str = "
+    SELECT href, etag FROM card_deletion WHERE address_book = $url;
+";
+
+/// Records that a card was uploaded
+const CARD_PUSHED_QUERY: &str = "
+    UPDATE type::thing('card', $uid) SET
+        address_book = $address_book,
+        href = $href,
+        etag = $etag,
+        modified = false;
+";
+
+/// Forgets a deletion that was pushed
+const DELETION_PUSHED_QUERY: &str = "
+    DELETE card_deletion WHERE href = $href;
+";
+
+/// Lists the ETag of every card known to be in an address book
+const REMOTE_ETAGS_QUERY: &str = "
+    SELECT href, etag FROM card WHERE address_book = $url AND href != NONE;
+";
+
+/// Stores the changes pulled from an address book in a single transaction
+///
+/// Cards with local modifications are left alone so the next push can
+/// upload them. If such a card was deleted on the server, it is detached from
+/// its old URL so the push recreates it.
+const APPLY_REMOTE_CHANGES_QUERY: &str = "
+    BEGIN TRANSACTION;
+    FOR $card IN $cards {
+        UPDATE type::thing('card', $card.uid) CONTENT $card
+        WHERE modified != true;
+    };
+    DELETE card
+    WHERE address_book = $url AND href INSIDE $removed AND modified != true;
+    UPDATE card SET href = NONE, etag = NONE
+    WHERE address_book = $url AND href INSIDE $removed;
+    COMMIT TRANSACTION;
+";
+
+/// What was seen of a CardDAV address book during the last synchronization
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct AddressBookRecord {
+    /// URL of the address book collection
+    pub(crate) url: String,
+    /// The display name of the address book
+    pub(crate) name: Option<String>,
+    /// The `getctag`, which changes whenever any card in the address book does
+    pub(crate) ctag: Option<String>,
+    /// The token to pass to the next `sync-collection` report
+    pub(crate) sync_token: Option<String>,
+}
+
+/// A card that was deleted locally but still has to be deleted on the server
+#[derive(Deserialize, Debug)]
+pub(crate) struct DeletionRecord {
+    /// URL of the card on the server
+    pub(crate) href: String,
+    /// ETag of the card when it was last synchronized
+    pub(crate) etag: Option<String>,
+}
+
+/// Local changes that have to be pushed to an address book
+#[derive(Debug)]
+pub(crate) struct PendingChanges {
+    /// Cards that were created or changed locally
+    pub(crate) cards: Vec<CardRecord>,
+    /// Cards that were deleted locally
+    pub(crate) deletions: Vec<DeletionRecord>,
+}
+
+/// A row of `REMOTE_ETAGS_QUERY`
+#[derive(Deserialize)]
+struct EtagRow {
+    /// URL of the card on the server
+    href: String,
+    /// ETag of the card when it was last synchronized
+    etag: Option<String>,
+}
+
+/// Adds what was seen of the address books kept in the state store to a new
+/// database
+pub(super) async fn restore(
+    database: &Surreal<Db>,
+    state: &StateStore,
+) -> Result<(), Errors> {
+    let address_books: Vec<AddressBookRecord> =
+        state.load(STATE_TABLE).map_err(Errors::State)?;
+    for address_book in address_books {
+        database
+            .query(SAVE_ADDRESS_BOOK_STATE_QUERY)
+            .bind(("state", address_book))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(Errors::query)?;
+    }
+    Ok(())
+}
+
+/// Message requesting what was seen of an address book during the last
+/// synchronization
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Option<AddressBookRecord>, Errors>")]
+pub(crate) struct AddressBookStateMessage {
+    /// URL of the address book collection
+    pub(crate) url: String,
+}
+
+impl Handler<AddressBookStateMessage> for DatabaseActor {
+    type Result =
+        ResponseActFuture<Self, Result<Option<AddressBookRecord>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: AddressBookStateMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(ADDRESS_BOOK_STATE_QUERY)
+                    .bind(("url", msg.url))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("look up address book", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting that the state of an address book is stored after a
+/// synchronization
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct SaveAddressBookStateMessage {
+    /// The state to store
+    pub(crate) state: AddressBookRecord,
+}
+
+impl Handler<SaveAddressBookStateMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: SaveAddressBookStateMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(SAVE_ADDRESS_BOOK_STATE_QUERY)
+                    .bind(("state", msg.state))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                state
+                    .persist::<AddressBookRecord>(
+                        &database,
+                        STATE_TABLE,
+                        ALL_ADDRESS_BOOK_STATES_QUERY,
+                    )
+                    .await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("save address book state", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting the local changes to push to an address book
+#[derive(Message, Debug)]
+#[rtype(result = "Result<PendingChanges, Errors>")]
+pub(crate) struct PendingChangesMessage {
+    /// URL of the address book collection
+    pub(crate) url: String,
+    /// Whether cards that aren't in any address book yet should be pushed
+    /// to this one
+    pub(crate) include_unsynced: bool,
+}
+
+impl Handler<PendingChangesMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<PendingChanges, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: PendingChangesMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let mut response = database
+                    .query(MODIFIED_CARDS_QUERY)
+                    .query(DELETIONS_QUERY)
+                    .bind(("url", msg.url))
+                    .bind(("include_unsynced", msg.include_unsynced))
+                    .await
+                    .map_err(Errors::query)?;
+                Ok(PendingChanges {
+                    cards: response.take(0).map_err(Errors::query)?,
+                    deletions: response.take(1).map_err(Errors::query)?,
+                })
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("look up address book changes", result)
+            }),
+        )
+    }
+}
+
+/// Message recording that a card was uploaded to an address book
+///
+/// Leaving out the ETag makes the next pull download the card again, which is
+/// how conflicts are resolved in favour of the server.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct CardPushedMessage {
+    /// UID of the card
+    pub(crate) uid: String,
+    /// URL of the address book collection
+    pub(crate) address_book: String,
+    /// URL of the card on the server
+    pub(crate) href: String,
+    /// The new ETag of the card, if the server sent one
+    pub(crate) etag: Option<String>,
+}
+
+impl Handler<CardPushedMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: CardPushedMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(CARD_PUSHED_QUERY)
+                    .bind(("uid", msg.uid))
+                    .bind(("address_book", msg.address_book))
+                    .bind(("href", msg.href))
+                    .bind(("etag", msg.etag))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                address_book::persist_cards(&database, &state).await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("record pushed card", result)
+            }),
+        )
+    }
+}
+
+/// Message recording that a deletion was pushed to an address book
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct DeletionPushedMessage {
+    /// URL of the deleted card on the server
+    pub(crate) href: String,
+}
+
+impl Handler<DeletionPushedMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: DeletionPushedMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(DELETION_PUSHED_QUERY)
+                    .bind(("href", msg.href))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                address_book::persist_deletions(&database, &state).await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("record pushed deletion", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting the ETags of the cards known to be in an address book
+///
+/// Responds with a map from card URL to ETag.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<HashMap<String, Option<String>>, Errors>")]
+pub(crate) struct RemoteEtagsMessage {
+    /// URL of the address book collection
+    pub(crate) url: String,
+}
+
+impl Handler<RemoteEtagsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<
+        Self,
+        Result<HashMap<String, Option<String>>, Errors>,
+    >;
+
+    fn handle(
+        &mut self,
+        msg: RemoteEtagsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let rows: Vec<EtagRow> = database
+                    .query(REMOTE_ETAGS_QUERY)
+                    .bind(("url", msg.url))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)?;
+                Ok(rows.into_iter().map(|row| (row.href, row.etag)).collect())
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("list card ETags", result)),
+        )
+    }
+}
+
+/// Message requesting that changes pulled from an address book are stored
+///
+/// Responds with the number of cards that were written.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<usize, Errors>")]
+pub(crate) struct ApplyRemoteChangesMessage {
+    /// URL of the address book collection
+    pub(crate) url: String,
+    /// Cards that were created or changed on the server
+    pub(crate) cards: Vec<CardRecord>,
+    /// URLs of cards that were deleted on the server
+    pub(crate) removed: Vec<String>,
+}
+
+impl Handler<ApplyRemoteChangesMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<usize, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ApplyRemoteChangesMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received {} changed and {} removed cards from {}",
+            msg.cards.len(),
+            msg.removed.len(),
+            msg.url
+        );
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                let count = msg.cards.len();
+                database
+                    .query(APPLY_REMOTE_CHANGES_QUERY)
+                    .bind(("url", msg.url))
+                    .bind(("cards", msg.cards))
+                    .bind(("removed", msg.removed))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                address_book::persist_cards(&database, &state).await?;
+                Ok(count)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("apply address book changes", result)
+            }),
+        )
+    }
+}
@@ -0,0 +1,452 @@
+//! Keeps the contacts table up to date with the addresses seen in emails
+//!
+//! The addresses of each inserted batch of emails are tallied and added to
+//! the contacts. Emails are marked once counted, so fetching them again or
+//! finding a copy in another mailbox doesn't count them twice.
+
+use std::collections::{HashMap, HashSet};
+
+use actix::prelude::*;
+use serde::Deserialize;
+use surrealdb::sql::Thing;
+use time::OffsetDateTime;
+
+use super::{structures::ContactRecord, DatabaseActor, Errors};
+use crate::mail::StringAddress;
+
+/// Selects the participants of the given emails that haven't been counted
+/// yet
+const PARTICIPANTS_QUERY: &str = "
+    SELECT message_id, from, to, cc, date FROM $emails
+    WHERE contacts_counted != true;
+";
+
+/// Selects which of some message IDs belong to emails already counted, such
+/// as copies of a message in another mailbox
+const COUNTED_QUERY: &str = "
+    SELECT VALUE message_id FROM mail
+    WHERE contacts_counted = true AND message_id INSIDE $message_ids;
+";
+
+/// Selects the given contacts
+const CONTACTS_QUERY: &str = "
+    SELECT * FROM $contacts;
+";
+
+/// Writes contacts and marks the emails they were counted from in a single
+/// transaction, leaving out the user's own accounts
+///
+/// Contacts are keyed by address. Only the fields derived from emails are
+/// merged, so anything else stored on a contact is kept.
+const UPSERT_CONTACTS_QUERY: &str = "
+    BEGIN TRANSACTION;
+    FOR $contact IN $contacts {
+        UPDATE type::thing('contact', $contact.address) MERGE $contact;
+    };
+    UPDATE $emails SET contacts_counted = true;
+    DELETE $accounts;
+    COMMIT TRANSACTION;
+";
+
+/// Finds contacts whose address or name starts with a prefix, the people the
+/// user writes to most often first
+const COMPLETE_QUERY: &str = "
+    SELECT * FROM contact
+    WHERE string::startsWith(address, $prefix)
+        OR string::startsWith(string::lowercase(name ?? ''), $prefix)
+    ORDER BY sent DESC, received DESC, address
+    LIMIT $limit;
+";
+
+/// The participants of an email
+#[derive(Deserialize)]
+struct Participants {
+    /// The `Message-ID` header, used to count copies in several mailboxes once
+    message_id: Option<String>,
+    /// The email sender(s)
+    from: Option<Vec<StringAddress>>,
+    /// The primary recipient(s)
+    to: Option<Vec<StringAddress>>,
+    /// The carbon copy recipient(s)
+    cc: Option<Vec<StringAddress>>,
+    /// Date in UTC
+    #[serde(with = "time::serde::rfc3339::option")]
+    date: Option<OffsetDateTime>,
+}
+
+/// How an address took part in an email
+#[derive(Clone, Copy)]
+enum Role {
+    /// The address sent the email
+    Sender,
+    /// The user sent the email to the address
+    SentTo,
+    /// Someone else sent the email to the address and the user
+    CopiedWith,
+}
+
+/// Records one appearance of an address in an email
+fn record(
+    contacts: &mut HashMap<String, ContactRecord>,
+    address: &StringAddress,
+    date: Option<OffsetDateTime>,
+    role: Role,
+) {
+    let Some(key) = address.address() else {
+        return;
+    };
+    let contact = contacts.entry(key.clone()).or_insert(ContactRecord {
+        address: key,
+        name: None,
+        first_seen: None,
+        last_seen: None,
+        received: 0,
+        sent: 0,
+        copied: 0,
+    });
+    // Emails are processed oldest first, so the newest name wins
+    if let Some(name) = address.name() {
+        contact.name = Some(name.to_owned());
+    }
+    contact.first_seen = contact.first_seen.min(date).or(date);
+    contact.last_seen = contact.last_seen.max(date);
+    match role {
+        Role::Sender => contact.received += 1,
+        Role::SentTo => contact.sent += 1,
+        Role::CopiedWith => contact.copied += 1,
+    }
+}
+
+/// Tallies every address seen in a set of emails
+///
+/// The user's own accounts, given in lowercase, are left out.
+fn build_contacts(
+    mut emails: Vec<Participants>,
+    accounts: &HashSet<String>,
+) -> Vec<ContactRecord> {
+    emails.sort_by_key(|email| email.date);
+    let mut seen_message_ids = HashSet::new();
+    let mut contacts = HashMap::new();
+    for email in &emails {
+        if let Some(message_id) = &email.message_id {
+            if !seen_message_ids.insert(message_id) {
+                continue;
+            }
+        }
+        let from = email.from.iter().flatten();
+        let recipients = email.to.iter().chain(&email.cc).flatten();
+        let sent_by_user = from.clone().any(|address| {
+            address.address().is_some_and(|address| accounts.contains(&address))
+        });
+        let recipient_role = if sent_by_user {
+            Role::SentTo
+        } else {
+            Role::CopiedWith
+        };
+        for address in from {
+            record(&mut contacts, address, email.date, Role::Sender);
+        }
+        for address in recipients {
+            record(&mut contacts, address, email.date, recipient_role);
+        }
+    }
+    contacts
+        .into_values()
+        .filter(|contact| !accounts.contains(&contact.address))
+        .collect()
+}
+
+/// The earlier of two optional dates
+fn earliest(
+    a: Option<OffsetDateTime>,
+    b: Option<OffsetDateTime>,
+) -> Option<OffsetDateTime> {
+    a.zip(b).map(|(a, b)| a.min(b)).or(a).or(b)
+}
+
+/// Adds what was tallied from new emails to the contacts known before
+fn merge(
+    known: Vec<ContactRecord>,
+    tallied: Vec<ContactRecord>,
+) -> Vec<ContactRecord> {
+    let mut known: HashMap<String, ContactRecord> = known
+        .into_iter()
+        .map(|contact| (contact.address.clone(), contact))
+        .collect();
+    tallied
+        .into_iter()
+        .map(|new| {
+            let Some(old) = known.remove(&new.address) else {
+                return new;
+            };
+            // The name used most recently wins
+            let name = if new.last_seen >= old.last_seen {
+                new.name.or(old.name)
+            } else {
+                old.name.or(new.name)
+            };
+            ContactRecord {
+                address: new.address,
+                name,
+                first_seen: earliest(old.first_seen, new.first_seen),
+                last_seen: old.last_seen.max(new.last_seen),
+                received: old.received.saturating_add(new.received),
+                sent: old.sent.saturating_add(new.sent),
+                copied: old.copied.saturating_add(new.copied),
+            }
+        })
+        .collect()
+}
+
+/// Message requesting that contacts are updated from newly inserted emails
+///
+/// Every address in the From, To and Cc fields of the emails that haven't
+/// been counted yet is added to its contact. Responds with the number of
+/// contacts updated.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<usize, Errors>")]
+pub(crate) struct RefreshContactsMessage {
+    /// IDs of the inserted email records
+    pub(crate) emails: Vec<Thing>,
+}
+
+impl Handler<RefreshContactsMessage> for DatabaseActor {
+    type Result = AtomicResponse<Self, Result<usize, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: RefreshContactsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received {} emails to count contacts in",
+            msg.emails.len()
+        );
+        let database = self.database.clone();
+        let accounts = self.accounts.clone();
+        // Counting one batch at a time keeps two batches from adding to the
+        // same contact, or from both counting copies of a message
+        AtomicResponse::new(Box::pin(
+            async move {
+                let emails: Vec<Participants> = database
+                    .query(PARTICIPANTS_QUERY)
+                    .bind(("emails", &msg.emails))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)?;
+                let message_ids: Vec<&String> = emails
+                    .iter()
+                    .filter_map(|email| email.message_id.as_ref())
+                    .collect();
+                let counted: HashSet<String> = database
+                    .query(COUNTED_QUERY)
+                    .bind(("message_ids", message_ids))
+                    .await
+                    .map_err(Errors::query)?
+                    .take::<Vec<String>>(0)
+                    .map_err(Errors::query)?
+                    .into_iter()
+                    .collect();
+                let emails = emails
+                    .into_iter()
+                    .filter(|email| {
+                        email
+                            .message_id
+                            .as_ref()
+                            .is_none_or(|id| !counted.contains(id))
+                    })
+                    .collect();
+                let tallied = build_contacts(emails, &accounts);
+                let ids: Vec<Thing> = tallied
+                    .iter()
+                    .map(|contact| {
+                        Thing::from(("contact", contact.address.as_str()))
+                    })
+                    .collect();
+                let known: Vec<ContactRecord> = database
+                    .query(CONTACTS_QUERY)
+                    .bind(("contacts", ids))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)?;
+                let contacts = merge(known, tallied);
+                let count = contacts.len();
+                let accounts: Vec<Thing> = accounts
+                    .iter()
+                    .map(|account| Thing::from(("contact", account.as_str())))
+                    .collect();
+                database
+                    .query(UPSERT_CONTACTS_QUERY)
+                    .bind(("contacts", contacts))
+                    .bind(("emails", msg.emails))
+                    .bind(("accounts", accounts))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                Ok(count)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("refresh contacts", result)),
+        ))
+    }
+}
+
+/// Message requesting contacts to suggest while an address is being typed
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<ContactRecord>, Errors>")]
+pub(crate) struct CompleteAddressMessage {
+    /// What has been typed so far
+    pub(crate) prefix: String,
+    /// Maximum number of suggestions
+    pub(crate) limit: usize,
+}
+
+impl Handler<CompleteAddressMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Vec<ContactRecord>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: CompleteAddressMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(COMPLETE_QUERY)
+                    .bind(("prefix", msg.prefix.to_lowercase()))
+                    .bind(("limit", msg.limit))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("complete address", result)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+    use serde_json::json;
+    use time::OffsetDateTime;
+
+    use super::CompleteAddressMessage;
+    use crate::{
+        database::{
+            structures::ContactRecord,
+            testing::{self, insert, start, ME},
+            DatabaseActor,
+        },
+        mail::{ImapEmail, StringAddress},
+    };
+
+    /// An address with a display name
+    fn address(name: &str, address: &str) -> Vec<StringAddress> {
+        let (mailbox, host) =
+            address.split_once('@').expect("The address should have a host");
+        vec![serde_json::from_value(json!({
+            "name": name,
+            "adl": null,
+            "mailbox": mailbox,
+            "host": host,
+        }))
+        .expect("The address should be valid")]
+    }
+
+    /// An email from one address to another, dated by its UID
+    fn email(
+        uid: u32,
+        message_id: &str,
+        from: Vec<StringAddress>,
+        to: Vec<StringAddress>,
+    ) -> ImapEmail {
+        let mut email = testing::email(uid, message_id);
+        email.envelope.from = Some(from);
+        email.envelope.to = Some(to);
+        email
+    }
+
+    /// The contacts whose address or name starts with a prefix
+    async fn contacts(
+        database: &Addr<DatabaseActor>,
+        prefix: &str,
+    ) -> Vec<ContactRecord> {
+        database
+            .send(CompleteAddressMessage {
+                prefix: prefix.to_owned(),
+                limit: 10,
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The contacts should be listed")
+    }
+
+    /// Emails are counted once, however often they are fetched or copied
+    #[actix_rt::test]
+    async fn counts_each_email_once() {
+        let (_directory, database, _) = start("contacts-once").await;
+        let from_alice = || {
+            email(1, "<a@x>", address("Alice", "alice@x.org"), address("", ME))
+        };
+        insert(&database, "INBOX", vec![from_alice()]).await;
+        insert(&database, "INBOX", vec![from_alice()]).await;
+        insert(
+            &database,
+            "Archive",
+            vec![ImapEmail {
+                uid: 7,
+                ..from_alice()
+            }],
+        )
+        .await;
+        let alice = contacts(&database, "alice").await;
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].received, 1);
+        assert_eq!(alice[0].sent, 0);
+    }
+
+    /// Later batches add to what earlier ones counted, and the most recent
+    /// name wins
+    #[actix_rt::test]
+    async fn adds_to_earlier_batches() {
+        let (_directory, database, _) = start("contacts-batches").await;
+        insert(
+            &database,
+            "INBOX",
+            vec![email(
+                1,
+                "<a@x>",
+                address("Alice", "alice@x.org"),
+                address("", ME),
+            )],
+        )
+        .await;
+        insert(
+            &database,
+            "Sent",
+            vec![email(
+                2,
+                "<b@x>",
+                address("", ME),
+                address("Alice Smith", "alice@x.org"),
+            )],
+        )
+        .await;
+        let alice = contacts(&database, "alice").await;
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].name.as_deref(), Some("Alice Smith"));
+        assert_eq!(alice[0].received, 1);
+        assert_eq!(alice[0].sent, 1);
+        assert_eq!(
+            alice[0].first_seen,
+            OffsetDateTime::from_unix_timestamp(1).ok()
+        );
+        assert!(contacts(&database, "me@").await.is_empty());
+    }
+}
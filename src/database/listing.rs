@@ -0,0 +1,604 @@
+//! Listings of mailboxes and emails for display
+//!
+//! Mailboxes can hold far more emails than can be shown at once, so they are
+//! listed a page at a time in the order the user picked. The emails matching a
+//! search query are listed the same way, across every mailbox.
+
+use std::fmt::Write;
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Thing;
+use time::OffsetDateTime;
+
+use super::{
+    actor::unblock,
+    query::Query,
+    remote_content::{self, ALLOWANCES_QUERY},
+    search::{self, RemoteHits, HIGHLIGHT_CLOSE, HIGHLIGHT_OPEN},
+    structures::{AttachmentRecord, Location},
+    DatabaseActor, Errors,
+};
+use crate::mail::{
+    mime::{self, Bodies},
+    StringAddress,
+};
+
+/// Selects an email with everything needed to read it
+const READ_EMAIL_QUERY: &str = "
+    SELECT account, $mailbox AS mailbox, $uid AS uid, date, subject, from, to,
+        cc, reply_to, flags, message_id, references, body_text, raw, partial,
+        attachments
+    FROM mail
+    WHERE account = $account
+        AND locations CONTAINS { mailbox: $mailbox, uid: $uid }
+    LIMIT 1;
+";
+
+/// What emails can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SortField {
+    /// The date of the email
+    Date,
+    /// The senders, by name and address
+    Sender,
+    /// The subject, ignoring case
+    Subject,
+    /// The size of the full source
+    Size,
+    /// How well the email matches the free text of a search, or the date when
+    /// there is no free text
+    Relevance,
+}
+
+/// The order of a listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sort {
+    /// What to sort by
+    pub(crate) field: SortField,
+    /// Whether the largest, latest or last in the alphabet come first
+    pub(crate) descending: bool,
+}
+
+impl Default for Sort {
+    /// Newest first
+    fn default() -> Self {
+        Self {
+            field: SortField::Date,
+            descending: true,
+        }
+    }
+}
+
+/// Which emails a listing shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Listing {
+    /// The emails of a mailbox
+    Mailbox {
+        /// The account the mailbox belongs to
+        account: String,
+        /// Name of the mailbox
+        mailbox: String,
+    },
+    /// The emails of every account matching a search query
+    Query(String),
+}
+
+/// The listed emails as parts of `SurrealQL` statements
+pub(super) struct Selection {
+    /// Condition on `mail` records selecting the listed emails
+    pub(super) condition: String,
+    /// The location of each listed email that is shown, and that actions on
+    /// it apply to
+    location: String,
+    /// Values of the parameters of the condition and the location
+    pub(super) bindings: Vec<(String, String)>,
+    /// Number of free text terms in the condition, which rank and highlight
+    /// the listed emails
+    text_terms: usize,
+}
+
+impl Selection {
+    /// Also selects the hits of searching on IMAP servers, which may not match
+    /// locally, e.g. because only their envelopes have been synced
+    fn include(&mut self, remote_hits: Vec<RemoteHits>) {
+        if remote_hits.is_empty() {
+            return;
+        }
+        let mut condition = format!("({})", self.condition);
+        for (index, hits) in remote_hits.into_iter().enumerate() {
+            let uids = hits
+                .uids
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<String>>()
+                .join(", ");
+            write!(
+                condition,
+                " OR (account = $remote_account{index} AND locations[WHERE \
+                 mailbox = $remote_mailbox{index}].uid ANYINSIDE [{uids}])"
+            )
+            .expect("Writing to a String can't fail");
+            self.bindings
+                .push((format!("remote_account{index}"), hits.account));
+            self.bindings
+                .push((format!("remote_mailbox{index}"), hits.mailbox));
+        }
+        self.condition = condition;
+    }
+}
+
+impl Listing {
+    /// Selects the listed emails
+    ///
+    /// Emails listed by a query are shown in a mailbox the query names with
+    /// `folder:` if they are in one, e.g. in `INBOX` rather than in Gmail's
+    /// `[Gmail]/All Mail`.
+    pub(super) fn selection(&self) -> Result<Selection, Errors> {
+        match self {
+            Listing::Mailbox {
+                account,
+                mailbox,
+            } => Ok(Selection {
+                condition: "account = $account AND $mailbox INSIDE \
+                            locations.mailbox"
+                    .to_owned(),
+                location: "locations[WHERE mailbox = $mailbox][0]".to_owned(),
+                bindings: vec![
+                    ("account".to_owned(), account.clone()),
+                    ("mailbox".to_owned(), mailbox.clone()),
+                ],
+                text_terms: 0,
+            }),
+            Listing::Query(query) => {
+                let compiled =
+                    Query::parse(query).map_err(Errors::Parse)?.compile();
+                let location = format!(
+                    "array::concat(locations[WHERE mailbox INSIDE [{}]], \
+                     locations)[0]",
+                    compiled.folders.join(", ")
+                );
+                Ok(Selection {
+                    condition: compiled.condition,
+                    location,
+                    bindings: compiled.bindings,
+                    text_terms: compiled.text_terms,
+                })
+            }
+        }
+    }
+}
+
+/// Builds the statement that counts the listed emails
+pub(super) fn count_statement(condition: &str) -> String {
+    format!(
+        "RETURN (SELECT count() FROM mail WHERE {condition} GROUP \
+         ALL)[0].count ?? 0;"
+    )
+}
+
+/// Builds the statement that selects a page of the listed emails
+///
+/// Emails are ordered by their sort key, with the record ID breaking ties so
+/// the order is total. A page continues right after the last email of the
+/// previous one when `after` is set, so earlier pages don't have to be sorted
+/// and skipped again. Emails matching free text come with the part of their
+/// body around the first match.
+fn list_statement(sort: Sort, selection: &Selection, after: bool) -> String {
+    let score = search::score(selection.text_terms);
+    let key = match sort.field {
+        SortField::Relevance if selection.text_terms > 0 => score.as_str(),
+        SortField::Date | SortField::Relevance => "date ?? ''",
+        SortField::Sender => "from_text",
+        SortField::Subject => "string::lowercase(subject ?? '')",
+        SortField::Size => "size ?? 0",
+    };
+    let (direction, comparison) = if sort.descending {
+        ("DESC", "<")
+    } else {
+        ("ASC", ">")
+    };
+    let snippet = if selection.text_terms > 0 {
+        "search::highlight($open, $close, 2)"
+    } else {
+        "NONE"
+    };
+    let (continuation, start) = if after {
+        (format!(" AND [{key}, id] {comparison} [$after_key, $after_id]"), "")
+    } else {
+        (String::new(), " START $start")
+    };
+    format!(
+        "SELECT id, account, {} AS location, date, subject, from, flags, \
+         size, {score} AS score, {snippet} AS snippet, {key} AS sort_key FROM \
+         mail WHERE ({}){continuation} ORDER BY sort_key {direction}, id \
+         {direction} LIMIT $limit{start};",
+        selection.location, selection.condition
+    )
+}
+
+/// Where an email is in the order of a listing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct Position {
+    /// The value the email is sorted by
+    #[serde(rename = "sort_key")]
+    key: serde_json::Value,
+    /// The record ID of the email, which orders emails with the same key
+    id: Thing,
+}
+
+/// An email as listed
+#[derive(Deserialize, Debug)]
+pub(crate) struct EmailSummary {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is shown in, with its UID there
+    pub(crate) location: Location,
+    /// Date in UTC
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub(crate) date: Option<OffsetDateTime>,
+    /// Subject
+    pub(crate) subject: Option<String>,
+    /// The email sender(s)
+    pub(crate) from: Option<Vec<StringAddress>>,
+    /// IMAP flags such as `\Seen`
+    pub(crate) flags: Vec<String>,
+    /// Size of the full source in bytes, if known
+    #[serde(default)]
+    pub(crate) size: Option<u32>,
+    /// The part of the body around the first match of a search's free text,
+    /// with matched terms wrapped in `HIGHLIGHT_OPEN` and `HIGHLIGHT_CLOSE`
+    pub(crate) snippet: Option<String>,
+    /// Where the email is in the order of the listing, if it was listed
+    #[serde(flatten)]
+    pub(crate) position: Option<Position>,
+}
+
+/// A page of the listed emails
+#[derive(Debug)]
+pub(crate) struct EmailPage {
+    /// How many emails are listed in total, if they were counted
+    pub(crate) total: Option<usize>,
+    /// The emails on the page, in order
+    pub(crate) emails: Vec<EmailSummary>,
+}
+
+/// An email with everything needed to read it
+#[derive(Deserialize, Debug)]
+pub(crate) struct EmailContent {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email in its mailbox
+    pub(crate) uid: u32,
+    /// Date in UTC
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub(crate) date: Option<OffsetDateTime>,
+    /// Subject
+    pub(crate) subject: Option<String>,
+    /// The email sender(s)
+    pub(crate) from: Option<Vec<StringAddress>>,
+    /// The primary recipient(s)
+    pub(crate) to: Option<Vec<StringAddress>>,
+    /// The carbon copy recipient(s)
+    pub(crate) cc: Option<Vec<StringAddress>>,
+    /// Where replies should go, if not to the sender(s)
+    #[serde(default)]
+    pub(crate) reply_to: Option<Vec<StringAddress>>,
+    /// IMAP flags such as `\Seen`
+    pub(crate) flags: Vec<String>,
+    /// The `Message-ID` header
+    pub(crate) message_id: Option<String>,
+    /// Message IDs from the `References` header, oldest first
+    #[serde(default)]
+    pub(crate) references: Vec<String>,
+    /// The decoded text of the message, if its source was fetched
+    pub(crate) body_text: Option<String>,
+    /// Hash of the full source in the blob store, if it was fetched
+    pub(crate) raw: Option<String>,
+    /// Whether the source leaves out attachments too large for the sync
+    /// policy
+    #[serde(default)]
+    pub(crate) partial: Option<bool>,
+    /// The files attached to the email, if its source was fetched
+    #[serde(default)]
+    pub(crate) attachments: Vec<AttachmentRecord>,
+    /// The plain text and HTML alternatives of the body, read from the full
+    /// source
+    #[serde(skip)]
+    pub(crate) bodies: Bodies,
+    /// Whether the user allowed remote content in the email
+    #[serde(skip)]
+    pub(crate) remote_content: bool,
+}
+
+/// Message requesting a page of the emails of a mailbox or matching a query
+///
+/// Responds with the emails on the page and, if asked to count them, how many
+/// are listed in total. Callers that keep the total and the position of the
+/// last email of each page can spare the database counting and skipping every
+/// email before the page.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<EmailPage, Errors>")]
+pub(crate) struct ListEmailsMessage {
+    /// Which emails to list
+    pub(crate) listing: Listing,
+    /// The order to list the emails in
+    pub(crate) sort: Sort,
+    /// How many emails to skip, if the end of the previous page isn't known
+    pub(crate) start: usize,
+    /// Where the last email of the previous page is, to continue from
+    pub(crate) after: Option<Position>,
+    /// Whether to count the listed emails
+    pub(crate) count: bool,
+    /// Maximum number of emails to return
+    pub(crate) limit: usize,
+    /// Results of searching the listed query on IMAP servers, which are
+    /// listed even if their local copies don't match
+    pub(crate) remote_hits: Vec<RemoteHits>,
+}
+
+impl Handler<ListEmailsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<EmailPage, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ListEmailsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let mut selection = match msg.listing.selection() {
+            Ok(selection) => selection,
+            Err(e) => return Box::pin(fut::ready(Err(e))),
+        };
+        selection.include(msg.remote_hits);
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let mut statement = database.query(list_statement(
+                    msg.sort,
+                    &selection,
+                    msg.after.is_some(),
+                ));
+                if msg.count {
+                    statement =
+                        statement.query(count_statement(&selection.condition));
+                }
+                statement = statement
+                    .bind(("start", msg.start))
+                    .bind(("limit", msg.limit))
+                    .bind(("open", HIGHLIGHT_OPEN.to_string()))
+                    .bind(("close", HIGHLIGHT_CLOSE.to_string()));
+                if let Some(after) = msg.after {
+                    statement = statement
+                        .bind(("after_key", after.key))
+                        .bind(("after_id", after.id));
+                }
+                for binding in selection.bindings {
+                    statement = statement.bind(binding);
+                }
+                let mut response = statement.await.map_err(Errors::query)?;
+                let mut emails: Vec<EmailSummary> =
+                    response.take(0).map_err(Errors::query)?;
+                let total: Option<usize> = if msg.count {
+                    Some(
+                        response
+                            .take::<Option<usize>>(1)
+                            .map_err(Errors::query)?
+                            .unwrap_or_default(),
+                    )
+                } else {
+                    None
+                };
+                for email in &mut emails {
+                    email.snippet =
+                        email.snippet.as_deref().map(search::snippet);
+                }
+                Ok(EmailPage {
+                    total,
+                    emails,
+                })
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("list emails", result)),
+        )
+    }
+}
+
+/// Message requesting an email to read
+///
+/// Responds with `None` if the mailbox has no email with the UID. The bodies
+/// are read from the blob store if the full source was fetched, and whether
+/// remote content is allowed from the user's allowances.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Option<EmailContent>, Errors>")]
+pub(crate) struct ReadEmailMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email
+    pub(crate) uid: u32,
+}
+
+impl Handler<ReadEmailMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Option<EmailContent>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ReadEmailMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let blobs = self.blobs.clone();
+        Box::pin(
+            async move {
+                let mut response = database
+                    .query(READ_EMAIL_QUERY)
+                    .query(ALLOWANCES_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .bind(("uid", msg.uid))
+                    .await
+                    .map_err(Errors::query)?;
+                let emails: Vec<EmailContent> =
+                    response.take(0).map_err(Errors::query)?;
+                let message_allowed: Option<bool> =
+                    response.take(1).map_err(Errors::query)?;
+                let allowed_senders: Vec<String> =
+                    response.take(2).map_err(Errors::query)?;
+                let Some(mut email) = emails.into_iter().next() else {
+                    return Ok(None);
+                };
+                email.remote_content = remote_content::is_allowed(
+                    message_allowed.unwrap_or_default(),
+                    &allowed_senders,
+                    email.from.as_ref(),
+                );
+                if let Some(raw) = email.raw.clone() {
+                    email.bodies = unblock(move || {
+                        let raw = blobs.get(&raw).map_err(Errors::Blob)?;
+                        Ok(mime::bodies(&raw))
+                    })
+                    .await?;
+                }
+                Ok(Some(email))
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("read email", result)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+    use time::OffsetDateTime;
+
+    use super::{ListEmailsMessage, Listing, Position, Sort, SortField};
+    use crate::{
+        database::{
+            testing::{self, insert, start, ME},
+            DatabaseActor,
+        },
+        mail::ImapEmail,
+    };
+
+    /// How many emails are listed
+    const EMAILS: u32 = 11;
+
+    /// Every order the emails can be listed in
+    const SORTS: [SortField; 5] = [
+        SortField::Date,
+        SortField::Sender,
+        SortField::Subject,
+        SortField::Size,
+        SortField::Relevance,
+    ];
+
+    /// An email in the inbox, with many sort keys shared with other emails
+    fn email(uid: u32) -> ImapEmail {
+        let subjects = [None, Some("Report"), Some("report"), Some("Weekly")];
+        let mut email = testing::email(uid, &format!("<{uid}@example.com>"));
+        email.envelope.date = (!uid.is_multiple_of(3))
+            .then(|| {
+                OffsetDateTime::from_unix_timestamp(i64::from(uid % 3)).ok()
+            })
+            .flatten();
+        email.envelope.subject = subjects
+            .get(usize::try_from(uid % 4).expect("Four fits"))
+            .copied()
+            .flatten()
+            .map(str::to_owned);
+        email.size = (!uid.is_multiple_of(2)).then_some(uid % 5);
+        email
+    }
+
+    /// Lists the UIDs of a listing a page of `limit` emails at a time, each
+    /// page continuing from the end of the one before
+    async fn page_through(
+        database: &Addr<DatabaseActor>,
+        listing: &Listing,
+        sort: Sort,
+        limit: usize,
+    ) -> Vec<u32> {
+        let mut uids = Vec::new();
+        let mut after: Option<Position> = None;
+        loop {
+            let page = database
+                .send(ListEmailsMessage {
+                    listing: listing.clone(),
+                    sort,
+                    start: 0,
+                    after: after.take(),
+                    count: false,
+                    limit,
+                    remote_hits: Vec::new(),
+                })
+                .await
+                .expect("The database should be running")
+                .expect("The page should be listed");
+            assert_eq!(page.total, None, "Emails are only counted on request");
+            let Some(last) = page.emails.last() else {
+                return uids;
+            };
+            after.clone_from(&last.position);
+            uids.extend(page.emails.iter().map(|email| email.location.uid));
+            assert!(
+                uids.len() <= usize::try_from(EMAILS).expect("Eleven fits"),
+                "Pages shouldn't repeat emails"
+            );
+        }
+    }
+
+    /// Pages continuing from the end of the one before neither skip nor
+    /// repeat emails, in every order and even when sort keys are shared
+    #[actix_rt::test]
+    async fn continues_pages() {
+        let (_directory, database, _) = start("listing-continue").await;
+        insert(&database, "INBOX", (1..=EMAILS).map(email).collect()).await;
+        let listings = [
+            Listing::Mailbox {
+                account: ME.to_owned(),
+                mailbox: "INBOX".to_owned(),
+            },
+            Listing::Query("report OR weekly".to_owned()),
+        ];
+        for listing in &listings {
+            for field in SORTS {
+                for descending in [true, false] {
+                    let sort = Sort {
+                        field,
+                        descending,
+                    };
+                    let whole = database
+                        .send(ListEmailsMessage {
+                            listing: listing.clone(),
+                            sort,
+                            start: 0,
+                            after: None,
+                            count: true,
+                            limit: 100,
+                            remote_hits: Vec::new(),
+                        })
+                        .await
+                        .expect("The database should be running")
+                        .expect("The listing should be listed");
+                    let expected: Vec<u32> = whole
+                        .emails
+                        .iter()
+                        .map(|email| email.location.uid)
+                        .collect();
+                    assert_eq!(whole.total, Some(expected.len()));
+                    assert_eq!(
+                        page_through(&database, listing, sort, 2).await,
+                        expected,
+                        "for {listing:?} by {sort:?}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Bookkeeping for synchronizing mailboxes with IMAP servers incrementally
+//!
+//! The `MailActor` remembers what it saw of each mailbox, so the next
+//! synchronization only downloads the messages that arrived since and the
+//! flags that changed. Unlike the address book state this isn't kept in the
+//! state store: emails aren't kept across restarts, so a restart has to
+//! download every mailbox again anyway.
+
+use std::collections::HashMap;
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    mailbox_changes::mailboxes, structures::EmailRecord, DatabaseActor, Errors,
+};
+use crate::mail::MailboxState;
+
+/// Looks up what was last seen of a mailbox
+const MAILBOX_STATE_QUERY: &str = "
+    SELECT * OMIT id FROM type::thing('mailbox_state', [$account, $mailbox]);
+";
+
+/// Stores what was seen of a mailbox
+const SAVE_MAILBOX_STATE_QUERY: &str = "
+    UPDATE type::thing('mailbox_state', [$account, $mailbox]) CONTENT $state;
+";
+
+/// Lists the UIDs and flags of every email in a mailbox
+const MAILBOX_FLAGS_QUERY: &str = "
+    SELECT locations[WHERE mailbox = $mailbox].uid AS uids, flags FROM mail
+    WHERE account = $account AND $mailbox INSIDE locations.mailbox;
+";
+
+/// Replaces the flags of emails and returns them as they are now
+const SET_FLAGS_QUERY: &str = "
+    BEGIN TRANSACTION;
+    FOR $change IN $changes {
+        UPDATE mail SET flags = $change.flags
+        WHERE account = $account
+            AND locations[WHERE mailbox = $mailbox].uid CONTAINS $change.uid;
+    };
+    SELECT * FROM mail WHERE account = $account
+        AND locations[WHERE mailbox = $mailbox].uid ANYINSIDE $uids;
+    COMMIT TRANSACTION;
+";
+
+/// A row of `MAILBOX_FLAGS_QUERY`
+#[derive(Deserialize)]
+struct FlagsRow {
+    /// The UIDs of the email in the mailbox, of which there is only one
+    uids: Vec<u32>,
+    /// IMAP flags such as `\Seen`
+    flags: Vec<String>,
+}
+
+/// The flags a single email is given by `SET_FLAGS_QUERY`
+#[derive(Serialize)]
+struct FlagChange {
+    /// UID of the email in the mailbox
+    uid: u32,
+    /// Every flag the email has on the server
+    flags: Vec<String>,
+}
+
+/// Message asking what was seen of a mailbox during the last synchronization
+///
+/// Responds with `None` if the mailbox wasn't synchronized yet.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Option<MailboxState>, Errors>")]
+pub(crate) struct MailboxStateMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// Name of the mailbox
+    pub(crate) mailbox: String,
+}
+
+impl Handler<MailboxStateMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Option<MailboxState>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: MailboxStateMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(MAILBOX_STATE_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .await
+                    .map_err(Errors::query)?
+                    .take::<Option<MailboxState>>(0)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("look up a mailbox state", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting that what was seen of a mailbox is remembered
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct SaveMailboxStateMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// Name of the mailbox
+    pub(crate) mailbox: String,
+    /// What was seen of the mailbox
+    pub(crate) state: MailboxState,
+}
+
+impl Handler<SaveMailboxStateMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: SaveMailboxStateMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(SAVE_MAILBOX_STATE_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .bind(("state", msg.state))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                Ok(())
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("save a mailbox state", result)
+            }),
+        )
+    }
+}
+
+/// Message asking for the flags of every email in a mailbox
+///
+/// Responds with the flags by UID, which also tells which UIDs are known.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<HashMap<u32, Vec<String>>, Errors>")]
+pub(crate) struct MailboxFlagsMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// Name of the mailbox
+    pub(crate) mailbox: String,
+}
+
+impl Handler<MailboxFlagsMessage> for DatabaseActor {
+    type Result =
+        ResponseActFuture<Self, Result<HashMap<u32, Vec<String>>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: MailboxFlagsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let rows: Vec<FlagsRow> = database
+                    .query(MAILBOX_FLAGS_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)?;
+                Ok(rows
+                    .into_iter()
+                    .filter_map(|row| Some((*row.uids.first()?, row.flags)))
+                    .collect())
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("look up mailbox flags", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting that emails get the flags they have on the server
+///
+/// Unlike `UpdateFlagsMessage` this replaces every flag of each email, so
+/// flags removed by another client go away too.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct SetFlagsMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// The mailbox the emails are in
+    pub(crate) mailbox: String,
+    /// Every flag of each email, by UID
+    pub(crate) flags: HashMap<u32, Vec<String>>,
+}
+
+impl Handler<SetFlagsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: SetFlagsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received flags of {} emails in {} for {}",
+            msg.flags.len(),
+            msg.mailbox,
+            msg.account
+        );
+        let database = self.database.clone();
+        let uids: Vec<u32> = msg.flags.keys().copied().collect();
+        let changes: Vec<FlagChange> = msg
+            .flags
+            .into_iter()
+            .map(|(uid, flags)| FlagChange {
+                uid,
+                flags,
+            })
+            .collect();
+        Box::pin(
+            async move {
+                database
+                    .query(SET_FLAGS_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .bind(("changes", changes))
+                    .bind(("uids", uids))
+                    .await
+                    .map_err(Errors::query)?
+                    .take::<Vec<EmailRecord>>(1)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                // Messages from Gmail change in every mailbox they are in
+                let result = result.map(|emails| {
+                    for (account, mailbox) in mailboxes(&emails) {
+                        actor.notify(&account, &mailbox);
+                    }
+                });
+                actor.track("set flags", result)
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        MailboxFlagsMessage, MailboxStateMessage, SaveMailboxStateMessage,
+        SetFlagsMessage,
+    };
+    use crate::{
+        database::testing::{email, insert, start, ME},
+        mail::MailboxState,
+    };
+
+    /// What was seen of a mailbox is kept per mailbox, and flags set from
+    /// the server replace the ones stored
+    #[actix_rt::test]
+    async fn remembers_mailboxes_and_flags() {
+        let (_directory, database, _) = start("mail-sync").await;
+        let state = MailboxState {
+            uid_validity: 7,
+            uid_next: 3,
+            highest_modseq: Some(42),
+        };
+        database
+            .send(SaveMailboxStateMessage {
+                account: ME.to_owned(),
+                mailbox: "INBOX".to_owned(),
+                state: state.clone(),
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The state should be saved");
+        for (mailbox, expected) in [("INBOX", Some(state)), ("Archive", None)] {
+            let found = database
+                .send(MailboxStateMessage {
+                    account: ME.to_owned(),
+                    mailbox: mailbox.to_owned(),
+                })
+                .await
+                .expect("The database should be running")
+                .expect("The state should be looked up");
+            assert_eq!(found, expected, "for {mailbox}");
+        }
+
+        let mut seen = email(1, "<seen@example.com>");
+        seen.flags = vec!["\\Seen".to_owned(), "\\Flagged".to_owned()];
+        insert(&database, "INBOX", vec![seen, email(2, "<new@example.com>")])
+            .await;
+        insert(&database, "Archive", vec![email(1, "<old@example.com>")]).await;
+        database
+            .send(SetFlagsMessage {
+                account: ME.to_owned(),
+                mailbox: "INBOX".to_owned(),
+                flags: HashMap::from([(1, vec!["\\Seen".to_owned()])]),
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The flags should be set");
+        let flags = database
+            .send(MailboxFlagsMessage {
+                account: ME.to_owned(),
+                mailbox: "INBOX".to_owned(),
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The flags should be looked up");
+        assert_eq!(
+            flags,
+            HashMap::from([(1, vec!["\\Seen".to_owned()]), (2, Vec::new())])
+        );
+    }
+}
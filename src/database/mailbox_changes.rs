@@ -0,0 +1,234 @@
+//! Optimistic updates of the local copy of a mailbox
+//!
+//! Operations like flagging, moving or deleting emails are applied to the
+//! database before the server confirms them, so the interface reacts at once.
+//! The previous records are handed back to the caller, which restores them if
+//! the server rejects the operation.
+
+use std::collections::{HashMap, HashSet};
+
+use actix::prelude::*;
+use surrealdb::sql::Thing;
+
+use super::{
+    actor::INSERT_EMAILS_QUERY, structures::EmailRecord,
+    threading::RethreadMessage, DatabaseActor, Errors,
+};
+
+/// The mailboxes the given emails are in, by account and name
+pub(super) fn mailboxes(emails: &[EmailRecord]) -> HashSet<(String, String)> {
+    emails
+        .iter()
+        .flat_map(EmailRecord::mailboxes)
+        .map(|(account, mailbox)| (account.to_owned(), mailbox.to_owned()))
+        .collect()
+}
+
+/// Removes emails from a mailbox and returns them as they were
+///
+/// Messages from Gmail that are in other mailboxes too keep their record.
+const REMOVE_EMAILS_QUERY: &str = "
+    BEGIN TRANSACTION;
+    SELECT * FROM mail WHERE account = $account
+        AND locations[WHERE mailbox = $mailbox].uid ANYINSIDE $uids;
+    UPDATE mail SET locations =
+            locations[WHERE mailbox != $mailbox OR uid NOTINSIDE $uids]
+        WHERE account = $account
+        AND locations[WHERE mailbox = $mailbox].uid ANYINSIDE $uids;
+    DELETE mail WHERE account = $account AND locations = [];
+    COMMIT TRANSACTION;
+";
+
+/// Adds flags to emails and returns them as they were
+const ADD_FLAGS_QUERY: &str = "
+    UPDATE mail SET flags = array::union(flags, $flags)
+    WHERE account = $account
+        AND locations[WHERE mailbox = $mailbox].uid ANYINSIDE $uids
+    RETURN BEFORE;
+";
+
+/// Removes flags from emails and returns them as they were
+const REMOVE_FLAGS_QUERY: &str = "
+    UPDATE mail SET flags = array::complement(flags, $flags)
+    WHERE account = $account
+        AND locations[WHERE mailbox = $mailbox].uid ANYINSIDE $uids
+    RETURN BEFORE;
+";
+
+/// Message requesting that emails are removed from a mailbox
+///
+/// Responds with the removed records, so they can be restored with a
+/// `RestoreEmailsMessage` or written to another mailbox. Their conversations
+/// are threaded again afterwards.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<EmailRecord>, Errors>")]
+pub(crate) struct RemoveEmailsMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// The mailbox to remove the emails from
+    pub(crate) mailbox: String,
+    /// UIDs of the emails to remove
+    pub(crate) uids: Vec<u32>,
+}
+
+impl Handler<RemoveEmailsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Vec<EmailRecord>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: RemoveEmailsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let account = msg.account.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(REMOVE_EMAILS_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .bind(("uids", msg.uids))
+                    .await
+                    .map_err(Errors::query)?
+                    .take::<Vec<EmailRecord>>(0)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(move |result, actor, ctx| {
+                // Messages from Gmail change in every mailbox they are in
+                if let Ok(emails) = &result {
+                    for (account, mailbox) in mailboxes(emails) {
+                        actor.notify(&account, &mailbox);
+                    }
+                    ctx.notify(RethreadMessage {
+                        account,
+                        emails: emails.iter().map(EmailRecord::id).collect(),
+                    });
+                }
+                actor.track("remove emails", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting that email records are written back
+///
+/// Each record goes to the mailboxes and UIDs it names, replacing any record
+/// already there. Their conversations are threaded again afterwards.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct RestoreEmailsMessage {
+    /// The records to write
+    pub(crate) emails: Vec<EmailRecord>,
+}
+
+impl Handler<RestoreEmailsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: RestoreEmailsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received {} emails to restore",
+            msg.emails.len()
+        );
+        let database = self.database.clone();
+        let mailboxes = mailboxes(&msg.emails);
+        // Restored emails can belong to several accounts, whose conversations
+        // are threaded apart
+        let mut ids: HashMap<String, Vec<Thing>> = HashMap::new();
+        for email in &msg.emails {
+            ids.entry(email.account().to_owned()).or_default().push(email.id());
+        }
+        Box::pin(
+            async move {
+                database
+                    .query(INSERT_EMAILS_QUERY)
+                    .bind(("emails", msg.emails))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                Ok(())
+            }
+            .into_actor(self)
+            .map(move |result, actor, ctx| {
+                if result.is_ok() {
+                    for (account, mailbox) in &mailboxes {
+                        actor.notify(account, mailbox);
+                    }
+                    for (account, emails) in ids {
+                        ctx.notify(RethreadMessage {
+                            account,
+                            emails,
+                        });
+                    }
+                }
+                actor.track("restore emails", result)
+            }),
+        )
+    }
+}
+
+/// Message requesting that flags are added to or removed from emails
+///
+/// Responds with the records as they were before, so they can be restored
+/// with a `RestoreEmailsMessage`.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<EmailRecord>, Errors>")]
+pub(crate) struct UpdateFlagsMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// The mailbox the emails are in
+    pub(crate) mailbox: String,
+    /// UIDs of the emails to update
+    pub(crate) uids: Vec<u32>,
+    /// The flags to add or remove, e.g. `\Seen`
+    pub(crate) flags: Vec<String>,
+    /// Whether to add the flags instead of removing them
+    pub(crate) add: bool,
+}
+
+impl Handler<UpdateFlagsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Vec<EmailRecord>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: UpdateFlagsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let query = if msg.add {
+            ADD_FLAGS_QUERY
+        } else {
+            REMOVE_FLAGS_QUERY
+        };
+        Box::pin(
+            async move {
+                database
+                    .query(query)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .bind(("uids", msg.uids))
+                    .bind(("flags", msg.flags))
+                    .await
+                    .map_err(Errors::query)?
+                    .take::<Vec<EmailRecord>>(0)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                // Messages from Gmail change in every mailbox they are in
+                if let Ok(emails) = &result {
+                    for (account, mailbox) in mailboxes(emails) {
+                        actor.notify(&account, &mailbox);
+                    }
+                }
+                actor.track("update flags", result)
+            }),
+        )
+    }
+}
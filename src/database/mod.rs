@@ -1,8 +1,45 @@
 //! Database related functionality
 
+/// Contains the queue of server-side operations waiting to be run
+pub(crate) mod action_queue;
 /// Contains the database actor and its messages
 mod actor;
+/// Contains the address book maintained by the user
+pub(crate) mod address_book;
+/// Contains the export of attachments to files
+pub(crate) mod attachments;
+/// Contains the content-addressed store for raw messages and attachments
+mod blob_store;
+/// Contains the bookkeeping for synchronizing the address book over CardDAV
+pub(crate) mod card_sync;
+/// Contains the contacts harvested from emails
+pub(crate) mod contacts;
+/// Contains the listings of mailboxes and emails for display
+pub(crate) mod listing;
+/// Contains the bookkeeping for synchronizing mailboxes incrementally
+pub(crate) mod mail_sync;
+/// Contains the optimistic updates of local mailboxes
+pub(crate) mod mailbox_changes;
+/// Contains the notifications sent to actors when emails change
+pub(crate) mod notifications;
+/// Contains the parser for the search query language
+pub(crate) mod query;
+/// Contains the allowances for loading remote content in HTML emails
+pub(crate) mod remote_content;
+/// Contains the search queries saved as smart folders
+pub(crate) mod saved_searches;
+/// Contains the full-text search over emails
+pub(crate) mod search;
+/// Contains the files keeping what the user decided across restarts
+mod state_store;
 /// Contains structures stored in the database
-mod structures;
+pub(crate) mod structures;
+/// Contains the fixtures shared by tests that need a database
+#[cfg(test)]
+pub(crate) mod testing;
+/// Contains the grouping of emails into conversations
+pub(crate) mod threading;
+/// Contains the vCard reader and writer for the address book
+pub(crate) mod vcard;
 
 pub(crate) use actor::*;
@@ -0,0 +1,53 @@
+//! Notifications about changes to the emails in the database
+//!
+//! Actors showing emails subscribe to the `DatabaseActor`, which tells them
+//! which mailbox changed after every write, so they can reload what they show.
+
+use actix::prelude::*;
+
+use super::DatabaseActor;
+
+/// Message sent to subscribers after emails of a mailbox were written
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "()")]
+pub(crate) struct EmailsChangedMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// The mailbox whose emails changed
+    pub(crate) mailbox: String,
+}
+
+/// Message requesting that an actor is notified of changes to emails
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct SubscribeMessage {
+    /// Where to send an `EmailsChangedMessage` after every change
+    pub(crate) subscriber: Recipient<EmailsChangedMessage>,
+}
+
+impl Handler<SubscribeMessage> for DatabaseActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SubscribeMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        self.subscribers.push(msg.subscriber);
+    }
+}
+
+impl DatabaseActor {
+    /// Tells subscribers that emails of a mailbox changed, forgetting the
+    /// ones that have stopped
+    pub(super) fn notify(&mut self, account: &str, mailbox: &str) {
+        self.subscribers.retain(Recipient::connected);
+        for subscriber in &self.subscribers {
+            subscriber.do_send(EmailsChangedMessage {
+                account: account.to_owned(),
+                mailbox: mailbox.to_owned(),
+            });
+        }
+    }
+}
@@ -0,0 +1,823 @@
+//! Parser for the search query language
+//!
+//! Queries look like the ones Gmail and notmuch accept. Bare words and quoted
+//! phrases are matched against the full-text indexes, and `field:value`
+//! operators narrow results down by specific fields:
+//!
+//! - `from:`, `to:` and `subject:` match part of the sender, the recipients or
+//!   the subject
+//! - `has:attachment` matches emails with attachments
+//! - `is:unread`, `is:read` and `is:flagged` match on IMAP flags
+//! - `before:` and `after:` take a `YYYY-MM-DD` date
+//! - `folder:` (or `in:`) and `account:` match the mailbox and account exactly
+//! - `tag:` (or `label:`) matches a tag, such as a Gmail label, ignoring case
+//!   and the backslash of system labels like `\Inbox`
+//!
+//! Terms next to each other must all match. They can be combined with `OR`,
+//! negated with `NOT` or a leading `-`, and grouped with parentheses, e.g.
+//! `from:alice (subject:report OR has:attachment) -is:read`.
+
+use std::fmt::Display;
+
+use time::{
+    format_description::{well_known::Rfc3339, FormatItem},
+    macros::format_description,
+    Date, OffsetDateTime,
+};
+
+/// Format of the dates accepted by `before:` and `after:`
+const DATE_FORMAT: &[FormatItem<'_>] =
+    format_description!("[year]-[month]-[day]");
+
+/// Format of dates in IMAP search criteria, e.g. `31-Jan-2024`
+const IMAP_DATE_FORMAT: &[FormatItem<'_>] =
+    format_description!("[day]-[month repr:short]-[year]");
+
+/// Errors that can occur while parsing a query
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    /// The query ended while a term or closing parenthesis was expected
+    UnexpectedEnd,
+    /// A token appeared where it isn't allowed, e.g. a stray `)`
+    UnexpectedToken(String),
+    /// A quoted phrase was never closed
+    UnclosedQuote,
+    /// A `has:` or `is:` operator was given a value it doesn't understand
+    UnknownValue(String),
+    /// A `before:` or `after:` operator was given something that isn't a date
+    InvalidDate(String),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnexpectedEnd => write!(f, "query ended unexpectedly"),
+            ParseError::UnexpectedToken(token) => {
+                write!(f, "unexpected \"{token}\"")
+            }
+            ParseError::UnclosedQuote => write!(f, "unclosed quote"),
+            ParseError::UnknownValue(value) => {
+                write!(f, "unknown operator value \"{value}\"")
+            }
+            ParseError::InvalidDate(date) => {
+                write!(f, "\"{date}\" is not a YYYY-MM-DD date")
+            }
+        }
+    }
+}
+
+/// A single condition in a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Term {
+    /// Free text matched against the full-text indexes
+    Text(String),
+    /// Part of a sender's name or address
+    From(String),
+    /// Part of a recipient's name or address
+    To(String),
+    /// Part of the subject
+    Subject(String),
+    /// The email has at least one attachment
+    HasAttachment,
+    /// The email doesn't have the `\Seen` flag
+    Unread,
+    /// The email has the `\Seen` flag
+    Read,
+    /// The email has the `\Flagged` flag
+    Flagged,
+    /// The email was sent before the start of this day (UTC)
+    Before(Date),
+    /// The email was sent on or after the start of this day (UTC)
+    After(Date),
+    /// The email is in this mailbox
+    Folder(String),
+    /// The email belongs to this account
+    Account(String),
+    /// The email has this tag, in lowercase
+    Tag(String),
+}
+
+/// A parsed search query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Query {
+    /// Every query must match
+    And(Vec<Query>),
+    /// At least one query must match
+    Or(Vec<Query>),
+    /// The query must not match
+    Not(Box<Query>),
+    /// A single condition
+    Term(Term),
+}
+
+/// A lexical token of the query language
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    /// `(`
+    Open,
+    /// `)`
+    Close,
+    /// `AND`
+    And,
+    /// `OR`
+    Or,
+    /// `NOT` or a leading `-`
+    Not,
+    /// A word or quoted phrase, optionally prefixed by `field:`
+    Word {
+        /// The part before the colon, if any
+        field: Option<String>,
+        /// The word or phrase itself
+        value: String,
+    },
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Not => write!(f, "NOT"),
+            Token::Word {
+                field: Some(field),
+                value,
+            } => write!(f, "{field}:{value}"),
+            Token::Word {
+                field: None,
+                value,
+            } => write!(f, "{value}"),
+        }
+    }
+}
+
+/// Reads a quoted phrase, assuming the opening quote was already consumed
+fn read_quoted(
+    characters: &mut std::iter::Peekable<std::str::Chars<'_>>,
+) -> Result<String, ParseError> {
+    let mut phrase = String::new();
+    for character in characters.by_ref() {
+        if character == '"' {
+            return Ok(phrase);
+        }
+        phrase.push(character);
+    }
+    Err(ParseError::UnclosedQuote)
+}
+
+/// Splits a query into tokens
+fn tokenize(query: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut characters = query.chars().peekable();
+    while let Some(&character) = characters.peek() {
+        match character {
+            _ if character.is_whitespace() => {
+                characters.next();
+            }
+            '(' => {
+                characters.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                characters.next();
+                tokens.push(Token::Close);
+            }
+            '-' => {
+                characters.next();
+                tokens.push(Token::Not);
+            }
+            '"' => {
+                characters.next();
+                tokens.push(Token::Word {
+                    field: None,
+                    value: read_quoted(&mut characters)?,
+                });
+            }
+            _ => {
+                let mut word = String::new();
+                let mut field = None;
+                while let Some(&character) = characters.peek() {
+                    if character.is_whitespace() || "()".contains(character) {
+                        break;
+                    }
+                    characters.next();
+                    if character == ':' && field.is_none() {
+                        field = Some(std::mem::take(&mut word));
+                        if characters.peek() == Some(&'"') {
+                            characters.next();
+                            word = read_quoted(&mut characters)?;
+                            break;
+                        }
+                        continue;
+                    }
+                    word.push(character);
+                }
+                tokens.push(match (field, word.as_str()) {
+                    (None, "AND") => Token::And,
+                    (None, "OR") => Token::Or,
+                    (None, "NOT") => Token::Not,
+                    (field, _) => Token::Word {
+                        field,
+                        value: word,
+                    },
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a `YYYY-MM-DD` date
+fn parse_date(value: &str) -> Result<Date, ParseError> {
+    match Date::parse(&value.replace('/', "-"), DATE_FORMAT) {
+        Ok(date) => Ok(date),
+        Err(e) => {
+            log::trace!("Failed to parse date \"{value}\": {e}");
+            Err(ParseError::InvalidDate(value.to_owned()))
+        }
+    }
+}
+
+/// Turns a word token into a term
+fn term(field: Option<String>, value: String) -> Result<Term, ParseError> {
+    let Some(field) = field else {
+        return Ok(Term::Text(value));
+    };
+    Ok(match field.to_lowercase().as_str() {
+        "from" => Term::From(value.to_lowercase()),
+        "to" => Term::To(value.to_lowercase()),
+        "subject" => Term::Subject(value.to_lowercase()),
+        "folder" | "in" => Term::Folder(value),
+        "account" => Term::Account(value),
+        "tag" | "label" => Term::Tag(value.to_lowercase()),
+        "before" => Term::Before(parse_date(&value)?),
+        "after" => Term::After(parse_date(&value)?),
+        "has" => match value.to_lowercase().as_str() {
+            "attachment" | "attachments" => Term::HasAttachment,
+            _ => return Err(ParseError::UnknownValue(value)),
+        },
+        "is" => match value.to_lowercase().as_str() {
+            "unread" => Term::Unread,
+            "read" => Term::Read,
+            "flagged" | "starred" => Term::Flagged,
+            _ => return Err(ParseError::UnknownValue(value)),
+        },
+        // Anything else, like a URL, is just text that happens to contain a
+        // colon
+        _ => Term::Text(format!("{field}:{value}")),
+    })
+}
+
+/// Recursive descent parser over the tokens of a query
+struct Parser {
+    /// The tokens of the query, in order
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    /// Parses `and ("OR" and)*`
+    fn or(&mut self) -> Result<Query, ParseError> {
+        let mut alternatives = vec![self.and()?];
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            alternatives.push(self.and()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.remove(0)
+        } else {
+            Query::Or(alternatives)
+        })
+    }
+
+    /// Parses `unary ("AND"? unary)*`
+    fn and(&mut self) -> Result<Query, ParseError> {
+        let mut conditions = vec![self.unary()?];
+        loop {
+            match self.tokens.peek() {
+                None | Some(Token::Or | Token::Close) => break,
+                Some(Token::And) => {
+                    self.tokens.next();
+                }
+                Some(_) => {}
+            }
+            conditions.push(self.unary()?);
+        }
+        Ok(if conditions.len() == 1 {
+            conditions.remove(0)
+        } else {
+            Query::And(conditions)
+        })
+    }
+
+    /// Parses `"NOT" unary | "(" or ")" | word`
+    fn unary(&mut self) -> Result<Query, ParseError> {
+        match self.tokens.next() {
+            None => Err(ParseError::UnexpectedEnd),
+            Some(Token::Not) => Ok(Query::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let query = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(query),
+                    Some(token) => {
+                        Err(ParseError::UnexpectedToken(token.to_string()))
+                    }
+                    None => Err(ParseError::UnexpectedEnd),
+                }
+            }
+            Some(Token::Word {
+                field,
+                value,
+            }) => Ok(Query::Term(term(field, value)?)),
+            Some(token) => Err(ParseError::UnexpectedToken(token.to_string())),
+        }
+    }
+}
+
+/// A query compiled into a condition in `SurrealQL`
+pub(crate) struct CompiledQuery {
+    /// Condition to put after `WHERE`
+    pub(crate) condition: String,
+    /// Values for the `$qN` parameters used in `condition`
+    pub(crate) bindings: Vec<(String, String)>,
+    /// Number of free text terms. Text term `n` uses the full-text match
+    /// references `3n` (subject), `3n + 1` (addresses) and `3n + 2` (body).
+    pub(crate) text_terms: usize,
+    /// The parameters holding the mailboxes of `folder:` terms
+    pub(crate) folders: Vec<String>,
+}
+
+impl CompiledQuery {
+    /// Adds a parameter and returns its name in the condition
+    fn bind(&mut self, value: String) -> String {
+        let name = format!("q{}", self.bindings.len());
+        let parameter = format!("${name}");
+        self.bindings.push((name, value));
+        parameter
+    }
+}
+
+/// Formats the start of a day (UTC) the way email dates are stored
+fn start_of_day(date: Date) -> String {
+    OffsetDateTime::new_utc(date, time::Time::MIDNIGHT)
+        .format(&Rfc3339)
+        .expect("Midnight UTC is always a valid RFC 3339 timestamp")
+}
+
+impl Query {
+    /// Parses a query string
+    ///
+    /// An empty query matches every email.
+    pub(crate) fn parse(query: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(query)?;
+        if tokens.is_empty() {
+            return Ok(Query::And(Vec::new()));
+        }
+        let mut parser = Parser {
+            tokens: tokens.into_iter().peekable(),
+        };
+        let query = parser.or()?;
+        match parser.tokens.next() {
+            None => Ok(query),
+            Some(token) => Err(ParseError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    /// Compiles the query into a condition on `mail` records
+    pub(crate) fn compile(&self) -> CompiledQuery {
+        let mut compiled = CompiledQuery {
+            condition: String::new(),
+            bindings: Vec::new(),
+            text_terms: 0,
+            folders: Vec::new(),
+        };
+        compiled.condition = self.compile_into(&mut compiled);
+        compiled
+    }
+
+    /// Whether emails in a mailbox of an account can match the query, as far
+    /// as its `folder:` and `account:` terms tell
+    pub(crate) fn admits(&self, account: &str, mailbox: &str) -> bool {
+        match self {
+            Query::And(queries) => {
+                queries.iter().all(|query| query.admits(account, mailbox))
+            }
+            Query::Or(queries) => {
+                queries.iter().any(|query| query.admits(account, mailbox))
+            }
+            Query::Not(query) => match query.as_ref() {
+                Query::Term(Term::Folder(folder)) => folder != mailbox,
+                Query::Term(Term::Account(address)) => address != account,
+                _ => true,
+            },
+            Query::Term(Term::Folder(folder)) => folder == mailbox,
+            Query::Term(Term::Account(address)) => address == account,
+            Query::Term(_) => true,
+        }
+    }
+
+    /// Compiles the query into IMAP `SEARCH` criteria
+    ///
+    /// Conditions IMAP can't express, like `has:attachment`, are left out, so
+    /// the server may return more messages than the query strictly matches,
+    /// but never fewer. `folder:` and `account:` are left out too since they
+    /// decide which mailbox is searched in the first place.
+    pub(crate) fn imap_criteria(&self) -> String {
+        self.imap_criteria_inner().unwrap_or_else(|| "ALL".to_owned())
+    }
+
+    /// Compiles the query into IMAP `SEARCH` criteria, or `None` if it can't
+    /// be expressed and has to match everything
+    fn imap_criteria_inner(&self) -> Option<String> {
+        match self {
+            Query::And(queries) => {
+                let criteria: Vec<String> = queries
+                    .iter()
+                    .filter_map(Query::imap_criteria_inner)
+                    .collect();
+                (!criteria.is_empty())
+                    .then(|| format!("({})", criteria.join(" ")))
+            }
+            Query::Or(queries) => queries
+                .iter()
+                .map(Query::imap_criteria_inner)
+                .collect::<Option<Vec<String>>>()?
+                .into_iter()
+                .reduce(|left, right| format!("OR {left} {right}")),
+            // Leaving out part of a negated query would match fewer messages,
+            // so it has to be expressed in full or not at all
+            Query::Not(query) if query.is_imap_exact() => {
+                Some(format!("NOT {}", query.imap_criteria_inner()?))
+            }
+            Query::Not(_) => None,
+            Query::Term(term) => term.imap_criteria(),
+        }
+    }
+
+    /// Whether IMAP can express every term of the query, so its criteria
+    /// match exactly the messages the query does
+    fn is_imap_exact(&self) -> bool {
+        match self {
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().all(Query::is_imap_exact)
+            }
+            Query::Not(query) => query.is_imap_exact(),
+            Query::Term(term) => term.is_imap_exact(),
+        }
+    }
+
+    /// Compiles the query, adding its parameters to `compiled`
+    fn compile_into(&self, compiled: &mut CompiledQuery) -> String {
+        match self {
+            Query::And(queries) if queries.is_empty() => "true".to_owned(),
+            Query::Or(queries) if queries.is_empty() => "false".to_owned(),
+            Query::And(queries) | Query::Or(queries) => {
+                let separator = if matches!(self, Query::And(_)) {
+                    " AND "
+                } else {
+                    " OR "
+                };
+                let conditions: Vec<String> = queries
+                    .iter()
+                    .map(|query| query.compile_into(compiled))
+                    .collect();
+                format!("({})", conditions.join(separator))
+            }
+            Query::Not(query) => format!("!({})", query.compile_into(compiled)),
+            Query::Term(term) => term.compile_into(compiled),
+        }
+    }
+}
+
+/// Quotes a string for use in IMAP search criteria
+fn imap_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Formats a date for use in IMAP search criteria
+fn imap_date(date: Date) -> String {
+    date.format(IMAP_DATE_FORMAT)
+        .expect("Every date can be formatted as an IMAP date")
+}
+
+impl Term {
+    /// Compiles the term into IMAP `SEARCH` criteria, or `None` if IMAP
+    /// can't express it
+    fn imap_criteria(&self) -> Option<String> {
+        Some(match self {
+            Term::Text(text) => format!("TEXT {}", imap_quote(text)),
+            Term::From(text) => format!("FROM {}", imap_quote(text)),
+            Term::To(text) => {
+                let text = imap_quote(text);
+                format!("OR TO {text} CC {text}")
+            }
+            Term::Subject(text) => format!("SUBJECT {}", imap_quote(text)),
+            Term::Unread => "UNSEEN".to_owned(),
+            Term::Read => "SEEN".to_owned(),
+            Term::Flagged => "FLAGGED".to_owned(),
+            // `BEFORE` and `SINCE` would compare the date the server received
+            // the message on. The `SENT` keys compare the `Date` header like
+            // the local query, but on the day in the sender's time zone
+            // rather than in UTC, so each bound is widened by a day.
+            Term::Before(date) => format!(
+                "SENTBEFORE {}",
+                imap_date(date.next_day().unwrap_or(*date))
+            ),
+            Term::After(date) => format!(
+                "SENTSINCE {}",
+                imap_date(date.previous_day().unwrap_or(*date))
+            ),
+            Term::HasAttachment
+            | Term::Folder(_)
+            | Term::Account(_)
+            | Term::Tag(_) => return None,
+        })
+    }
+
+    /// Whether the IMAP criteria of the term match exactly the messages the
+    /// term does
+    ///
+    /// Dates are matched a day early or late, and some terms can't be
+    /// expressed at all.
+    fn is_imap_exact(&self) -> bool {
+        !matches!(self, Term::Before(_) | Term::After(_))
+            && self.imap_criteria().is_some()
+    }
+
+    /// Compiles the term, adding its parameters to `compiled`
+    fn compile_into(&self, compiled: &mut CompiledQuery) -> String {
+        match self {
+            Term::Text(text) => {
+                let parameter = compiled.bind(text.clone());
+                let reference = compiled.text_terms * 3;
+                compiled.text_terms += 1;
+                format!(
+                    "(subject @{}@ {parameter} OR addresses @{}@ {parameter} \
+                     OR body_text @{}@ {parameter})",
+                    reference,
+                    reference + 1,
+                    reference + 2
+                )
+            }
+            Term::From(text) => {
+                let parameter = compiled.bind(text.clone());
+                format!("string::contains(from_text, {parameter})")
+            }
+            Term::To(text) => {
+                let parameter = compiled.bind(text.clone());
+                format!("string::contains(to_text, {parameter})")
+            }
+            Term::Subject(text) => {
+                let parameter = compiled.bind(text.clone());
+                format!(
+                    "string::contains(string::lowercase(subject ?? ''), \
+                     {parameter})"
+                )
+            }
+            Term::HasAttachment => "array::len(attachments) > 0".to_owned(),
+            Term::Unread => "'\\\\Seen' NOTINSIDE flags".to_owned(),
+            Term::Read => "'\\\\Seen' INSIDE flags".to_owned(),
+            Term::Flagged => "'\\\\Flagged' INSIDE flags".to_owned(),
+            Term::Before(date) => {
+                let parameter = compiled.bind(start_of_day(*date));
+                format!("(date != NONE AND date < {parameter})")
+            }
+            Term::After(date) => {
+                let parameter = compiled.bind(start_of_day(*date));
+                format!("(date != NONE AND date >= {parameter})")
+            }
+            Term::Folder(folder) => {
+                let parameter = compiled.bind(folder.clone());
+                compiled.folders.push(parameter.clone());
+                format!("{parameter} INSIDE locations.mailbox")
+            }
+            Term::Account(account) => {
+                let parameter = compiled.bind(account.clone());
+                format!("account = {parameter}")
+            }
+            Term::Tag(tag) => {
+                let parameter = compiled.bind(tag.clone());
+                let system = compiled.bind(format!("\\{tag}"));
+                format!(
+                    "array::len(tags[WHERE string::lowercase($this) INSIDE \
+                     [{parameter}, {system}]]) > 0"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::date;
+
+    use super::{ParseError, Query, Term};
+
+    /// Parses a query that is expected to be valid
+    fn parse(query: &str) -> Query {
+        Query::parse(query).expect("The query should be valid")
+    }
+
+    /// A free text term
+    fn text(value: &str) -> Query {
+        Query::Term(Term::Text(value.to_owned()))
+    }
+
+    /// Terms next to each other bind tighter than `OR`, and `NOT` tighter
+    /// than both
+    #[test]
+    fn respects_precedence() {
+        assert_eq!(
+            parse("a b OR c AND d"),
+            Query::Or(vec![
+                Query::And(vec![text("a"), text("b")]),
+                Query::And(vec![text("c"), text("d")]),
+            ])
+        );
+        assert_eq!(
+            parse("NOT a b"),
+            Query::And(vec![Query::Not(Box::new(text("a"))), text("b")])
+        );
+        assert_eq!(
+            parse("a (b OR c)"),
+            Query::And(vec![text("a"), Query::Or(vec![text("b"), text("c")]),])
+        );
+        assert_eq!(parse(""), Query::And(Vec::new()));
+    }
+
+    /// `NOT` and a leading `-` negate terms and groups alike
+    #[test]
+    fn negates() {
+        assert_eq!(
+            parse("-is:read NOT (from:Alice OR has:attachment)"),
+            Query::And(vec![
+                Query::Not(Box::new(Query::Term(Term::Read))),
+                Query::Not(Box::new(Query::Or(vec![
+                    Query::Term(Term::From("alice".to_owned())),
+                    Query::Term(Term::HasAttachment),
+                ]))),
+            ])
+        );
+        assert_eq!(
+            parse("--a"),
+            Query::Not(Box::new(Query::Not(Box::new(text("a")))))
+        );
+    }
+
+    /// Quoted phrases keep their spaces and operators, and unknown fields
+    /// stay text
+    #[test]
+    fn reads_quotes() {
+        assert_eq!(
+            parse("subject:\"Weekly Report\" \"a OR (b)\" folder:\"My Mail\""),
+            Query::And(vec![
+                Query::Term(Term::Subject("weekly report".to_owned())),
+                text("a OR (b)"),
+                Query::Term(Term::Folder("My Mail".to_owned())),
+            ])
+        );
+        assert_eq!(parse("https://example.com"), text("https://example.com"));
+        assert_eq!(
+            parse("label:\\Inbox"),
+            Query::Term(Term::Tag("\\inbox".to_owned()))
+        );
+    }
+
+    /// Dates are read with dashes or slashes
+    #[test]
+    fn reads_dates() {
+        assert_eq!(
+            parse("after:2024-01-31 before:2024/02/05"),
+            Query::And(vec![
+                Query::Term(Term::After(date!(2024 - 01 - 31))),
+                Query::Term(Term::Before(date!(2024 - 02 - 05))),
+            ])
+        );
+        assert!(matches!(
+            Query::parse("before:yesterday"),
+            Err(ParseError::InvalidDate(date)) if date == "yesterday"
+        ));
+        assert!(matches!(
+            Query::parse("after:2024-02-30"),
+            Err(ParseError::InvalidDate(_))
+        ));
+    }
+
+    /// Malformed queries are rejected with the reason
+    #[test]
+    fn rejects_malformed_queries() {
+        assert!(matches!(Query::parse("(a"), Err(ParseError::UnexpectedEnd)));
+        assert!(matches!(Query::parse("a OR"), Err(ParseError::UnexpectedEnd)));
+        assert!(matches!(Query::parse("-"), Err(ParseError::UnexpectedEnd)));
+        assert!(matches!(
+            Query::parse("a)"),
+            Err(ParseError::UnexpectedToken(token)) if token == ")"
+        ));
+        assert!(matches!(
+            Query::parse("OR a"),
+            Err(ParseError::UnexpectedToken(token)) if token == "OR"
+        ));
+        assert!(matches!(
+            Query::parse("subject:\"open"),
+            Err(ParseError::UnclosedQuote)
+        ));
+        assert!(matches!(
+            Query::parse("is:maybe"),
+            Err(ParseError::UnknownValue(value)) if value == "maybe"
+        ));
+        assert!(matches!(
+            Query::parse("has:nothing"),
+            Err(ParseError::UnknownValue(_))
+        ));
+    }
+
+    /// Queries turn into the IMAP criteria matching the same messages
+    #[test]
+    fn compiles_imap_criteria() {
+        let cases = [
+            ("", "ALL"),
+            (
+                "from:alice subject:\"Weekly report\"",
+                "(FROM \"alice\" SUBJECT \"weekly report\")",
+            ),
+            ("to:bob", "OR TO \"bob\" CC \"bob\""),
+            ("a OR b OR c", "OR OR TEXT \"a\" TEXT \"b\" TEXT \"c\""),
+            ("-is:read is:flagged", "(NOT SEEN FLAGGED)"),
+            (
+                "after:2024-01-31 before:2024-02-05",
+                "(SENTSINCE 30-Jan-2024 SENTBEFORE 06-Feb-2024)",
+            ),
+            ("from:a\\b", "FROM \"a\\\\b\""),
+        ];
+        for (query, criteria) in cases {
+            assert_eq!(parse(query).imap_criteria(), criteria, "for {query}");
+        }
+    }
+
+    /// Dates are compared with the `Date` header rather than the arrival
+    /// date, a day wider since servers take the day in the sender's time
+    /// zone
+    #[test]
+    fn compares_sent_dates() {
+        let cases = [
+            ("before:2024-02-29", "SENTBEFORE 01-Mar-2024"),
+            ("after:2024-01-01", "SENTSINCE 31-Dec-2023"),
+            ("-after:2024-01-01", "ALL"),
+        ];
+        for (query, criteria) in cases {
+            assert_eq!(parse(query).imap_criteria(), criteria, "for {query}");
+        }
+    }
+
+    /// Terms IMAP can't express widen the criteria, but never narrow them
+    #[test]
+    fn widens_imap_criteria() {
+        let cases = [
+            ("has:attachment is:unread", "(UNSEEN)"),
+            ("folder:INBOX tag:work", "ALL"),
+            ("is:unread OR has:attachment", "ALL"),
+            ("-has:attachment", "ALL"),
+            ("is:flagged -(is:read has:attachment)", "(FLAGGED)"),
+            ("-(is:read from:alice)", "NOT (SEEN FROM \"alice\")"),
+            ("is:unread -before:2024-01-31", "(UNSEEN)"),
+            ("-(after:2024-01-31 OR is:read)", "ALL"),
+        ];
+        for (query, criteria) in cases {
+            assert_eq!(parse(query).imap_criteria(), criteria, "for {query}");
+        }
+    }
+
+    /// Text terms get their own full-text references and every value is
+    /// bound as a parameter
+    #[test]
+    fn compiles_conditions() {
+        let compiled = parse("hello -folder:Spam OR \"big news\"").compile();
+        assert_eq!(
+            compiled.condition,
+            "(((subject @0@ $q0 OR addresses @1@ $q0 OR body_text @2@ $q0) \
+             AND !($q1 INSIDE locations.mailbox)) OR (subject @3@ $q2 OR \
+             addresses @4@ $q2 OR body_text @5@ $q2))"
+        );
+        assert_eq!(compiled.text_terms, 2);
+        assert_eq!(compiled.folders, ["$q1"]);
+        assert_eq!(
+            compiled.bindings,
+            [
+                ("q0".to_owned(), "hello".to_owned()),
+                ("q1".to_owned(), "Spam".to_owned()),
+                ("q2".to_owned(), "big news".to_owned()),
+            ]
+        );
+    }
+
+    /// Only mailboxes that `folder:` and `account:` terms allow are searched
+    #[test]
+    fn admits_mailboxes() {
+        let query =
+            parse("(folder:INBOX OR folder:Sent) -account:a@example.com");
+        assert!(query.admits("b@example.com", "INBOX"));
+        assert!(query.admits("b@example.com", "Sent"));
+        assert!(!query.admits("b@example.com", "Spam"));
+        assert!(!query.admits("a@example.com", "INBOX"));
+        assert!(parse("hello").admits("a@example.com", "Spam"));
+    }
+}
@@ -0,0 +1,209 @@
+//! The user's allowances for loading remote content in HTML emails
+//!
+//! Remote content is blocked by default, since loading it tells the sender
+//! the email was read. The user can allow it for a single email or for
+//! everything from a sender. Allowances are keyed by what they allow, so
+//! allowing the same thing twice keeps a single record. They are kept in the
+//! state store, so they survive restarts.
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use surrealdb::{engine::local::Db, Surreal};
+
+use super::{state_store::StateStore, DatabaseActor, Errors};
+use crate::mail::StringAddress;
+
+/// Name of the state store table the allowed emails are kept in
+const MESSAGES_TABLE: &str = "allowed_messages";
+
+/// Name of the state store table the allowed senders are kept in
+const SENDERS_TABLE: &str = "allowed_senders";
+
+/// Finds whether remote content is allowed for an email, as whether the email
+/// itself is allowed and the senders allowed in its account
+///
+/// An email allowed in one mailbox is allowed in every mailbox it is in, as
+/// Gmail files the same message under several labels.
+pub(super) const ALLOWANCES_QUERY: &str = "
+    RETURN count(
+        SELECT VALUE id FROM allowed_message
+        WHERE account = $account AND { mailbox: mailbox, uid: uid } INSIDE (
+            SELECT VALUE locations FROM mail WHERE account = $account
+                AND locations CONTAINS { mailbox: $mailbox, uid: $uid }
+        )[0]
+    ) > 0;
+    SELECT VALUE address FROM allowed_sender WHERE account = $account;
+";
+
+/// Allows remote content in an email
+const ALLOW_MESSAGE_QUERY: &str = "
+    UPDATE type::thing('allowed_message', [$account, $mailbox, $uid]) CONTENT {
+        account: $account,
+        mailbox: $mailbox,
+        uid: $uid,
+    };
+";
+
+/// Allows remote content in everything from a sender
+const ALLOW_SENDER_QUERY: &str = "
+    UPDATE type::thing('allowed_sender', [$account, $address]) CONTENT {
+        account: $account,
+        address: $address,
+    };
+";
+
+/// Selects every email remote content is allowed in
+const ALL_MESSAGES_QUERY: &str =
+    "SELECT account, mailbox, uid FROM allowed_message";
+
+/// Selects every sender remote content is allowed from
+const ALL_SENDERS_QUERY: &str = "SELECT account, address FROM allowed_sender";
+
+/// An email remote content is allowed in, as kept in the state store
+#[derive(Serialize, Deserialize)]
+struct AllowedMessage {
+    /// The account the email belongs to
+    account: String,
+    /// The mailbox the email is in
+    mailbox: String,
+    /// UID of the email
+    uid: u32,
+}
+
+/// A sender remote content is allowed from, as kept in the state store
+#[derive(Serialize, Deserialize)]
+struct AllowedSender {
+    /// The account the sender's emails belong to
+    account: String,
+    /// The bare address of the sender, in lowercase
+    address: String,
+}
+
+/// Adds the allowances kept in the state store to a new database
+pub(super) async fn restore(
+    database: &Surreal<Db>,
+    state: &StateStore,
+) -> Result<(), Errors> {
+    let messages: Vec<AllowedMessage> =
+        state.load(MESSAGES_TABLE).map_err(Errors::State)?;
+    for message in messages {
+        database
+            .query(ALLOW_MESSAGE_QUERY)
+            .bind(("account", message.account))
+            .bind(("mailbox", message.mailbox))
+            .bind(("uid", message.uid))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(Errors::query)?;
+    }
+    let senders: Vec<AllowedSender> =
+        state.load(SENDERS_TABLE).map_err(Errors::State)?;
+    for sender in senders {
+        database
+            .query(ALLOW_SENDER_QUERY)
+            .bind(("account", sender.account))
+            .bind(("address", sender.address))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(Errors::query)?;
+    }
+    Ok(())
+}
+
+/// Whether an email from the given senders may load remote content
+pub(super) fn is_allowed(
+    message_allowed: bool,
+    allowed_senders: &[String],
+    from: Option<&Vec<StringAddress>>,
+) -> bool {
+    message_allowed
+        || from
+            .into_iter()
+            .flatten()
+            .filter_map(StringAddress::address)
+            .any(|address| allowed_senders.contains(&address))
+}
+
+/// What remote content is allowed for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Allowance {
+    /// A single email
+    Message {
+        /// The mailbox the email is in
+        mailbox: String,
+        /// UID of the email
+        uid: u32,
+    },
+    /// Every email from a sender, by bare address
+    Sender(String),
+}
+
+/// Message allowing remote content in emails of an account
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct AllowRemoteContentMessage {
+    /// The account the emails belong to
+    pub(crate) account: String,
+    /// What remote content is allowed for
+    pub(crate) allowance: Allowance,
+}
+
+impl Handler<AllowRemoteContentMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: AllowRemoteContentMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                match msg.allowance {
+                    Allowance::Message {
+                        mailbox,
+                        uid,
+                    } => {
+                        database
+                            .query(ALLOW_MESSAGE_QUERY)
+                            .bind(("account", msg.account))
+                            .bind(("mailbox", mailbox))
+                            .bind(("uid", uid))
+                            .await
+                            .and_then(surrealdb::Response::check)
+                            .map_err(Errors::query)?;
+                        state
+                            .persist::<AllowedMessage>(
+                                &database,
+                                MESSAGES_TABLE,
+                                ALL_MESSAGES_QUERY,
+                            )
+                            .await
+                    }
+                    Allowance::Sender(address) => {
+                        database
+                            .query(ALLOW_SENDER_QUERY)
+                            .bind(("account", msg.account))
+                            .bind(("address", address.to_lowercase()))
+                            .await
+                            .and_then(surrealdb::Response::check)
+                            .map_err(Errors::query)?;
+                        state
+                            .persist::<AllowedSender>(
+                                &database,
+                                SENDERS_TABLE,
+                                ALL_SENDERS_QUERY,
+                            )
+                            .await
+                    }
+                }
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("allow remote content", result)
+            }),
+        )
+    }
+}
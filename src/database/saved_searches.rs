@@ -0,0 +1,210 @@
+//! Search queries the user saved under a name, shown as smart folders
+//!
+//! Saved searches are keyed by name, so saving a query under a name that is
+//! already taken replaces the query. They are listed with the number of
+//! unread emails matching them, which callers refresh whenever emails change.
+//! They are kept in the state store, so they survive restarts.
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use surrealdb::{engine::local::Db, Surreal};
+
+use super::{
+    listing::{count_statement, Listing},
+    query::Query,
+    state_store::StateStore,
+    DatabaseActor, Errors,
+};
+
+/// Name of the state store table the saved searches are kept in
+const STATE_TABLE: &str = "saved_searches";
+
+/// Saves a query under a name, replacing any query saved under it before
+const SAVE_SEARCH_QUERY: &str = "
+    UPDATE type::thing('saved_search', $name) CONTENT {
+        name: $name,
+        query: $query,
+    };
+";
+
+/// Deletes the query saved under a name
+const DELETE_SEARCH_QUERY: &str = "
+    DELETE type::thing('saved_search', $name);
+";
+
+/// Selects every saved search in alphabetical order
+const SAVED_SEARCHES_QUERY: &str = "
+    SELECT name, query FROM saved_search ORDER BY name COLLATE;
+";
+
+/// A query saved under a name
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SavedSearch {
+    /// The name shown in the folder tree
+    pub(crate) name: String,
+    /// The search query
+    pub(crate) query: String,
+    /// How many emails matching the query are unread
+    #[serde(skip)]
+    pub(crate) unread: usize,
+}
+
+/// Adds the searches kept in the state store to a new database
+pub(super) async fn restore(
+    database: &Surreal<Db>,
+    state: &StateStore,
+) -> Result<(), Errors> {
+    let searches: Vec<SavedSearch> =
+        state.load(STATE_TABLE).map_err(Errors::State)?;
+    for search in searches {
+        database
+            .query(SAVE_SEARCH_QUERY)
+            .bind(("name", search.name))
+            .bind(("query", search.query))
+            .await
+            .and_then(surrealdb::Response::check)
+            .map_err(Errors::query)?;
+    }
+    Ok(())
+}
+
+/// Message saving a search query under a name
+///
+/// Fails without saving anything if the query can't be parsed.
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct SaveSearchMessage {
+    /// The name to save the query under
+    pub(crate) name: String,
+    /// The search query
+    pub(crate) query: String,
+}
+
+impl Handler<SaveSearchMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: SaveSearchMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        if let Err(e) = Query::parse(&msg.query) {
+            return Box::pin(fut::ready(Err(Errors::Parse(e))));
+        }
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(SAVE_SEARCH_QUERY)
+                    .bind(("name", msg.name))
+                    .bind(("query", msg.query))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                state
+                    .persist::<SavedSearch>(
+                        &database,
+                        STATE_TABLE,
+                        SAVED_SEARCHES_QUERY,
+                    )
+                    .await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("save search", result)),
+        )
+    }
+}
+
+/// Message deleting the search query saved under a name
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct DeleteSearchMessage {
+    /// The name the query was saved under
+    pub(crate) name: String,
+}
+
+impl Handler<DeleteSearchMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: DeleteSearchMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        let state = self.state.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(DELETE_SEARCH_QUERY)
+                    .bind(("name", msg.name))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                state
+                    .persist::<SavedSearch>(
+                        &database,
+                        STATE_TABLE,
+                        SAVED_SEARCHES_QUERY,
+                    )
+                    .await
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("delete search", result)),
+        )
+    }
+}
+
+/// Message requesting every saved search with its number of unread emails
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<Vec<SavedSearch>, Errors>")]
+pub(crate) struct ListSavedSearchesMessage;
+
+impl Handler<ListSavedSearchesMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Vec<SavedSearch>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ListSavedSearchesMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let mut searches: Vec<SavedSearch> = database
+                    .query(SAVED_SEARCHES_QUERY)
+                    .await
+                    .map_err(Errors::query)?
+                    .take(0)
+                    .map_err(Errors::query)?;
+                // Each query binds its own `$qN` parameters, so the counts
+                // can't share a request
+                for search in &mut searches {
+                    let unread =
+                        Listing::Query(format!("({}) is:unread", search.query));
+                    let selection = unread.selection()?;
+                    let mut statement =
+                        database.query(count_statement(&selection.condition));
+                    for binding in selection.bindings {
+                        statement = statement.bind(binding);
+                    }
+                    let count: Option<usize> = statement
+                        .await
+                        .map_err(Errors::query)?
+                        .take(0)
+                        .map_err(Errors::query)?;
+                    search.unread = count.unwrap_or_default();
+                }
+                Ok(searches)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("list saved searches", result)
+            }),
+        )
+    }
+}
@@ -0,0 +1,150 @@
+//! Full-text search over the emails in the database
+
+use std::collections::HashSet;
+
+use actix::prelude::*;
+
+use super::{DatabaseActor, Errors};
+
+/// Defines the analyzer and the full-text indexes used by free text terms
+///
+/// Each searched field gets its own index so results can be ranked and
+/// highlighted per field.
+pub(super) const SEARCH_SCHEMA: &str = "
+    DEFINE ANALYZER mail_text
+        TOKENIZERS blank, class, punct
+        FILTERS lowercase, ascii, snowball(english);
+    DEFINE INDEX mail_subject_search ON mail FIELDS subject
+        SEARCH ANALYZER mail_text BM25 HIGHLIGHTS;
+    DEFINE INDEX mail_addresses_search ON mail FIELDS addresses
+        SEARCH ANALYZER mail_text BM25 HIGHLIGHTS;
+    DEFINE INDEX mail_body_search ON mail FIELDS body_text
+        SEARCH ANALYZER mail_text BM25 HIGHLIGHTS;
+";
+
+/// Builds the expression ranking emails by how well they match the free text
+/// terms of a compiled query
+///
+/// The subject is weighted above the addresses, which are weighted above the
+/// body, so a term in the subject outranks the same term buried in a long
+/// message. Without free text terms every email ranks the same.
+pub(super) fn score(text_terms: usize) -> String {
+    if text_terms == 0 {
+        return "0".to_owned();
+    }
+    (0..text_terms)
+        .map(|term| {
+            let reference = term * 3;
+            format!(
+                "(search::score({reference}) ?? 0) * 3 + (search::score({}) \
+                 ?? 0) * 2 + (search::score({}) ?? 0)",
+                reference + 1,
+                reference + 2
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" + ")
+}
+
+/// Marks the start of a matched term in highlighted text
+pub(crate) const HIGHLIGHT_OPEN: char = '\u{2}';
+/// Marks the end of a matched term in highlighted text
+pub(crate) const HIGHLIGHT_CLOSE: char = '\u{3}';
+
+/// How many characters of context to keep on each side of the first match when
+/// building a snippet
+const SNIPPET_CONTEXT: usize = 80;
+
+/// Cuts a highlighted body down to the text surrounding its first match, with
+/// matched terms still wrapped in `HIGHLIGHT_OPEN` and `HIGHLIGHT_CLOSE`
+pub(super) fn snippet(highlighted: &str) -> String {
+    let characters: Vec<char> = highlighted.chars().collect();
+    let first_match = characters
+        .iter()
+        .position(|character| *character == HIGHLIGHT_OPEN)
+        .unwrap_or(0);
+    let start = first_match.saturating_sub(SNIPPET_CONTEXT);
+    let end = (first_match + SNIPPET_CONTEXT * 2).min(characters.len());
+    let excerpt: String = characters[start..end].iter().collect();
+    let mut snippet =
+        excerpt.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < characters.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// UIDs of messages that matched a search on the IMAP server
+#[derive(Debug, Clone)]
+pub(crate) struct RemoteHits {
+    /// The account that was searched
+    pub(crate) account: String,
+    /// The mailbox that was searched
+    pub(crate) mailbox: String,
+    /// UIDs of the matching messages
+    pub(crate) uids: Vec<u32>,
+}
+
+/// Message asking which of the given UIDs of a mailbox have no record yet
+///
+/// Responds with the UIDs that are missing from the database.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<u32>, Errors>")]
+pub(crate) struct MissingUidsMessage {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// The mailbox the UIDs belong to
+    pub(crate) mailbox: String,
+    /// The UIDs to look for
+    pub(crate) uids: Vec<u32>,
+}
+
+impl Handler<MissingUidsMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Vec<u32>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: MissingUidsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received {} UIDs to look up in {} for {}",
+            msg.uids.len(),
+            msg.mailbox,
+            msg.account
+        );
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                let mut response = database
+                    .query(
+                        "SELECT VALUE locations[WHERE mailbox = $mailbox].uid \
+                         FROM mail WHERE account = $account AND \
+                         locations[WHERE mailbox = $mailbox].uid ANYINSIDE \
+                         $uids",
+                    )
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .bind(("uids", msg.uids.clone()))
+                    .await
+                    .map_err(Errors::query)?;
+                let known: HashSet<u32> = response
+                    .take::<Vec<Vec<u32>>>(0)
+                    .map_err(Errors::query)?
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                Ok(msg
+                    .uids
+                    .into_iter()
+                    .filter(|uid| !known.contains(uid))
+                    .collect())
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("look up UIDs", result)),
+        )
+    }
+}
@@ -0,0 +1,99 @@
+//! A directory of files keeping what the user decided across restarts
+//!
+//! The database lives in memory, so mail is synchronized again after a
+//! restart. What only the user can recreate, such as saved searches, is also
+//! written to a JSON file per table, which is read back when the database is
+//! created. Tables are small, so each file is replaced as a whole.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::lock::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use surrealdb::{engine::local::Db, Surreal};
+
+use super::Errors;
+
+/// A directory of JSON files, one per table
+#[derive(Clone)]
+pub(crate) struct StateStore {
+    /// Directory the files are stored in
+    root: PathBuf,
+    /// Held while a table is read and written, so an older copy of a table
+    /// never replaces a newer one
+    writing: Arc<Mutex<()>>,
+}
+
+impl StateStore {
+    /// Opens the state store in the given directory, creating it if necessary
+    pub(crate) fn new(root: &Path) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_owned(),
+            writing: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Gets the path a table is stored at
+    fn path(&self, table: &str) -> PathBuf {
+        self.root.join(table).with_extension("json")
+    }
+
+    /// Reads the records of a table, which has none if it was never written
+    pub(crate) fn load<T: DeserializeOwned>(
+        &self,
+        table: &str,
+    ) -> io::Result<Vec<T>> {
+        match fs::read(self.path(table)) {
+            Ok(json) => Ok(serde_json::from_slice(&json)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the records of a table
+    ///
+    /// The file is written on a blocking thread, so the caller's arbiter
+    /// keeps running.
+    pub(crate) async fn save<T: Serialize>(
+        &self,
+        table: &str,
+        records: &[T],
+    ) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(records)?;
+        let path = self.path(table);
+        actix_rt::task::spawn_blocking(move || {
+            // Write to a temporary file first so a crash never leaves a
+            // truncated table
+            let temporary = path.with_extension("tmp");
+            fs::write(&temporary, json)?;
+            fs::rename(&temporary, &path)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    /// Replaces the records of a table with those a query selects from the
+    /// database
+    ///
+    /// No other table is written from running the query to writing the
+    /// file, so a slower writer never replaces the records with older ones.
+    pub(crate) async fn persist<T: DeserializeOwned + Serialize>(
+        &self,
+        database: &Surreal<Db>,
+        table: &str,
+        query: &str,
+    ) -> Result<(), Errors> {
+        let _writing = self.writing.lock().await;
+        let records: Vec<T> = database
+            .query(query)
+            .await
+            .map_err(Errors::query)?
+            .take(0)
+            .map_err(Errors::query)?;
+        self.save(table, &records).await.map_err(Errors::State)
+    }
+}
@@ -1,35 +1,231 @@
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use surrealdb::sql::{Id, Thing, Value};
+use time::{OffsetDateTime, UtcOffset};
 
-use crate::mail::{ImapEmail, StringAddress};
+use crate::mail::{mime, ImapEmail, StringAddress};
 
 /// Represents a contact from the emails
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct ContactRecord {
-    /// The person's email address
-    address: String,
+    /// The person's email address, in lowercase
+    pub(crate) address: String,
+    /// The display name most recently used with the address
+    pub(crate) name: Option<String>,
+    /// Date of the oldest email involving the address
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub(crate) first_seen: Option<OffsetDateTime>,
+    /// Date of the newest email involving the address
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub(crate) last_seen: Option<OffsetDateTime>,
+    /// Number of emails received from the address
+    pub(crate) received: u32,
+    /// Number of emails the user sent to the address
+    pub(crate) sent: u32,
+    /// Number of emails someone else sent to both the user and the address
+    pub(crate) copied: u32,
+}
+
+/// Reference to an attachment stored in the blob store
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct AttachmentRecord {
+    /// Hash of the attachment contents in the blob store
+    pub(crate) blob: String,
+    /// The file name suggested by the sender, if any
+    pub(crate) filename: Option<String>,
+    /// The MIME type of the attachment
+    pub(crate) mime_type: String,
+    /// Size of the decoded attachment in bytes
+    pub(crate) size: usize,
+}
+
+/// Where an email is on the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Location {
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email in the mailbox
+    pub(crate) uid: u32,
 }
 
 /// Represents an individual retrieved through IMAP
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct EmailRecord {
-    /// UID
-    uid: u32,
-    /// Date
+    /// The account the email belongs to
+    account: String,
+    /// The mailboxes the email is in, with its UID in each. A message from
+    /// Gmail is in a mailbox per label, other emails are in a single one.
+    locations: Vec<Location>,
+    /// Date, normalized to UTC so that stored dates sort and compare correctly
+    #[serde(with = "time::serde::rfc3339::option")]
     date: Option<OffsetDateTime>,
     /// Subject
     subject: Option<String>,
     /// The email sender(s)
-    from: Option<Vec<StringAddress>>
+    from: Option<Vec<StringAddress>>,
+    /// The primary recipient(s)
+    to: Option<Vec<StringAddress>>,
+    /// The carbon copy recipient(s)
+    cc: Option<Vec<StringAddress>>,
+    /// Where replies should go, if not to the sender(s)
+    #[serde(default)]
+    reply_to: Option<Vec<StringAddress>>,
+    /// Every sender and recipient formatted as `Name <address>`, separated by
+    /// newlines. This is what the address search index covers.
+    addresses: String,
+    /// The senders formatted as `name <address>` in lowercase
+    from_text: String,
+    /// The primary and carbon copy recipients formatted as `name <address>`
+    /// in lowercase
+    to_text: String,
+    /// IMAP flags such as `\Seen`
+    flags: Vec<String>,
+    /// The `Message-ID` header
+    message_id: Option<String>,
+    /// The first message ID in the `In-Reply-To` header
+    in_reply_to: Option<String>,
+    /// Message IDs from the `References` header, oldest first
+    references: Vec<String>,
+    /// The decoded text of the message, if its source was fetched. Left out
+    /// when writing records without it, so a body fetched earlier is kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_text: Option<String>,
+    /// Hash of the full RFC 822 source in the blob store, if it was fetched.
+    /// Left out when writing records without it, like `body_text`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<String>,
+    /// Whether `raw` leaves out attachments too large for the sync policy.
+    /// Only written along with `raw`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    partial: Option<bool>,
+    /// Attachments of the email, only known if its source was fetched
+    attachments: Vec<AttachmentRecord>,
+    /// Gmail's ID of the message, the same in every folder it appears in.
+    /// Records with one are keyed by it, so there is one record per message
+    /// with a location for every folder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gmail_message_id: Option<String>,
+    /// Gmail's ID of the conversation the message belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gmail_thread_id: Option<String>,
+    /// Tags of the email. These are the labels of messages from Gmail.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Size of the full source in bytes. Left out when unknown, so a size
+    /// reported earlier is kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u32>,
 }
 
-impl From<ImapEmail> for EmailRecord {
-    fn from(value: ImapEmail) -> Self {
+/// Formats lists of addresses as `Name <address>`, one address per line
+fn address_text(lists: &[&Option<Vec<StringAddress>>]) -> String {
+    lists
+        .iter()
+        .copied()
+        .flatten()
+        .flatten()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+impl EmailRecord {
+    /// Creates a record for an email fetched from a mailbox of an account
+    ///
+    /// `raw` and `attachments` refer to data already written to the blob store.
+    pub(crate) fn new(
+        account: &str,
+        mailbox: &str,
+        value: ImapEmail,
+        raw: Option<String>,
+        attachments: Vec<AttachmentRecord>,
+    ) -> Self {
+        let envelope = value.envelope;
+        let size = value.size;
+        let partial = raw.is_some().then_some(value.partial);
+        let addresses =
+            address_text(&[&envelope.from, &envelope.to, &envelope.cc]);
+        let from_text = address_text(&[&envelope.from]).to_lowercase();
+        let to_text =
+            address_text(&[&envelope.to, &envelope.cc]).to_lowercase();
+        let (gmail_message_id, gmail_thread_id, tags) = match value.gmail {
+            Some(gmail) => (
+                Some(gmail.message_id.to_string()),
+                Some(gmail.thread_id.to_string()),
+                gmail.labels,
+            ),
+            None => (None, None, Vec::new()),
+        };
         Self {
-            uid: value.uid,
-            date: value.envelope.date,
-            subject: value.envelope.subject,
-            from: value.envelope.from
+            account: account.to_owned(),
+            locations: vec![Location {
+                mailbox: mailbox.to_owned(),
+                uid: value.uid,
+            }],
+            date: envelope.date.map(|date| date.to_offset(UtcOffset::UTC)),
+            subject: envelope.subject,
+            from: envelope.from,
+            to: envelope.to,
+            cc: envelope.cc,
+            reply_to: envelope.reply_to,
+            addresses,
+            from_text,
+            to_text,
+            flags: value.flags,
+            message_id: envelope.message_id,
+            in_reply_to: envelope.in_reply_to,
+            references: value.references,
+            body_text: value.raw.as_deref().and_then(mime::text_body),
+            raw,
+            partial,
+            attachments,
+            gmail_message_id,
+            gmail_thread_id,
+            tags,
+            size,
         }
     }
+
+    /// The UID of the email in a mailbox, if it is in it
+    pub(crate) fn uid_in(&self, mailbox: &str) -> Option<u32> {
+        self.locations
+            .iter()
+            .find(|location| location.mailbox == mailbox)
+            .map(|location| location.uid)
+    }
+
+    /// The account the email belongs to
+    pub(crate) fn account(&self) -> &str {
+        &self.account
+    }
+
+    /// The ID `INSERT_EMAILS_QUERY` writes the record under: the account with
+    /// Gmail's ID of the message, or with the first mailbox and UID
+    pub(crate) fn id(&self) -> Thing {
+        let mut key = vec![Value::from(self.account.as_str())];
+        if let Some(gmail_message_id) = &self.gmail_message_id {
+            key.push(Value::from(gmail_message_id.as_str()));
+        } else if let Some(location) = self.locations.first() {
+            key.push(Value::from(location.mailbox.as_str()));
+            key.push(Value::from(location.uid));
+        }
+        Thing::from(("mail", Id::from(key)))
+    }
+
+    /// The account and every mailbox the email is in
+    pub(crate) fn mailboxes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.locations
+            .iter()
+            .map(|location| (self.account.as_str(), location.mailbox.as_str()))
+    }
+
+    /// Moves the record from one mailbox to another, where the email has a
+    /// different UID
+    pub(crate) fn relocate(mut self, from: &str, to: &str, uid: u32) -> Self {
+        self.locations.retain(|location| location.mailbox != from);
+        self.locations.push(Location {
+            mailbox: to.to_owned(),
+            uid,
+        });
+        self
+    }
 }
@@ -0,0 +1,106 @@
+//! Fixtures shared by the tests of the database and of the actors using it
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use actix::prelude::*;
+use surrealdb::{engine::local::Db, Surreal};
+use time::OffsetDateTime;
+
+use super::{DatabaseActor, InsertEmailsMessage};
+use crate::mail::{Envelope, ImapEmail};
+
+/// The account emails are inserted for
+pub(crate) const ME: &str = "me@example.com";
+
+/// A temporary directory of a test's own, deleted with everything in it when
+/// dropped
+pub(crate) struct TestDirectory(PathBuf);
+
+impl TestDirectory {
+    /// Creates an empty directory named after the test and this process
+    pub(crate) fn new(test: &str) -> Self {
+        let path =
+            env::temp_dir().join(format!("weasel-{test}-{}", process::id()));
+        // Left over by a test that was killed before it could clean up
+        if path.exists() {
+            fs::remove_dir_all(&path).expect("Should remove the old directory");
+        }
+        fs::create_dir_all(&path).expect("Should create the directory");
+        Self(path)
+    }
+
+    /// Gets the path of the directory
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            log::warn!("Failed to remove {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// Starts a database in a directory of its own
+///
+/// Returns the directory, which must be kept until the test ends, the actor
+/// and a connection to query its records directly.
+pub(crate) async fn start(
+    test: &str,
+) -> (TestDirectory, Addr<DatabaseActor>, Surreal<Db>) {
+    let directory = TestDirectory::new(test);
+    let actor = DatabaseActor::new(
+        &directory.path().join("blobs"),
+        &directory.path().join("state"),
+    )
+    .await
+    .expect("The database should start");
+    let connection = actor.database.clone();
+    (directory, actor.start(), connection)
+}
+
+/// An email with only a Message-ID, dated by its UID
+pub(crate) fn email(uid: u32, message_id: &str) -> ImapEmail {
+    ImapEmail {
+        uid,
+        envelope: Envelope {
+            date: OffsetDateTime::from_unix_timestamp(i64::from(uid)).ok(),
+            subject: None,
+            from: None,
+            to: None,
+            cc: None,
+            reply_to: None,
+            message_id: Some(message_id.to_owned()),
+            in_reply_to: None,
+        },
+        raw: None,
+        flags: Vec::new(),
+        references: Vec::new(),
+        gmail: None,
+        size: None,
+        partial: false,
+    }
+}
+
+/// Writes emails to a mailbox of `ME`
+pub(crate) async fn insert(
+    database: &Addr<DatabaseActor>,
+    mailbox: &str,
+    emails: Vec<ImapEmail>,
+) {
+    database
+        .send(InsertEmailsMessage {
+            account: ME.to_owned(),
+            mailbox: mailbox.to_owned(),
+            emails,
+        })
+        .await
+        .expect("The database should be running")
+        .expect("The emails should be written");
+}
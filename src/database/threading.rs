@@ -0,0 +1,848 @@
+//! Groups emails into conversations
+//!
+//! This is an implementation of [Jamie Zawinski's threading
+//! algorithm](https://www.jwz.org/doc/threading.html). Messages are linked
+//! through their `Message-ID`, `In-Reply-To` and `References` headers, and
+//! conversations whose links are broken are merged by subject. Threading runs
+//! over every mailbox at once, so replies filed in Sent end up in the same
+//! conversation as the messages they answer.
+//!
+//! Only the conversations that changed emails may belong to are threaded
+//! again: those of the emails linked to them by message ID, and of the emails
+//! linked to those in turn, along with the conversations whose subject is
+//! that of one of the resulting roots. Conversations never span accounts.
+
+use std::collections::{HashMap, HashSet};
+
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use surrealdb::{engine::local::Db, sql::Thing, Surreal};
+use time::OffsetDateTime;
+
+use super::{listing::EmailSummary, DatabaseActor, Errors};
+
+/// Selects what the threading algorithm needs to know about some emails
+const THREAD_INPUT_QUERY: &str = "
+    SELECT id, locations.mailbox AS mailboxes, message_id, in_reply_to,
+        references, subject, date
+    FROM $emails;
+";
+
+/// Selects the emails of an account whose message ID or references include
+/// one of some message IDs
+const LINKED_EMAILS_QUERY: &str = "
+    SELECT VALUE id FROM mail WHERE account = $account AND (
+        message_id INSIDE $ids OR references ANYINSIDE $ids
+            OR in_reply_to INSIDE $ids
+    );
+";
+
+/// Selects the conversations of an account containing one of some emails
+const LINKED_THREADS_QUERY: &str = "
+    SELECT id, messages FROM thread
+    WHERE account = $account AND messages ANYINSIDE $emails;
+";
+
+/// Selects the conversations of an account about one of some subjects, which
+/// are those of the roots of the conversations
+const SUBJECT_THREADS_QUERY: &str = "
+    SELECT id, messages FROM thread
+    WHERE account = $account AND string::lowercase(subject) INSIDE $subjects;
+";
+
+/// Replaces some thread records in a single transaction
+const REPLACE_THREADS_QUERY: &str = "
+    BEGIN TRANSACTION;
+    DELETE thread WHERE id INSIDE $replaced;
+    FOR $thread IN $threads {
+        CREATE thread CONTENT $thread;
+    };
+    COMMIT TRANSACTION;
+";
+
+/// Selects the emails of the conversation an email is in, in reply order,
+/// each in the given mailbox if it is there
+const CONVERSATION_QUERY: &str = "
+    LET $email = (
+        SELECT VALUE id FROM mail WHERE account = $account
+            AND locations[WHERE mailbox = $mailbox].uid CONTAINS $uid
+    )[0];
+    LET $messages = (
+        SELECT VALUE messages FROM thread WHERE messages CONTAINS $email
+    )[0] ?? [];
+    SELECT account,
+        locations[WHERE mailbox = $mailbox][0] ?? locations[0] AS location,
+        date, subject, from, flags, size
+    FROM $messages;
+";
+
+/// Prefixes mail clients put in front of the subject of replies and forwards
+const REPLY_PREFIXES: [&str; 6] = ["re", "fwd", "fw", "aw", "sv", "wg"];
+
+/// What the threading algorithm needs to know about an email
+#[derive(Deserialize)]
+struct ThreadInput {
+    /// ID of the email record
+    id: Thing,
+    /// The mailboxes the email is in
+    mailboxes: Vec<String>,
+    /// The `Message-ID` header
+    message_id: Option<String>,
+    /// The first message ID in the `In-Reply-To` header
+    in_reply_to: Option<String>,
+    /// Message IDs from the `References` header, oldest first
+    references: Vec<String>,
+    /// Subject
+    subject: Option<String>,
+    /// Date in UTC
+    #[serde(with = "time::serde::rfc3339::option")]
+    date: Option<OffsetDateTime>,
+}
+
+/// A conversation stored in the database
+#[derive(Serialize, Debug)]
+pub(crate) struct ThreadRecord {
+    /// The account the emails belong to
+    pub(crate) account: String,
+    /// Subject of the first message, without reply prefixes
+    pub(crate) subject: Option<String>,
+    /// The emails in the conversation, in reply order
+    pub(crate) messages: Vec<Thing>,
+    /// Every mailbox containing part of the conversation
+    pub(crate) mailboxes: Vec<String>,
+    /// Date of the newest message
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub(crate) latest: Option<OffsetDateTime>,
+}
+
+/// A conversation as found when looking for those linked to changed emails
+#[derive(Deserialize)]
+struct LinkedThread {
+    /// ID of the thread record
+    id: Thing,
+    /// The emails in the conversation
+    messages: Vec<Thing>,
+}
+
+/// A node in the message ID graph
+#[derive(Default)]
+struct Container {
+    /// Indices of the messages with this ID. There is usually at most one, but
+    /// the same message can be filed in several mailboxes.
+    messages: Vec<usize>,
+    /// The container this one is a reply to
+    parent: Option<usize>,
+    /// The containers that reply to this one
+    children: Vec<usize>,
+}
+
+/// Strips reply and forward prefixes like `Re:` and `Fwd: ` from a subject
+///
+/// Returns the stripped subject and whether any prefix was removed.
+fn strip_reply_prefixes(subject: &str) -> (&str, bool) {
+    let mut rest = subject.trim();
+    let mut stripped = false;
+    'strip: loop {
+        for prefix in REPLY_PREFIXES {
+            let Some(candidate) = rest.get(..prefix.len()) else {
+                continue;
+            };
+            if !candidate.eq_ignore_ascii_case(prefix) {
+                continue;
+            }
+            let after = rest.get(prefix.len()..).unwrap_or_default();
+            if let Some(after) = after.strip_prefix(':') {
+                rest = after.trim_start();
+                stripped = true;
+                continue 'strip;
+            }
+        }
+        break;
+    }
+    (rest, stripped)
+}
+
+/// The state of the threading algorithm
+struct Threader<'a> {
+    /// The messages being threaded
+    messages: &'a [ThreadInput],
+    /// Every container, referenced by index
+    containers: Vec<Container>,
+    /// Maps message IDs to containers
+    by_id: HashMap<String, usize>,
+}
+
+impl<'a> Threader<'a> {
+    /// Creates a threader for a set of messages
+    fn new(messages: &'a [ThreadInput]) -> Self {
+        Self {
+            messages,
+            containers: Vec::new(),
+            by_id: HashMap::new(),
+        }
+    }
+
+    /// Gets the container for a message ID, creating an empty one if needed
+    fn container(&mut self, id: &str) -> usize {
+        if let Some(&container) = self.by_id.get(id) {
+            return container;
+        }
+        self.containers.push(Container::default());
+        let container = self.containers.len() - 1;
+        self.by_id.insert(id.to_owned(), container);
+        container
+    }
+
+    /// Whether `ancestor` is `node` or one of its ancestors
+    fn is_ancestor(&self, ancestor: usize, node: usize) -> bool {
+        let mut current = Some(node);
+        while let Some(node) = current {
+            if node == ancestor {
+                return true;
+            }
+            current = self.containers[node].parent;
+        }
+        false
+    }
+
+    /// Makes `child` a reply to `parent`, unless that would create a loop
+    fn set_parent(&mut self, child: usize, parent: Option<usize>) {
+        if let Some(parent) = parent {
+            if self.is_ancestor(child, parent) {
+                return;
+            }
+        }
+        if let Some(old) = self.containers[child].parent {
+            self.containers[old].children.retain(|&c| c != child);
+        }
+        self.containers[child].parent = parent;
+        if let Some(parent) = parent {
+            self.containers[parent].children.push(child);
+        }
+    }
+
+    /// Adds every message to the graph and links it to what it replies to
+    fn link_messages(&mut self) {
+        for (index, message) in self.messages.iter().enumerate() {
+            // Messages without an ID get a unique one so they stay separate
+            let id = message
+                .message_id
+                .clone()
+                .unwrap_or_else(|| format!("<weasel-missing-id-{index}>"));
+            let container = self.container(&id);
+            self.containers[container].messages.push(index);
+
+            let mut references = message.references.clone();
+            if let Some(in_reply_to) = &message.in_reply_to {
+                if references.last() != Some(in_reply_to) {
+                    references.push(in_reply_to.clone());
+                }
+            }
+            references.retain(|reference| *reference != id);
+
+            // Link each reference to the next, without overriding links that
+            // an earlier message already established
+            for pair in references.windows(2) {
+                let parent = self.container(&pair[0]);
+                let child = self.container(&pair[1]);
+                if self.containers[child].parent.is_none() {
+                    self.set_parent(child, Some(parent));
+                }
+            }
+            let parent =
+                references.last().map(|reference| self.container(reference));
+            self.set_parent(container, parent);
+        }
+    }
+
+    /// Removes empty containers below `node`, promoting their children
+    ///
+    /// Returns the containers that should take the place of `node`.
+    fn prune(&mut self, node: usize, is_root: bool) -> Vec<usize> {
+        let children = std::mem::take(&mut self.containers[node].children);
+        let children: Vec<usize> = children
+            .into_iter()
+            .flat_map(|child| self.prune(child, false))
+            .collect();
+        for &child in &children {
+            self.containers[child].parent = Some(node);
+        }
+        self.containers[node].children = children;
+
+        let container = &self.containers[node];
+        if !container.messages.is_empty() {
+            return vec![node];
+        }
+        if container.children.is_empty() {
+            return Vec::new();
+        }
+        // An empty root with several children holds a conversation together
+        if is_root && container.children.len() > 1 {
+            return vec![node];
+        }
+        let children = std::mem::take(&mut self.containers[node].children);
+        let parent = self.containers[node].parent;
+        for &child in &children {
+            self.containers[child].parent = parent;
+        }
+        children
+    }
+
+    /// Gets the subject of a container, falling back to its first child's
+    fn subject(&self, node: usize) -> Option<&str> {
+        let container = &self.containers[node];
+        let message = container.messages.first().copied().or_else(|| {
+            container.children.iter().find_map(|&child| {
+                self.containers[child].messages.first().copied()
+            })
+        })?;
+        self.messages[message].subject.as_deref()
+    }
+
+    /// Gets the subject a root container is grouped by, in lowercase and
+    /// without reply prefixes, and whether it had any
+    fn grouping_subject(&self, root: usize) -> Option<(String, bool)> {
+        self.subject(root)
+            .map(strip_reply_prefixes)
+            .map(|(subject, is_reply)| (subject.to_lowercase(), is_reply))
+            .filter(|(subject, _)| !subject.is_empty())
+    }
+
+    /// Merges root containers whose messages have the same subject
+    fn group_by_subject(&mut self, roots: Vec<usize>) -> Vec<usize> {
+        let mut by_subject: HashMap<String, usize> = HashMap::new();
+        let mut merged_roots = Vec::new();
+        for root in roots {
+            let Some((subject, is_reply)) = self.grouping_subject(root) else {
+                merged_roots.push(root);
+                continue;
+            };
+            let Some(&existing) = by_subject.get(&subject) else {
+                by_subject.insert(subject, root);
+                merged_roots.push(root);
+                continue;
+            };
+            let existing_is_reply = self
+                .subject(existing)
+                .is_some_and(|subject| strip_reply_prefixes(subject).1);
+            let existing_is_empty =
+                self.containers[existing].messages.is_empty();
+            if existing_is_empty || (is_reply && !existing_is_reply) {
+                // This root is a reply to the thread that's already there
+                self.set_parent(root, Some(existing));
+            } else if !is_reply && existing_is_reply {
+                // The existing root turns out to be a reply to this one
+                self.set_parent(existing, Some(root));
+                merged_roots.retain(|&r| r != existing);
+                merged_roots.push(root);
+                by_subject.insert(subject, root);
+            } else {
+                // Neither is a reply to the other, so they become siblings
+                self.containers.push(Container::default());
+                let parent = self.containers.len() - 1;
+                self.set_parent(existing, Some(parent));
+                self.set_parent(root, Some(parent));
+                merged_roots.retain(|&r| r != existing);
+                merged_roots.push(parent);
+                by_subject.insert(subject, parent);
+            }
+        }
+        merged_roots
+    }
+
+    /// Gets the earliest date of the messages in a container
+    fn date(&self, node: usize) -> Option<OffsetDateTime> {
+        self.containers[node]
+            .messages
+            .iter()
+            .filter_map(|&message| self.messages[message].date)
+            .min()
+    }
+
+    /// Collects the messages below `node` in reply order
+    fn collect(&self, node: usize, messages: &mut Vec<usize>) {
+        messages.extend(&self.containers[node].messages);
+        let mut children = self.containers[node].children.clone();
+        children.sort_by_key(|&child| self.date(child));
+        for child in children {
+            self.collect(child, messages);
+        }
+    }
+
+    /// Links the messages and returns the root set, before it is grouped by
+    /// subject
+    fn roots(&mut self) -> Vec<usize> {
+        self.link_messages();
+        let roots: Vec<usize> = (0..self.containers.len())
+            .filter(|&container| self.containers[container].parent.is_none())
+            .collect();
+        roots.into_iter().flat_map(|root| self.prune(root, true)).collect()
+    }
+
+    /// Gets the subjects the root set would be grouped by
+    fn root_subjects(mut self) -> Vec<String> {
+        self.roots()
+            .into_iter()
+            .filter_map(|root| self.grouping_subject(root))
+            .map(|(subject, _)| subject)
+            .collect()
+    }
+
+    /// Runs the whole algorithm and returns the conversations as lists of
+    /// message indices
+    fn thread(mut self) -> Vec<Vec<usize>> {
+        let roots = self.roots();
+        let roots = self.group_by_subject(roots);
+        roots
+            .into_iter()
+            .map(|root| {
+                let mut messages = Vec::new();
+                self.collect(root, &mut messages);
+                messages
+            })
+            .filter(|messages| !messages.is_empty())
+            .collect()
+    }
+}
+
+/// Builds the thread records for a set of emails of an account
+fn build_threads(account: &str, messages: &[ThreadInput]) -> Vec<ThreadRecord> {
+    Threader::new(messages)
+        .thread()
+        .into_iter()
+        .map(|conversation| {
+            let mut mailboxes: Vec<String> = conversation
+                .iter()
+                .flat_map(|&message| messages[message].mailboxes.clone())
+                .collect();
+            mailboxes.sort();
+            mailboxes.dedup();
+            ThreadRecord {
+                account: account.to_owned(),
+                subject: conversation
+                    .iter()
+                    .find_map(|&message| messages[message].subject.as_deref())
+                    .map(|subject| strip_reply_prefixes(subject).0.to_owned()),
+                latest: conversation
+                    .iter()
+                    .filter_map(|&message| messages[message].date)
+                    .max(),
+                messages: conversation
+                    .into_iter()
+                    .map(|message| messages[message].id.clone())
+                    .collect(),
+                mailboxes,
+            }
+        })
+        .collect()
+}
+
+/// Collects what the threading algorithm needs to know about the given
+/// emails of an account, the emails linked to them and the conversations any
+/// of them are in
+///
+/// Conversations are only merged by subject when one of their roots would be,
+/// so only the subjects of the root set are looked up, once the emails linked
+/// by message ID are all known. Returns the emails and the IDs of their
+/// conversations, which are to be replaced.
+async fn linked(
+    database: &Surreal<Db>,
+    account: &str,
+    emails: Vec<Thing>,
+) -> Result<(Vec<ThreadInput>, Vec<Thing>), Errors> {
+    // Record IDs aren't hashable keys, so the sets hold them as text
+    let mut known: HashSet<String> = HashSet::new();
+    let mut collected: Vec<Thing> = Vec::new();
+    let mut add = |emails: Vec<Thing>, collected: &mut Vec<Thing>| {
+        for email in emails {
+            if known.insert(email.to_string()) {
+                collected.push(email);
+            }
+        }
+    };
+    add(emails, &mut collected);
+    let mut inputs: Vec<ThreadInput> = Vec::new();
+    let mut fetched = 0;
+    let mut replaced: Vec<Thing> = Vec::new();
+    let mut threads: HashSet<String> = HashSet::new();
+    let mut searched: HashSet<String> = HashSet::new();
+    loop {
+        while fetched < collected.len() {
+            let added: Vec<ThreadInput> = database
+                .query(THREAD_INPUT_QUERY)
+                .bind(("emails", collected.get(fetched..).unwrap_or_default()))
+                .await
+                .map_err(Errors::query)?
+                .take(0)
+                .map_err(Errors::query)?;
+            fetched = collected.len();
+            let ids: Vec<&String> = added
+                .iter()
+                .flat_map(|input| {
+                    input
+                        .message_id
+                        .iter()
+                        .chain(&input.in_reply_to)
+                        .chain(&input.references)
+                })
+                .collect();
+            let linked_emails: Vec<Thing> = database
+                .query(LINKED_EMAILS_QUERY)
+                .bind(("account", account.to_owned()))
+                .bind(("ids", ids))
+                .await
+                .map_err(Errors::query)?
+                .take(0)
+                .map_err(Errors::query)?;
+            inputs.extend(added);
+            add(linked_emails, &mut collected);
+            let linked_threads: Vec<LinkedThread> = database
+                .query(LINKED_THREADS_QUERY)
+                .bind(("account", account.to_owned()))
+                .bind(("emails", &collected))
+                .await
+                .map_err(Errors::query)?
+                .take(0)
+                .map_err(Errors::query)?;
+            for thread in linked_threads {
+                if threads.insert(thread.id.to_string()) {
+                    replaced.push(thread.id);
+                    add(thread.messages, &mut collected);
+                }
+            }
+        }
+        let subjects: Vec<String> = Threader::new(&inputs)
+            .root_subjects()
+            .into_iter()
+            .filter(|subject| searched.insert(subject.clone()))
+            .collect();
+        if subjects.is_empty() {
+            return Ok((inputs, replaced));
+        }
+        let subject_threads: Vec<LinkedThread> = database
+            .query(SUBJECT_THREADS_QUERY)
+            .bind(("account", account.to_owned()))
+            .bind(("subjects", subjects))
+            .await
+            .map_err(Errors::query)?
+            .take(0)
+            .map_err(Errors::query)?;
+        for thread in subject_threads {
+            if threads.insert(thread.id.to_string()) {
+                replaced.push(thread.id);
+                add(thread.messages, &mut collected);
+            }
+        }
+    }
+}
+
+/// Message requesting that the conversations of some emails are threaded
+/// again, after they were added, changed or removed
+///
+/// The thread records of every conversation linked to the emails are
+/// replaced. Responds with the number of conversations they now form.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<usize, Errors>")]
+pub(crate) struct RethreadMessage {
+    /// The account the emails belong to
+    pub(crate) account: String,
+    /// IDs of the changed email records
+    pub(crate) emails: Vec<Thing>,
+}
+
+impl Handler<RethreadMessage> for DatabaseActor {
+    type Result = AtomicResponse<Self, Result<usize, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: RethreadMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!(
+            "DatabaseActor received {} emails to thread",
+            msg.emails.len()
+        );
+        let database = self.database.clone();
+        // Threading one batch at a time keeps two batches from replacing the
+        // same conversation
+        AtomicResponse::new(Box::pin(
+            async move {
+                let (messages, replaced) =
+                    linked(&database, &msg.account, msg.emails).await?;
+                let threads = build_threads(&msg.account, &messages);
+                let count = threads.len();
+                database
+                    .query(REPLACE_THREADS_QUERY)
+                    .bind(("replaced", replaced))
+                    .bind(("threads", threads))
+                    .await
+                    .and_then(surrealdb::Response::check)
+                    .map_err(Errors::query)?;
+                log::trace!(
+                    "Threaded {} emails into {count} conversations",
+                    messages.len()
+                );
+                Ok(count)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track("thread emails", result)),
+        ))
+    }
+}
+
+/// Message requesting the emails of the conversation an email is in, in
+/// reply order
+///
+/// Each email is listed in the mailbox of the given one if it is there too.
+/// Responds with nothing if the email hasn't been threaded yet.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Vec<EmailSummary>, Errors>")]
+pub(crate) struct ConversationMessage {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email in the mailbox
+    pub(crate) uid: u32,
+}
+
+impl Handler<ConversationMessage> for DatabaseActor {
+    type Result = ResponseActFuture<Self, Result<Vec<EmailSummary>, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ConversationMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("DatabaseActor received {msg:?}");
+        let database = self.database.clone();
+        Box::pin(
+            async move {
+                database
+                    .query(CONVERSATION_QUERY)
+                    .bind(("account", msg.account))
+                    .bind(("mailbox", msg.mailbox))
+                    .bind(("uid", msg.uid))
+                    .await
+                    .map_err(Errors::query)?
+                    .take(2)
+                    .map_err(Errors::query)
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| {
+                actor.track("list conversation", result)
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+
+    use super::ConversationMessage;
+    use crate::{
+        database::{
+            mailbox_changes::RemoveEmailsMessage,
+            testing::{self, insert, start, ME},
+            DatabaseActor, InsertEmailsMessage,
+        },
+        mail::ImapEmail,
+    };
+
+    /// An email with the given headers, dated by its UID
+    fn email(
+        uid: u32,
+        message_id: &str,
+        in_reply_to: Option<&str>,
+        subject: &str,
+    ) -> ImapEmail {
+        let mut email = testing::email(uid, message_id);
+        email.envelope.subject = Some(subject.to_owned());
+        email.envelope.in_reply_to = in_reply_to.map(str::to_owned);
+        email.references = in_reply_to.into_iter().map(str::to_owned).collect();
+        email
+    }
+
+    /// The subjects and mailboxes of the conversation of an email in the
+    /// inbox of `ME`, in reply order
+    async fn conversation(
+        database: &Addr<DatabaseActor>,
+        uid: u32,
+    ) -> Vec<(String, String)> {
+        conversation_of(database, ME, uid).await
+    }
+
+    /// The subjects and mailboxes of the conversation of an email in the
+    /// inbox of an account, in reply order
+    async fn conversation_of(
+        database: &Addr<DatabaseActor>,
+        account: &str,
+        uid: u32,
+    ) -> Vec<(String, String)> {
+        database
+            .send(ConversationMessage {
+                account: account.to_owned(),
+                mailbox: "INBOX".to_owned(),
+                uid,
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The conversation should be listed")
+            .into_iter()
+            .map(|email| {
+                (email.subject.unwrap_or_default(), email.location.mailbox)
+            })
+            .collect()
+    }
+
+    /// Pairs subjects with mailboxes
+    fn expected(emails: &[(&str, &str)]) -> Vec<(String, String)> {
+        emails
+            .iter()
+            .map(|&(subject, mailbox)| (subject.to_owned(), mailbox.to_owned()))
+            .collect()
+    }
+
+    /// Replies filed in Sent join the conversation they answer
+    #[actix_rt::test]
+    async fn threads_replies_across_mailboxes() {
+        let (_directory, database, _) = start("threading-across").await;
+        insert(&database, "INBOX", vec![email(1, "<a@x>", None, "Plans")])
+            .await;
+        insert(
+            &database,
+            "Sent",
+            vec![email(2, "<b@x>", Some("<a@x>"), "Re: Plans")],
+        )
+        .await;
+        assert_eq!(
+            conversation(&database, 1).await,
+            expected(&[("Plans", "INBOX"), ("Re: Plans", "Sent")])
+        );
+    }
+
+    /// Conversations that arrived in pieces are joined once the email linking
+    /// them arrives
+    #[actix_rt::test]
+    async fn joins_conversations_through_a_late_email() {
+        let (_directory, database, _) = start("threading-late").await;
+        insert(
+            &database,
+            "INBOX",
+            vec![
+                email(1, "<a@x>", None, "Question"),
+                email(3, "<c@x>", Some("<b@x>"), "Answer"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            conversation(&database, 1).await,
+            expected(&[("Question", "INBOX")])
+        );
+        insert(
+            &database,
+            "INBOX",
+            vec![email(2, "<b@x>", Some("<a@x>"), "Follow-up")],
+        )
+        .await;
+        assert_eq!(
+            conversation(&database, 1).await,
+            expected(&[
+                ("Question", "INBOX"),
+                ("Follow-up", "INBOX"),
+                ("Answer", "INBOX"),
+            ])
+        );
+    }
+
+    /// Emails of another account stay out of the conversation, even when
+    /// they reply to the same message ID or share the subject
+    #[actix_rt::test]
+    async fn keeps_accounts_apart() {
+        let (_directory, database, _) = start("threading-accounts").await;
+        insert(&database, "INBOX", vec![email(1, "<a@x>", None, "Plans")])
+            .await;
+        database
+            .send(InsertEmailsMessage {
+                account: "other@example.com".to_owned(),
+                mailbox: "INBOX".to_owned(),
+                emails: vec![
+                    email(2, "<b@x>", Some("<a@x>"), "Re: Plans"),
+                    email(3, "<c@x>", None, "Plans"),
+                ],
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The emails should be written");
+        assert_eq!(
+            conversation(&database, 1).await,
+            expected(&[("Plans", "INBOX")])
+        );
+        assert_eq!(
+            conversation_of(&database, "other@example.com", 2).await,
+            expected(&[("Plans", "INBOX"), ("Re: Plans", "INBOX")])
+        );
+    }
+
+    /// Emails without links to each other are grouped by subject
+    #[actix_rt::test]
+    async fn groups_by_subject() {
+        let (_directory, database, _) = start("threading-subject").await;
+        insert(&database, "INBOX", vec![email(1, "<a@x>", None, "Lunch")])
+            .await;
+        insert(
+            &database,
+            "INBOX",
+            vec![
+                email(2, "<b@x>", None, "Re: Lunch"),
+                email(3, "<c@x>", None, "Dinner"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            conversation(&database, 1).await,
+            expected(&[("Lunch", "INBOX"), ("Re: Lunch", "INBOX")])
+        );
+        assert_eq!(
+            conversation(&database, 3).await,
+            expected(&[("Dinner", "INBOX")])
+        );
+    }
+
+    /// Removed emails leave their conversation
+    #[actix_rt::test]
+    async fn removes_emails_from_conversations() {
+        let (_directory, database, connection) =
+            start("threading-removed").await;
+        insert(
+            &database,
+            "INBOX",
+            vec![
+                email(1, "<a@x>", None, "Plans"),
+                email(2, "<b@x>", Some("<a@x>"), "Re: Plans"),
+            ],
+        )
+        .await;
+        database
+            .send(RemoveEmailsMessage {
+                account: ME.to_owned(),
+                mailbox: "INBOX".to_owned(),
+                uids: vec![2],
+            })
+            .await
+            .expect("The database should be running")
+            .expect("The email should be removed");
+        assert_eq!(
+            conversation(&database, 1).await,
+            expected(&[("Plans", "INBOX")])
+        );
+        let sizes: Vec<usize> = connection
+            .query("SELECT VALUE array::len(messages) FROM thread")
+            .await
+            .expect("The threads should be selected")
+            .take(0)
+            .expect("The threads should have messages");
+        assert_eq!(sizes, vec![1]);
+    }
+}
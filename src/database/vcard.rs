@@ -0,0 +1,728 @@
+//! Reading and writing address book entries as vCards
+//!
+//! Both [vCard 3.0](https://www.rfc-editor.org/rfc/rfc2426) and
+//! [vCard 4.0](https://www.rfc-editor.org/rfc/rfc6350) are understood. The
+//! properties the address book models are parsed into a `CardRecord`; every
+//! other property is kept so that it survives a round trip. When a card is
+//! written in another version than it was read in, those properties are
+//! converted where the versions differ, or dropped if the other version
+//! doesn't define them.
+
+use std::fmt::{Display, Write};
+
+use serde::{Deserialize, Serialize};
+use time::{
+    format_description::well_known::Rfc3339, macros::format_description,
+    OffsetDateTime, PrimitiveDateTime, UtcOffset,
+};
+
+use super::address_book::{CardEmail, CardPhone, CardRecord};
+
+/// Longest a line may be in octets before it has to be folded
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Properties vCard 3.0 defines but 4.0 doesn't
+const ONLY_V3: &[&str] =
+    &["AGENT", "CLASS", "LABEL", "MAILER", "NAME", "PROFILE", "SORT-STRING"];
+
+/// Properties vCard 4.0 defines but 3.0 doesn't
+const ONLY_V4: &[&str] = &[
+    "ANNIVERSARY",
+    "CLIENTPIDMAP",
+    "GENDER",
+    "KIND",
+    "LANG",
+    "MEMBER",
+    "RELATED",
+    "XML",
+];
+
+/// A vCard version that can be read and written
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Version {
+    /// vCard 3.0, defined in RFC 2426
+    V3,
+    /// vCard 4.0, defined in RFC 6350
+    V4,
+}
+
+/// Errors that can occur while parsing vCards
+#[derive(Debug)]
+pub(crate) enum ParseError {
+    /// A line is neither a property nor a continuation of one
+    MalformedLine(usize),
+    /// A property appeared outside of `BEGIN:VCARD` and `END:VCARD`
+    OutsideCard(usize),
+    /// A card was opened inside another card
+    NestedCard(usize),
+    /// The input ended before the last card was closed
+    UnclosedCard,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::MalformedLine(line) => {
+                write!(f, "line {line} is not a vCard property")
+            }
+            ParseError::OutsideCard(line) => {
+                write!(f, "line {line} is outside of a vCard")
+            }
+            ParseError::NestedCard(line) => {
+                write!(f, "line {line} starts a vCard inside another one")
+            }
+            ParseError::UnclosedCard => {
+                write!(f, "the last vCard is not closed")
+            }
+        }
+    }
+}
+
+/// A single content line, e.g. `item1.EMAIL;TYPE=work:jane@example.com`
+struct Property {
+    /// The property name in uppercase, without its group
+    name: String,
+    /// Parameters as lowercase names and their unquoted values
+    parameters: Vec<(String, Vec<String>)>,
+    /// The still escaped value
+    value: String,
+}
+
+impl Property {
+    /// Values of every `TYPE` parameter in lowercase, except `pref`
+    ///
+    /// vCard 2.1 style parameters without a name are treated as types.
+    fn kinds(&self) -> Vec<String> {
+        self.parameters
+            .iter()
+            .filter(|(name, _)| name == "type" || name.is_empty())
+            .flat_map(|(_, values)| values)
+            .map(|value| value.to_lowercase())
+            .filter(|value| value != "pref")
+            .collect()
+    }
+
+    /// Whether the property is marked as preferred, with either the 3.0
+    /// `TYPE=pref` or the 4.0 `PREF=1` parameter
+    fn is_preferred(&self) -> bool {
+        self.parameters.iter().any(|(name, values)| match name.as_str() {
+            "type" | "" => {
+                values.iter().any(|value| value.eq_ignore_ascii_case("pref"))
+            }
+            "pref" => values.iter().any(|value| value == "1"),
+            _ => false,
+        })
+    }
+}
+
+/// Splits text at a delimiter that isn't escaped with a backslash
+fn split_unescaped(text: &str, delimiter: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, character) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if character == '\\' {
+            escaped = true;
+        } else if character == delimiter {
+            parts.push(text.get(start..index).unwrap_or_default());
+            start = index + character.len_utf8();
+        }
+    }
+    parts.push(text.get(start..).unwrap_or_default());
+    parts
+}
+
+/// Resolves the backslash escapes in a text value
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            unescaped.push(character);
+            continue;
+        }
+        match characters.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Escapes a text value so it can be written in a content line
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '\\' | ',' | ';' => {
+                escaped.push('\\');
+                escaped.push(character);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Unescapes a text value, treating an empty one as missing
+fn text(value: &str) -> Option<String> {
+    let text = unescape(value);
+    (!text.trim().is_empty()).then_some(text)
+}
+
+/// Splits a content line into its name, parameters and value
+///
+/// Returns `None` if the line has no `:` outside of quotes.
+fn parse_property(line: &str) -> Option<Property> {
+    let mut in_quotes = false;
+    let colon = line.char_indices().find_map(|(index, character)| {
+        match character {
+            '"' => in_quotes = !in_quotes,
+            ':' if !in_quotes => return Some(index),
+            _ => {}
+        }
+        None
+    })?;
+    let (head, value) = line.split_at(colon);
+    let mut segments = head.split(';');
+    let name = segments.next()?;
+    // Drop the group, as in `item1.EMAIL`
+    let name = name.rsplit('.').next().unwrap_or(name).to_uppercase();
+    if name.is_empty() {
+        return None;
+    }
+    let parameters = segments
+        .map(|segment| {
+            let (name, values) =
+                segment.split_once('=').unwrap_or(("", segment));
+            let values = values
+                .split(',')
+                .map(|value| value.trim_matches('"').to_owned())
+                .collect();
+            (name.to_lowercase(), values)
+        })
+        .collect();
+    Some(Property {
+        name,
+        parameters,
+        value: value.get(1..).unwrap_or_default().to_owned(),
+    })
+}
+
+/// Joins folded lines back together, keeping the 1-based number of the line
+/// each logical line started on
+fn unfold(input: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        if let Some(continuation) = line.strip_prefix([' ', '\t']) {
+            if let Some((_, previous)) = lines.last_mut() {
+                previous.push_str(continuation);
+                continue;
+            }
+        }
+        if !line.trim().is_empty() {
+            lines.push((index + 1, line.to_owned()));
+        }
+    }
+    lines
+}
+
+/// Parses a `REV` timestamp in either the extended format used by 3.0 or the
+/// basic format used by 4.0
+fn parse_revision(value: &str) -> Option<OffsetDateTime> {
+    if let Ok(revision) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Some(revision.to_offset(UtcOffset::UTC));
+    }
+    PrimitiveDateTime::parse(
+        value,
+        format_description!("[year][month][day]T[hour][minute][second]Z"),
+    )
+    .ok()
+    .map(PrimitiveDateTime::assume_utc)
+}
+
+/// Formats a `REV` timestamp for the given version
+fn format_revision(revision: OffsetDateTime, version: Version) -> String {
+    let revision = revision.to_offset(UtcOffset::UTC);
+    let formatted = match version {
+        Version::V3 => revision.format(format_description!(
+            "[year]-[month]-[day]T[hour]:[minute]:[second]Z"
+        )),
+        Version::V4 => revision.format(format_description!(
+            "[year][month][day]T[hour][minute][second]Z"
+        )),
+    };
+    formatted.expect("Every UTC date can be formatted")
+}
+
+/// Applies a property to the card being parsed
+fn apply(card: &mut CardRecord, property: &Property, line: &str) {
+    match property.name.as_str() {
+        // Cards older than 4.0 are read like 3.0 cards
+        "VERSION" => {
+            card.extra_version = Some(if property.value.trim() == "4.0" {
+                Version::V4
+            } else {
+                Version::V3
+            });
+        }
+        "PRODID" => {}
+        "UID" => {
+            if let Some(uid) = text(&property.value) {
+                card.uid = uid;
+            }
+        }
+        "FN" => card.name = unescape(&property.value),
+        "N" => {
+            let components = split_unescaped(&property.value, ';');
+            card.family_name = components.first().and_then(|part| text(part));
+            card.given_name = components.get(1).and_then(|part| text(part));
+        }
+        "ORG" => {
+            card.organization = split_unescaped(&property.value, ';')
+                .first()
+                .and_then(|part| text(part));
+        }
+        "EMAIL" => {
+            if let Some(address) = text(&property.value) {
+                card.emails.push(CardEmail {
+                    address: address.trim().to_owned(),
+                    kinds: property
+                        .kinds()
+                        .into_iter()
+                        .filter(|kind| kind != "internet")
+                        .collect(),
+                    preferred: property.is_preferred(),
+                });
+            }
+        }
+        "TEL" => {
+            if let Some(number) = text(&property.value) {
+                let number = number.strip_prefix("tel:").unwrap_or(&number);
+                card.phones.push(CardPhone {
+                    number: number.trim().to_owned(),
+                    kinds: property
+                        .kinds()
+                        .into_iter()
+                        .filter(|kind| kind != "voice")
+                        .collect(),
+                    preferred: property.is_preferred(),
+                });
+            }
+        }
+        "CATEGORIES" => card.groups.extend(
+            split_unescaped(&property.value, ',').into_iter().filter_map(text),
+        ),
+        "NOTE" => card.note = text(&property.value),
+        "REV" => card.revision = parse_revision(&property.value),
+        _ => card.extra.push(line.to_owned()),
+    }
+}
+
+/// Parses every vCard in a `.vcf` file
+///
+/// Cards without an `FN` fall back to their structured name, then to their
+/// first email address.
+pub(crate) fn parse(input: &str) -> Result<Vec<CardRecord>, ParseError> {
+    let mut cards = Vec::new();
+    let mut current: Option<CardRecord> = None;
+    for (number, line) in unfold(input) {
+        let property =
+            parse_property(&line).ok_or(ParseError::MalformedLine(number))?;
+        match (property.name.as_str(), property.value.trim()) {
+            ("BEGIN", value) if value.eq_ignore_ascii_case("vcard") => {
+                if current.is_some() {
+                    return Err(ParseError::NestedCard(number));
+                }
+                current = Some(CardRecord::default());
+            }
+            ("END", value) if value.eq_ignore_ascii_case("vcard") => {
+                let mut card =
+                    current.take().ok_or(ParseError::OutsideCard(number))?;
+                if card.name.trim().is_empty() {
+                    card.name = card.fallback_name();
+                }
+                cards.push(card);
+            }
+            _ => apply(
+                current.as_mut().ok_or(ParseError::OutsideCard(number))?,
+                &property,
+                &line,
+            ),
+        }
+    }
+    match current {
+        Some(_) => Err(ParseError::UnclosedCard),
+        None => Ok(cards),
+    }
+}
+
+/// Appends a content line, folding it so no line exceeds `MAX_LINE_LENGTH`
+/// octets
+fn push_line(output: &mut String, line: &str) {
+    let mut length = 0;
+    for character in line.chars() {
+        if length + character.len_utf8() > MAX_LINE_LENGTH {
+            output.push_str("\r\n ");
+            length = 1;
+        }
+        output.push(character);
+        length += character.len_utf8();
+    }
+    output.push_str("\r\n");
+}
+
+/// Formats the parameters describing an email address or phone number
+fn type_parameters(
+    kinds: &[String],
+    preferred: bool,
+    version: Version,
+) -> String {
+    let mut kinds: Vec<String> = kinds
+        .iter()
+        .filter(|kind| {
+            !kind.is_empty()
+                && kind.chars().all(|character| {
+                    character.is_ascii_alphanumeric() || character == '-'
+                })
+        })
+        .cloned()
+        .collect();
+    let mut parameters = String::new();
+    match version {
+        Version::V3 => {
+            if preferred {
+                kinds.push("pref".to_owned());
+            }
+            if !kinds.is_empty() {
+                write!(parameters, ";TYPE={}", kinds.join(",").to_uppercase())
+                    .expect("Writing to a String can't fail");
+            }
+        }
+        Version::V4 => {
+            if !kinds.is_empty() {
+                write!(parameters, ";TYPE={}", kinds.join(","))
+                    .expect("Writing to a String can't fail");
+            }
+            if preferred {
+                parameters.push_str(";PREF=1");
+            }
+        }
+    }
+    parameters
+}
+
+/// Formats the value of a parameter, quoting it if it contains delimiters
+fn parameter_value(value: &str) -> String {
+    if value.contains([':', ';', ',']) {
+        format!("\"{value}\"")
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Gets the MIME type of inline binary data in vCard 3.0, such as
+/// `image/jpeg` for a `PHOTO` of type `JPEG`
+fn media_type(property: &str, format: &str) -> String {
+    let format = format.to_lowercase();
+    if format.contains('/') {
+        return format;
+    }
+    let kind = match property {
+        "PHOTO" | "LOGO" => "image",
+        "SOUND" => "audio",
+        _ => "application",
+    };
+    format!("{kind}/{format}")
+}
+
+/// Converts a content line that isn't modelled to another version
+///
+/// Returns `None` if the other version doesn't define the property.
+/// Preferences and inline binary data, which 3.0 writes as parameters and
+/// 4.0 as `PREF` and `data:` URIs, are converted.
+fn convert(line: &str, from: Version, to: Version) -> Option<String> {
+    if from == to {
+        return Some(line.to_owned());
+    }
+    let property = parse_property(line)?;
+    let undefined = match to {
+        Version::V3 => ONLY_V4,
+        Version::V4 => ONLY_V3,
+    };
+    if undefined.contains(&property.name.as_str()) {
+        return None;
+    }
+    // Keep the group, as in `item1.X-ABLABEL`
+    let name_end = line.find([';', ':']).unwrap_or(line.len());
+    let name = line.get(..name_end).unwrap_or_default();
+    let kinds = property.kinds();
+    let preferred = property.is_preferred();
+    let binary = property.parameters.iter().any(|(name, values)| {
+        name == "encoding"
+            && values.iter().any(|value| {
+                value.eq_ignore_ascii_case("b")
+                    || value.eq_ignore_ascii_case("base64")
+            })
+    });
+    let mut parameters = String::new();
+    let value = match to {
+        Version::V4 if binary => {
+            let format = kinds.first().map_or_else(
+                || "application/octet-stream".to_owned(),
+                |format| media_type(&property.name, format),
+            );
+            format!("data:{format};base64,{}", property.value)
+        }
+        Version::V3 if property.value.starts_with("data:") => {
+            // Only base64 encoded data can be inlined in 3.0
+            let (format, data) = property
+                .value
+                .strip_prefix("data:")
+                .and_then(|data| data.split_once(";base64,"))?;
+            let format = format.rsplit('/').next().unwrap_or(format);
+            write!(parameters, ";ENCODING=b;TYPE={}", format.to_uppercase())
+                .expect("Writing to a String can't fail");
+            data.to_owned()
+        }
+        _ => {
+            parameters.push_str(&type_parameters(&kinds, preferred, to));
+            property.value.clone()
+        }
+    };
+    let converted = ["type", "", "pref", "encoding", "mediatype"];
+    for (parameter, values) in &property.parameters {
+        // Inline data needs no `VALUE` parameter in either version
+        let inlined =
+            parameter == "value" && (binary || value != property.value);
+        if converted.contains(&parameter.as_str()) || inlined {
+            continue;
+        }
+        let values: Vec<String> =
+            values.iter().map(|value| parameter_value(value)).collect();
+        write!(
+            parameters,
+            ";{}={}",
+            parameter.to_uppercase(),
+            values.join(",")
+        )
+        .expect("Writing to a String can't fail");
+    }
+    Some(format!("{name}{parameters}:{value}"))
+}
+
+/// Writes a card as a vCard of the given version
+///
+/// Properties that were imported but aren't modelled are converted to the
+/// version, if they were imported from another one.
+pub(crate) fn write(card: &CardRecord, version: Version) -> String {
+    let mut output = String::new();
+    push_line(&mut output, "BEGIN:VCARD");
+    push_line(
+        &mut output,
+        match version {
+            Version::V3 => "VERSION:3.0",
+            Version::V4 => "VERSION:4.0",
+        },
+    );
+    if !card.uid.is_empty() {
+        push_line(&mut output, &format!("UID:{}", escape(&card.uid)));
+    }
+    push_line(&mut output, &format!("FN:{}", escape(&card.name)));
+    // N is required by 3.0 and optional in 4.0
+    if version == Version::V3
+        || card.family_name.is_some()
+        || card.given_name.is_some()
+    {
+        let family =
+            card.family_name.as_deref().map(escape).unwrap_or_default();
+        let given = card.given_name.as_deref().map(escape).unwrap_or_default();
+        push_line(&mut output, &format!("N:{family};{given};;;"));
+    }
+    if let Some(organization) = &card.organization {
+        push_line(&mut output, &format!("ORG:{}", escape(organization)));
+    }
+    for email in &card.emails {
+        let mut kinds = email.kinds.clone();
+        if version == Version::V3 {
+            kinds.insert(0, "internet".to_owned());
+        }
+        let parameters = type_parameters(&kinds, email.preferred, version);
+        push_line(
+            &mut output,
+            &format!("EMAIL{parameters}:{}", escape(&email.address)),
+        );
+    }
+    for phone in &card.phones {
+        let parameters =
+            type_parameters(&phone.kinds, phone.preferred, version);
+        push_line(
+            &mut output,
+            &format!("TEL{parameters}:{}", escape(&phone.number)),
+        );
+    }
+    if !card.groups.is_empty() {
+        let groups: Vec<String> =
+            card.groups.iter().map(|group| escape(group)).collect();
+        push_line(&mut output, &format!("CATEGORIES:{}", groups.join(",")));
+    }
+    if let Some(note) = &card.note {
+        push_line(&mut output, &format!("NOTE:{}", escape(note)));
+    }
+    if let Some(revision) = card.revision {
+        push_line(
+            &mut output,
+            &format!("REV:{}", format_revision(revision, version)),
+        );
+    }
+    let from = card.extra_version.unwrap_or(version);
+    for line in &card.extra {
+        if let Some(line) = convert(line, from, version) {
+            push_line(&mut output, &line);
+        }
+    }
+    push_line(&mut output, "END:VCARD");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, write, ParseError, Version, MAX_LINE_LENGTH};
+    use crate::database::address_book::CardRecord;
+
+    /// Parses a file that holds a single card
+    fn parse_one(vcf: &str) -> CardRecord {
+        let mut cards = parse(vcf).expect("The card should parse");
+        assert_eq!(cards.len(), 1);
+        cards.remove(0)
+    }
+
+    /// Writes a card and reads it back
+    fn round_trip(card: &CardRecord, version: Version) -> CardRecord {
+        parse_one(&write(card, version))
+    }
+
+    /// Long lines are folded within the octet limit, without splitting a
+    /// character, and unfolded again when read
+    #[test]
+    fn folds_long_lines() {
+        let note = "Fünf Wörter über Straßen, ".repeat(8);
+        let card = CardRecord {
+            name: "Jürgen Groß".to_owned(),
+            note: Some(note.trim_end().to_owned()),
+            ..CardRecord::default()
+        };
+        for version in [Version::V3, Version::V4] {
+            let vcf = write(&card, version);
+            assert!(vcf
+                .split("\r\n")
+                .all(|line| line.len() <= MAX_LINE_LENGTH));
+            assert!(vcf.contains("\r\n "), "The note should be folded");
+            assert_eq!(round_trip(&card, version).note, card.note);
+        }
+        let folded =
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Jane\r\n  Doe\r\nEND:VCARD\r\n";
+        assert_eq!(parse_one(folded).name, "Jane Doe");
+    }
+
+    /// Commas, semicolons, backslashes and line breaks survive in text values
+    #[test]
+    fn escapes_text() {
+        let card = CardRecord {
+            name: "Doe, Jane; \\ Jr.".to_owned(),
+            family_name: Some("Doe; Smith".to_owned()),
+            given_name: Some("Jane, Mary".to_owned()),
+            organization: Some("Acme, Inc.".to_owned()),
+            note: Some("First line\nSecond; third, \\ fourth".to_owned()),
+            ..CardRecord::default()
+        };
+        for version in [Version::V3, Version::V4] {
+            let read = round_trip(&card, version);
+            assert_eq!(read.name, card.name);
+            assert_eq!(read.family_name, card.family_name);
+            assert_eq!(read.given_name, card.given_name);
+            assert_eq!(read.organization, card.organization);
+            assert_eq!(read.note, card.note);
+        }
+        let escaped = "BEGIN:VCARD\r\nVERSION:4.0\r\nFN:Jane\r\nNOTE:a\\,b\\;\
+                       c\\nd\\Ne\\\\f\r\nEND:VCARD\r\n";
+        assert_eq!(parse_one(escaped).note.as_deref(), Some("a,b;c\nd\ne\\f"));
+    }
+
+    /// Phone numbers written as `tel:` URIs in 4.0 are read as plain numbers
+    /// with their types and preference
+    #[test]
+    fn reads_tel_uris() {
+        let vcf = concat!(
+            "BEGIN:VCARD\r\n",
+            "VERSION:4.0\r\n",
+            "FN:Jane\r\n",
+            "TEL;VALUE=uri;TYPE=\"cell,voice\";PREF=1:tel:+1-555-555-0100\r\n",
+            "TEL;VALUE=uri;TYPE=work:tel:+1-555-555-0199;ext=7\r\n",
+            "END:VCARD\r\n",
+        );
+        let card = parse_one(vcf);
+        let phones: Vec<(&str, &[String], bool)> = card
+            .phones
+            .iter()
+            .map(|phone| {
+                (phone.number.as_str(), phone.kinds.as_slice(), phone.preferred)
+            })
+            .collect();
+        assert_eq!(
+            phones,
+            vec![
+                ("+1-555-555-0100", &["cell".to_owned()][..], true),
+                ("+1-555-555-0199;ext=7", &["work".to_owned()][..], false),
+            ]
+        );
+        assert!(card.extra.is_empty());
+        for version in [Version::V3, Version::V4] {
+            assert_eq!(round_trip(&card, version).phones, card.phones);
+        }
+    }
+
+    /// Categories become groups, split at unescaped commas only
+    #[test]
+    fn reads_categories() {
+        let vcf = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Jane\r\nCATEGORIES:Work,\
+                   Friends\\, close,\r\nCATEGORIES:Book club\r\nEND:VCARD\r\n";
+        let card = parse_one(vcf);
+        assert_eq!(card.groups, vec!["Work", "Friends, close", "Book club"]);
+        for version in [Version::V3, Version::V4] {
+            assert_eq!(round_trip(&card, version).groups, card.groups);
+        }
+    }
+
+    /// Malformed files are rejected with the line at fault
+    #[test]
+    fn rejects_malformed_cards() {
+        let cases = [
+            (
+                "BEGIN:VCARD\r\nVERSION:3.0\r\nFN Jane\r\nEND:VCARD\r\n",
+                "MalformedLine(3)",
+            ),
+            ("FN:Jane\r\nBEGIN:VCARD\r\nEND:VCARD\r\n", "OutsideCard(1)"),
+            (
+                "BEGIN:VCARD\r\nFN:Jane\r\nEND:VCARD\r\nEND:VCARD\r\n",
+                "OutsideCard(4)",
+            ),
+            (
+                "BEGIN:VCARD\r\nFN:Jane\r\nBEGIN:VCARD\r\nEND:VCARD\r\n",
+                "NestedCard(3)",
+            ),
+            ("BEGIN:VCARD\r\nFN:Jane\r\n", "UnclosedCard"),
+            ("BEGIN:VCARD\r\n:Jane\r\nEND:VCARD\r\n", "MalformedLine(2)"),
+        ];
+        for (vcf, expected) in cases {
+            let error: ParseError =
+                parse(vcf).expect_err("The file should be rejected");
+            assert_eq!(format!("{error:?}"), expected, "{vcf:?}");
+        }
+    }
+}
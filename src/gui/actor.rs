@@ -2,17 +2,33 @@
 
 use actix::prelude::*;
 use druid::{
-    widget::{Button, Flex, Label},
-    AppLauncher, LocalizedString, Widget, WidgetExt, WindowDesc,
+    widget::{Flex, Split},
+    AppLauncher, Widget, WidgetExt, WindowDesc,
 };
 
-use super::watchdog_actor::GuiWatchdogActor;
-use crate::gui::watchdog_actor;
+use super::{
+    bridge::{ConnectMessage, GuiBridgeActor},
+    delegate::Delegate,
+    panes,
+    shortcuts::{self, KeyboardShortcuts, Shortcuts, MAIN_WINDOW},
+    state::AppState,
+    watchdog_actor::{self, GuiWatchdogActor},
+};
+use crate::config::{Account, KeyBindings};
+
+/// Title of the main window
+const WINDOW_TITLE: &str = "Weasel";
 
 /// An actor that handles rendering the GUI with druid
 pub(crate) struct GuiActor {
     /// TODO: Delete this
     watchdog_addr: Addr<GuiWatchdogActor>,
+    /// The bridge to the other actors
+    bridge_addr: Addr<GuiBridgeActor>,
+    /// The accounts whose folders are shown
+    accounts: Vec<Account>,
+    /// The keyboard shortcuts of the main window
+    keybindings: KeyBindings,
 }
 
 impl Actor for GuiActor {
@@ -25,9 +41,17 @@ impl Actor for GuiActor {
 
 impl GuiActor {
     /// Create a new GUI actor
-    pub(crate) fn new(watchdog_addr: Addr<GuiWatchdogActor>) -> Self {
+    pub(crate) fn new(
+        watchdog_addr: Addr<GuiWatchdogActor>,
+        bridge_addr: Addr<GuiBridgeActor>,
+        accounts: Vec<Account>,
+        keybindings: KeyBindings,
+    ) -> Self {
         Self {
             watchdog_addr,
+            bridge_addr,
+            accounts,
+            keybindings,
         }
     }
 }
@@ -46,28 +70,41 @@ impl Handler<StartMessage> for GuiActor {
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
         log::trace!("GuiActor received {msg:?}");
-        let main_window = WindowDesc::new(ui_builder());
-        let data = 0_u32;
-        AppLauncher::with_window(main_window)
-            .log_to_console()
-            .launch(data)
-            .expect("GUI Panicked");
+        let shortcuts = Shortcuts::new(&self.keybindings);
+        let data = AppState {
+            actions: shortcuts.palette(),
+            ..AppState::new(&self.accounts)
+        };
+        let main_window = WindowDesc::new(ui_builder(shortcuts))
+            .title(WINDOW_TITLE)
+            .window_size((1200.0, 800.0));
+        let launcher = AppLauncher::with_window(main_window)
+            .delegate(Delegate::new(self.bridge_addr.clone()))
+            .log_to_console();
+        self.bridge_addr.do_send(ConnectMessage {
+            sink: launcher.get_external_handle(),
+        });
+        launcher.launch(data).expect("GUI Panicked");
         log::trace!("Window has closed.");
         self.watchdog_addr.do_send(watchdog_actor::StopMessage);
     }
 }
 
-/// Build UI
-fn ui_builder() -> impl Widget<u32> {
-    // The label text will be computed dynamically based on the current
-    // locale and count
-    let text = LocalizedString::new("hello-counter")
-        .with_arg("count", |data: &u32, _env| (*data).into());
-    let label = Label::new(text).padding(5.0).center();
-    let button = Button::new("increment")
-        .on_click(move |_ctx, data, _env| {
-            *data += 1;
-        })
-        .padding(5.0);
-    Flex::column().with_child(label).with_child(button)
+/// Build the main window: the folder tree on the left, the message list in
+/// the middle and the reader on the right, below the command palette while it
+/// is open
+fn ui_builder(shortcuts: Shortcuts) -> impl Widget<AppState> {
+    let panes = Split::columns(
+        panes::folder_tree(),
+        Split::columns(panes::message_list(), panes::reader())
+            .split_point(0.4)
+            .draggable(true),
+    )
+    .split_point(0.2)
+    .draggable(true);
+    Flex::column()
+        .with_child(shortcuts::palette())
+        .with_flex_child(panes, 1.0)
+        .controller(KeyboardShortcuts::new(shortcuts))
+        .with_id(MAIN_WINDOW)
 }
@@ -0,0 +1,1483 @@
+//! Contains the actor connecting the GUI to the other actors
+//!
+//! Druid runs its own event loop on the GUI thread, which can't await actor
+//! responses. The delegate sends what the user does to the `GuiBridgeActor`
+//! instead, which runs with the other actors, asks them for what to show and
+//! hands the results back to druid as commands through an `ExtEventSink`. It
+//! also subscribes to changes in the database, so the window updates as mail
+//! is synchronized.
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
+};
+
+use actix::prelude::*;
+use druid::{im::Vector, ExtEventSink, Selector, Target};
+use time::{format_description::FormatItem, macros::format_description};
+
+use super::{
+    delegate::{
+        AddressQuery, ChangedFolder, CompletedAddresses, ComposeKind,
+        EmailChange, LoadedEmail, LoadedPage, PreparedDraft, SentDraft,
+        ADDRESSES_COMPLETED, DRAFT_READY, DRAFT_SENT, EMAIL_LOADED,
+        FOLDER_CHANGED, HEALTH_CHANGED, PAGE_LOADED, SMART_FOLDERS_LOADED,
+    },
+    state::{
+        AddressField, AttachmentEntry, ConversationEntry, FolderEntry,
+        MessageSummary, PAGE_SIZE,
+    },
+};
+use crate::{
+    config::Account,
+    database::{
+        address_book::{
+            CardEmail, CardRecord, ExportVCardsMessage, ImportVCardsMessage,
+            SaveCardMessage,
+        },
+        attachments::ExportAttachmentMessage,
+        contacts::CompleteAddressMessage,
+        listing::{
+            EmailContent, EmailSummary, ListEmailsMessage, Listing, Position,
+            ReadEmailMessage, Sort,
+        },
+        notifications::{EmailsChangedMessage, SubscribeMessage},
+        query::Query,
+        remote_content::{AllowRemoteContentMessage, Allowance},
+        saved_searches::{
+            DeleteSearchMessage, ListSavedSearchesMessage, SaveSearchMessage,
+            SavedSearch,
+        },
+        search::RemoteHits,
+        structures::AttachmentRecord,
+        threading::ConversationMessage,
+        vcard::Version,
+        DatabaseActor, Health, HealthMessage,
+    },
+    mail::{
+        compose::{self, Draft},
+        html, mime, styled, ArchiveEmailsMessage, DeleteEmailsMessage, Errors,
+        FetchBodiesMessage, FlagEmailsMessage, MailActor, RemoteSearchMessage,
+        SendEmailMessage, StringAddress, SyncNowMessage, SyncSchedulerActor,
+    },
+};
+
+/// How dates are shown
+const DATE_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]");
+
+/// The flag of emails that have been read
+const SEEN: &str = "\\Seen";
+
+/// The flag of emails marked for attention
+const FLAGGED: &str = "\\Flagged";
+
+/// The flag of emails that have been replied to
+const ANSWERED: &str = "\\Answered";
+
+/// How many contacts are suggested for an address being typed
+const SUGGESTIONS: usize = 5;
+
+/// Directory below the system's temporary directory that attachments are
+/// written to before they are opened
+const OPENED_ATTACHMENTS: &str = "weasel-attachments";
+
+/// How long after emails changed the unread counts of the smart folders are
+/// updated, so a synchronization writing many batches updates them once
+const COUNT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the health of the database is checked, to show the GUI when
+/// its operations keep failing
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A mailbox of an account, as account address and mailbox name
+type MailboxKey = (String, String);
+
+/// What is known about a version of the message list, so its pages don't have
+/// to be counted and skipped to again
+#[derive(Default)]
+struct ListedPages {
+    /// The listing and the version of the message list this is about
+    version: Option<(Listing, u64)>,
+    /// How many emails are listed, once counted
+    total: Option<usize>,
+    /// Where the last email of each loaded page is, by page index
+    ends: HashMap<usize, Position>,
+}
+
+/// An actor that forwards between the GUI and the other actors
+pub(crate) struct GuiBridgeActor {
+    /// The configured accounts, to know which of their mailboxes are searched
+    /// on the server
+    accounts: Vec<Account>,
+    /// Address of the database actor, which has everything that is shown
+    database_addr: Addr<DatabaseActor>,
+    /// Mail actors by the address of their account
+    mail_actors: HashMap<String, Addr<MailActor>>,
+    /// Address of the scheduler, to synchronize mailboxes on request
+    scheduler_addr: Addr<SyncSchedulerActor>,
+    /// Where to send commands for the GUI, once it is running
+    sink: Option<ExtEventSink>,
+    /// Which emails are listed
+    folder: Option<Listing>,
+    /// Hits of searching the listed query on the servers so far
+    remote_hits: Vec<RemoteHits>,
+    /// The total and page ends of the version of the message list last
+    /// loaded
+    listed: ListedPages,
+    /// The email shown in the reader, by mailbox and UID
+    email: Option<(MailboxKey, u32)>,
+    /// Whether the unread counts of the smart folders are due to be updated
+    counts_due: bool,
+    /// The health of the database last shown in the GUI
+    health: Health,
+}
+
+impl GuiBridgeActor {
+    /// Creates a bridge to the given actors
+    pub(crate) fn new(
+        accounts: Vec<Account>,
+        database_addr: Addr<DatabaseActor>,
+        mail_actors: HashMap<String, Addr<MailActor>>,
+        scheduler_addr: Addr<SyncSchedulerActor>,
+    ) -> Self {
+        Self {
+            accounts,
+            database_addr,
+            mail_actors,
+            scheduler_addr,
+            sink: None,
+            folder: None,
+            remote_hits: Vec::new(),
+            listed: ListedPages::default(),
+            email: None,
+            counts_due: false,
+            health: Health::Healthy,
+        }
+    }
+
+    /// Sends a command to the GUI
+    fn submit<T: Any + Send>(&self, selector: Selector<T>, payload: T) {
+        let Some(sink) = &self.sink else {
+            log::warn!("GUI bridge has no GUI to send {selector} to");
+            return;
+        };
+        if let Err(e) = sink.submit_command(selector, payload, Target::Auto) {
+            log::warn!("GUI bridge failed to send {selector}: {e}");
+        }
+    }
+
+    /// Shows the email in the reader of the GUI, with its conversation
+    ///
+    /// When the user has just opened it, the body is downloaded if it is
+    /// missing and the email is marked as read.
+    fn load_email(&self, opened: bool, ctx: &mut Context<Self>) {
+        let Some(((account, mailbox), uid)) = self.email.clone() else {
+            return;
+        };
+        let key = ((account.clone(), mailbox.clone()), uid);
+        let database = self.database_addr.clone();
+        let read = ReadEmailMessage {
+            account: account.clone(),
+            mailbox: mailbox.clone(),
+            uid,
+        };
+        let conversation = ConversationMessage {
+            account,
+            mailbox,
+            uid,
+        };
+        ctx.spawn(
+            async move {
+                let email = database.send(read).await;
+                let conversation = database.send(conversation).await;
+                (email, conversation)
+            }
+            .into_actor(self)
+            .map(move |(result, conversation), actor, _ctx| {
+                if actor.email.as_ref() != Some(&key) {
+                    return;
+                }
+                let email = match result {
+                    Ok(Ok(Some(email))) => email,
+                    Ok(Ok(None)) => return,
+                    Ok(Err(e)) => {
+                        log::warn!("GUI bridge failed to read email: {e}");
+                        return;
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                        return;
+                    }
+                };
+                if opened {
+                    actor.complete(&email);
+                }
+                let conversation = match conversation {
+                    Ok(Ok(emails)) => conversation_entries(emails, &key),
+                    Ok(Err(e)) => {
+                        log::warn!(
+                            "GUI bridge failed to list the conversation: {e}"
+                        );
+                        Vector::new()
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                        Vector::new()
+                    }
+                };
+                actor.submit(
+                    EMAIL_LOADED,
+                    LoadedEmail {
+                        conversation,
+                        ..loaded_email(email)
+                    },
+                );
+            }),
+        );
+    }
+
+    /// Sends the smart folders with their unread counts to the GUI
+    fn load_smart_folders(&self, ctx: &mut Context<Self>) {
+        ctx.spawn(
+            self.database_addr
+                .send(ListSavedSearchesMessage)
+                .into_actor(self)
+                .map(|result, actor, _ctx| match result {
+                    Ok(Ok(searches)) => actor.submit(
+                        SMART_FOLDERS_LOADED,
+                        searches.into_iter().map(smart_folder).collect(),
+                    ),
+                    Ok(Err(e)) => {
+                        log::warn!(
+                            "GUI bridge failed to list saved searches: {e}"
+                        );
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                    }
+                }),
+        );
+    }
+
+    /// Updates the unread counts of the smart folders shortly, unless that is
+    /// already due
+    fn count_smart_folders(&mut self, ctx: &mut Context<Self>) {
+        if self.counts_due {
+            return;
+        }
+        self.counts_due = true;
+        ctx.run_later(COUNT_DELAY, |actor, ctx| {
+            actor.counts_due = false;
+            actor.load_smart_folders(ctx);
+        });
+    }
+
+    /// Shows the GUI what went wrong with the database when its health
+    /// changes
+    fn check_health(&self, ctx: &mut Context<Self>) {
+        ctx.spawn(self.database_addr.send(HealthMessage).into_actor(self).map(
+            |result, actor, _ctx| {
+                let health = match result {
+                    Ok(health) => health,
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                        return;
+                    }
+                };
+                if health == actor.health {
+                    return;
+                }
+                let problem = match &health {
+                    Health::Healthy => None,
+                    Health::Degraded {
+                        failures,
+                        last_error,
+                    } => Some(format!(
+                        "Database failing ({failures} in a row): {last_error}"
+                    )),
+                };
+                actor.health = health;
+                actor.submit(HEALTH_CHANGED, problem);
+            },
+        ));
+    }
+
+    /// Searches the mailboxes that aren't fully synced on their servers
+    ///
+    /// Each mailbox's hits are listed with the local results as they arrive,
+    /// as long as the query is still listed.
+    fn search_remotely(&self, text: &str, ctx: &mut Context<Self>) {
+        let Ok(query) = Query::parse(text) else {
+            return;
+        };
+        for account in &self.accounts {
+            let Some(mail_actor) = self.mail_actors.get(&account.address)
+            else {
+                continue;
+            };
+            for folder in &account.folders {
+                if account.sync_policy(&folder.mailbox).is_complete()
+                    || !query.admits(&account.address, &folder.mailbox)
+                {
+                    continue;
+                }
+                let listing = Listing::Query(text.to_owned());
+                ctx.spawn(
+                    mail_actor
+                        .send(RemoteSearchMessage {
+                            mailbox: folder.mailbox.clone(),
+                            query: query.clone(),
+                        })
+                        .into_actor(self)
+                        .map(move |result, actor, _ctx| match result {
+                            Ok(Ok(hits)) => {
+                                if actor.folder.as_ref() != Some(&listing)
+                                    || hits.uids.is_empty()
+                                {
+                                    return;
+                                }
+                                let changed = ChangedFolder {
+                                    account: hits.account.clone(),
+                                    mailbox: hits.mailbox.clone(),
+                                };
+                                actor.remote_hits.push(hits);
+                                actor.submit(FOLDER_CHANGED, changed);
+                            }
+                            Ok(Err(e)) => {
+                                log::warn!(
+                                    "GUI bridge failed to search on the \
+                                     server: {e}"
+                                );
+                            }
+                            Err(e) => {
+                                log::warn!(
+                                    "GUI bridge failed to reach a mail actor: \
+                                     {e}"
+                                );
+                            }
+                        }),
+                );
+            }
+        }
+    }
+
+    /// Writes an attachment to a file, then opens the file if asked to
+    fn export_attachment(
+        &self,
+        blob: String,
+        path: PathBuf,
+        open: bool,
+    ) -> ResponseActFuture<Self, ()> {
+        Box::pin(
+            self.database_addr
+                .send(ExportAttachmentMessage {
+                    blob,
+                    path: path.clone(),
+                })
+                .into_actor(self)
+                .map(move |result, _actor, _ctx| match result {
+                    Ok(Ok(())) if open => open_file(&path),
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        log::warn!(
+                            "GUI bridge failed to write {}: {e}",
+                            path.display()
+                        );
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                    }
+                }),
+        )
+    }
+
+    /// Asks the mail actor of an opened email to download its body if it
+    /// hasn't been, or only partially, and to mark it as read
+    ///
+    /// Both show up in the GUI through the change notifications.
+    fn complete(&self, email: &EmailContent) {
+        let Some(mail_actor) = self.mail_actors.get(&email.account) else {
+            log::warn!("GUI bridge has no mail actor for {}", email.account);
+            return;
+        };
+        if email.raw.is_none() || email.partial == Some(true) {
+            mail_actor.do_send(FetchBodiesMessage {
+                mailbox: email.mailbox.clone(),
+                uids: vec![email.uid],
+            });
+        }
+        if !email.flags.iter().any(|flag| flag == SEEN) {
+            mail_actor.do_send(FlagEmailsMessage {
+                mailbox: email.mailbox.clone(),
+                uids: vec![email.uid],
+                flags: vec![SEEN.to_owned()],
+                add: true,
+            });
+        }
+    }
+}
+
+impl Actor for GuiBridgeActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::trace!("GUI bridge started");
+        self.database_addr.do_send(SubscribeMessage {
+            subscriber: ctx.address().recipient(),
+        });
+        ctx.run_interval(HEALTH_INTERVAL, |actor, ctx| {
+            actor.check_health(ctx);
+        });
+    }
+}
+
+/// Formats addresses for display, by name where there is one
+fn names(addresses: Option<&Vec<StringAddress>>) -> String {
+    addresses
+        .into_iter()
+        .flatten()
+        .map(|address| {
+            address
+                .name()
+                .map(str::to_owned)
+                .or_else(|| address.address())
+                .unwrap_or_default()
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Formats addresses in full for display
+fn full_addresses(lists: &[Option<&Vec<StringAddress>>]) -> String {
+    lists
+        .iter()
+        .flatten()
+        .copied()
+        .flatten()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Formats a date for display
+fn date(date: Option<time::OffsetDateTime>) -> String {
+    date.and_then(|date| date.format(DATE_FORMAT).ok()).unwrap_or_default()
+}
+
+/// Formats a size in bytes for display
+fn size(bytes: Option<u32>) -> String {
+    /// Bytes in a kilobyte
+    const KILOBYTE: u32 = 1024;
+    match bytes {
+        None => String::new(),
+        Some(bytes) if bytes < KILOBYTE => format!("{bytes} B"),
+        Some(bytes) if bytes < KILOBYTE * KILOBYTE => {
+            format!("{} KB", bytes / KILOBYTE)
+        }
+        Some(bytes) => format!("{} MB", bytes / KILOBYTE / KILOBYTE),
+    }
+}
+
+/// Turns a listed email into a row of the message list, naming its account
+/// if emails of several accounts may be listed
+fn summary(email: EmailSummary, across: bool) -> MessageSummary {
+    MessageSummary {
+        origin: across.then(|| email.account.clone()),
+        from: names(email.from.as_ref()),
+        subject: email.subject.unwrap_or_default(),
+        date: date(email.date),
+        size: size(email.size),
+        unread: !email.flags.iter().any(|flag| flag == SEEN),
+        flagged: email.flags.iter().any(|flag| flag == FLAGGED),
+        snippet: email.snippet.unwrap_or_default(),
+        account: email.account,
+        mailbox: email.location.mailbox,
+        uid: email.location.uid,
+    }
+}
+
+/// Turns a saved search into a row of the folder tree
+fn smart_folder(search: SavedSearch) -> FolderEntry {
+    FolderEntry {
+        listing: Some(Listing::Query(search.query)),
+        name: search.name,
+        depth: 1,
+        unread: Some(search.unread),
+        saved: true,
+    }
+}
+
+/// Turns an email into what the reader shows
+///
+/// The HTML is rendered here rather than on the GUI thread, since long emails
+/// take a while.
+fn loaded_email(email: EmailContent) -> LoadedEmail {
+    let plain = match (email.bodies.plain.or(email.body_text), email.raw) {
+        (Some(text), _) => styled::plain(&text),
+        (None, Some(_)) => Vec::new(),
+        (None, None) => styled::plain("Downloading…"),
+    };
+    let html = email
+        .bodies
+        .html
+        .as_deref()
+        .map(|body| html::render(body, email.remote_content));
+    LoadedEmail {
+        subject: email.subject.unwrap_or_default(),
+        from: full_addresses(&[email.from.as_ref()]),
+        sender: email.from.iter().flatten().find_map(StringAddress::address),
+        to: full_addresses(&[email.to.as_ref(), email.cc.as_ref()]),
+        date: date(email.date),
+        plain,
+        blocked: html.as_ref().map_or(0, |html| html.blocked),
+        trackers: html.as_ref().map_or(0, |html| html.trackers),
+        html: html.map(|html| html.spans),
+        remote_content: email.remote_content,
+        conversation: Vector::new(),
+        attachments: email
+            .attachments
+            .into_iter()
+            .map(attachment_entry)
+            .collect(),
+        account: email.account,
+        mailbox: email.mailbox,
+        uid: email.uid,
+    }
+}
+
+/// Turns the emails of a conversation into rows of the reader, marking the
+/// email shown in it
+fn conversation_entries(
+    emails: Vec<EmailSummary>,
+    shown: &(MailboxKey, u32),
+) -> Vector<ConversationEntry> {
+    emails
+        .into_iter()
+        .map(|email| {
+            let current = email.account == shown.0 .0
+                && email.location.mailbox == shown.0 .1
+                && email.location.uid == shown.1;
+            ConversationEntry {
+                email: summary(email, false),
+                current,
+            }
+        })
+        .collect()
+}
+
+/// Opens a file with the application the system associates with its type
+fn open_file(path: &Path) {
+    log::trace!("GUI bridge opening {}", path.display());
+    if let Err(e) = process::Command::new("xdg-open").arg(path).spawn() {
+        log::warn!("GUI bridge failed to open {}: {e}", path.display());
+    }
+}
+
+/// Reads a text file on a blocking thread, so the bridge keeps answering the
+/// GUI
+async fn read_file(path: PathBuf) -> io::Result<String> {
+    actix_rt::task::spawn_blocking(move || fs::read_to_string(path))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Writes a text file on a blocking thread, like `read_file`
+async fn write_file(path: PathBuf, contents: String) -> io::Result<()> {
+    actix_rt::task::spawn_blocking(move || fs::write(path, contents))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Turns the sender of an email into a card for the address book
+fn sender_card(email: &EmailContent) -> Option<CardRecord> {
+    let sender = email.from.as_ref()?.first()?;
+    Some(CardRecord {
+        name: sender.name().unwrap_or_default().to_owned(),
+        emails: vec![CardEmail {
+            address: sender.address()?,
+            kinds: Vec::new(),
+            preferred: true,
+        }],
+        ..CardRecord::default()
+    })
+}
+
+/// Turns an attachment of an email into a row of the reader
+fn attachment_entry(record: AttachmentRecord) -> AttachmentEntry {
+    AttachmentEntry {
+        name: mime::safe_filename(record.filename.as_deref()),
+        size: size(u32::try_from(record.size).ok()),
+        blob: record.blob,
+        mime_type: record.mime_type,
+    }
+}
+
+/// Explains why a draft couldn't be sent
+fn send_error(error: &Errors) -> String {
+    match error {
+        Errors::Compose => "Add at least one recipient, and check that every \
+                            address is complete."
+            .to_owned(),
+        Errors::Connect | Errors::Disconnected => {
+            "The outgoing mail server couldn't be reached securely.".to_owned()
+        }
+        Errors::Login => "The outgoing mail server refused the account's \
+                          password."
+            .to_owned(),
+        Errors::Attach => {
+            "One of the attached files couldn't be read.".to_owned()
+        }
+        _ => "The outgoing mail server refused the email or one of its \
+              recipients."
+            .to_owned(),
+    }
+}
+
+/// Message handing the bridge the sink to send commands to the GUI through,
+/// before the GUI starts
+#[derive(Message)]
+#[rtype(result = "()")]
+pub(crate) struct ConnectMessage {
+    /// The sink of the GUI's event loop
+    pub(crate) sink: ExtEventSink,
+}
+
+impl Handler<ConnectMessage> for GuiBridgeActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ConnectMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received the GUI's sink");
+        self.sink = Some(msg.sink);
+        self.load_smart_folders(ctx);
+    }
+}
+
+/// Message from the GUI that the user selected a mailbox or searched
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct ShowFolderMessage {
+    /// Which emails are listed now
+    pub(crate) listing: Listing,
+}
+
+impl Handler<ShowFolderMessage> for GuiBridgeActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ShowFolderMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        self.remote_hits.clear();
+        if let Listing::Query(query) = &msg.listing {
+            self.search_remotely(query, ctx);
+        }
+        self.folder = Some(msg.listing);
+        self.email = None;
+    }
+}
+
+/// Message from the GUI requesting a page of the message list
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct LoadPageMessage {
+    /// Which emails are listed
+    pub(crate) listing: Listing,
+    /// The order of the list
+    pub(crate) sort: Sort,
+    /// The version of the list the page is for
+    pub(crate) version: u64,
+    /// Index of the page
+    pub(crate) page: usize,
+}
+
+impl Handler<LoadPageMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: LoadPageMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let across = matches!(msg.listing, Listing::Query(_))
+            && self.mail_actors.len() > 1;
+        let remote_hits = if self.folder.as_ref() == Some(&msg.listing) {
+            self.remote_hits.clone()
+        } else {
+            Vec::new()
+        };
+        let version = (msg.listing.clone(), msg.version);
+        if self.listed.version.as_ref() != Some(&version) {
+            self.listed = ListedPages {
+                version: Some(version),
+                ..ListedPages::default()
+            };
+        }
+        // Pages are mostly loaded while scrolling, right after the one before
+        let after = msg
+            .page
+            .checked_sub(1)
+            .and_then(|previous| self.listed.ends.get(&previous))
+            .cloned();
+        Box::pin(
+            self.database_addr
+                .send(ListEmailsMessage {
+                    listing: msg.listing.clone(),
+                    sort: msg.sort,
+                    start: msg.page * PAGE_SIZE,
+                    after,
+                    count: self.listed.total.is_none(),
+                    limit: PAGE_SIZE,
+                    remote_hits,
+                })
+                .into_actor(self)
+                .map(move |result, actor, _ctx| match result {
+                    Ok(Ok(listed)) => {
+                        let listed_pages = &mut actor.listed;
+                        if listed_pages.version.as_ref().is_some_and(
+                            |(listing, version)| {
+                                *listing == msg.listing
+                                    && *version == msg.version
+                            },
+                        ) {
+                            listed_pages.total =
+                                listed.total.or(listed_pages.total);
+                            if let Some(end) = listed
+                                .emails
+                                .last()
+                                .and_then(|last| last.position.clone())
+                            {
+                                listed_pages.ends.insert(msg.page, end);
+                            }
+                        }
+                        let total = listed.total.or(listed_pages.total);
+                        actor.submit(
+                            PAGE_LOADED,
+                            LoadedPage {
+                                listing: msg.listing,
+                                version: msg.version,
+                                page: msg.page,
+                                total: total.unwrap_or_default(),
+                                rows: listed
+                                    .emails
+                                    .into_iter()
+                                    .map(|email| summary(email, across))
+                                    .collect(),
+                            },
+                        );
+                    }
+                    Ok(Err(e)) => {
+                        log::warn!(
+                            "GUI bridge failed to list {:?}: {e}",
+                            msg.listing
+                        );
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                    }
+                }),
+        )
+    }
+}
+
+/// Message from the GUI that the user opened an email
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct ShowEmailMessage {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email
+    pub(crate) uid: u32,
+}
+
+impl Handler<ShowEmailMessage> for GuiBridgeActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ShowEmailMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        self.email = Some(((msg.account, msg.mailbox), msg.uid));
+        self.load_email(true, ctx);
+    }
+}
+
+/// Message from the GUI that the user allowed remote content
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct LoadRemoteContentMessage {
+    /// The account the emails belong to
+    pub(crate) account: String,
+    /// What remote content is allowed for
+    pub(crate) allowance: Allowance,
+}
+
+impl Handler<LoadRemoteContentMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: LoadRemoteContentMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        Box::pin(
+            self.database_addr
+                .send(AllowRemoteContentMessage {
+                    account: msg.account,
+                    allowance: msg.allowance,
+                })
+                .into_actor(self)
+                .map(|result, actor, ctx| match result {
+                    // The shown email is rendered again with what was allowed
+                    Ok(Ok(())) => actor.load_email(false, ctx),
+                    Ok(Err(e)) => {
+                        log::warn!(
+                            "GUI bridge failed to allow remote content: {e}"
+                        );
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                    }
+                }),
+        )
+    }
+}
+
+/// Message from the GUI to start a reply to or forward of an email
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct PrepareDraftMessage {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email
+    pub(crate) uid: u32,
+    /// Whether to reply, reply to all or forward
+    pub(crate) kind: ComposeKind,
+}
+
+impl Handler<PrepareDraftMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: PrepareDraftMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        Box::pin(
+            self.database_addr
+                .send(ReadEmailMessage {
+                    account: msg.account.clone(),
+                    mailbox: msg.mailbox,
+                    uid: msg.uid,
+                })
+                .into_actor(self)
+                .map(move |result, actor, _ctx| {
+                    let email = match result {
+                        Ok(Ok(Some(email))) => email,
+                        Ok(Ok(None)) => return,
+                        Ok(Err(e)) => {
+                            log::warn!("GUI bridge failed to read email: {e}");
+                            return;
+                        }
+                        Err(e) => {
+                            log::warn!(
+                                "GUI bridge failed to reach the database: {e}"
+                            );
+                            return;
+                        }
+                    };
+                    let draft = match msg.kind {
+                        ComposeKind::New => Draft::default(),
+                        ComposeKind::Reply => {
+                            compose::reply(&email, &msg.account, false)
+                        }
+                        ComposeKind::ReplyAll => {
+                            compose::reply(&email, &msg.account, true)
+                        }
+                        ComposeKind::Forward => compose::forward(&email),
+                    };
+                    let replied = matches!(
+                        msg.kind,
+                        ComposeKind::Reply | ComposeKind::ReplyAll
+                    )
+                    .then_some((email.mailbox, email.uid));
+                    actor.submit(
+                        DRAFT_READY,
+                        PreparedDraft {
+                            account: msg.account,
+                            draft,
+                            replied,
+                        },
+                    );
+                }),
+        )
+    }
+}
+
+/// Message from the GUI asking for contacts matching an address being typed
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct CompleteAddressesMessage {
+    /// The draft the address is typed in
+    pub(crate) draft: u64,
+    /// The field the address is typed in
+    pub(crate) field: AddressField,
+    /// What has been typed of the address
+    pub(crate) prefix: String,
+}
+
+impl Handler<CompleteAddressesMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: CompleteAddressesMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        Box::pin(
+            self.database_addr
+                .send(CompleteAddressMessage {
+                    prefix: msg.prefix.clone(),
+                    limit: SUGGESTIONS,
+                })
+                .into_actor(self)
+                .map(move |result, actor, _ctx| match result {
+                    Ok(Ok(contacts)) => actor.submit(
+                        ADDRESSES_COMPLETED,
+                        CompletedAddresses {
+                            query: AddressQuery {
+                                draft: msg.draft,
+                                field: msg.field,
+                                prefix: msg.prefix,
+                            },
+                            addresses: contacts
+                                .iter()
+                                .map(|contact| {
+                                    compose::mailbox(
+                                        contact.name.as_deref(),
+                                        &contact.address,
+                                    )
+                                })
+                                .collect(),
+                        },
+                    ),
+                    Ok(Err(e)) => {
+                        log::warn!(
+                            "GUI bridge failed to complete an address: {e}"
+                        );
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                    }
+                }),
+        )
+    }
+}
+
+/// Message from the GUI to send a draft
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct SendDraftMessage {
+    /// ID of the draft
+    pub(crate) draft: u64,
+    /// The account to send the email from
+    pub(crate) account: String,
+    /// The email to send
+    pub(crate) email: Draft,
+    /// The mailbox and UID of the email replied to, if it is a reply
+    pub(crate) replied: Option<(String, u32)>,
+}
+
+impl Handler<SendDraftMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: SendDraftMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let Some(mail_actor) = self.mail_actors.get(&msg.account).cloned()
+        else {
+            log::warn!("GUI bridge has no mail actor for {}", msg.account);
+            self.submit(
+                DRAFT_SENT,
+                SentDraft {
+                    draft: msg.draft,
+                    error: Some("The account isn't configured.".to_owned()),
+                },
+            );
+            return Box::pin(fut::ready(()));
+        };
+        Box::pin(
+            mail_actor
+                .send(SendEmailMessage {
+                    draft: msg.email,
+                })
+                .into_actor(self)
+                .map(move |result, actor, _ctx| {
+                    let error = match result {
+                        Ok(Ok(())) => None,
+                        Ok(Err(e)) => Some(send_error(&e)),
+                        Err(e) => {
+                            log::warn!(
+                                "GUI bridge failed to reach the mail actor: \
+                                 {e}"
+                            );
+                            Some(
+                                "The email couldn't be handed over for \
+                                 sending."
+                                    .to_owned(),
+                            )
+                        }
+                    };
+                    if let (None, Some((mailbox, uid))) = (&error, msg.replied)
+                    {
+                        mail_actor.do_send(FlagEmailsMessage {
+                            mailbox,
+                            uids: vec![uid],
+                            flags: vec![ANSWERED.to_owned()],
+                            add: true,
+                        });
+                    }
+                    actor.submit(
+                        DRAFT_SENT,
+                        SentDraft {
+                            draft: msg.draft,
+                            error,
+                        },
+                    );
+                }),
+        )
+    }
+}
+
+/// Message from the GUI to save an attachment to a file the user chose
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct SaveAttachmentMessage {
+    /// Hash of the attachment in the blob store
+    pub(crate) blob: String,
+    /// Where to save the attachment
+    pub(crate) path: PathBuf,
+}
+
+impl Handler<SaveAttachmentMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: SaveAttachmentMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        self.export_attachment(msg.blob, msg.path, false)
+    }
+}
+
+/// Message from the GUI to open an attachment
+///
+/// The attachment is written to a temporary file first, in a directory of its
+/// own so attachments with the same name don't overwrite each other.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct OpenAttachmentMessage {
+    /// Hash of the attachment in the blob store
+    pub(crate) blob: String,
+    /// The name to give the temporary file, free of directories
+    pub(crate) name: String,
+}
+
+impl Handler<OpenAttachmentMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: OpenAttachmentMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let path = env::temp_dir()
+            .join(OPENED_ATTACHMENTS)
+            .join(&msg.blob)
+            .join(&msg.name);
+        self.export_attachment(msg.blob, path, true)
+    }
+}
+
+/// Message from the GUI to add the contacts in a `.vcf` file to the address
+/// book
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct ImportContactsMessage {
+    /// The file the user chose
+    pub(crate) path: PathBuf,
+}
+
+impl Handler<ImportContactsMessage> for GuiBridgeActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(
+        &mut self,
+        msg: ImportContactsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let database_addr = self.database_addr.clone();
+        Box::pin(async move {
+            let vcf = match read_file(msg.path.clone()).await {
+                Ok(vcf) => vcf,
+                Err(e) => {
+                    log::warn!(
+                        "GUI bridge failed to read {}: {e}",
+                        msg.path.display()
+                    );
+                    return;
+                }
+            };
+            match database_addr
+                .send(ImportVCardsMessage {
+                    vcf,
+                })
+                .await
+            {
+                Ok(Ok(count)) => log::info!(
+                    "GUI bridge imported {count} contacts from {}",
+                    msg.path.display()
+                ),
+                Ok(Err(e)) => log::warn!(
+                    "GUI bridge failed to import {}: {e}",
+                    msg.path.display()
+                ),
+                Err(e) => {
+                    log::warn!("GUI bridge failed to reach the database: {e}");
+                }
+            }
+        })
+    }
+}
+
+/// Message from the GUI to write the address book to a `.vcf` file
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct ExportContactsMessage {
+    /// The file the user chose
+    pub(crate) path: PathBuf,
+    /// The vCard version to write
+    pub(crate) version: Version,
+}
+
+impl Handler<ExportContactsMessage> for GuiBridgeActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(
+        &mut self,
+        msg: ExportContactsMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let database_addr = self.database_addr.clone();
+        Box::pin(async move {
+            let export = ExportVCardsMessage {
+                version: msg.version,
+                group: None,
+            };
+            let vcf = match database_addr.send(export).await {
+                Ok(Ok(vcf)) => vcf,
+                Ok(Err(e)) => {
+                    log::warn!("GUI bridge failed to export contacts: {e}");
+                    return;
+                }
+                Err(e) => {
+                    log::warn!("GUI bridge failed to reach the database: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = write_file(msg.path.clone(), vcf).await {
+                log::warn!(
+                    "GUI bridge failed to write {}: {e}",
+                    msg.path.display()
+                );
+            }
+        })
+    }
+}
+
+/// Message from the GUI to add the sender of the email shown in the reader
+/// to the address book
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct AddSenderMessage;
+
+impl Handler<AddSenderMessage> for GuiBridgeActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(
+        &mut self,
+        msg: AddSenderMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let Some(((account, mailbox), uid)) = self.email.clone() else {
+            return Box::pin(async {});
+        };
+        let database_addr = self.database_addr.clone();
+        Box::pin(async move {
+            let read = ReadEmailMessage {
+                account,
+                mailbox,
+                uid,
+            };
+            let card = match database_addr.send(read).await {
+                Ok(Ok(email)) => email.as_ref().and_then(sender_card),
+                Ok(Err(e)) => {
+                    log::warn!("GUI bridge failed to read email: {e}");
+                    return;
+                }
+                Err(e) => {
+                    log::warn!("GUI bridge failed to reach the database: {e}");
+                    return;
+                }
+            };
+            let Some(card) = card else {
+                return;
+            };
+            match database_addr
+                .send(SaveCardMessage {
+                    card,
+                })
+                .await
+            {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => {
+                    log::warn!("GUI bridge failed to save contact: {e}");
+                }
+                Err(e) => {
+                    log::warn!("GUI bridge failed to reach the database: {e}");
+                }
+            }
+        })
+    }
+}
+
+/// Message from the GUI to archive, delete or flag an email
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct ChangeEmailMessage {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email
+    pub(crate) uid: u32,
+    /// What to do to the email
+    pub(crate) change: EmailChange,
+}
+
+impl Handler<ChangeEmailMessage> for GuiBridgeActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ChangeEmailMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let Some(mail_actor) = self.mail_actors.get(&msg.account) else {
+            log::warn!("GUI bridge has no mail actor for {}", msg.account);
+            return;
+        };
+        let uids = vec![msg.uid];
+        // The change shows up in the GUI through the change notifications
+        match msg.change {
+            EmailChange::Archive => {
+                mail_actor.do_send(ArchiveEmailsMessage {
+                    mailbox: msg.mailbox,
+                    uids,
+                });
+            }
+            EmailChange::Delete => mail_actor.do_send(DeleteEmailsMessage {
+                mailbox: msg.mailbox,
+                uids,
+                permanently: false,
+            }),
+            EmailChange::Read(add) => mail_actor.do_send(FlagEmailsMessage {
+                mailbox: msg.mailbox,
+                uids,
+                flags: vec![SEEN.to_owned()],
+                add,
+            }),
+            EmailChange::Flagged(add) => {
+                mail_actor.do_send(FlagEmailsMessage {
+                    mailbox: msg.mailbox,
+                    uids,
+                    flags: vec![FLAGGED.to_owned()],
+                    add,
+                });
+            }
+        }
+    }
+}
+
+/// Message from the GUI to save a search query as a smart folder
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct SaveSmartFolderMessage {
+    /// The name of the smart folder, replacing any other of that name
+    pub(crate) name: String,
+    /// The search query
+    pub(crate) query: String,
+}
+
+impl Handler<SaveSmartFolderMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: SaveSmartFolderMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        Box::pin(
+            self.database_addr
+                .send(SaveSearchMessage {
+                    name: msg.name,
+                    query: msg.query,
+                })
+                .into_actor(self)
+                .map(|result, actor, ctx| match result {
+                    Ok(Ok(())) => actor.load_smart_folders(ctx),
+                    Ok(Err(e)) => {
+                        log::warn!("GUI bridge failed to save search: {e}");
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                    }
+                }),
+        )
+    }
+}
+
+/// Message from the GUI to delete a smart folder
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct DeleteSmartFolderMessage {
+    /// The name of the smart folder
+    pub(crate) name: String,
+}
+
+impl Handler<DeleteSmartFolderMessage> for GuiBridgeActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(
+        &mut self,
+        msg: DeleteSmartFolderMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        Box::pin(
+            self.database_addr
+                .send(DeleteSearchMessage {
+                    name: msg.name,
+                })
+                .into_actor(self)
+                .map(|result, actor, ctx| match result {
+                    Ok(Ok(())) => actor.load_smart_folders(ctx),
+                    Ok(Err(e)) => {
+                        log::warn!("GUI bridge failed to delete search: {e}");
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "GUI bridge failed to reach the database: {e}"
+                        );
+                    }
+                }),
+        )
+    }
+}
+
+impl Handler<SyncNowMessage> for GuiBridgeActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SyncNowMessage,
+        _ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        self.scheduler_addr.do_send(msg);
+    }
+}
+
+impl Handler<EmailsChangedMessage> for GuiBridgeActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: EmailsChangedMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("GUI bridge received {msg:?}");
+        let changed = (msg.account, msg.mailbox);
+        let listed = match &self.folder {
+            Some(Listing::Mailbox {
+                account,
+                mailbox,
+            }) => *account == changed.0 && *mailbox == changed.1,
+            // Any change may add emails to or remove them from a search
+            Some(Listing::Query(_)) => true,
+            None => false,
+        };
+        if listed {
+            self.submit(
+                FOLDER_CHANGED,
+                ChangedFolder {
+                    account: changed.0.clone(),
+                    mailbox: changed.1.clone(),
+                },
+            );
+        }
+        if self.email.as_ref().is_some_and(|(mailbox, _)| *mailbox == changed) {
+            self.load_email(false, ctx);
+        }
+        self.count_smart_folders(ctx);
+    }
+}
@@ -0,0 +1,221 @@
+//! Contains the composer window, where emails are written
+//!
+//! Every composer shows one of the drafts of the application state, found by
+//! its ID, so several emails can be written at once. Addresses typed into the
+//! recipient fields are completed from the contacts: the fields tell the
+//! delegate what is being typed, which asks the bridge for matching contacts.
+
+use druid::{
+    commands, lens,
+    widget::{
+        Button, Controller, CrossAxisAlignment, Flex, Label, LineBreaking,
+        List, Maybe, SizedBox, TextBox,
+    },
+    Data, Env, FileDialogOptions, Lens, UpdateCtx, Widget, WidgetExt,
+};
+
+use super::{
+    delegate::{
+        AddressQuery, ACCEPT_SUGGESTION, ATTACH_FILES, COMPLETE_ADDRESS,
+        REMOVE_ATTACHMENT, SEND_DRAFT,
+    },
+    panes::PADDING,
+    state::{AddressField, AppState, AttachedFile, ComposerState, Suggestion},
+};
+
+/// Width of the labels in front of the fields
+const LABEL_WIDTH: f64 = 64.0;
+
+/// The address being typed into a recipient field, which is whatever follows
+/// the last comma
+pub(super) fn typed(field: &str) -> &str {
+    field.rsplit(',').next().unwrap_or(field).trim()
+}
+
+/// Asks for contacts matching the address typed into a recipient field
+/// whenever the field changes
+struct AddressCompletion {
+    /// The draft the field belongs to
+    draft: u64,
+    /// Which recipient field it is
+    field: AddressField,
+}
+
+impl<W: Widget<String>> Controller<String, W> for AddressCompletion {
+    fn update(
+        &mut self,
+        child: &mut W,
+        ctx: &mut UpdateCtx,
+        old_data: &String,
+        data: &String,
+        env: &Env,
+    ) {
+        if old_data != data {
+            ctx.submit_command(COMPLETE_ADDRESS.with(AddressQuery {
+                draft: self.draft,
+                field: self.field,
+                prefix: typed(data).to_owned(),
+            }));
+        }
+        child.update(ctx, old_data, data, env);
+    }
+}
+
+/// Builds a row with a label in front of a field
+fn field_row(
+    label: &'static str,
+    field: impl Widget<ComposerState> + 'static,
+) -> impl Widget<ComposerState> {
+    Flex::row()
+        .with_child(Label::new(label).fix_width(LABEL_WIDTH))
+        .with_flex_child(field, 1.0)
+        .padding((0.0, PADDING / 2.0))
+}
+
+/// Builds a recipient field, completing the addresses typed into it
+fn address_row(
+    label: &'static str,
+    draft: u64,
+    field: AddressField,
+    text: impl Lens<ComposerState, String> + 'static,
+) -> impl Widget<ComposerState> {
+    field_row(
+        label,
+        TextBox::new()
+            .with_placeholder("name@example.com, …")
+            .controller(AddressCompletion {
+                draft,
+                field,
+            })
+            .expand_width()
+            .lens(text),
+    )
+}
+
+/// Builds the buttons of the contacts matching the address being typed
+fn suggestions() -> impl Widget<ComposerState> {
+    List::new(|| {
+        Button::dynamic(|suggestion: &Suggestion, _env| {
+            suggestion.address.clone()
+        })
+        .on_click(|ctx, suggestion: &mut Suggestion, _env| {
+            ctx.submit_command(ACCEPT_SUGGESTION.with(suggestion.clone()));
+        })
+    })
+    .horizontal()
+    .with_spacing(PADDING)
+    .lens(ComposerState::suggestions)
+}
+
+/// Builds the list of attached files, each with a button to remove it
+fn attachments() -> impl Widget<ComposerState> {
+    List::new(|| {
+        Flex::row()
+            .with_child(Label::dynamic(|attached: &AttachedFile, _env| {
+                format!("📎 {}", attached.name)
+            }))
+            .with_spacer(PADDING)
+            .with_child(Button::new("Remove").on_click(
+                |ctx, attached: &mut AttachedFile, _env| {
+                    ctx.submit_command(
+                        REMOVE_ATTACHMENT.with(attached.clone()),
+                    );
+                },
+            ))
+    })
+    .with_spacing(PADDING / 2.0)
+    .lens(ComposerState::attachments)
+}
+
+/// Builds the form of a draft
+fn form(draft: u64) -> impl Widget<ComposerState> {
+    let send = Button::dynamic(|data: &ComposerState, _env| {
+        if data.sending {
+            "Sending…".to_owned()
+        } else {
+            "Send".to_owned()
+        }
+    })
+    .on_click(|ctx, data: &mut ComposerState, _env| {
+        if !data.sending {
+            ctx.submit_command(SEND_DRAFT.with(data.id));
+        }
+    });
+    // The open panel answers the window it was opened from, which tells the
+    // delegate which draft to attach the files to
+    let attach = Button::new("Attach files…").on_click(|ctx, _data, _env| {
+        let options = FileDialogOptions::new()
+            .multi_selection()
+            .accept_multiple_command(ATTACH_FILES);
+        ctx.submit_command(commands::SHOW_OPEN_PANEL.with(options));
+    });
+    let status =
+        Label::dynamic(|data: &ComposerState, _env| data.status.clone())
+            .with_line_break_mode(LineBreaking::WordWrap);
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(Label::dynamic(|data: &ComposerState, _env| {
+            format!("From: {}", data.account)
+        }))
+        .with_spacer(PADDING)
+        .with_child(address_row(
+            "To",
+            draft,
+            AddressField::To,
+            ComposerState::to,
+        ))
+        .with_child(address_row(
+            "Cc",
+            draft,
+            AddressField::Cc,
+            ComposerState::cc,
+        ))
+        .with_child(address_row(
+            "Bcc",
+            draft,
+            AddressField::Bcc,
+            ComposerState::bcc,
+        ))
+        .with_child(suggestions())
+        .with_child(field_row(
+            "Subject",
+            TextBox::new().expand_width().lens(ComposerState::subject),
+        ))
+        .with_spacer(PADDING)
+        .with_flex_child(
+            TextBox::multiline()
+                .with_line_wrapping(true)
+                .expand()
+                .lens(ComposerState::body),
+            1.0,
+        )
+        .with_spacer(PADDING)
+        .with_child(attachments())
+        .with_child(
+            Flex::row()
+                .with_child(send)
+                .with_spacer(PADDING)
+                .with_child(attach)
+                .with_spacer(PADDING)
+                .with_flex_child(status, 1.0),
+        )
+        .padding(PADDING * 2.0)
+}
+
+/// Builds the content of the composer window of a draft
+///
+/// The window is left empty once the draft is gone, while it closes.
+pub(crate) fn build(draft: u64) -> impl Widget<AppState> {
+    Maybe::new(move || form(draft), SizedBox::empty).lens(lens::Map::new(
+        move |data: &AppState| data.drafts.get(&draft).cloned(),
+        move |data: &mut AppState, composer: Option<ComposerState>| {
+            let Some(composer) = composer else {
+                return;
+            };
+            // Putting back an unchanged draft would still count as a change
+            if !data.drafts.get(&draft).is_some_and(|old| old.same(&composer)) {
+                data.drafts.insert(draft, composer);
+            }
+        },
+    ))
+}
@@ -0,0 +1,986 @@
+//! Contains the commands of the GUI and the delegate that applies them to the
+//! application state
+//!
+//! Rows of lists only see their own item, so selecting one is done by
+//! submitting a command the delegate handles with the whole state at hand.
+//! What the user does is passed on to the `GuiBridgeActor`, which answers with
+//! commands of its own once the other actors have responded.
+
+use std::{
+    collections::{HashMap, HashSet},
+    process,
+};
+
+use actix::Addr;
+use druid::{
+    commands, im::Vector, AppDelegate, Command, DelegateCtx, Env,
+    FileDialogOptions, FileInfo, FileSpec, Handled, Selector, Target,
+    WindowDesc, WindowId,
+};
+
+use super::{
+    bridge::{
+        AddSenderMessage, ChangeEmailMessage, CompleteAddressesMessage,
+        DeleteSmartFolderMessage, ExportContactsMessage, GuiBridgeActor,
+        ImportContactsMessage, LoadPageMessage, LoadRemoteContentMessage,
+        OpenAttachmentMessage, PrepareDraftMessage, SaveAttachmentMessage,
+        SaveSmartFolderMessage, SendDraftMessage, ShowEmailMessage,
+        ShowFolderMessage,
+    },
+    composer, rich_text,
+    shortcuts::Action,
+    state::{
+        AddressField, AppState, AttachedFile, AttachmentEntry, ComposerState,
+        ConversationEntry, FolderEntry, MessageSummary, Page, ReaderState,
+        Suggestion,
+    },
+};
+use crate::{
+    database::{
+        listing::{Listing, Sort, SortField},
+        remote_content::Allowance,
+        vcard::Version,
+    },
+    mail::{
+        compose::Draft,
+        styled::{is_safe_link, Span},
+        SyncNowMessage,
+    },
+};
+
+/// The files the address book is imported from and exported to
+pub(crate) const VCARD_FILES: FileSpec = FileSpec::new("vCard", &["vcf"]);
+
+/// Addresses shorter than this aren't completed, since too many contacts
+/// would match
+const MIN_COMPLETION_LENGTH: usize = 2;
+
+/// Selects a folder, listing its emails
+pub(crate) const SELECT_FOLDER: Selector<FolderEntry> =
+    Selector::new("weasel.select-folder");
+
+/// Selects an email, showing it in the reader
+pub(crate) const SELECT_MESSAGE: Selector<MessageSummary> =
+    Selector::new("weasel.select-message");
+
+/// Lists the emails of every account matching a search query
+pub(crate) const SEARCH: Selector<String> = Selector::new("weasel.search");
+
+/// Saves the listed search as a smart folder under a name, the query itself
+/// if the name is empty
+pub(crate) const SAVE_SEARCH: Selector<String> =
+    Selector::new("weasel.save-search");
+
+/// Deletes a smart folder, by name
+pub(crate) const DELETE_SEARCH: Selector<String> =
+    Selector::new("weasel.delete-search");
+
+/// Sent by the bridge with the rows of the smart folders, whenever they or
+/// their unread counts change
+pub(crate) const SMART_FOLDERS_LOADED: Selector<Vector<FolderEntry>> =
+    Selector::new("weasel.smart-folders-loaded");
+
+/// Sent by the bridge when the health of the database changes, with what
+/// went wrong while it is degraded
+pub(crate) const HEALTH_CHANGED: Selector<Option<String>> =
+    Selector::new("weasel.health-changed");
+
+/// Runs an action of the keyboard shortcuts
+///
+/// The root of the main window runs it rather than the delegate, since some
+/// actions move the keyboard focus.
+pub(crate) const RUN_ACTION: Selector<Action> =
+    Selector::new("weasel.run-action");
+
+/// Archives, deletes or flags the email shown in the reader
+pub(crate) const CHANGE_EMAIL: Selector<EmailChange> =
+    Selector::new("weasel.change-email");
+
+/// Synchronizes every folder right away
+pub(crate) const SYNC_NOW: Selector = Selector::new("weasel.sync-now");
+
+/// Sorts the message list by a field, or reverses it if it already is
+pub(crate) const SET_SORT: Selector<SortField> =
+    Selector::new("weasel.set-sort");
+
+/// Sent by the message list for a page of emails it needs
+pub(crate) const REQUEST_PAGE: Selector<usize> =
+    Selector::new("weasel.request-page");
+
+/// Sent by the bridge with a page of the emails of a folder
+pub(crate) const PAGE_LOADED: Selector<LoadedPage> =
+    Selector::new("weasel.page-loaded");
+
+/// Sent by the bridge when emails of the listed folder changed
+pub(crate) const FOLDER_CHANGED: Selector<ChangedFolder> =
+    Selector::new("weasel.folder-changed");
+
+/// Sent by the bridge with an email to show in the reader
+pub(crate) const EMAIL_LOADED: Selector<LoadedEmail> =
+    Selector::new("weasel.email-loaded");
+
+/// Allows remote content in the email shown in the reader, which shows links
+/// to its remote images
+pub(crate) const ALLOW_REMOTE_CONTENT: Selector =
+    Selector::new("weasel.allow-remote-content");
+
+/// Allows remote content in every email from the sender of the email shown in
+/// the reader, which shows links to the remote images in them
+pub(crate) const TRUST_SENDER: Selector = Selector::new("weasel.trust-sender");
+
+/// Opens a link of an email in the browser
+pub(crate) const OPEN_LINK: Selector<String> =
+    Selector::new("weasel.open-link");
+
+/// Asks where to save an attachment of the email shown in the reader, then
+/// saves it there
+pub(crate) const SAVE_ATTACHMENT: Selector<AttachmentEntry> =
+    Selector::new("weasel.save-attachment");
+
+/// Sent by the save panel with where to save the attachment
+const SAVE_ATTACHMENT_AS: Selector<FileInfo> =
+    Selector::new("weasel.save-attachment-as");
+
+/// Opens an attachment of the email shown in the reader with the application
+/// the system associates with its type
+pub(crate) const OPEN_ATTACHMENT: Selector<AttachmentEntry> =
+    Selector::new("weasel.open-attachment");
+
+/// Sent by the open panel of the folder tree with a `.vcf` file whose
+/// contacts to add to the address book
+pub(crate) const IMPORT_CONTACTS: Selector<FileInfo> =
+    Selector::new("weasel.import-contacts");
+
+/// Asks where to save the address book, then writes it there as vCards of
+/// the given version
+pub(crate) const EXPORT_CONTACTS: Selector<Version> =
+    Selector::new("weasel.export-contacts");
+
+/// Sent by the save panel with where to write the address book
+const EXPORT_CONTACTS_TO: Selector<FileInfo> =
+    Selector::new("weasel.export-contacts-to");
+
+/// Adds the sender of the email shown in the reader to the address book
+pub(crate) const ADD_SENDER: Selector = Selector::new("weasel.add-sender");
+
+/// Sent by the open panel of a composer with the files to attach
+pub(crate) const ATTACH_FILES: Selector<Vec<FileInfo>> =
+    Selector::new("weasel.attach-files");
+
+/// Removes a file from the attachments of a draft
+pub(crate) const REMOVE_ATTACHMENT: Selector<AttachedFile> =
+    Selector::new("weasel.remove-attachment");
+
+/// Opens a composer, for a new email or one answering the email shown in the
+/// reader
+pub(crate) const COMPOSE: Selector<ComposeKind> =
+    Selector::new("weasel.compose");
+
+/// Sent by the bridge with a reply or forward to open a composer for
+pub(crate) const DRAFT_READY: Selector<PreparedDraft> =
+    Selector::new("weasel.draft-ready");
+
+/// Sent by a recipient field with the address being typed into it
+pub(crate) const COMPLETE_ADDRESS: Selector<AddressQuery> =
+    Selector::new("weasel.complete-address");
+
+/// Sent by the bridge with the contacts matching an address being typed
+pub(crate) const ADDRESSES_COMPLETED: Selector<CompletedAddresses> =
+    Selector::new("weasel.addresses-completed");
+
+/// Replaces the address being typed with a suggested contact
+pub(crate) const ACCEPT_SUGGESTION: Selector<Suggestion> =
+    Selector::new("weasel.accept-suggestion");
+
+/// Sends the draft with the given ID
+pub(crate) const SEND_DRAFT: Selector<u64> = Selector::new("weasel.send-draft");
+
+/// Sent by the bridge once a draft was sent or failed to be
+pub(crate) const DRAFT_SENT: Selector<SentDraft> =
+    Selector::new("weasel.draft-sent");
+
+/// What a new composer starts with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ComposeKind {
+    /// An empty email
+    New,
+    /// A reply to the senders of the email shown in the reader
+    Reply,
+    /// A reply to everyone the email shown in the reader was sent to
+    ReplyAll,
+    /// The email shown in the reader, forwarded
+    Forward,
+}
+
+/// What is done to an email from the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EmailChange {
+    /// Move it to the account's archive mailbox
+    Archive,
+    /// Move it to the trash
+    Delete,
+    /// Mark it as read, or as unread if `false`
+    Read(bool),
+    /// Flag it, or remove its flag if `false`
+    Flagged(bool),
+}
+
+/// A reply or forward prepared by the bridge
+pub(crate) struct PreparedDraft {
+    /// The account to send the email from
+    pub(crate) account: String,
+    /// What the composer starts with
+    pub(crate) draft: Draft,
+    /// The mailbox and UID of the email replied to, if it is a reply
+    pub(crate) replied: Option<(String, u32)>,
+}
+
+/// An address being typed into a recipient field
+pub(crate) struct AddressQuery {
+    /// The draft the field belongs to
+    pub(crate) draft: u64,
+    /// Which recipient field it is
+    pub(crate) field: AddressField,
+    /// What has been typed of the address
+    pub(crate) prefix: String,
+}
+
+/// The contacts matching an address being typed
+pub(crate) struct CompletedAddresses {
+    /// What was completed
+    pub(crate) query: AddressQuery,
+    /// The matching contacts, formatted for the recipient field
+    pub(crate) addresses: Vec<String>,
+}
+
+/// The outcome of sending a draft
+pub(crate) struct SentDraft {
+    /// ID of the draft
+    pub(crate) draft: u64,
+    /// What went wrong, if the draft wasn't sent
+    pub(crate) error: Option<String>,
+}
+
+/// A page of the listed emails, as loaded by the bridge
+pub(crate) struct LoadedPage {
+    /// Which emails were listed
+    pub(crate) listing: Listing,
+    /// The version of the message list the page was requested for
+    pub(crate) version: u64,
+    /// Index of the page
+    pub(crate) page: usize,
+    /// How many emails are listed
+    pub(crate) total: usize,
+    /// Rows of the page
+    pub(crate) rows: Vector<MessageSummary>,
+}
+
+/// A folder whose emails changed
+pub(crate) struct ChangedFolder {
+    /// The account the mailbox belongs to
+    pub(crate) account: String,
+    /// The mailbox that changed
+    pub(crate) mailbox: String,
+}
+
+/// An email read by the bridge
+pub(crate) struct LoadedEmail {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email
+    pub(crate) uid: u32,
+    /// Subject
+    pub(crate) subject: String,
+    /// The senders
+    pub(crate) from: String,
+    /// Bare address of the first sender, if any
+    pub(crate) sender: Option<String>,
+    /// The primary and carbon copy recipients
+    pub(crate) to: String,
+    /// Date
+    pub(crate) date: String,
+    /// The plain text of the email, or what to show while it downloads
+    pub(crate) plain: Vec<Span>,
+    /// The HTML of the email made safe to show, if it has any
+    pub(crate) html: Option<Vec<Span>>,
+    /// Whether the user allowed remote content in the email
+    pub(crate) remote_content: bool,
+    /// How many remote images the HTML left out
+    pub(crate) blocked: usize,
+    /// How many tracking pixels the HTML left out
+    pub(crate) trackers: usize,
+    /// The files attached to the email
+    pub(crate) attachments: Vector<AttachmentEntry>,
+    /// The emails of the conversation the email is in, in reply order
+    pub(crate) conversation: Vector<ConversationEntry>,
+}
+
+/// Applies commands to the application state
+pub(crate) struct Delegate {
+    /// Where to send what the user does
+    bridge: Addr<GuiBridgeActor>,
+    /// Pages of the message list already requested, by version and index.
+    /// The list asks for missing pages on every change until they arrive.
+    requested: HashSet<(u64, usize)>,
+    /// The drafts shown by the open composer windows
+    composers: HashMap<WindowId, u64>,
+    /// The ID of the next draft
+    next_draft: u64,
+    /// The attachment the save panel is open for
+    saving: Option<AttachmentEntry>,
+    /// The vCard version to export the address book as, while the save
+    /// panel is open for it
+    exporting: Option<Version>,
+}
+
+impl Delegate {
+    /// Creates a delegate passing what the user does on to the bridge
+    pub(crate) fn new(bridge: Addr<GuiBridgeActor>) -> Self {
+        Self {
+            bridge,
+            requested: HashSet::new(),
+            composers: HashMap::new(),
+            next_draft: 0,
+            saving: None,
+            exporting: None,
+        }
+    }
+
+    /// Asks the bridge for a page of the message list, unless it already was
+    fn request_page(&mut self, page: usize, data: &AppState) {
+        let Some(listing) = &data.listing else {
+            return;
+        };
+        let list = &data.message_list;
+        self.requested.retain(|(version, _page)| *version == list.version);
+        if !self.requested.insert((list.version, page)) {
+            return;
+        }
+        self.bridge.do_send(LoadPageMessage {
+            listing: listing.clone(),
+            sort: list.sort,
+            version: list.version,
+            page,
+        });
+    }
+
+    /// Shows an email in the reader, if it is still the selected one
+    fn show_email(email: &LoadedEmail, data: &mut AppState) {
+        let selected =
+            data.message_list.selected.as_ref().is_some_and(|selected| {
+                selected.account == email.account
+                    && selected.mailbox == email.mailbox
+                    && selected.uid == email.uid
+            });
+        if !selected {
+            return;
+        }
+        // Reloads of the same email keep the chosen view
+        let show_plain =
+            data.reader.as_ref().is_some_and(|reader| reader.show_plain);
+        data.reader = Some(ReaderState {
+            subject: email.subject.clone(),
+            from: email.from.clone(),
+            sender: email.sender.clone(),
+            to: email.to.clone(),
+            date: email.date.clone(),
+            plain: rich_text::build(&email.plain),
+            html: email.html.as_deref().map(rich_text::build),
+            show_plain,
+            remote_content: email.remote_content,
+            blocked: email.blocked,
+            trackers: email.trackers,
+            attachments: email.attachments.clone(),
+            conversation: email.conversation.clone(),
+        });
+    }
+
+    /// Allows remote content in the selected email, or in everything from its
+    /// sender
+    fn allow_remote_content(&self, sender: bool, data: &AppState) {
+        let Some(message) = &data.message_list.selected else {
+            return;
+        };
+        let allowance = if sender {
+            let Some(address) =
+                data.reader.as_ref().and_then(|reader| reader.sender.clone())
+            else {
+                return;
+            };
+            Allowance::Sender(address)
+        } else {
+            Allowance::Message {
+                mailbox: message.mailbox.clone(),
+                uid: message.uid,
+            }
+        };
+        self.bridge.do_send(LoadRemoteContentMessage {
+            account: message.account.clone(),
+            allowance,
+        });
+    }
+
+    /// Opens a composer window for a new email, or asks the bridge to prepare
+    /// a reply to or forward of the selected email
+    fn compose(
+        &mut self,
+        kind: ComposeKind,
+        ctx: &mut DelegateCtx,
+        data: &mut AppState,
+    ) {
+        if kind == ComposeKind::New {
+            // From the listed account, or the shown email's in views across
+            // accounts
+            let account = match &data.listing {
+                Some(Listing::Mailbox {
+                    account,
+                    ..
+                }) => Some(account.clone()),
+                _ => data
+                    .message_list
+                    .selected
+                    .as_ref()
+                    .map(|message| message.account.clone()),
+            }
+            .or_else(|| data.accounts.front().cloned());
+            let Some(account) = account else {
+                log::warn!("GUI has no account to write an email from");
+                return;
+            };
+            self.open_composer(account, Draft::default(), None, ctx, data);
+        } else if let Some(message) = &data.message_list.selected {
+            self.bridge.do_send(PrepareDraftMessage {
+                account: message.account.clone(),
+                mailbox: message.mailbox.clone(),
+                uid: message.uid,
+                kind,
+            });
+        }
+    }
+
+    /// Opens a composer window for a draft
+    fn open_composer(
+        &mut self,
+        account: String,
+        draft: Draft,
+        replied: Option<(String, u32)>,
+        ctx: &mut DelegateCtx,
+        data: &mut AppState,
+    ) {
+        let id = self.next_draft;
+        self.next_draft += 1;
+        let title = if draft.subject.is_empty() {
+            "New email".to_owned()
+        } else {
+            draft.subject.clone()
+        };
+        let window = WindowDesc::new(composer::build(id))
+            .title(title)
+            .window_size((720.0, 640.0));
+        self.composers.insert(window.id, id);
+        data.drafts.insert(id, ComposerState::new(id, account, draft, replied));
+        ctx.new_window(window);
+    }
+
+    /// Asks the bridge for contacts matching the address being typed, or
+    /// clears the suggestions if too little has been typed
+    fn complete_address(&self, query: &AddressQuery, data: &mut AppState) {
+        let Some(composer) = data.drafts.get_mut(&query.draft) else {
+            return;
+        };
+        composer.suggestions.clear();
+        if query.prefix.chars().count() < MIN_COMPLETION_LENGTH {
+            return;
+        }
+        self.bridge.do_send(CompleteAddressesMessage {
+            draft: query.draft,
+            field: query.field,
+            prefix: query.prefix.clone(),
+        });
+    }
+
+    /// Suggests the contacts matching an address, if it is still the one
+    /// being typed
+    fn show_suggestions(completed: &CompletedAddresses, data: &mut AppState) {
+        let query = &completed.query;
+        let Some(composer) = data.drafts.get_mut(&query.draft) else {
+            return;
+        };
+        if composer::typed(composer.field(query.field)) != query.prefix {
+            return;
+        }
+        composer.suggestions = completed
+            .addresses
+            .iter()
+            .map(|address| Suggestion {
+                draft: query.draft,
+                field: query.field,
+                address: address.clone(),
+            })
+            .collect();
+    }
+
+    /// Replaces the address being typed with a suggested contact
+    fn accept_suggestion(suggestion: &Suggestion, data: &mut AppState) {
+        let Some(composer) = data.drafts.get_mut(&suggestion.draft) else {
+            return;
+        };
+        let field = composer.field(suggestion.field);
+        let kept = field.rfind(',').map_or(0, |comma| comma + 1);
+        field.truncate(kept);
+        if kept > 0 {
+            field.push(' ');
+        }
+        field.push_str(&suggestion.address);
+        field.push_str(", ");
+        composer.suggestions.clear();
+    }
+
+    /// Hands a draft to the bridge to send
+    fn send_draft(&self, id: u64, data: &mut AppState) {
+        let Some(composer) = data.drafts.get_mut(&id) else {
+            return;
+        };
+        composer.sending = true;
+        composer.status = String::new();
+        self.bridge.do_send(SendDraftMessage {
+            draft: id,
+            account: composer.account.clone(),
+            email: composer.draft(),
+            replied: composer.replied.clone(),
+        });
+    }
+
+    /// Closes the composer of a sent draft, or shows why it wasn't sent
+    fn draft_sent(
+        &self,
+        sent: &SentDraft,
+        ctx: &mut DelegateCtx,
+        data: &mut AppState,
+    ) {
+        let Some(composer) = data.drafts.get_mut(&sent.draft) else {
+            return;
+        };
+        composer.sending = false;
+        if let Some(error) = &sent.error {
+            composer.status.clone_from(error);
+            return;
+        }
+        let window = self.composers.iter().find_map(|(window, draft)| {
+            (*draft == sent.draft).then_some(*window)
+        });
+        if let Some(window) = window {
+            ctx.submit_command(commands::CLOSE_WINDOW.to(window));
+        }
+    }
+
+    /// Asks where to save an attachment, in the window it was chosen in
+    fn save_attachment(
+        &mut self,
+        attachment: &AttachmentEntry,
+        target: Target,
+        ctx: &mut DelegateCtx,
+    ) {
+        let options = FileDialogOptions::new()
+            .default_name(attachment.name.clone())
+            .accept_command(SAVE_ATTACHMENT_AS);
+        self.saving = Some(attachment.clone());
+        ctx.submit_command(commands::SHOW_SAVE_PANEL.with(options).to(target));
+    }
+
+    /// Asks where to save the address book
+    fn export_contacts(
+        &mut self,
+        version: Version,
+        target: Target,
+        ctx: &mut DelegateCtx,
+    ) {
+        let options = FileDialogOptions::new()
+            .allowed_types(vec![VCARD_FILES])
+            .default_name("contacts.vcf")
+            .accept_command(EXPORT_CONTACTS_TO);
+        self.exporting = Some(version);
+        ctx.submit_command(commands::SHOW_SAVE_PANEL.with(options).to(target));
+    }
+
+    /// Adds files chosen in the open panel of a composer window to its draft
+    fn attach_files(
+        &self,
+        files: &[FileInfo],
+        target: Target,
+        data: &mut AppState,
+    ) {
+        let Target::Window(window) = target else {
+            return;
+        };
+        let Some(id) = self.composers.get(&window) else {
+            return;
+        };
+        let Some(composer) = data.drafts.get_mut(id) else {
+            return;
+        };
+        for file in files {
+            let attached = AttachedFile::new(*id, file.path.clone());
+            if !composer
+                .attachments
+                .iter()
+                .any(|existing| existing.path == attached.path)
+            {
+                composer.attachments.push_back(attached);
+            }
+        }
+    }
+
+    /// Lists other emails, from the start
+    ///
+    /// Searches are listed best match first, and folders newest first unless
+    /// another order was picked.
+    fn show_listing(&mut self, listing: Listing, data: &mut AppState) {
+        self.bridge.do_send(ShowFolderMessage {
+            listing: listing.clone(),
+        });
+        if matches!(listing, Listing::Query(_)) {
+            data.message_list.sort = Sort {
+                field: SortField::Relevance,
+                descending: true,
+            };
+        } else if data.message_list.sort.field == SortField::Relevance {
+            data.message_list.sort = Sort::default();
+        }
+        data.listing = Some(listing);
+        data.message_list.reset();
+        data.reader = None;
+        self.requested.clear();
+    }
+
+    /// Lists the emails of a folder, unless it is only the parent of others
+    fn select_folder(&mut self, folder: &FolderEntry, data: &mut AppState) {
+        log::trace!("GUI selected {folder:?}");
+        let Some(listing) = &folder.listing else {
+            return;
+        };
+        self.show_listing(listing.clone(), data);
+        data.search.clear();
+    }
+
+    /// Lists the emails matching a search query, unless it is empty
+    fn search(&mut self, query: &str, data: &mut AppState) {
+        log::trace!("GUI searching for {query:?}");
+        if query.trim().is_empty() {
+            return;
+        }
+        self.show_listing(Listing::Query(query.to_owned()), data);
+    }
+
+    /// Saves the listed search as a smart folder, unless no search is listed
+    fn save_search(&mut self, name: &str, data: &mut AppState) {
+        data.saving_search = None;
+        let Some(Listing::Query(query)) = &data.listing else {
+            log::warn!("GUI has no search to save");
+            return;
+        };
+        let name = if name.trim().is_empty() {
+            query.trim()
+        } else {
+            name.trim()
+        };
+        log::trace!("GUI saving {query:?} as {name:?}");
+        self.bridge.do_send(SaveSmartFolderMessage {
+            name: name.to_owned(),
+            query: query.clone(),
+        });
+    }
+
+    /// Applies a command about the folder tree, smart folders and searches
+    fn folder_command(
+        &mut self,
+        cmd: &Command,
+        data: &mut AppState,
+    ) -> Handled {
+        if let Some(folder) = cmd.get(SELECT_FOLDER) {
+            self.select_folder(folder, data);
+        } else if let Some(query) = cmd.get(SEARCH) {
+            self.search(query, data);
+        } else if let Some(name) = cmd.get(SAVE_SEARCH) {
+            self.save_search(name, data);
+        } else if let Some(name) = cmd.get(DELETE_SEARCH) {
+            log::trace!("GUI deleting smart folder {name:?}");
+            self.bridge.do_send(DeleteSmartFolderMessage {
+                name: name.clone(),
+            });
+        } else if let Some(folders) = cmd.get(SMART_FOLDERS_LOADED) {
+            data.smart_folders = folders.clone();
+        } else if let Some(problem) = cmd.get(HEALTH_CHANGED) {
+            data.database_problem.clone_from(problem);
+        } else {
+            return Handled::No;
+        }
+        Handled::Yes
+    }
+
+    /// Whether changes to a mailbox may change the listed emails, which is
+    /// the case for every mailbox while a search is listed
+    fn is_listed(data: &AppState, account: &str, mailbox: &str) -> bool {
+        match &data.listing {
+            Some(Listing::Mailbox {
+                account: listed_account,
+                mailbox: listed_mailbox,
+            }) => listed_account == account && listed_mailbox == mailbox,
+            Some(Listing::Query(_)) => true,
+            None => false,
+        }
+    }
+
+    /// Has the bridge change the email shown in the reader
+    ///
+    /// Archived and deleted emails leave the list, so the email below them,
+    /// or above them at the end of the list, is shown in their place.
+    fn change_email(
+        &self,
+        change: EmailChange,
+        ctx: &mut DelegateCtx,
+        data: &mut AppState,
+    ) {
+        let Some(message) = data.message_list.selected.clone() else {
+            return;
+        };
+        // Toggling again before the list is reloaded toggles back
+        if let Some(selected) = &mut data.message_list.selected {
+            match change {
+                EmailChange::Read(read) => selected.unread = !read,
+                EmailChange::Flagged(flagged) => selected.flagged = flagged,
+                EmailChange::Archive | EmailChange::Delete => {}
+            }
+        }
+        if matches!(change, EmailChange::Archive | EmailChange::Delete) {
+            let list = &data.message_list;
+            if let Some(next) =
+                list.neighbour(true).or_else(|| list.neighbour(false))
+            {
+                ctx.submit_command(SELECT_MESSAGE.with(next.clone()));
+            } else {
+                data.message_list.selected = None;
+                data.reader = None;
+            }
+        }
+        self.bridge.do_send(ChangeEmailMessage {
+            account: message.account,
+            mailbox: message.mailbox,
+            uid: message.uid,
+            change,
+        });
+    }
+
+    /// Applies the commands of composers and attachments
+    fn composer_command(
+        &mut self,
+        ctx: &mut DelegateCtx,
+        target: Target,
+        cmd: &Command,
+        data: &mut AppState,
+    ) -> Handled {
+        if let Some(kind) = cmd.get(COMPOSE) {
+            self.compose(*kind, ctx, data);
+        } else if let Some(prepared) = cmd.get(DRAFT_READY) {
+            self.open_composer(
+                prepared.account.clone(),
+                prepared.draft.clone(),
+                prepared.replied.clone(),
+                ctx,
+                data,
+            );
+        } else if let Some(query) = cmd.get(COMPLETE_ADDRESS) {
+            self.complete_address(query, data);
+        } else if let Some(completed) = cmd.get(ADDRESSES_COMPLETED) {
+            Self::show_suggestions(completed, data);
+        } else if let Some(suggestion) = cmd.get(ACCEPT_SUGGESTION) {
+            Self::accept_suggestion(suggestion, data);
+        } else if let Some(id) = cmd.get(SEND_DRAFT) {
+            self.send_draft(*id, data);
+        } else if let Some(sent) = cmd.get(DRAFT_SENT) {
+            self.draft_sent(sent, ctx, data);
+        } else if let Some(files) = cmd.get(ATTACH_FILES) {
+            self.attach_files(files, target, data);
+        } else if let Some(attached) = cmd.get(REMOVE_ATTACHMENT) {
+            if let Some(composer) = data.drafts.get_mut(&attached.draft) {
+                composer
+                    .attachments
+                    .retain(|existing| existing.path != attached.path);
+            }
+        } else if let Some(attachment) = cmd.get(SAVE_ATTACHMENT) {
+            self.save_attachment(attachment, target, ctx);
+        } else if let Some(file) = cmd.get(SAVE_ATTACHMENT_AS) {
+            if let Some(attachment) = self.saving.take() {
+                self.bridge.do_send(SaveAttachmentMessage {
+                    blob: attachment.blob,
+                    path: file.path.clone(),
+                });
+            }
+        } else if let Some(attachment) = cmd.get(OPEN_ATTACHMENT) {
+            self.bridge.do_send(OpenAttachmentMessage {
+                blob: attachment.blob.clone(),
+                name: attachment.name.clone(),
+            });
+        } else if let Some(file) = cmd.get(IMPORT_CONTACTS) {
+            self.bridge.do_send(ImportContactsMessage {
+                path: file.path.clone(),
+            });
+        } else if let Some(version) = cmd.get(EXPORT_CONTACTS) {
+            self.export_contacts(*version, target, ctx);
+        } else if let Some(file) = cmd.get(EXPORT_CONTACTS_TO) {
+            if let Some(version) = self.exporting.take() {
+                self.bridge.do_send(ExportContactsMessage {
+                    path: file.path.clone(),
+                    version,
+                });
+            }
+        } else if cmd.is(ADD_SENDER) {
+            self.bridge.do_send(AddSenderMessage);
+        } else {
+            return Handled::No;
+        }
+        Handled::Yes
+    }
+}
+
+/// Opens a link in the default browser or mail client
+///
+/// Only `http`, `https` and `mailto` links are opened, whatever the email
+/// links to.
+fn open_link(url: &str) {
+    if !is_safe_link(url) {
+        log::warn!("GUI refused to open {url}");
+        return;
+    }
+    log::trace!("GUI opening {url}");
+    if let Err(e) = process::Command::new("xdg-open").arg(url).spawn() {
+        log::warn!("GUI failed to open {url}: {e}");
+    }
+}
+
+impl AppDelegate<AppState> for Delegate {
+    fn command(
+        &mut self,
+        ctx: &mut DelegateCtx,
+        target: Target,
+        cmd: &Command,
+        data: &mut AppState,
+        _env: &Env,
+    ) -> Handled {
+        if self.folder_command(cmd, data).is_handled() {
+            Handled::Yes
+        } else if let Some(change) = cmd.get(CHANGE_EMAIL) {
+            self.change_email(*change, ctx, data);
+            Handled::Yes
+        } else if let Some(message) = cmd.get(SELECT_MESSAGE) {
+            log::trace!("GUI selected {message:?}");
+            self.bridge.do_send(ShowEmailMessage {
+                account: message.account.clone(),
+                mailbox: message.mailbox.clone(),
+                uid: message.uid,
+            });
+            data.message_list.selected = Some(message.clone());
+            data.reader = Some(ReaderState {
+                subject: message.subject.clone(),
+                from: message.from.clone(),
+                date: message.date.clone(),
+                ..ReaderState::default()
+            });
+            Handled::Yes
+        } else if cmd.is(SYNC_NOW) {
+            self.bridge.do_send(SyncNowMessage {
+                account: None,
+                mailbox: None,
+            });
+            Handled::Yes
+        } else if let Some(field) = cmd.get(SET_SORT) {
+            let list = &mut data.message_list;
+            let sort = if list.sort.field == *field {
+                Sort {
+                    descending: !list.sort.descending,
+                    ..list.sort
+                }
+            } else {
+                Sort {
+                    field: *field,
+                    // Newest, largest and best matches first, the rest
+                    // alphabetically
+                    descending: matches!(
+                        field,
+                        SortField::Date
+                            | SortField::Size
+                            | SortField::Relevance
+                    ),
+                }
+            };
+            let selected = list.selected.take();
+            list.reset();
+            list.sort = sort;
+            list.selected = selected;
+            Handled::Yes
+        } else if let Some(page) = cmd.get(REQUEST_PAGE) {
+            self.request_page(*page, data);
+            Handled::Yes
+        } else if let Some(loaded) = cmd.get(PAGE_LOADED) {
+            if data.listing.as_ref() == Some(&loaded.listing)
+                && loaded.version == data.message_list.version
+            {
+                let list = &mut data.message_list;
+                list.total = loaded.total;
+                // Keep the flags of the selected email up to date, since
+                // they are toggled from it
+                if let Some(selected) = &mut list.selected {
+                    if let Some(row) =
+                        loaded.rows.iter().find(|row| row.is(selected))
+                    {
+                        selected.clone_from(row);
+                    }
+                }
+                list.pages.insert(
+                    loaded.page,
+                    Page {
+                        version: loaded.version,
+                        rows: loaded.rows.clone(),
+                    },
+                );
+            }
+            Handled::Yes
+        } else if let Some(changed) = cmd.get(FOLDER_CHANGED) {
+            if Self::is_listed(data, &changed.account, &changed.mailbox) {
+                // The list asks for the pages in view again, showing the
+                // outdated ones until they arrive
+                data.message_list.version += 1;
+            }
+            Handled::Yes
+        } else if let Some(email) = cmd.get(EMAIL_LOADED) {
+            Self::show_email(email, data);
+            Handled::Yes
+        } else if cmd.is(ALLOW_REMOTE_CONTENT) {
+            self.allow_remote_content(false, data);
+            Handled::Yes
+        } else if cmd.is(TRUST_SENDER) {
+            self.allow_remote_content(true, data);
+            Handled::Yes
+        } else if let Some(url) = cmd.get(OPEN_LINK) {
+            open_link(url);
+            Handled::Yes
+        } else {
+            self.composer_command(ctx, target, cmd, data)
+        }
+    }
+
+    fn window_removed(
+        &mut self,
+        id: WindowId,
+        data: &mut AppState,
+        _env: &Env,
+        _ctx: &mut DelegateCtx,
+    ) {
+        // Closing a composer discards its draft
+        if let Some(draft) = self.composers.remove(&id) {
+            data.drafts.remove(&draft);
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! Contains the message list widget
+//!
+//! Folders can hold hundreds of thousands of emails, so the list only builds
+//! widgets for the rows in view and a few around them, and asks for the pages
+//! of emails it needs as it is scrolled. Rows whose page hasn't arrived yet
+//! show a placeholder. It does its own scrolling, since a `Scroll` would need
+//! the height of every row laid out.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use druid::{
+    kurbo::RoundedRect,
+    lens, theme,
+    widget::{
+        CrossAxisAlignment, Flex, Label, LineBreaking, Maybe, RawLabel,
+        ViewSwitcher,
+    },
+    BoxConstraints, Data, Env, Event, EventCtx, LayoutCtx, Lens, LifeCycle,
+    LifeCycleCtx, PaintCtx, Point, Rect, RenderContext, Size, UpdateCtx,
+    Widget, WidgetExt, WidgetPod,
+};
+
+use super::{
+    delegate::{REQUEST_PAGE, SELECT_MESSAGE},
+    panes::{bold, selection_background, PADDING},
+    rich_text,
+    state::{MessageListState, MessageSummary, PAGE_SIZE},
+};
+
+/// Height of a row
+const ROW_HEIGHT: f64 = 44.0;
+
+/// How many rows are built beyond the visible ones on each side, so short
+/// scrolls don't show empty space
+const OVERSCAN: usize = 5;
+
+/// Width of the scrollbar along the right edge
+const SCROLLBAR_WIDTH: f64 = 8.0;
+
+/// The shortest the scrollbar thumb gets, so it can still be grabbed
+const MIN_THUMB_HEIGHT: f64 = 24.0;
+
+/// What a row is built from
+#[derive(Clone, Data, Lens)]
+struct MessageRow {
+    /// The email, if its page has been loaded
+    message: Option<MessageSummary>,
+    /// Whether the email is shown in the reader
+    selected: bool,
+}
+
+/// Builds the content of a row for a loaded email, in bold if it is unread
+///
+/// Emails matching a search show the matching part of their body after the
+/// subject.
+fn message_content() -> impl Widget<MessageSummary> {
+    ViewSwitcher::new(
+        |message: &MessageSummary, _env| message.unread,
+        |unread, _data, _env| {
+            let mut from = Label::dynamic(|message: &MessageSummary, _env| {
+                message.from.clone()
+            });
+            let mut subject =
+                Label::dynamic(|message: &MessageSummary, _env| {
+                    message.subject.clone()
+                });
+            if *unread {
+                from.set_font(bold());
+                subject.set_font(bold());
+            }
+            let details = Label::dynamic(|message: &MessageSummary, _env| {
+                let flag = if message.flagged {
+                    "⚑  "
+                } else {
+                    ""
+                };
+                let origin = message
+                    .origin
+                    .as_ref()
+                    .map(|origin| format!("{origin}  "))
+                    .unwrap_or_default();
+                format!("{origin}{flag}{}  {}", message.size, message.date)
+            });
+            let snippet = RawLabel::new()
+                .with_line_break_mode(LineBreaking::Clip)
+                .with_text_color(theme::PLACEHOLDER_COLOR)
+                .lens(lens::Map::new(
+                    |message: &MessageSummary| {
+                        rich_text::highlighted(&message.snippet)
+                    },
+                    |_message: &mut MessageSummary, _snippet| {},
+                ));
+            Box::new(
+                Flex::column()
+                    .cross_axis_alignment(CrossAxisAlignment::Start)
+                    .with_child(
+                        Flex::row()
+                            .with_flex_child(from.expand_width(), 1.0)
+                            .with_child(details),
+                    )
+                    .with_child(
+                        Flex::row()
+                            .with_child(subject)
+                            .with_spacer(PADDING)
+                            .with_flex_child(snippet.expand_width(), 1.0),
+                    )
+                    .padding(PADDING),
+            )
+        },
+    )
+}
+
+/// Builds a row, which opens its email when clicked
+fn message_row() -> impl Widget<MessageRow> {
+    Maybe::new(message_content, || Label::new("Loading…").padding(PADDING))
+        .lens(MessageRow::message)
+        .expand_width()
+        .background(selection_background(|row: &MessageRow| row.selected))
+        .on_click(|ctx, row: &mut MessageRow, _env| {
+            if let Some(message) = &row.message {
+                ctx.submit_command(SELECT_MESSAGE.with(message.clone()));
+            }
+        })
+}
+
+/// The height of a number of rows
+fn rows_height(rows: usize) -> f64 {
+    f64::from(u32::try_from(rows).unwrap_or(u32::MAX)) * ROW_HEIGHT
+}
+
+/// The index of the row at a distance from the top of the first row
+fn row_at(y: f64) -> usize {
+    // Bisecting avoids a float to integer cast, which would need care to not
+    // truncate
+    let mut low = 0;
+    let mut high = usize::try_from(u32::MAX).unwrap_or(usize::MAX);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if rows_height(middle + 1) <= y {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+/// A list of emails that only builds the rows in view
+pub(crate) struct MessageList {
+    /// Widgets of the rows currently built, by row index
+    rows: BTreeMap<usize, WidgetPod<MessageRow, Box<dyn Widget<MessageRow>>>>,
+    /// How far the list is scrolled down
+    offset: f64,
+    /// The size of the list, as of the last layout
+    size: Size,
+    /// Where the scrollbar thumb was grabbed, relative to its top, while it
+    /// is dragged
+    grab: Option<f64>,
+}
+
+impl MessageList {
+    /// Creates an empty list scrolled to the top
+    pub(crate) fn new() -> Self {
+        Self {
+            rows: BTreeMap::new(),
+            offset: 0.0,
+            size: Size::ZERO,
+            grab: None,
+        }
+    }
+
+    /// What the row at an index is built from
+    fn row_data(data: &MessageListState, index: usize) -> MessageRow {
+        let message = data.row(index).cloned();
+        let selected = message.as_ref().is_some_and(|message| {
+            data.selected.as_ref().is_some_and(|selected| selected.is(message))
+        });
+        MessageRow {
+            message,
+            selected,
+        }
+    }
+
+    /// How far the list can be scrolled down
+    fn max_offset(&self, data: &MessageListState) -> f64 {
+        (rows_height(data.total) - self.size.height).max(0.0)
+    }
+
+    /// Scrolls to a position, staying within the list
+    fn scroll_to(&mut self, offset: f64, data: &MessageListState) {
+        self.offset = offset.clamp(0.0, self.max_offset(data));
+    }
+
+    /// Scrolls just enough to bring the selected row into view, if it is
+    /// loaded
+    fn reveal_selected(&mut self, data: &MessageListState) {
+        let Some(index) =
+            data.selected.as_ref().and_then(|selected| data.index_of(selected))
+        else {
+            return;
+        };
+        let (top, bottom) = (rows_height(index), rows_height(index + 1));
+        if top < self.offset {
+            self.scroll_to(top, data);
+        } else if bottom > self.offset + self.size.height {
+            self.scroll_to(bottom - self.size.height, data);
+        }
+    }
+
+    /// Indices of the rows in view, past the last row if none are
+    fn visible(&self) -> (usize, usize) {
+        (row_at(self.offset), row_at(self.offset + self.size.height) + 1)
+    }
+
+    /// Builds the rows in view and around them, and drops the others
+    ///
+    /// Returns whether rows were added or removed.
+    fn build_rows(&mut self, data: &MessageListState) -> bool {
+        let (first, end) = self.visible();
+        let range = first.saturating_sub(OVERSCAN)
+            ..end.saturating_add(OVERSCAN).min(data.total);
+        let count = self.rows.len();
+        self.rows.retain(|index, _row| range.contains(index));
+        let mut changed = self.rows.len() != count;
+        for index in range {
+            if let Entry::Vacant(entry) = self.rows.entry(index) {
+                entry.insert(WidgetPod::new(message_row().boxed()));
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// The pages of the rows in view that have to be loaded
+    ///
+    /// The first page is needed as long as the list is empty, since the
+    /// number of emails is only known once it has been loaded.
+    fn missing_pages(&self, data: &MessageListState) -> Vec<usize> {
+        let (first, end) = self.visible();
+        let last = end.min(data.total).max(first + 1) - 1;
+        (first / PAGE_SIZE..=last / PAGE_SIZE)
+            .filter(|page| data.needs(*page))
+            .collect()
+    }
+
+    /// Where the scrollbar thumb is, if the list is longer than its view
+    fn thumb(&self, data: &MessageListState) -> Option<Rect> {
+        let max_offset = self.max_offset(data);
+        if max_offset <= 0.0 {
+            return None;
+        }
+        let height = (self.size.height * self.size.height
+            / rows_height(data.total))
+        .max(MIN_THUMB_HEIGHT);
+        let top = self.offset / max_offset * (self.size.height - height);
+        Some(Rect::new(
+            self.size.width - SCROLLBAR_WIDTH,
+            top,
+            self.size.width,
+            top + height,
+        ))
+    }
+
+    /// Scrolls so the top of the scrollbar thumb is at a position
+    fn drag_thumb(&mut self, top: f64, thumb: Rect, data: &MessageListState) {
+        let track = self.size.height - thumb.height();
+        if track > 0.0 {
+            self.scroll_to(top / track * self.max_offset(data), data);
+        }
+    }
+
+    /// Catches up with a change of the scroll position
+    fn scrolled(&mut self, ctx: &mut EventCtx, data: &MessageListState) {
+        if self.build_rows(data) {
+            ctx.children_changed();
+        }
+        for page in self.missing_pages(data) {
+            ctx.submit_command(REQUEST_PAGE.with(page));
+        }
+        ctx.request_layout();
+    }
+}
+
+impl Widget<MessageListState> for MessageList {
+    fn event(
+        &mut self,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut MessageListState,
+        env: &Env,
+    ) {
+        match event {
+            Event::Wheel(mouse) => {
+                self.scroll_to(self.offset + mouse.wheel_delta.y, data);
+                self.scrolled(ctx, data);
+                ctx.set_handled();
+                return;
+            }
+            Event::MouseDown(mouse) => {
+                if let Some(thumb) = self.thumb(data) {
+                    if mouse.pos.x >= thumb.x0 {
+                        let grab = if thumb.contains(mouse.pos) {
+                            mouse.pos.y - thumb.y0
+                        } else {
+                            // Clicking the track centers the thumb there
+                            thumb.height() / 2.0
+                        };
+                        self.grab = Some(grab);
+                        self.drag_thumb(mouse.pos.y - grab, thumb, data);
+                        self.scrolled(ctx, data);
+                        ctx.set_active(true);
+                        ctx.set_handled();
+                        return;
+                    }
+                }
+            }
+            Event::MouseMove(mouse) if ctx.is_active() => {
+                if let (Some(grab), Some(thumb)) = (self.grab, self.thumb(data))
+                {
+                    self.drag_thumb(mouse.pos.y - grab, thumb, data);
+                    self.scrolled(ctx, data);
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            Event::MouseUp(_) if ctx.is_active() && self.grab.is_some() => {
+                self.grab = None;
+                ctx.set_active(false);
+                ctx.set_handled();
+                return;
+            }
+            _ => {}
+        }
+        for (index, row) in &mut self.rows {
+            if row.is_initialized() {
+                let mut row_data = Self::row_data(data, *index);
+                row.event(ctx, event, &mut row_data, env);
+            }
+        }
+    }
+
+    fn lifecycle(
+        &mut self,
+        ctx: &mut LifeCycleCtx,
+        event: &LifeCycle,
+        data: &MessageListState,
+        env: &Env,
+    ) {
+        match event {
+            LifeCycle::WidgetAdded => {
+                self.build_rows(data);
+            }
+            LifeCycle::Size(size) => {
+                self.size = *size;
+                self.scroll_to(self.offset, data);
+                if self.build_rows(data) {
+                    ctx.children_changed();
+                }
+                for page in self.missing_pages(data) {
+                    ctx.submit_command(REQUEST_PAGE.with(page));
+                }
+                ctx.request_layout();
+            }
+            _ => {}
+        }
+        for (index, row) in &mut self.rows {
+            row.lifecycle(ctx, event, &Self::row_data(data, *index), env);
+        }
+    }
+
+    fn update(
+        &mut self,
+        ctx: &mut UpdateCtx,
+        old_data: &MessageListState,
+        data: &MessageListState,
+        env: &Env,
+    ) {
+        if old_data.same(data) {
+            return;
+        }
+        // A new folder or order starts from scratch, and from the top
+        if data.version != old_data.version && data.pages.is_empty() {
+            self.offset = 0.0;
+        }
+        self.scroll_to(self.offset, data);
+        // Emails selected from the keyboard may be out of view
+        if !old_data.selected.same(&data.selected) {
+            self.reveal_selected(data);
+        }
+        if self.build_rows(data) {
+            ctx.children_changed();
+        }
+        for (index, row) in &mut self.rows {
+            if row.is_initialized() {
+                row.update(ctx, &Self::row_data(data, *index), env);
+            }
+        }
+        for page in self.missing_pages(data) {
+            ctx.submit_command(REQUEST_PAGE.with(page));
+        }
+        ctx.request_layout();
+    }
+
+    fn layout(
+        &mut self,
+        ctx: &mut LayoutCtx,
+        bc: &BoxConstraints,
+        data: &MessageListState,
+        env: &Env,
+    ) -> Size {
+        let size = bc.max();
+        let row_size = Size::new(size.width - SCROLLBAR_WIDTH, ROW_HEIGHT);
+        for (index, row) in &mut self.rows {
+            if row.is_initialized() {
+                row.layout(
+                    ctx,
+                    &BoxConstraints::tight(row_size),
+                    &Self::row_data(data, *index),
+                    env,
+                );
+                row.set_origin(
+                    ctx,
+                    Point::new(0.0, rows_height(*index) - self.offset),
+                );
+            }
+        }
+        size
+    }
+
+    fn paint(
+        &mut self,
+        ctx: &mut PaintCtx,
+        data: &MessageListState,
+        env: &Env,
+    ) {
+        let bounds = ctx.size().to_rect();
+        ctx.with_save(|ctx| {
+            ctx.clip(bounds);
+            for (index, row) in &mut self.rows {
+                if row.is_initialized() {
+                    row.paint(ctx, &Self::row_data(data, *index), env);
+                }
+            }
+        });
+        if let Some(thumb) = self.thumb(data) {
+            let thumb = RoundedRect::from_rect(thumb, SCROLLBAR_WIDTH / 2.0);
+            ctx.fill(thumb, &env.get(theme::SCROLLBAR_COLOR));
+        }
+    }
+}
@@ -1,4 +1,12 @@
 //! GUI functionality
 
 pub(crate) mod actor;
+pub(crate) mod bridge;
+mod composer;
+mod delegate;
+mod message_list;
+mod panes;
+mod rich_text;
+mod shortcuts;
+mod state;
 pub(crate) mod watchdog_actor;
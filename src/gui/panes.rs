@@ -0,0 +1,502 @@
+//! The panes of the main window: the folder tree, the message list and the
+//! reader
+
+use druid::{
+    commands, lens, theme,
+    widget::{
+        Button, CrossAxisAlignment, Either, Flex, Label, LineBreaking, List,
+        Maybe, Painter, RawLabel, Scroll, SizedBox, TextBox, ViewSwitcher,
+    },
+    Data, FileDialogOptions, FontDescriptor, FontFamily, FontWeight, Insets,
+    LensExt, RenderContext, Widget, WidgetExt,
+};
+
+use super::{
+    delegate::{
+        ComposeKind, ADD_SENDER, ALLOW_REMOTE_CONTENT, COMPOSE, DELETE_SEARCH,
+        EXPORT_CONTACTS, IMPORT_CONTACTS, OPEN_ATTACHMENT, SAVE_ATTACHMENT,
+        SAVE_SEARCH, SELECT_FOLDER, SELECT_MESSAGE, SET_SORT, SYNC_NOW,
+        TRUST_SENDER, VCARD_FILES,
+    },
+    message_list::MessageList,
+    shortcuts::{SearchField, SEARCH_FIELD},
+    state::{
+        AppState, AttachmentEntry, ConversationEntry, FolderEntry,
+        MessageListState, ReaderState,
+    },
+};
+use crate::database::{
+    listing::{Listing, Sort, SortField},
+    vcard::Version,
+};
+
+/// Horizontal space per level of nesting in the folder tree
+const INDENT: f64 = 12.0;
+
+/// Space around the content of rows and panes
+pub(super) const PADDING: f64 = 4.0;
+
+/// Paints the background of rows that are selected
+pub(super) fn selection_background<T: Data>(
+    is_selected: impl Fn(&T) -> bool + 'static,
+) -> Painter<T> {
+    Painter::new(move |ctx, data: &T, env| {
+        if is_selected(data) {
+            let bounds = ctx.size().to_rect();
+            ctx.fill(bounds, &env.get(theme::SELECTED_TEXT_BACKGROUND_COLOR));
+        }
+    })
+}
+
+/// The interface font in bold, for subjects and unread emails
+pub(super) fn bold() -> FontDescriptor {
+    FontDescriptor::new(FontFamily::SYSTEM_UI).with_weight(FontWeight::BOLD)
+}
+
+/// Builds a row of the folder tree, indented by its depth
+///
+/// Smart folders show how many of their emails are unread, and have a button
+/// to delete them.
+fn folder_row() -> impl Widget<(Option<Listing>, FolderEntry)> {
+    ViewSwitcher::new(
+        |(_, folder): &(Option<Listing>, FolderEntry), _env| {
+            (folder.depth, folder.saved)
+        },
+        |(depth, saved), _data, _env| {
+            let label = Label::dynamic(
+                |(_, folder): &(Option<Listing>, FolderEntry), _env| {
+                    match folder.unread {
+                        Some(unread) if unread > 0 => {
+                            format!("{} ({unread})", folder.name)
+                        }
+                        _ => folder.name.clone(),
+                    }
+                },
+            );
+            let indent = INDENT * f64::from(*depth);
+            let label = label
+                .padding(Insets::new(
+                    PADDING + indent,
+                    PADDING,
+                    PADDING,
+                    PADDING,
+                ))
+                .expand_width()
+                .on_click(|ctx, (_, folder), _env| {
+                    ctx.submit_command(SELECT_FOLDER.with(folder.clone()));
+                });
+            let mut row = Flex::row().with_flex_child(label, 1.0);
+            if *saved {
+                row.add_child(Button::new("✕").on_click(
+                    |ctx,
+                     (_, folder): &mut (Option<Listing>, FolderEntry),
+                     _env| {
+                        ctx.submit_command(
+                            DELETE_SEARCH.with(folder.name.clone()),
+                        );
+                    },
+                ));
+            }
+            Box::new(row)
+        },
+    )
+    .expand_width()
+    .background(selection_background(
+        |(selected, folder): &(Option<Listing>, FolderEntry)| {
+            selected.is_some() && *selected == folder.listing
+        },
+    ))
+}
+
+/// Builds a button opening a composer
+fn compose_button<T: Data>(
+    label: &'static str,
+    kind: ComposeKind,
+) -> impl Widget<T> {
+    Button::new(label).on_click(move |ctx, _data, _env| {
+        ctx.submit_command(COMPOSE.with(kind));
+    })
+}
+
+/// Builds a button exporting the address book as vCards of a version
+fn export_button<T: Data>(
+    label: &'static str,
+    version: Version,
+) -> impl Widget<T> {
+    Button::new(label).on_click(move |ctx, _data, _env| {
+        ctx.submit_command(EXPORT_CONTACTS.with(version));
+    })
+}
+
+/// Builds the folder tree, listing the views across accounts, the folders of
+/// every account and the smart folders below buttons to write an email, to
+/// synchronize them and to move the address book in and out, above what went
+/// wrong with the database while it is degraded
+pub(crate) fn folder_tree() -> impl Widget<AppState> {
+    let sync = Button::new("Sync now").on_click(|ctx, _data, _env| {
+        ctx.submit_command(SYNC_NOW);
+    });
+    let buttons = Flex::row()
+        .with_child(compose_button("Compose", ComposeKind::New))
+        .with_spacer(PADDING)
+        .with_child(sync);
+    let import =
+        Button::new("Import contacts…").on_click(|ctx, _data, _env| {
+            let options = FileDialogOptions::new()
+                .allowed_types(vec![VCARD_FILES])
+                .accept_command(IMPORT_CONTACTS);
+            ctx.submit_command(commands::SHOW_OPEN_PANEL.with(options));
+        });
+    let contacts = Flex::row()
+        .with_child(import)
+        .with_spacer(PADDING)
+        .with_child(export_button("Export vCard 3.0…", Version::V3))
+        .with_spacer(PADDING)
+        .with_child(export_button("Export vCard 4.0…", Version::V4));
+    let tree =
+        Scroll::new(List::new(folder_row)).vertical().lens(lens::Map::new(
+            |data: &AppState| (data.listing.clone(), data.folder_rows()),
+            // Rows don't change what they show
+            |_data: &mut AppState, _rows| {},
+        ));
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(buttons.padding(PADDING))
+        .with_child(contacts.padding(PADDING))
+        .with_flex_child(tree, 1.0)
+        .with_child(
+            Maybe::or_empty(|| {
+                Label::raw()
+                    .with_line_break_mode(LineBreaking::WordWrap)
+                    .padding(PADDING)
+            })
+            .lens(AppState::database_problem),
+        )
+}
+
+/// Builds a button sorting the message list by a field, showing the
+/// direction when the list is sorted by it
+fn sort_button(label: &'static str, field: SortField) -> impl Widget<Sort> {
+    Button::dynamic(move |sort: &Sort, _env| {
+        match (sort.field == field, sort.descending) {
+            (false, _) => label.to_owned(),
+            (true, false) => format!("{label} ▲"),
+            (true, true) => format!("{label} ▼"),
+        }
+    })
+    .on_click(move |ctx, _data, _env| {
+        ctx.submit_command(SET_SORT.with(field));
+    })
+}
+
+/// Builds the button saving the listed search as a smart folder, shown while
+/// a search is listed
+fn save_search_button() -> impl Widget<AppState> {
+    Either::new(
+        |data: &AppState, _env| matches!(data.listing, Some(Listing::Query(_))),
+        Button::new("Save…").on_click(|_ctx, data: &mut AppState, _env| {
+            data.saving_search = Some(String::new());
+        }),
+        SizedBox::empty(),
+    )
+}
+
+/// Builds the field for the name of a smart folder, with buttons to save the
+/// listed search under it or to cancel, shown while it is being saved
+fn save_search_row() -> impl Widget<AppState> {
+    let name = TextBox::new()
+        .with_placeholder("Name of the smart folder")
+        .expand_width()
+        .lens(lens::Map::new(
+            |data: &AppState| data.saving_search.clone().unwrap_or_default(),
+            |data: &mut AppState, name| {
+                if data.saving_search.is_some() {
+                    data.saving_search = Some(name);
+                }
+            },
+        ));
+    let save =
+        Button::new("Save").on_click(|ctx, data: &mut AppState, _env| {
+            let name = data.saving_search.clone().unwrap_or_default();
+            ctx.submit_command(SAVE_SEARCH.with(name));
+        });
+    let cancel =
+        Button::new("Cancel").on_click(|_ctx, data: &mut AppState, _env| {
+            data.saving_search = None;
+        });
+    Either::new(
+        |data: &AppState, _env| data.saving_search.is_some(),
+        Flex::row()
+            .with_flex_child(name, 1.0)
+            .with_spacer(PADDING)
+            .with_child(save)
+            .with_spacer(PADDING)
+            .with_child(cancel)
+            .padding(PADDING),
+        SizedBox::empty(),
+    )
+}
+
+/// Builds the message list, listing the emails of the selected folder or
+/// matching a search below the search field and buttons to sort them
+pub(crate) fn message_list() -> impl Widget<AppState> {
+    let search = Flex::row()
+        .with_flex_child(
+            TextBox::new()
+                .with_placeholder("Search, e.g. from:alice is:unread")
+                .expand_width()
+                .lens(AppState::search)
+                .controller(SearchField)
+                .with_id(SEARCH_FIELD),
+            1.0,
+        )
+        .with_spacer(PADDING)
+        .with_child(save_search_button());
+    let sort = Flex::row()
+        .with_child(sort_button("Date", SortField::Date))
+        .with_spacer(PADDING)
+        .with_child(sort_button("From", SortField::Sender))
+        .with_spacer(PADDING)
+        .with_child(sort_button("Subject", SortField::Subject))
+        .with_spacer(PADDING)
+        .with_child(sort_button("Size", SortField::Size))
+        .lens(AppState::message_list.then(MessageListState::sort));
+    // Only searches rank emails
+    let relevance = Either::new(
+        |data: &AppState, _env| matches!(data.listing, Some(Listing::Query(_))),
+        Flex::row().with_spacer(PADDING).with_child(
+            sort_button("Relevance", SortField::Relevance)
+                .lens(AppState::message_list.then(MessageListState::sort)),
+        ),
+        SizedBox::empty(),
+    );
+    let sort =
+        Flex::row().with_child(sort).with_child(relevance).padding(PADDING);
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(search.padding(PADDING))
+        .with_child(save_search_row())
+        .with_child(sort)
+        .with_flex_child(MessageList::new().lens(AppState::message_list), 1.0)
+}
+
+/// Builds a row of the attachments of an email, with buttons to open and
+/// save it
+fn attachment_row() -> impl Widget<AttachmentEntry> {
+    Flex::row()
+        .with_flex_child(
+            Label::dynamic(|attachment: &AttachmentEntry, _env| {
+                format!(
+                    "{} ({}, {})",
+                    attachment.name, attachment.mime_type, attachment.size
+                )
+            })
+            .expand_width(),
+            1.0,
+        )
+        .with_child(Button::new("Open").on_click(
+            |ctx, attachment: &mut AttachmentEntry, _env| {
+                ctx.submit_command(OPEN_ATTACHMENT.with(attachment.clone()));
+            },
+        ))
+        .with_spacer(PADDING)
+        .with_child(Button::new("Save…").on_click(
+            |ctx, attachment: &mut AttachmentEntry, _env| {
+                ctx.submit_command(SAVE_ATTACHMENT.with(attachment.clone()));
+            },
+        ))
+}
+
+/// Builds a row of the conversation of an email, which opens the email in
+/// the row when clicked
+fn conversation_row() -> impl Widget<ConversationEntry> {
+    Label::dynamic(|entry: &ConversationEntry, _env| {
+        format!(
+            "{}  {} — {}",
+            entry.email.date, entry.email.from, entry.email.subject
+        )
+    })
+    .with_line_break_mode(LineBreaking::Clip)
+    .expand_width()
+    .background(selection_background(|entry: &ConversationEntry| entry.current))
+    .on_click(|ctx, entry: &mut ConversationEntry, _env| {
+        ctx.submit_command(SELECT_MESSAGE.with(entry.email.clone()));
+    })
+}
+
+/// Builds the list of the emails in the conversation of an email, shown when
+/// there is more than the email itself
+fn conversation() -> impl Widget<ReaderState> {
+    Either::new(
+        |data: &ReaderState, _env| data.conversation.len() > 1,
+        Flex::column()
+            .cross_axis_alignment(CrossAxisAlignment::Start)
+            .with_spacer(PADDING)
+            .with_child(Label::dynamic(|data: &ReaderState, _env| {
+                format!("Conversation ({} emails)", data.conversation.len())
+            }))
+            .with_child(
+                List::new(conversation_row).lens(ReaderState::conversation),
+            ),
+        SizedBox::empty(),
+    )
+}
+
+/// Builds the view of an email in the reader
+fn reader_email() -> impl Widget<ReaderState> {
+    let text = |text: fn(&ReaderState) -> String| {
+        Label::dynamic(move |data: &ReaderState, _env| text(data))
+            .with_line_break_mode(LineBreaking::WordWrap)
+    };
+    let answer = Flex::row()
+        .with_child(compose_button("Reply", ComposeKind::Reply))
+        .with_spacer(PADDING)
+        .with_child(compose_button("Reply all", ComposeKind::ReplyAll))
+        .with_spacer(PADDING)
+        .with_child(compose_button("Forward", ComposeKind::Forward))
+        .with_spacer(PADDING)
+        .with_child(
+            Button::new("Add sender to contacts")
+                .on_click(|ctx, _data, _env| ctx.submit_command(ADD_SENDER)),
+        );
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(answer)
+        .with_spacer(PADDING)
+        .with_child(
+            text(|data| data.subject.clone())
+                .with_font(bold())
+                .with_text_size(18.0),
+        )
+        .with_child(text(|data| format!("From: {}", data.from)))
+        .with_child(text(|data| format!("To: {}", data.to)))
+        .with_child(
+            Flex::row()
+                .with_child(text(|data| data.date.clone()))
+                .with_flex_spacer(1.0)
+                .with_child(Either::new(
+                    |data: &ReaderState, _env| data.html.is_some(),
+                    view_toggle(),
+                    SizedBox::empty(),
+                ))
+                .must_fill_main_axis(true),
+        )
+        .with_child(
+            List::new(attachment_row)
+                .with_spacing(PADDING / 2.0)
+                .padding((0.0, PADDING, 0.0, 0.0))
+                .lens(ReaderState::attachments),
+        )
+        .with_child(conversation())
+        .with_child(Either::new(
+            |data: &ReaderState, _env| {
+                data.html.is_some()
+                    && !data.show_plain
+                    && (data.blocked > 0 || data.trackers > 0)
+            },
+            remote_content_notice(),
+            SizedBox::empty(),
+        ))
+        .with_spacer(PADDING * 2.0)
+        .with_flex_child(
+            Scroll::new(
+                RawLabel::new()
+                    .with_line_break_mode(LineBreaking::WordWrap)
+                    .lens(lens::Map::new(
+                        ReaderState::body,
+                        |_data: &mut ReaderState, _body| {},
+                    ))
+                    .expand_width(),
+            )
+            .vertical(),
+            1.0,
+        )
+        .padding(PADDING * 2.0)
+}
+
+/// Describes what was left out of the HTML of an email to protect the user's
+/// privacy
+fn privacy_notice(data: &ReaderState) -> String {
+    let plural = |count: usize| {
+        if count == 1 {
+            ""
+        } else {
+            "s"
+        }
+    };
+    let mut notice = Vec::new();
+    if data.blocked > 0 {
+        notice.push(format!(
+            "{} remote image{} hidden.",
+            data.blocked,
+            plural(data.blocked)
+        ));
+    }
+    if data.trackers > 0 {
+        notice.push(format!(
+            "{} tracker{} removed.",
+            data.trackers,
+            plural(data.trackers)
+        ));
+    }
+    notice.join(" ")
+}
+
+/// Builds the notice of what was left out of the HTML of an email, with
+/// buttons to show links to the remote images instead
+///
+/// Remote images are never loaded, only linked to, so the buttons say so.
+fn remote_content_notice() -> impl Widget<ReaderState> {
+    let trust = Either::new(
+        |data: &ReaderState, _env| data.sender.is_some(),
+        Button::dynamic(|data: &ReaderState, _env| {
+            format!(
+                "Always show image links from {}",
+                data.sender.as_deref().unwrap_or_default()
+            )
+        })
+        .on_click(|ctx, _data, _env| ctx.submit_command(TRUST_SENDER)),
+        SizedBox::empty(),
+    );
+    let buttons = Flex::row()
+        .with_child(Button::new("Show remote image links").on_click(
+            |ctx, _data, _env| {
+                ctx.submit_command(ALLOW_REMOTE_CONTENT);
+            },
+        ))
+        .with_spacer(PADDING)
+        .with_child(trust);
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_spacer(PADDING)
+        .with_child(
+            Label::dynamic(|data: &ReaderState, _env| privacy_notice(data))
+                .with_line_break_mode(LineBreaking::WordWrap),
+        )
+        .with_child(Either::new(
+            |data: &ReaderState, _env| data.blocked > 0 && !data.remote_content,
+            buttons,
+            SizedBox::empty(),
+        ))
+}
+
+/// Builds the button switching between the HTML and the plain text of an
+/// email
+fn view_toggle() -> impl Widget<ReaderState> {
+    Button::dynamic(|data: &ReaderState, _env| {
+        if data.show_plain {
+            "Show HTML".to_owned()
+        } else {
+            "Show plain text".to_owned()
+        }
+    })
+    .on_click(|_ctx, data: &mut ReaderState, _env| {
+        data.show_plain = !data.show_plain;
+    })
+}
+
+/// Builds the reader, showing the selected email
+pub(crate) fn reader() -> impl Widget<AppState> {
+    Maybe::new(reader_email, || Label::new("No email selected").center())
+        .lens(AppState::reader)
+}
@@ -0,0 +1,81 @@
+//! Turns styled message bodies into druid rich text
+//!
+//! Rich text holds commands for its links, which can't be sent between
+//! threads, so the bridge sends the styled spans and they are only turned into
+//! rich text on the GUI thread.
+
+use druid::{
+    text::{RichText, RichTextBuilder},
+    Color, FontFamily, FontStyle, FontWeight,
+};
+
+use super::delegate::OPEN_LINK;
+use crate::{
+    database::search::{HIGHLIGHT_CLOSE, HIGHLIGHT_OPEN},
+    mail::styled::Span,
+};
+
+/// Colors of quoted text, by quote level starting at one and repeating
+const QUOTE_COLORS: [Color; 3] = [
+    Color::rgb8(0x6A, 0x9F, 0xD8),
+    Color::rgb8(0x7F, 0xB0, 0x6E),
+    Color::rgb8(0xC9, 0x8F, 0x5A),
+];
+
+/// Color of links
+const LINK_COLOR: Color = Color::rgb8(0x4E, 0x94, 0xCE);
+
+/// Text sizes of HTML headings, from `<h1>` to `<h6>`
+const HEADING_SIZES: [f64; 6] = [24.0, 20.0, 18.0, 16.0, 15.0, 14.0];
+
+/// Builds rich text from styled spans
+pub(crate) fn build(spans: &[Span]) -> RichText {
+    let mut builder = RichTextBuilder::new();
+    for span in spans {
+        let style = &span.style;
+        let mut adder = builder.push(&span.text);
+        if style.bold {
+            adder.weight(FontWeight::BOLD);
+        }
+        if style.italic {
+            adder.style(FontStyle::Italic);
+        }
+        if style.monospace {
+            adder.font_family(FontFamily::MONOSPACE);
+        }
+        if let Some(size) = usize::from(style.heading)
+            .checked_sub(1)
+            .and_then(|index| HEADING_SIZES.get(index))
+        {
+            adder.size(*size);
+        }
+        if let Some(color) = usize::from(style.quote_level)
+            .checked_sub(1)
+            .map(|index| &QUOTE_COLORS[index % QUOTE_COLORS.len()])
+        {
+            adder.text_color(*color);
+        }
+        if let Some(link) = &style.link {
+            adder
+                .text_color(LINK_COLOR)
+                .underline(true)
+                .link(OPEN_LINK.with(link.clone()));
+        }
+    }
+    builder.build()
+}
+
+/// Builds rich text from search results, with the matched terms in bold
+pub(crate) fn highlighted(text: &str) -> RichText {
+    let mut builder = RichTextBuilder::new();
+    for (index, part) in text.split(HIGHLIGHT_OPEN).enumerate() {
+        // Every part but the first starts with a matched term
+        let (matched, rest) = match part.split_once(HIGHLIGHT_CLOSE) {
+            Some((matched, rest)) if index > 0 => (matched, rest),
+            _ => ("", part),
+        };
+        builder.push(matched).weight(FontWeight::BOLD);
+        builder.push(rest);
+    }
+    builder.build()
+}
@@ -0,0 +1,666 @@
+//! Contains the keyboard shortcuts of the main window and the command palette
+//!
+//! Every action the keyboard can run has a name, used to bind keys to it in
+//! `weasel.toml`, and a description shown in the command palette, which lists
+//! every action with its keys. Shortcuts start from one of the built-in
+//! keymaps, which the configuration adds to or overrides.
+//!
+//! Druid only sends key presses down the path to the focused widget, so the
+//! root of the main window takes the focus whenever no text field needs it.
+//! Plain keys like `j` are shortcuts only while it has the focus, so they can
+//! still be typed into the search field; shortcuts with modifiers work
+//! everywhere.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+};
+
+use druid::{
+    im::Vector,
+    lens,
+    widget::{Controller, Either, Flex, Label, List, SizedBox, TextBox},
+    Data, Env, Event, EventCtx, KbKey, KeyEvent, Modifiers, Selector,
+    UpdateCtx, Widget, WidgetExt, WidgetId,
+};
+
+use super::{
+    delegate::{
+        ComposeKind, EmailChange, CHANGE_EMAIL, COMPOSE, RUN_ACTION, SEARCH,
+        SELECT_FOLDER, SELECT_MESSAGE, SYNC_NOW,
+    },
+    panes::{bold, selection_background, PADDING},
+    state::{AppState, FolderEntry, PaletteEntry},
+};
+use crate::config::{KeyBindings, Keymap};
+
+/// Identifies the root of the main window, which has the focus while no text
+/// field does
+pub(crate) const MAIN_WINDOW: WidgetId = WidgetId::reserved(1);
+
+/// Identifies the search field above the message list
+pub(crate) const SEARCH_FIELD: WidgetId = WidgetId::reserved(2);
+
+/// Identifies the field of the command palette
+const PALETTE_FIELD: WidgetId = WidgetId::reserved(3);
+
+/// Gives the keyboard focus to the widget it is sent to
+const FOCUS: Selector = Selector::new("weasel.focus");
+
+/// The action of bindings that remove the shortcut of a key
+const UNBOUND: &str = "none";
+
+/// What the keyboard can do
+#[derive(Clone, Copy, Data, PartialEq, Eq, Debug)]
+pub(crate) enum Action {
+    /// Show the email below the shown one
+    NextMessage,
+    /// Show the email above the shown one
+    PreviousMessage,
+    /// List the folder below the listed one
+    NextFolder,
+    /// List the folder above the listed one
+    PreviousFolder,
+    /// Write a new email
+    Compose,
+    /// Reply to the senders of the shown email
+    Reply,
+    /// Reply to everyone the shown email was sent to
+    ReplyAll,
+    /// Forward the shown email
+    Forward,
+    /// Archive the shown email
+    Archive,
+    /// Move the shown email to the trash
+    Delete,
+    /// Mark the shown email as read or unread
+    ToggleRead,
+    /// Flag the shown email or remove its flag
+    ToggleFlag,
+    /// Switch between the HTML and the plain text of the shown email
+    ToggleHtml,
+    /// Type into the search field
+    Search,
+    /// Synchronize every folder
+    SyncNow,
+    /// Open or close the command palette
+    CommandPalette,
+}
+
+/// Every action, in the order of the command palette
+const ACTIONS: [Action; 16] = [
+    Action::NextMessage,
+    Action::PreviousMessage,
+    Action::NextFolder,
+    Action::PreviousFolder,
+    Action::Compose,
+    Action::Reply,
+    Action::ReplyAll,
+    Action::Forward,
+    Action::Archive,
+    Action::Delete,
+    Action::ToggleRead,
+    Action::ToggleFlag,
+    Action::ToggleHtml,
+    Action::Search,
+    Action::SyncNow,
+    Action::CommandPalette,
+];
+
+impl Action {
+    /// The name of the action in the configuration
+    fn name(self) -> &'static str {
+        match self {
+            Action::NextMessage => "next-message",
+            Action::PreviousMessage => "previous-message",
+            Action::NextFolder => "next-folder",
+            Action::PreviousFolder => "previous-folder",
+            Action::Compose => "compose",
+            Action::Reply => "reply",
+            Action::ReplyAll => "reply-all",
+            Action::Forward => "forward",
+            Action::Archive => "archive",
+            Action::Delete => "delete",
+            Action::ToggleRead => "toggle-read",
+            Action::ToggleFlag => "toggle-flag",
+            Action::ToggleHtml => "toggle-html",
+            Action::Search => "search",
+            Action::SyncNow => "sync-now",
+            Action::CommandPalette => "command-palette",
+        }
+    }
+
+    /// What the action does, as the command palette shows it
+    fn description(self) -> &'static str {
+        match self {
+            Action::NextMessage => "Next email",
+            Action::PreviousMessage => "Previous email",
+            Action::NextFolder => "Next folder",
+            Action::PreviousFolder => "Previous folder",
+            Action::Compose => "Write a new email",
+            Action::Reply => "Reply",
+            Action::ReplyAll => "Reply to all",
+            Action::Forward => "Forward",
+            Action::Archive => "Archive",
+            Action::Delete => "Move to trash",
+            Action::ToggleRead => "Mark as read or unread",
+            Action::ToggleFlag => "Flag or unflag",
+            Action::ToggleHtml => "Switch between HTML and plain text",
+            Action::Search => "Search",
+            Action::SyncNow => "Synchronize now",
+            Action::CommandPalette => "Command palette",
+        }
+    }
+
+    /// Finds an action by its name in the configuration
+    fn from_name(name: &str) -> Option<Self> {
+        ACTIONS.into_iter().find(|action| action.name() == name)
+    }
+}
+
+/// The shortcuts of the vim keymap
+const VIM_KEYS: [(&str, Action); 18] = [
+    ("j", Action::NextMessage),
+    ("arrowdown", Action::NextMessage),
+    ("k", Action::PreviousMessage),
+    ("arrowup", Action::PreviousMessage),
+    ("J", Action::NextFolder),
+    ("K", Action::PreviousFolder),
+    ("c", Action::Compose),
+    ("r", Action::Reply),
+    ("R", Action::ReplyAll),
+    ("f", Action::Forward),
+    ("a", Action::Archive),
+    ("d", Action::Delete),
+    ("u", Action::ToggleRead),
+    ("s", Action::ToggleFlag),
+    ("v", Action::ToggleHtml),
+    ("/", Action::Search),
+    ("f5", Action::SyncNow),
+    (":", Action::CommandPalette),
+];
+
+/// The shortcuts of the conventional keymap
+const CONVENTIONAL_KEYS: [(&str, Action); 16] = [
+    ("arrowdown", Action::NextMessage),
+    ("arrowup", Action::PreviousMessage),
+    ("ctrl+arrowdown", Action::NextFolder),
+    ("ctrl+arrowup", Action::PreviousFolder),
+    ("ctrl+n", Action::Compose),
+    ("ctrl+r", Action::Reply),
+    ("ctrl+shift+r", Action::ReplyAll),
+    ("ctrl+l", Action::Forward),
+    ("ctrl+shift+a", Action::Archive),
+    ("delete", Action::Delete),
+    ("ctrl+shift+u", Action::ToggleRead),
+    ("insert", Action::ToggleFlag),
+    ("ctrl+shift+h", Action::ToggleHtml),
+    ("ctrl+f", Action::Search),
+    ("f5", Action::SyncNow),
+    ("ctrl+shift+p", Action::CommandPalette),
+];
+
+/// Other ways to write the names of keys, and the names druid gives them
+const KEY_ALIASES: [(&str, &str); 11] = [
+    ("space", " "),
+    ("plus", "+"),
+    ("esc", "escape"),
+    ("del", "delete"),
+    ("return", "enter"),
+    ("up", "arrowup"),
+    ("down", "arrowdown"),
+    ("left", "arrowleft"),
+    ("right", "arrowright"),
+    ("pgup", "pageup"),
+    ("pgdown", "pagedown"),
+];
+
+/// The modifiers that make up shortcuts, in the order they are written
+const MODIFIERS: [(Modifiers, &str); 4] = [
+    (Modifiers::CONTROL, "ctrl"),
+    (Modifiers::ALT, "alt"),
+    (Modifiers::META, "meta"),
+    (Modifiers::SHIFT, "shift"),
+];
+
+/// A key pressed with modifiers
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Chord {
+    /// The modifiers held. Characters carry Shift themselves, so it is only
+    /// held for named keys.
+    mods: Modifiers,
+    /// The character typed, or the name of the key in lowercase
+    key: String,
+}
+
+impl Chord {
+    /// Creates a chord, writing a character typed with Shift as it is typed,
+    /// like `R` for `shift+r`
+    ///
+    /// Modifiers that don't make up shortcuts, like Caps Lock, are ignored.
+    fn new(held: Modifiers, key: &str) -> Self {
+        let mut mods = Modifiers::empty();
+        for (modifier, _name) in MODIFIERS {
+            mods.set(modifier, held.contains(modifier));
+        }
+        let key = if key.chars().count() == 1 {
+            let key = if mods.shift() {
+                key.to_uppercase()
+            } else {
+                key.to_owned()
+            };
+            mods.set(Modifiers::SHIFT, false);
+            key
+        } else {
+            let key = key.to_lowercase();
+            KEY_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == key)
+                .map_or(key, |(_, name)| (*name).to_owned())
+        };
+        Self {
+            mods,
+            key,
+        }
+    }
+
+    /// Parses a chord as written in the configuration, like `ctrl+shift+p`
+    fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let (modifiers, key) = text.rsplit_once('+').unwrap_or(("", text));
+        if key.is_empty() {
+            return None;
+        }
+        let mut mods = Modifiers::empty();
+        for modifier in modifiers.split('+').filter(|part| !part.is_empty()) {
+            let modifier = match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => Modifiers::CONTROL,
+                "alt" => Modifiers::ALT,
+                "meta" | "super" | "cmd" => Modifiers::META,
+                "shift" => Modifiers::SHIFT,
+                _ => return None,
+            };
+            mods.set(modifier, true);
+        }
+        Some(Self::new(mods, key))
+    }
+
+    /// The chord of a key press
+    fn pressed(event: &KeyEvent) -> Self {
+        match &event.key {
+            KbKey::Character(character) => Self::new(event.mods, character),
+            named => Self::new(event.mods, &named.to_string()),
+        }
+    }
+
+    /// Whether the chord can't be typed into a text field
+    fn has_modifier(&self) -> bool {
+        !self.mods.is_empty() && self.mods != Modifiers::SHIFT
+    }
+}
+
+impl Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in MODIFIERS {
+            if self.mods.contains(modifier) {
+                write!(f, "{name}+")?;
+            }
+        }
+        match self.key.as_str() {
+            " " => f.write_str("space"),
+            "+" => f.write_str("plus"),
+            key => f.write_str(key),
+        }
+    }
+}
+
+/// The actions bound to keys
+pub(crate) struct Shortcuts {
+    /// Actions by the chords that run them
+    actions: HashMap<Chord, Action>,
+}
+
+impl Shortcuts {
+    /// Binds the keys of the configured keymap, then the configured bindings
+    ///
+    /// Bindings of unknown keys or actions are left out with a warning.
+    pub(crate) fn new(config: &KeyBindings) -> Self {
+        let defaults: &[(&str, Action)] = match config.keymap {
+            Keymap::Vim => &VIM_KEYS,
+            Keymap::Conventional => &CONVENTIONAL_KEYS,
+        };
+        let mut actions: HashMap<Chord, Action> = defaults
+            .iter()
+            .map(|(key, action)| {
+                let chord =
+                    Chord::parse(key).expect("Built-in shortcuts are valid");
+                (chord, *action)
+            })
+            .collect();
+        for (key, name) in &config.bindings {
+            let Some(chord) = Chord::parse(key) else {
+                log::warn!("Ignoring the shortcut of unknown key {key:?}");
+                continue;
+            };
+            if name == UNBOUND {
+                actions.remove(&chord);
+            } else if let Some(action) = Action::from_name(name) {
+                actions.insert(chord, action);
+            } else {
+                log::warn!("Ignoring the shortcut {key:?} to unknown {name:?}");
+            }
+        }
+        Self {
+            actions,
+        }
+    }
+
+    /// The action of a key press, if it is a shortcut
+    ///
+    /// Keys that could be typed are only shortcuts when nothing is being
+    /// typed.
+    fn action(&self, event: &KeyEvent, typing: bool) -> Option<Action> {
+        let chord = Chord::pressed(event);
+        if typing && !chord.has_modifier() {
+            return None;
+        }
+        self.actions.get(&chord).copied()
+    }
+
+    /// Every action with its keys, as the command palette lists them
+    pub(crate) fn palette(&self) -> Vector<PaletteEntry> {
+        let mut keys: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (chord, action) in &self.actions {
+            keys.entry(action.name()).or_default().push(chord.to_string());
+        }
+        ACTIONS
+            .into_iter()
+            .map(|action| {
+                let mut bound = keys.remove(action.name()).unwrap_or_default();
+                bound.sort();
+                PaletteEntry {
+                    action,
+                    description: action.description().to_owned(),
+                    keys: bound.join(", "),
+                    highlighted: false,
+                }
+            })
+            .collect()
+    }
+}
+
+/// The selectable folder below or above the selected one, or the first if
+/// none is selected
+fn neighbour_folder(data: &AppState, below: bool) -> Option<FolderEntry> {
+    let rows = data.folder_rows();
+    let folders: Vec<&FolderEntry> =
+        rows.iter().filter(|folder| folder.listing.is_some()).collect();
+    let selected = data.listing.as_ref().and_then(|listing| {
+        folders
+            .iter()
+            .position(|folder| folder.listing.as_ref() == Some(listing))
+    });
+    let index = match selected {
+        None => 0,
+        Some(index) if below => index + 1,
+        Some(index) => index.checked_sub(1)?,
+    };
+    folders.get(index).map(|folder| (*folder).clone())
+}
+
+/// Runs an action on the main window
+fn run(action: Action, ctx: &mut EventCtx, data: &mut AppState) {
+    log::trace!("GUI running {action:?}");
+    if action != Action::CommandPalette {
+        data.palette = None;
+    }
+    let selected = data.message_list.selected.as_ref();
+    match action {
+        Action::NextMessage | Action::PreviousMessage => {
+            let below = action == Action::NextMessage;
+            if let Some(message) = data.message_list.neighbour(below) {
+                ctx.submit_command(SELECT_MESSAGE.with(message.clone()));
+            }
+        }
+        Action::NextFolder | Action::PreviousFolder => {
+            let below = action == Action::NextFolder;
+            if let Some(folder) = neighbour_folder(data, below) {
+                ctx.submit_command(SELECT_FOLDER.with(folder));
+            }
+        }
+        Action::Compose => ctx.submit_command(COMPOSE.with(ComposeKind::New)),
+        Action::Reply => ctx.submit_command(COMPOSE.with(ComposeKind::Reply)),
+        Action::ReplyAll => {
+            ctx.submit_command(COMPOSE.with(ComposeKind::ReplyAll));
+        }
+        Action::Forward => {
+            ctx.submit_command(COMPOSE.with(ComposeKind::Forward));
+        }
+        Action::Archive => {
+            ctx.submit_command(CHANGE_EMAIL.with(EmailChange::Archive));
+        }
+        Action::Delete => {
+            ctx.submit_command(CHANGE_EMAIL.with(EmailChange::Delete));
+        }
+        Action::ToggleRead => {
+            if let Some(message) = selected {
+                let change = EmailChange::Read(message.unread);
+                ctx.submit_command(CHANGE_EMAIL.with(change));
+            }
+        }
+        Action::ToggleFlag => {
+            if let Some(message) = selected {
+                let change = EmailChange::Flagged(!message.flagged);
+                ctx.submit_command(CHANGE_EMAIL.with(change));
+            }
+        }
+        Action::ToggleHtml => {
+            if let Some(reader) = &mut data.reader {
+                if reader.html.is_some() {
+                    reader.show_plain = !reader.show_plain;
+                }
+            }
+        }
+        Action::Search => ctx.set_focus(SEARCH_FIELD),
+        Action::SyncNow => ctx.submit_command(SYNC_NOW),
+        Action::CommandPalette => {
+            data.palette = match data.palette {
+                Some(_) => None,
+                None => Some(String::new()),
+            };
+        }
+    }
+}
+
+/// Runs the actions of the keys pressed on the main window and of the
+/// command palette
+///
+/// Wraps the root of the main window, which takes the focus when the window
+/// opens and whenever a text field gives it back.
+pub(crate) struct KeyboardShortcuts {
+    /// The actions bound to keys
+    shortcuts: Shortcuts,
+}
+
+impl KeyboardShortcuts {
+    /// Runs the actions bound to keys by the shortcuts
+    pub(crate) fn new(shortcuts: Shortcuts) -> Self {
+        Self {
+            shortcuts,
+        }
+    }
+}
+
+impl<W: Widget<AppState>> Controller<AppState, W> for KeyboardShortcuts {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
+        match event {
+            Event::WindowConnected => ctx.request_focus(),
+            Event::KeyDown(key) => {
+                // Plain keys go to the text field being typed into, if any
+                let typing = !ctx.is_focused();
+                if let Some(action) = self.shortcuts.action(key, typing) {
+                    ctx.request_focus();
+                    run(action, ctx, data);
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            Event::Command(cmd) if cmd.is(FOCUS) => {
+                ctx.request_focus();
+                ctx.set_handled();
+                return;
+            }
+            Event::Command(cmd) => {
+                if let Some(action) = cmd.get(RUN_ACTION) {
+                    ctx.request_focus();
+                    run(*action, ctx, data);
+                    ctx.set_handled();
+                    return;
+                }
+            }
+            _ => {}
+        }
+        child.event(ctx, event, data, env);
+    }
+
+    fn update(
+        &mut self,
+        child: &mut W,
+        ctx: &mut UpdateCtx,
+        old_data: &AppState,
+        data: &AppState,
+        env: &Env,
+    ) {
+        // Typing goes to the palette while it is open
+        match (&old_data.palette, &data.palette) {
+            (None, Some(_)) => ctx.submit_command(FOCUS.to(PALETTE_FIELD)),
+            (Some(_), None) => ctx.submit_command(FOCUS.to(MAIN_WINDOW)),
+            _ => {}
+        }
+        child.update(ctx, old_data, data, env);
+    }
+}
+
+/// Lists the emails matching the query typed into the search field when
+/// Enter is pressed, and gives the focus back when Escape is
+pub(crate) struct SearchField;
+
+impl<W: Widget<AppState>> Controller<AppState, W> for SearchField {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
+        match event {
+            Event::KeyDown(key) if key.key == KbKey::Enter => {
+                ctx.submit_command(SEARCH.with(data.search.clone()));
+                ctx.set_focus(MAIN_WINDOW);
+                ctx.set_handled();
+            }
+            Event::KeyDown(key) if key.key == KbKey::Escape => {
+                ctx.set_focus(MAIN_WINDOW);
+                ctx.set_handled();
+            }
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+}
+
+/// Runs the first action matching what is typed into the command palette
+/// when Enter is pressed, and closes the palette when Escape is
+struct PaletteField;
+
+impl<W: Widget<AppState>> Controller<AppState, W> for PaletteField {
+    fn event(
+        &mut self,
+        child: &mut W,
+        ctx: &mut EventCtx,
+        event: &Event,
+        data: &mut AppState,
+        env: &Env,
+    ) {
+        match event {
+            Event::Command(cmd) if cmd.is(FOCUS) => {
+                ctx.request_focus();
+                ctx.set_handled();
+            }
+            Event::KeyDown(key) if key.key == KbKey::Enter => {
+                if let Some(entry) = data.matching_actions().front() {
+                    ctx.submit_command(
+                        RUN_ACTION.with(entry.action).to(MAIN_WINDOW),
+                    );
+                }
+                ctx.set_handled();
+            }
+            Event::KeyDown(key) if key.key == KbKey::Escape => {
+                data.palette = None;
+                ctx.set_handled();
+            }
+            _ => child.event(ctx, event, data, env),
+        }
+    }
+}
+
+/// Builds a row of the command palette, which runs its action when clicked
+fn palette_row() -> impl Widget<PaletteEntry> {
+    Flex::row()
+        .with_flex_child(
+            Label::dynamic(|entry: &PaletteEntry, _env| {
+                entry.description.clone()
+            })
+            .expand_width(),
+            1.0,
+        )
+        .with_child(
+            Label::dynamic(|entry: &PaletteEntry, _env| entry.keys.clone())
+                .with_font(bold()),
+        )
+        .padding(PADDING)
+        .background(selection_background(|entry: &PaletteEntry| {
+            entry.highlighted
+        }))
+        .on_click(|ctx, entry: &mut PaletteEntry, _env| {
+            ctx.submit_command(RUN_ACTION.with(entry.action).to(MAIN_WINDOW));
+        })
+}
+
+/// Builds the command palette, listing the actions matching what is typed
+/// into it, shown above the panes while it is open
+pub(crate) fn palette() -> impl Widget<AppState> {
+    let field = TextBox::new()
+        .with_placeholder("Type to find an action…")
+        .expand_width()
+        .lens(lens::Map::new(
+            |data: &AppState| data.palette.clone().unwrap_or_default(),
+            |data: &mut AppState, filter: String| {
+                if let Some(palette) = &mut data.palette {
+                    *palette = filter;
+                }
+            },
+        ))
+        .controller(PaletteField)
+        .with_id(PALETTE_FIELD);
+    let actions = List::new(palette_row).lens(lens::Map::new(
+        AppState::matching_actions,
+        |_data: &mut AppState, _actions| {},
+    ));
+    Either::new(
+        |data: &AppState, _env| data.palette.is_some(),
+        Flex::column()
+            .with_child(field)
+            .with_spacer(PADDING)
+            .with_child(actions)
+            .padding(PADDING * 2.0),
+        SizedBox::empty(),
+    )
+}
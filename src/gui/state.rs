@@ -0,0 +1,583 @@
+//! The application state the GUI renders
+//!
+//! Druid redraws widgets whenever the part of the state they are bound to
+//! changes, so everything shown in the windows lives in [`AppState`].
+//! Collections are persistent vectors, which are cheap to clone and compare
+//! between updates.
+
+use std::{collections::HashSet, path::PathBuf};
+
+use druid::{
+    im::{OrdMap, Vector},
+    text::RichText,
+    Data, Lens,
+};
+
+use super::shortcuts::Action;
+use crate::{
+    config::Account,
+    database::listing::{Listing, Sort},
+    mail::compose::Draft,
+};
+
+/// The separator of nested mailbox names used to build the folder tree
+const MAILBOX_DELIMITER: char = '/';
+
+/// How many emails are loaded from the database at a time
+pub(crate) const PAGE_SIZE: usize = 100;
+
+/// The views of the folder tree that list emails of every account, by name
+/// and query. The unified inbox is built from the accounts' inboxes.
+const VIEWS: [(&str, &str); 2] =
+    [("All Unread", "is:unread"), ("All Flagged", "is:flagged")];
+
+/// A row of the folder tree
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct FolderEntry {
+    /// The emails the row lists: those of a mailbox, or those of every
+    /// account matching a query. Rows of accounts and of parents that aren't
+    /// synchronized themselves have none and can't be selected.
+    pub(crate) listing: Option<Listing>,
+    /// The text of the row, which is the last part of the mailbox name
+    pub(crate) name: String,
+    /// How deep the row is nested, accounts being at the top
+    pub(crate) depth: u32,
+    /// How many of the listed emails are unread, for smart folders
+    pub(crate) unread: Option<usize>,
+    /// Whether the row is a smart folder, which the user can delete
+    pub(crate) saved: bool,
+}
+
+/// A row of the message list
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct MessageSummary {
+    /// The account the email belongs to
+    pub(crate) account: String,
+    /// The mailbox the email is in
+    pub(crate) mailbox: String,
+    /// UID of the email in its mailbox
+    pub(crate) uid: u32,
+    /// The senders, as they should be displayed
+    pub(crate) from: String,
+    /// Subject
+    pub(crate) subject: String,
+    /// Date, as it should be displayed
+    pub(crate) date: String,
+    /// Size, as it should be displayed
+    pub(crate) size: String,
+    /// Whether the email hasn't been read yet
+    pub(crate) unread: bool,
+    /// Whether the email is flagged
+    pub(crate) flagged: bool,
+    /// The account shown in the row, when emails of several accounts are
+    /// listed
+    pub(crate) origin: Option<String>,
+    /// The part of the body matching a search, with matched terms marked, or
+    /// nothing
+    pub(crate) snippet: String,
+}
+
+impl MessageSummary {
+    /// Whether the row is for the same email as another one
+    pub(crate) fn is(&self, other: &Self) -> bool {
+        self.account == other.account
+            && self.mailbox == other.mailbox
+            && self.uid == other.uid
+    }
+}
+
+/// A file attached to the email shown in the reader
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct AttachmentEntry {
+    /// Hash of the file in the blob store
+    pub(crate) blob: String,
+    /// The name to save the file under
+    pub(crate) name: String,
+    /// The MIME type of the file
+    pub(crate) mime_type: String,
+    /// Size, as it should be displayed
+    pub(crate) size: String,
+}
+
+/// A row of the conversation of the email shown in the reader
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct ConversationEntry {
+    /// The email, as it would be listed
+    pub(crate) email: MessageSummary,
+    /// Whether it is the email shown in the reader
+    pub(crate) current: bool,
+}
+
+/// The email shown in the reader
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct ReaderState {
+    /// Subject
+    pub(crate) subject: String,
+    /// The senders
+    pub(crate) from: String,
+    /// Bare address of the first sender, if any
+    pub(crate) sender: Option<String>,
+    /// The primary and carbon copy recipients
+    pub(crate) to: String,
+    /// Date
+    pub(crate) date: String,
+    /// The plain text of the email, or what is shown while it downloads
+    pub(crate) plain: RichText,
+    /// The HTML of the email made safe to show, if it has any
+    pub(crate) html: Option<RichText>,
+    /// Whether the plain text is shown even though the email has HTML
+    pub(crate) show_plain: bool,
+    /// Whether the user allowed remote content in the email
+    pub(crate) remote_content: bool,
+    /// How many remote images the HTML left out
+    pub(crate) blocked: usize,
+    /// How many tracking pixels the HTML left out
+    pub(crate) trackers: usize,
+    /// The files attached to the email
+    pub(crate) attachments: Vector<AttachmentEntry>,
+    /// The emails of the conversation the email is in, in reply order
+    pub(crate) conversation: Vector<ConversationEntry>,
+}
+
+impl ReaderState {
+    /// The body to show, the HTML unless the plain text was asked for
+    pub(crate) fn body(&self) -> RichText {
+        match &self.html {
+            Some(html) if !self.show_plain => html.clone(),
+            _ => self.plain.clone(),
+        }
+    }
+}
+
+impl Default for ReaderState {
+    /// Nothing to show yet
+    fn default() -> Self {
+        Self {
+            subject: String::new(),
+            from: String::new(),
+            sender: None,
+            to: String::new(),
+            date: String::new(),
+            plain: RichText::new("".into()),
+            html: None,
+            show_plain: false,
+            remote_content: false,
+            blocked: 0,
+            trackers: 0,
+            attachments: Vector::new(),
+            conversation: Vector::new(),
+        }
+    }
+}
+
+impl Data for Sort {
+    fn same(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+impl Data for Listing {
+    fn same(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+/// Emails of the message list loaded from the database
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct Page {
+    /// The version of the list the page was loaded for
+    pub(crate) version: u64,
+    /// Rows of the page, in order
+    pub(crate) rows: Vector<MessageSummary>,
+}
+
+/// The message list, of which only the pages that have been scrolled to are
+/// loaded
+#[derive(Clone, Data, Lens, Default)]
+pub(crate) struct MessageListState {
+    /// How many emails the listed folder has
+    pub(crate) total: usize,
+    /// Loaded pages by their index
+    pub(crate) pages: OrdMap<usize, Page>,
+    /// Increases whenever the loaded pages may be out of date. Outdated pages
+    /// are still shown until they have been loaded again.
+    pub(crate) version: u64,
+    /// The order of the emails
+    pub(crate) sort: Sort,
+    /// The email shown in the reader
+    pub(crate) selected: Option<MessageSummary>,
+}
+
+impl MessageListState {
+    /// The row at an index, if its page has been loaded
+    pub(crate) fn row(&self, index: usize) -> Option<&MessageSummary> {
+        self.pages.get(&(index / PAGE_SIZE))?.rows.get(index % PAGE_SIZE)
+    }
+
+    /// The index of the row of an email, if its page has been loaded
+    pub(crate) fn index_of(&self, message: &MessageSummary) -> Option<usize> {
+        self.pages.iter().find_map(|(page, loaded)| {
+            let row = loaded.rows.iter().position(|row| row.is(message))?;
+            Some(page * PAGE_SIZE + row)
+        })
+    }
+
+    /// The row below or above the selected one, or the first row if none is
+    /// selected, if its page has been loaded
+    pub(crate) fn neighbour(&self, below: bool) -> Option<&MessageSummary> {
+        let selected =
+            self.selected.as_ref().and_then(|selected| self.index_of(selected));
+        let index = match selected {
+            None => 0,
+            Some(index) if below => index + 1,
+            Some(index) => index.checked_sub(1)?,
+        };
+        self.row(index)
+    }
+
+    /// Whether a page has to be loaded, because it hasn't been or is out of
+    /// date
+    pub(crate) fn needs(&self, page: usize) -> bool {
+        self.pages
+            .get(&page)
+            .is_none_or(|loaded| loaded.version != self.version)
+    }
+
+    /// Empties the list, to list another folder or another order
+    pub(crate) fn reset(&mut self) {
+        self.version += 1;
+        self.pages.clear();
+        self.total = 0;
+        self.selected = None;
+    }
+}
+
+/// A recipient field of the composer
+#[derive(Clone, Copy, Data, PartialEq, Eq, Debug)]
+pub(crate) enum AddressField {
+    /// The primary recipients
+    To,
+    /// The carbon copy recipients
+    Cc,
+    /// The blind carbon copy recipients
+    Bcc,
+}
+
+/// A contact suggested for the address being typed in the composer
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct Suggestion {
+    /// The draft the address is typed in
+    pub(crate) draft: u64,
+    /// The field the address is typed in
+    pub(crate) field: AddressField,
+    /// The address as it is written into the field
+    pub(crate) address: String,
+}
+
+/// A file attached to a draft
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct AttachedFile {
+    /// The draft the file is attached to
+    pub(crate) draft: u64,
+    /// Where the file is
+    #[data(eq)]
+    pub(crate) path: PathBuf,
+    /// The name of the file, as it should be displayed
+    pub(crate) name: String,
+}
+
+/// An email being written in a composer window
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct ComposerState {
+    /// Identifies the draft among the open composers
+    pub(crate) id: u64,
+    /// The account the email is sent from
+    pub(crate) account: String,
+    /// The primary recipients, as a comma separated list
+    pub(crate) to: String,
+    /// The carbon copy recipients, like `to`
+    pub(crate) cc: String,
+    /// The blind carbon copy recipients, like `to`
+    pub(crate) bcc: String,
+    /// Subject
+    pub(crate) subject: String,
+    /// The plain text of the email
+    pub(crate) body: String,
+    /// The message ID of the email replied to
+    pub(crate) in_reply_to: Option<String>,
+    /// The message IDs of the conversation replied to, oldest first
+    pub(crate) references: Vector<String>,
+    /// The mailbox and UID of the email replied to, which is marked as
+    /// answered once the reply is sent
+    pub(crate) replied: Option<(String, u32)>,
+    /// The files to attach
+    pub(crate) attachments: Vector<AttachedFile>,
+    /// Contacts matching the address being typed
+    pub(crate) suggestions: Vector<Suggestion>,
+    /// What went wrong the last time the email was sent, if anything
+    pub(crate) status: String,
+    /// Whether the email is being sent
+    pub(crate) sending: bool,
+}
+
+impl AttachedFile {
+    /// Attaches a file to a draft
+    pub(crate) fn new(draft: u64, path: PathBuf) -> Self {
+        let name = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        Self {
+            draft,
+            path,
+            name,
+        }
+    }
+}
+
+impl ComposerState {
+    /// Creates the state of a composer showing a draft
+    pub(crate) fn new(
+        id: u64,
+        account: String,
+        draft: Draft,
+        replied: Option<(String, u32)>,
+    ) -> Self {
+        Self {
+            id,
+            account,
+            to: draft.to,
+            cc: draft.cc,
+            bcc: draft.bcc,
+            subject: draft.subject,
+            body: draft.body,
+            in_reply_to: draft.in_reply_to,
+            references: draft.references.into(),
+            replied,
+            attachments: draft
+                .attachments
+                .into_iter()
+                .map(|path| AttachedFile::new(id, path))
+                .collect(),
+            suggestions: Vector::new(),
+            status: String::new(),
+            sending: false,
+        }
+    }
+
+    /// The text of a recipient field
+    pub(crate) fn field(&mut self, field: AddressField) -> &mut String {
+        match field {
+            AddressField::To => &mut self.to,
+            AddressField::Cc => &mut self.cc,
+            AddressField::Bcc => &mut self.bcc,
+        }
+    }
+
+    /// The draft to send
+    pub(crate) fn draft(&self) -> Draft {
+        Draft {
+            to: self.to.clone(),
+            cc: self.cc.clone(),
+            bcc: self.bcc.clone(),
+            subject: self.subject.clone(),
+            body: self.body.clone(),
+            in_reply_to: self.in_reply_to.clone(),
+            references: self.references.iter().cloned().collect(),
+            attachments: self
+                .attachments
+                .iter()
+                .map(|attached| attached.path.clone())
+                .collect(),
+        }
+    }
+}
+
+/// A row of the command palette
+#[derive(Clone, Data, Lens, Debug)]
+pub(crate) struct PaletteEntry {
+    /// The action the row runs
+    pub(crate) action: Action,
+    /// What the action does
+    pub(crate) description: String,
+    /// The keys bound to the action, as they are written in the
+    /// configuration
+    pub(crate) keys: String,
+    /// Whether the row is the one pressing Enter runs
+    pub(crate) highlighted: bool,
+}
+
+/// Everything the windows show: the main window and the composers
+#[derive(Clone, Data, Lens, Default)]
+pub(crate) struct AppState {
+    /// Rows of the folder tree for the configured accounts, in display order
+    pub(crate) folders: Vector<FolderEntry>,
+    /// Rows of the saved searches, in display order below the other folders
+    pub(crate) smart_folders: Vector<FolderEntry>,
+    /// Which emails the message list shows: those of a folder of the folder
+    /// tree, which is selected, or those matching a search
+    pub(crate) listing: Option<Listing>,
+    /// The query typed into the search field
+    pub(crate) search: String,
+    /// The name typed for the listed search while it is being saved as a
+    /// smart folder
+    pub(crate) saving_search: Option<String>,
+    /// The listed emails
+    pub(crate) message_list: MessageListState,
+    /// What the reader shows, if an email is selected
+    pub(crate) reader: Option<ReaderState>,
+    /// Addresses of the accounts, in the order of the folder tree
+    pub(crate) accounts: Vector<String>,
+    /// The emails being written, by the ID of their draft. Each has a
+    /// composer window of its own.
+    pub(crate) drafts: OrdMap<u64, ComposerState>,
+    /// Every action of the command palette, with its keys
+    pub(crate) actions: Vector<PaletteEntry>,
+    /// What is typed into the command palette, while it is open
+    pub(crate) palette: Option<String>,
+    /// What went wrong with the database, while its operations keep failing
+    pub(crate) database_problem: Option<String>,
+}
+
+impl AppState {
+    /// Creates the state of a window showing the folders of the given
+    /// accounts, with nothing selected
+    pub(crate) fn new(accounts: &[Account]) -> Self {
+        Self {
+            folders: folder_tree(accounts),
+            accounts: accounts
+                .iter()
+                .map(|account| account.address.clone())
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Every row of the folder tree, the smart folders below a header of
+    /// their own after the folders of the accounts
+    pub(crate) fn folder_rows(&self) -> Vector<FolderEntry> {
+        let mut rows = self.folders.clone();
+        if !self.smart_folders.is_empty() {
+            rows.push_back(FolderEntry {
+                listing: None,
+                name: "Smart folders".to_owned(),
+                depth: 0,
+                unread: None,
+                saved: false,
+            });
+            rows.append(self.smart_folders.clone());
+        }
+        rows
+    }
+
+    /// The rows of the command palette matching what is typed into it, the
+    /// first one highlighted
+    pub(crate) fn matching_actions(&self) -> Vector<PaletteEntry> {
+        let filter = self.palette.as_deref().unwrap_or_default().to_lowercase();
+        self.actions
+            .iter()
+            .filter(|entry| {
+                entry.description.to_lowercase().contains(filter.trim())
+            })
+            .enumerate()
+            .map(|(index, entry)| PaletteEntry {
+                highlighted: index == 0,
+                ..entry.clone()
+            })
+            .collect()
+    }
+}
+
+/// Lists the rows of the views across the given accounts: the unified
+/// inbox, if any account synchronizes its inbox, and the other `VIEWS`
+fn views(accounts: &[Account]) -> Vector<FolderEntry> {
+    let mut inboxes: Vec<&str> = accounts
+        .iter()
+        .flat_map(|account| &account.folders)
+        .map(|folder| folder.mailbox.as_str())
+        .filter(|mailbox| mailbox.eq_ignore_ascii_case("INBOX"))
+        .collect();
+    inboxes.sort_unstable();
+    inboxes.dedup();
+    let unified_inbox = (!inboxes.is_empty()).then(|| {
+        let query = inboxes
+            .iter()
+            .map(|inbox| format!("folder:{inbox}"))
+            .collect::<Vec<String>>()
+            .join(" OR ");
+        ("Unified Inbox", query)
+    });
+    let mut rows = Vector::new();
+    rows.push_back(FolderEntry {
+        listing: None,
+        name: "All accounts".to_owned(),
+        depth: 0,
+        unread: None,
+        saved: false,
+    });
+    for (name, query) in unified_inbox
+        .into_iter()
+        .chain(VIEWS.iter().map(|(name, query)| (*name, (*query).to_owned())))
+    {
+        rows.push_back(FolderEntry {
+            listing: Some(Listing::Query(query)),
+            name: name.to_owned(),
+            depth: 1,
+            unread: None,
+            saved: false,
+        });
+    }
+    rows
+}
+
+/// Lists the rows of the folder tree of the given accounts
+///
+/// The views across every account come first. Each account is followed by
+/// its folders, `INBOX` first and the rest in alphabetical order. Parents of
+/// nested mailboxes get a row even if they aren't synchronized, so the
+/// nesting shows.
+fn folder_tree(accounts: &[Account]) -> Vector<FolderEntry> {
+    if accounts.is_empty() {
+        return Vector::new();
+    }
+    let mut rows = views(accounts);
+    for account in accounts {
+        rows.push_back(FolderEntry {
+            listing: None,
+            name: account.address.clone(),
+            depth: 0,
+            unread: None,
+            saved: false,
+        });
+        let mut mailboxes: Vec<&str> = account
+            .folders
+            .iter()
+            .map(|folder| folder.mailbox.as_str())
+            .collect();
+        mailboxes.sort_by_key(|mailbox| {
+            (!mailbox.eq_ignore_ascii_case("INBOX"), mailbox.to_lowercase())
+        });
+        let mut added = HashSet::new();
+        for mailbox in mailboxes {
+            let parts: Vec<&str> = mailbox.split(MAILBOX_DELIMITER).collect();
+            for (index, (part, depth)) in parts.iter().zip(1..).enumerate() {
+                let path = parts[..=index].join(&MAILBOX_DELIMITER.to_string());
+                if !added.insert(path.clone()) {
+                    continue;
+                }
+                let is_mailbox = index == parts.len() - 1;
+                rows.push_back(FolderEntry {
+                    listing: is_mailbox.then(|| Listing::Mailbox {
+                        account: account.address.clone(),
+                        mailbox: path,
+                    }),
+                    name: (*part).to_owned(),
+                    depth,
+                    unread: None,
+                    saved: false,
+                });
+            }
+        }
+    }
+    rows
+}
@@ -2,10 +2,40 @@
 //!
 //! The star of the show for this crate is `MailAgent`
 
-use actix::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
 
-use super::imap_toolbox::{self, Errors};
-use crate::{config::Account, database::DatabaseActor};
+use actix::{dev::ToEnvelope, prelude::*};
+use time::OffsetDateTime;
+
+use super::{
+    compose::{self, Draft},
+    imap_toolbox::{self, Errors, Moved},
+    smtp,
+};
+use crate::{
+    config::Account,
+    database::{
+        self,
+        action_queue::{
+            Action, DequeueActionMessage, NextActionMessage,
+            QueueActionMessage, QueuedAction,
+        },
+        mail_sync::{
+            MailboxFlagsMessage, MailboxStateMessage, SaveMailboxStateMessage,
+            SetFlagsMessage,
+        },
+        mailbox_changes::{
+            RemoveEmailsMessage, RestoreEmailsMessage, UpdateFlagsMessage,
+        },
+        query::Query,
+        search::{MissingUidsMessage, RemoteHits},
+        structures::EmailRecord,
+        DatabaseActor, InsertEmailsMessage, INSERT_CHUNK_SIZE,
+    },
+};
 
 /// An actor that handles all transactions for a given email account
 pub(crate) struct MailActor {
@@ -13,6 +43,10 @@ pub(crate) struct MailActor {
     pub(crate) account: Account,
     /// Address of the database actor for inter-actor communication
     db_address: Addr<DatabaseActor>,
+    /// Whether the server couldn't be reached the last time it was tried.
+    /// Actions are only queued while this is set, until the next fetch
+    /// reconnects.
+    offline: bool,
 }
 
 impl MailActor {
@@ -24,8 +58,83 @@ impl MailActor {
         Self {
             account,
             db_address,
+            offline: false,
         }
     }
+
+    /// Notes whether the server could be reached while running an operation
+    fn track<T>(&mut self, result: Result<T, Errors>) -> Result<T, Errors> {
+        match &result {
+            Err(Errors::Connect | Errors::Login) => {
+                if !self.offline {
+                    log::info!(
+                        "Actor for {} went offline, queueing actions",
+                        self.account.address
+                    );
+                }
+                self.offline = true;
+            }
+            // The server wasn't necessarily tried
+            Err(Errors::Store(_) | Errors::Unreachable(_)) => {}
+            _ => {
+                if self.offline {
+                    log::info!(
+                        "Actor for {} is back online",
+                        self.account.address
+                    );
+                }
+                self.offline = false;
+            }
+        }
+        result
+    }
+
+    /// Applies an action to the local copy of its mailbox and queues it, then
+    /// runs the queue unless the account is offline
+    ///
+    /// Actions the server rejects are undone locally while the queue runs, so
+    /// only failures to reach the server or the database are returned.
+    fn submit(
+        &self,
+        action: Action,
+        ctx: &mut Context<Self>,
+    ) -> AtomicResponse<Self, Result<(), Errors>> {
+        let address = self.db_address.clone();
+        let account = self.account.clone();
+        let mail_actor = ctx.address();
+        let offline = self.offline;
+        AtomicResponse::new(Box::pin(
+            async move {
+                let before =
+                    apply_locally(&address, &account.address, &action).await?;
+                update_database(
+                    &address,
+                    &account.address,
+                    QueueActionMessage {
+                        account: account.address.clone(),
+                        action,
+                        before,
+                    },
+                )
+                .await?;
+                if offline {
+                    return Ok(());
+                }
+                replay(&address, &mail_actor, &account).await
+            }
+            .into_actor(self)
+            .map(move |result, actor, _ctx| {
+                if offline {
+                    return result;
+                }
+                match actor.track(result) {
+                    // The action stays queued until the next fetch reconnects
+                    Err(Errors::Connect | Errors::Login) => Ok(()),
+                    result => result,
+                }
+            }),
+        ))
+    }
 }
 
 impl Actor for MailActor {
@@ -45,39 +154,878 @@ pub(crate) struct FetchMessage {
 }
 
 impl Handler<FetchMessage> for MailActor {
-    type Result = Result<(), Errors>;
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
 
     fn handle(
         &mut self,
         msg: FetchMessage,
-        _ctx: &mut Context<Self>,
+        ctx: &mut Context<Self>,
     ) -> Self::Result {
         log::trace!("Actor for {} received {msg:?}", self.account.address);
-        let mail =
-            match imap_toolbox::fetch_mailbox(&self.account, &msg.mailbox) {
-                Ok(mail) => mail,
-                Err(e) => {
+        let address = self.db_address.clone();
+        let account = self.account.clone();
+        let mail_actor = ctx.address();
+        AtomicResponse::new(Box::pin(
+            async move {
+                // Queued actions go first, so emails they moved or deleted
+                // aren't fetched again
+                replay(&address, &mail_actor, &account).await?;
+                let known = update_database(
+                    &address,
+                    &account.address,
+                    MailboxStateMessage {
+                        account: account.address.clone(),
+                        mailbox: msg.mailbox.clone(),
+                    },
+                )
+                .await?;
+                let mut sync = unblock({
+                    let account = account.clone();
+                    let mailbox = msg.mailbox.clone();
+                    move || {
+                        let policy = account.sync_policy(&mailbox).clone();
+                        imap_toolbox::MailboxSync::open(
+                            &account,
+                            &mailbox,
+                            policy,
+                            known.as_ref(),
+                        )
+                    }
+                })
+                .await
+                .inspect_err(|e| {
                     log::warn!(
-                        "Actor for {} received error \"{e:?}\" when running \
+                        "Actor for {} received error \"{e}\" when running \
                          {msg:?}",
-                        self.account.address
+                        account.address
                     );
-                    return Err(e);
-                }
-            };
-        log::trace!("Actor for {} fetched mail", self.account.address);
-        for message in mail {
-            let address = self.db_address.clone();
-            actix::spawn(async move {
-                log::trace!("Sending message to database");
-                address
-                    .send(crate::database::NewEmailMessage {
-                        email: message,
+                })?;
+                reconcile(&address, &account.address, &msg.mailbox, &mut sync)
+                    .await?;
+                // Each chunk is stored before the next one is downloaded, so
+                // a large mailbox is never held in memory whole and can't
+                // flood the database actor's mailbox
+                loop {
+                    let (returned, emails) = unblock(move || {
+                        let emails = sync.next_chunk(INSERT_CHUNK_SIZE)?;
+                        Ok((sync, emails))
                     })
                     .await
-                    .expect("Sending message failed");
+                    .inspect_err(|e| {
+                        log::warn!(
+                            "Actor for {} received error \"{e}\" when running \
+                             {msg:?}",
+                            account.address
+                        );
+                    })?;
+                    sync = returned;
+                    let Some(emails) = emails else {
+                        break;
+                    };
+                    log::trace!("Sending {} emails to database", emails.len());
+                    update_database(
+                        &address,
+                        &account.address,
+                        InsertEmailsMessage {
+                            account: account.address.clone(),
+                            mailbox: msg.mailbox.clone(),
+                            emails,
+                        },
+                    )
+                    .await?;
+                }
+                log::trace!("Actor for {} fetched mail", account.address);
+                let state = sync.state.take();
+                unblock(move || sync.finish()).await?;
+                // Only remembered once everything was stored, so a failed
+                // synchronization is retried in full
+                if let Some(state) = state {
+                    update_database(
+                        &address,
+                        &account.address,
+                        SaveMailboxStateMessage {
+                            account: account.address.clone(),
+                            mailbox: msg.mailbox,
+                            state,
+                        },
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track(result)),
+        ))
+    }
+}
+
+/// A message to search a mailbox on the IMAP server instead of locally
+///
+/// This covers mailboxes that aren't fully synced. Envelopes of matching
+/// messages that aren't in the database yet are fetched and stored so the hits
+/// can be shown next to local results.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<RemoteHits, Errors>")]
+pub(crate) struct RemoteSearchMessage {
+    /// Which mailbox to search
+    pub(crate) mailbox: String,
+    /// What to search for
+    pub(crate) query: Query,
+}
+
+impl Handler<RemoteSearchMessage> for MailActor {
+    type Result = ResponseActFuture<Self, Result<RemoteHits, Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: RemoteSearchMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        let address = self.db_address.clone();
+        let account = self.account.clone();
+        Box::pin(
+            async move {
+                let uids = unblock({
+                    let account = account.clone();
+                    let mailbox = msg.mailbox.clone();
+                    let criteria = msg.query.imap_criteria();
+                    move || {
+                        imap_toolbox::search_mailbox(
+                            &account, &mailbox, &criteria,
+                        )
+                    }
+                })
+                .await
+                .inspect_err(|e| {
+                    log::warn!(
+                        "Actor for {} received error \"{e}\" when searching {}",
+                        account.address,
+                        msg.mailbox
+                    );
+                })?;
+                let missing = update_database(
+                    &address,
+                    &account.address,
+                    MissingUidsMessage {
+                        account: account.address.clone(),
+                        mailbox: msg.mailbox.clone(),
+                        uids: uids.clone(),
+                    },
+                )
+                .await?;
+                for chunk in missing.chunks(INSERT_CHUNK_SIZE) {
+                    let emails = unblock({
+                        let account = account.clone();
+                        let mailbox = msg.mailbox.clone();
+                        let chunk = chunk.to_vec();
+                        move || {
+                            imap_toolbox::fetch_envelopes(
+                                &account, &mailbox, &chunk,
+                            )
+                        }
+                    })
+                    .await?;
+                    update_database(
+                        &address,
+                        &account.address,
+                        InsertEmailsMessage {
+                            account: account.address.clone(),
+                            mailbox: msg.mailbox.clone(),
+                            emails,
+                        },
+                    )
+                    .await?;
+                }
+                Ok(RemoteHits {
+                    account: account.address,
+                    mailbox: msg.mailbox,
+                    uids,
+                })
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track(result)),
+        )
+    }
+}
+
+/// A message to download messages in full that the sync policy left without
+/// a body or without some attachments, e.g. because they are old or large
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct FetchBodiesMessage {
+    /// The mailbox the messages are in
+    pub(crate) mailbox: String,
+    /// UIDs of the messages to download
+    pub(crate) uids: Vec<u32>,
+}
+
+impl Handler<FetchBodiesMessage> for MailActor {
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: FetchBodiesMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        let address = self.db_address.clone();
+        let account = self.account.clone();
+        AtomicResponse::new(Box::pin(
+            async move {
+                for chunk in msg.uids.chunks(INSERT_CHUNK_SIZE) {
+                    let emails = unblock({
+                        let account = account.clone();
+                        let mailbox = msg.mailbox.clone();
+                        let chunk = chunk.to_vec();
+                        move || {
+                            imap_toolbox::fetch_messages(
+                                &account, &mailbox, &chunk,
+                            )
+                        }
+                    })
+                    .await?;
+                    update_database(
+                        &address,
+                        &account.address,
+                        InsertEmailsMessage {
+                            account: account.address.clone(),
+                            mailbox: msg.mailbox.clone(),
+                            emails,
+                        },
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            .into_actor(self)
+            .map(|result, actor, _ctx| actor.track(result)),
+        ))
+    }
+}
+
+/// Brings the emails stored for a mailbox up to date with what the server
+/// reported when it was opened
+///
+/// Emails that are gone from the server are removed, and emails whose flags
+/// changed get the flags they have now.
+async fn reconcile(
+    address: &Addr<DatabaseActor>,
+    account: &str,
+    mailbox: &str,
+    sync: &mut imap_toolbox::MailboxSync,
+) -> Result<(), Errors> {
+    let local = update_database(
+        address,
+        account,
+        MailboxFlagsMessage {
+            account: account.to_owned(),
+            mailbox: mailbox.to_owned(),
+        },
+    )
+    .await?;
+    let vanished: Vec<u32> = local
+        .keys()
+        .copied()
+        .filter(|uid| sync.reset || !sync.uids.contains(uid))
+        .collect();
+    if !vanished.is_empty() {
+        log::trace!("{} emails vanished from {mailbox}", vanished.len());
+        update_database(
+            address,
+            account,
+            RemoveEmailsMessage {
+                account: account.to_owned(),
+                mailbox: mailbox.to_owned(),
+                uids: vanished,
+            },
+        )
+        .await?;
+    }
+    let changed: HashMap<u32, Vec<String>> = mem::take(&mut sync.flags)
+        .into_iter()
+        .filter(|(uid, flags)| {
+            local.get(uid).is_some_and(|before| {
+                before.iter().collect::<HashSet<&String>>()
+                    != flags.iter().collect::<HashSet<&String>>()
+            })
+        })
+        .collect();
+    if !changed.is_empty() {
+        log::trace!("Flags of {} emails in {mailbox} changed", changed.len());
+        update_database(
+            address,
+            account,
+            SetFlagsMessage {
+                account: account.to_owned(),
+                mailbox: mailbox.to_owned(),
+                flags: changed,
+            },
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Runs blocking IMAP commands on a thread of their own, so the arbiter the
+/// mail actors share with the database and the GUI bridge keeps running
+async fn unblock<T, F>(operation: F) -> Result<T, Errors>
+where
+    F: FnOnce() -> Result<T, Errors> + Send + 'static,
+    T: Send + 'static,
+{
+    actix_rt::task::spawn_blocking(operation).await.unwrap_or_else(|e| {
+        log::warn!("The thread talking to the IMAP server failed: {e}");
+        Err(Errors::Disconnected)
+    })
+}
+
+/// Sends a message to the database actor, logging any failure
+async fn update_database<M, T>(
+    address: &Addr<DatabaseActor>,
+    account: &str,
+    message: M,
+) -> Result<T, Errors>
+where
+    M: Message<Result = Result<T, database::Errors>> + Send + 'static,
+    T: Send + 'static,
+    DatabaseActor: Handler<M>,
+    <DatabaseActor as Actor>::Context: ToEnvelope<DatabaseActor, M>,
+{
+    match address.send(message).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            log::warn!("Actor for {account} failed to update mail: {e}");
+            Err(Errors::Store(e))
+        }
+        Err(e) => {
+            log::warn!("Actor for {account} failed to reach the database: {e}");
+            Err(Errors::Unreachable(e))
+        }
+    }
+}
+
+/// Applies an action to the local copy of its mailbox ahead of the server
+///
+/// Responds with the changed records as they were before.
+async fn apply_locally(
+    address: &Addr<DatabaseActor>,
+    account: &str,
+    action: &Action,
+) -> Result<Vec<EmailRecord>, Errors> {
+    match action {
+        Action::Flag {
+            mailbox,
+            uids,
+            flags,
+            add,
+        } => {
+            update_database(
+                address,
+                account,
+                UpdateFlagsMessage {
+                    account: account.to_owned(),
+                    mailbox: mailbox.clone(),
+                    uids: uids.clone(),
+                    flags: flags.clone(),
+                    add: *add,
+                },
+            )
+            .await
+        }
+        Action::Move {
+            mailbox,
+            uids,
+            ..
+        }
+        | Action::Delete {
+            mailbox,
+            uids,
+            ..
+        } => {
+            update_database(
+                address,
+                account,
+                RemoveEmailsMessage {
+                    account: account.to_owned(),
+                    mailbox: mailbox.clone(),
+                    uids: uids.clone(),
+                },
+            )
+            .await
+        }
+        // The copies only show up once the server has made them
+        Action::Copy {
+            ..
+        } => Ok(Vec::new()),
+    }
+}
+
+/// What the server did with an action
+enum Outcome {
+    /// Flags were changed, except on emails that no longer exist
+    Flagged {
+        /// UIDs of the emails that no longer exist
+        vanished: Vec<u32>,
+    },
+    /// Emails were moved, possibly to the trash
+    Moved(Moved),
+    /// Emails were copied to the given mailbox
+    Copied(String),
+    /// Emails were deleted permanently
+    Expunged,
+    /// The mailbox of the action no longer exists
+    Vanished,
+}
+
+/// Runs the actions queued for an account in order
+///
+/// Every action runs over the same connection on a blocking thread, so
+/// replaying a long queue neither reconnects for each action nor stalls the
+/// arbiter. Stops at the first action for which the server can't be reached
+/// or its mailbox can't be opened, leaving it and every later action queued.
+async fn replay(
+    address: &Addr<DatabaseActor>,
+    mail_actor: &Addr<MailActor>,
+    account: &Account,
+) -> Result<(), Errors> {
+    let mut session = imap_toolbox::ActionSession::new(account.clone());
+    loop {
+        let next = update_database(
+            address,
+            &account.address,
+            NextActionMessage {
+                account: account.address.clone(),
+            },
+        )
+        .await?;
+        let Some(queued) = next else {
+            break;
+        };
+        log::trace!(
+            "Actor for {} running {:?}",
+            account.address,
+            queued.action
+        );
+        let action = queued.action.clone();
+        let (returned, outcome) = unblock(move || {
+            let outcome = perform(&mut session, &action);
+            Ok((session, outcome))
+        })
+        .await?;
+        session = returned;
+        let sequence = queued.sequence;
+        settle(address, mail_actor, account, queued, outcome).await?;
+        update_database(
+            address,
+            &account.address,
+            DequeueActionMessage {
+                account: account.address.clone(),
+                sequence,
+            },
+        )
+        .await?;
+    }
+    // Every action has been settled by now, so there is nothing left to redo
+    if let Err(e) = unblock(move || session.finish()).await {
+        log::warn!(
+            "Actor for {} received error \"{e}\" after replaying actions",
+            account.address
+        );
+    }
+    Ok(())
+}
+
+/// Runs an action on the server
+///
+/// An action whose mailbox fails to open only comes back as `Vanished` if the
+/// mailbox is gone for good, since it may fail to open for a moment.
+fn perform(
+    session: &mut imap_toolbox::ActionSession,
+    action: &Action,
+) -> Result<Outcome, Errors> {
+    let outcome = match action {
+        Action::Flag {
+            mailbox,
+            uids,
+            flags,
+            add,
+        } => session.store_flags(mailbox, uids, flags, *add).map(|vanished| {
+            Outcome::Flagged {
+                vanished,
+            }
+        }),
+        Action::Move {
+            mailbox,
+            uids,
+            destination,
+        } => session
+            .move_messages(mailbox, uids, destination)
+            .map(Outcome::Moved),
+        Action::Copy {
+            mailbox,
+            uids,
+            destination,
+        } => session
+            .copy_messages(mailbox, uids, destination)
+            .map(|()| Outcome::Copied(destination.clone())),
+        Action::Delete {
+            mailbox,
+            uids,
+            permanently,
+        } => session
+            .delete_messages(mailbox, uids, *permanently)
+            .map(|moved| moved.map_or(Outcome::Expunged, Outcome::Moved)),
+    };
+    match outcome {
+        Err(Errors::Select) => match session.mailbox_exists(action.mailbox()) {
+            Ok(false) => Ok(Outcome::Vanished),
+            Ok(true) | Err(_) => Err(Errors::Select),
+        },
+        outcome => outcome,
+    }
+}
+
+/// Settles the local effects of a queued action the server has run
+///
+/// An action the server rejects is undone locally. Emails that vanished in the
+/// meantime, e.g. because another client deleted them, are dropped locally
+/// instead. Only failures to reach the server or the database, or to open a
+/// mailbox that still exists, are returned.
+async fn settle(
+    address: &Addr<DatabaseActor>,
+    mail_actor: &Addr<MailActor>,
+    account: &Account,
+    queued: QueuedAction,
+    outcome: Result<Outcome, Errors>,
+) -> Result<(), Errors> {
+    let mailbox = queued.action.mailbox().to_owned();
+    match outcome {
+        Err(e @ (Errors::Connect | Errors::Login)) => return Err(e),
+        Err(Errors::Select) => {
+            log::warn!(
+                "Actor for {} failed to open {mailbox}, keeping {:?} queued",
+                account.address,
+                queued.action
+            );
+            return Err(Errors::Select);
+        }
+        Ok(Outcome::Vanished) => {
+            log::warn!(
+                "Actor for {} dropped {:?} because {mailbox} vanished",
+                account.address,
+                queued.action
+            );
+            forget(address, account, mailbox, queued.action.uids()).await?;
+        }
+        Err(e) => {
+            log::warn!(
+                "Actor for {} received error \"{e}\", undoing {:?}",
+                account.address,
+                queued.action
+            );
+            update_database(
+                address,
+                &account.address,
+                RestoreEmailsMessage {
+                    emails: queued.before,
+                },
+            )
+            .await?;
+        }
+        Ok(Outcome::Flagged {
+            vanished,
+        }) => {
+            if !vanished.is_empty() {
+                forget(address, account, mailbox, &vanished).await?;
+            }
+        }
+        Ok(Outcome::Moved(moved)) if moved.uids.is_empty() => {
+            mail_actor.do_send(FetchMessage {
+                mailbox: moved.destination,
+            });
+        }
+        Ok(Outcome::Moved(moved)) => {
+            let emails = queued
+                .before
+                .into_iter()
+                .filter_map(|email| {
+                    let uid = *moved.uids.get(&email.uid_in(&mailbox)?)?;
+                    Some(email.relocate(&mailbox, &moved.destination, uid))
+                })
+                .collect();
+            update_database(
+                address,
+                &account.address,
+                RestoreEmailsMessage {
+                    emails,
+                },
+            )
+            .await?;
+        }
+        Ok(Outcome::Copied(destination)) => {
+            mail_actor.do_send(FetchMessage {
+                mailbox: destination,
             });
         }
-        Ok(())
+        Ok(Outcome::Expunged) => {}
+    }
+    Ok(())
+}
+
+/// Drops the local records of emails that no longer exist on the server
+async fn forget(
+    address: &Addr<DatabaseActor>,
+    account: &Account,
+    mailbox: String,
+    uids: &[u32],
+) -> Result<(), Errors> {
+    update_database(
+        address,
+        &account.address,
+        RemoveEmailsMessage {
+            account: account.address.clone(),
+            mailbox,
+            uids: uids.to_vec(),
+        },
+    )
+    .await
+    .map(|_| ())
+}
+
+/// A message to add flags to or remove flags from emails, e.g. to mark them
+/// as read
+///
+/// The flags change locally at once, even while the account is offline.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct FlagEmailsMessage {
+    /// The mailbox the emails are in
+    pub(crate) mailbox: String,
+    /// UIDs of the emails to flag
+    pub(crate) uids: Vec<u32>,
+    /// The flags to add or remove, e.g. `\Seen`
+    pub(crate) flags: Vec<String>,
+    /// Whether to add the flags instead of removing them
+    pub(crate) add: bool,
+}
+
+impl Handler<FlagEmailsMessage> for MailActor {
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: FlagEmailsMessage,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        self.submit(
+            Action::Flag {
+                mailbox: msg.mailbox,
+                uids: msg.uids,
+                flags: msg.flags,
+                add: msg.add,
+            },
+            ctx,
+        )
+    }
+}
+
+/// A message to move emails to another mailbox of the same account
+///
+/// The emails disappear from the local copy of their mailbox at once, even
+/// while the account is offline, and come back if the server refuses the
+/// move.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct MoveEmailsMessage {
+    /// The mailbox the emails are in
+    pub(crate) mailbox: String,
+    /// UIDs of the emails to move
+    pub(crate) uids: Vec<u32>,
+    /// The mailbox to move the emails to
+    pub(crate) destination: String,
+}
+
+impl Handler<MoveEmailsMessage> for MailActor {
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: MoveEmailsMessage,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        self.submit(
+            Action::Move {
+                mailbox: msg.mailbox,
+                uids: msg.uids,
+                destination: msg.destination,
+            },
+            ctx,
+        )
+    }
+}
+
+/// A message to archive emails, moving them to the account's archive mailbox
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct ArchiveEmailsMessage {
+    /// The mailbox the emails are in
+    pub(crate) mailbox: String,
+    /// UIDs of the emails to archive
+    pub(crate) uids: Vec<u32>,
+}
+
+impl Handler<ArchiveEmailsMessage> for MailActor {
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: ArchiveEmailsMessage,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        let destination = self.account.archive_mailbox.clone();
+        self.submit(
+            Action::Move {
+                mailbox: msg.mailbox,
+                uids: msg.uids,
+                destination,
+            },
+            ctx,
+        )
+    }
+}
+
+/// A message to copy emails to another mailbox of the same account
+///
+/// The copies show up once the server has made them and the destination has
+/// been fetched again.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct CopyEmailsMessage {
+    /// The mailbox the emails are in
+    pub(crate) mailbox: String,
+    /// UIDs of the emails to copy
+    pub(crate) uids: Vec<u32>,
+    /// The mailbox to copy the emails to
+    pub(crate) destination: String,
+}
+
+impl Handler<CopyEmailsMessage> for MailActor {
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: CopyEmailsMessage,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        self.submit(
+            Action::Copy {
+                mailbox: msg.mailbox,
+                uids: msg.uids,
+                destination: msg.destination,
+            },
+            ctx,
+        )
+    }
+}
+
+/// A message to delete emails
+///
+/// Emails are moved to the account's trash mailbox unless they are deleted
+/// permanently. Either way they disappear from the local copy of their mailbox
+/// at once, even while the account is offline, and come back if the server
+/// refuses. Emails in the trash, or of an account without one, only stay
+/// deleted if they are deleted permanently.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct DeleteEmailsMessage {
+    /// The mailbox the emails are in
+    pub(crate) mailbox: String,
+    /// UIDs of the emails to delete
+    pub(crate) uids: Vec<u32>,
+    /// Whether to expunge the emails instead of moving them to the trash
+    pub(crate) permanently: bool,
+}
+
+impl Handler<DeleteEmailsMessage> for MailActor {
+    type Result = AtomicResponse<Self, Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: DeleteEmailsMessage,
+        ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        self.submit(
+            Action::Delete {
+                mailbox: msg.mailbox,
+                uids: msg.uids,
+                permanently: msg.permanently,
+            },
+            ctx,
+        )
+    }
+}
+
+/// A message to send an email from the account through its SMTP server
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), Errors>")]
+pub(crate) struct SendEmailMessage {
+    /// The email to send
+    pub(crate) draft: Draft,
+}
+
+impl Handler<SendEmailMessage> for MailActor {
+    type Result = ResponseFuture<Result<(), Errors>>;
+
+    fn handle(
+        &mut self,
+        msg: SendEmailMessage,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        log::trace!("Actor for {} received {msg:?}", self.account.address);
+        let account = self.account.clone();
+        // Reading attachments and talking to the SMTP server block, so they
+        // run on a thread of their own
+        Box::pin(async move {
+            actix_rt::task::spawn_blocking(move || send(&account, &msg.draft))
+                .await
+                .unwrap_or_else(|e| {
+                    log::warn!("The thread sending an email failed: {e}");
+                    Err(Errors::Send)
+                })
+        })
+    }
+}
+
+/// Builds an email from a draft with its attachments and sends it through the
+/// SMTP server of the account
+fn send(account: &Account, draft: &Draft) -> Result<(), Errors> {
+    let mut attachments = Vec::new();
+    for path in &draft.attachments {
+        let attachment = compose::attachment(path).map_err(|e| {
+            log::warn!("Failed to read attachment {}: {e}", path.display());
+            Errors::Attach
+        })?;
+        attachments.push(attachment);
     }
+    let outgoing = compose::build(
+        draft,
+        &attachments,
+        &account.address,
+        OffsetDateTime::now_utc(),
+    )
+    .ok_or(Errors::Compose)?;
+    smtp::send(account, &outgoing.recipients, &outgoing.message).inspect_err(
+        |e| {
+            log::warn!(
+                "Actor for {} failed to send an email: {e}",
+                account.address
+            );
+        },
+    )
 }
@@ -0,0 +1,789 @@
+//! Writing emails: drafts, replies and forwards, and the RFC 5322 messages
+//! they are sent as
+//!
+//! Replies quote the text of the original and thread under it through the
+//! `In-Reply-To` and `References` headers, as described in section 3.6.4 of
+//! RFC 5322.
+
+use std::{
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use mailparse::MailAddr;
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+use super::{mime::Attachment, StringAddress};
+use crate::database::listing::EmailContent;
+
+/// Lines of headers and quoted-printable text are kept within this length
+const LINE_LENGTH: usize = 76;
+
+/// The longest piece of text encoded into a single RFC 2047 encoded word,
+/// which keeps each word within 75 characters
+const ENCODED_WORD_BYTES: usize = 45;
+
+/// The characters of base64, by value
+const BASE64: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Characters that must be quoted in the display name of an address
+const NAME_SPECIALS: &[char] =
+    &['(', ')', '<', '>', '[', ']', ':', ';', '@', '\\', ',', '.', '"'];
+
+/// Bytes of an attachment encoded per line of base64, which makes lines of
+/// 76 characters
+const BASE64_LINE_BYTES: usize = 57;
+
+/// Characters allowed unencoded in RFC 2231 parameter values besides letters
+/// and digits
+const ATTRIBUTE_CHARACTERS: &[u8] = b"!#$&+-.^_`|~";
+
+/// Longest piece of an RFC 2231 encoded parameter value per line, before it
+/// is continued on the next
+const PARAMETER_SECTION_LENGTH: usize = 60;
+
+/// MIME types of attached files by their extension, in lowercase. Files with
+/// other extensions are sent as `application/octet-stream`.
+const MIME_TYPES: [(&str, &str); 24] = [
+    ("7z", "application/x-7z-compressed"),
+    ("csv", "text/csv"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.\
+         document",
+    ),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ics", "text/calendar"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("json", "application/json"),
+    ("md", "text/markdown"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("txt", "text/plain"),
+    ("vcf", "text/vcard"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("zip", "application/zip"),
+];
+
+/// Counts the message IDs generated by this process, so IDs generated in the
+/// same instant still differ
+static MESSAGE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An email being written
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Draft {
+    /// The primary recipients, as a comma separated list of addresses
+    pub(crate) to: String,
+    /// The carbon copy recipients, like `to`
+    pub(crate) cc: String,
+    /// The blind carbon copy recipients, like `to`. They are only given to
+    /// the server, never written into the message.
+    pub(crate) bcc: String,
+    /// Subject
+    pub(crate) subject: String,
+    /// The plain text of the email
+    pub(crate) body: String,
+    /// The message ID of the email replied to
+    pub(crate) in_reply_to: Option<String>,
+    /// The message IDs of the conversation replied to, oldest first
+    pub(crate) references: Vec<String>,
+    /// Files to attach, which are read when the email is sent
+    pub(crate) attachments: Vec<PathBuf>,
+}
+
+/// Formats a name and address for the recipient fields of a draft, quoting
+/// the name if it has to be
+pub(crate) fn mailbox(name: Option<&str>, bare: &str) -> String {
+    match name {
+        Some(name) if name.contains(NAME_SPECIALS) => {
+            let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("\"{escaped}\" <{bare}>")
+        }
+        Some(name) => format!("{name} <{bare}>"),
+        None => bare.to_owned(),
+    }
+}
+
+/// Formats an address for the recipient fields of a draft
+fn recipient(address: &StringAddress) -> Option<String> {
+    Some(mailbox(address.name(), &address.address()?))
+}
+
+/// Formats lists of addresses for a recipient field, leaving out the user's
+/// own address and addresses already listed in `skip`
+fn recipients(
+    lists: &[Option<&Vec<StringAddress>>],
+    own: &str,
+    skip: &mut Vec<String>,
+) -> String {
+    let mut formatted = Vec::new();
+    for address in lists.iter().flatten().copied().flatten() {
+        let Some(bare) = address.address() else {
+            continue;
+        };
+        if bare == own.to_lowercase() || skip.contains(&bare) {
+            continue;
+        }
+        if let Some(text) = recipient(address) {
+            formatted.push(text);
+            skip.push(bare);
+        }
+    }
+    formatted.join(", ")
+}
+
+/// Adds a prefix like `Re:` to a subject, unless it already starts with it
+fn prefixed(prefix: &str, subject: Option<&str>) -> String {
+    let subject = subject.unwrap_or_default().trim();
+    if subject.to_lowercase().starts_with(&prefix.to_lowercase()) {
+        subject.to_owned()
+    } else {
+        format!("{prefix} {subject}")
+    }
+}
+
+/// Formats a date for the text of a reply or forward
+fn date(date: Option<OffsetDateTime>) -> String {
+    date.and_then(|date| date.format(&Rfc2822).ok())
+        .unwrap_or_else(|| "an unknown date".to_owned())
+}
+
+/// The plain text of an email to quote or forward
+fn text(email: &EmailContent) -> &str {
+    email
+        .bodies
+        .plain
+        .as_deref()
+        .or(email.body_text.as_deref())
+        .unwrap_or_default()
+}
+
+/// Starts a reply to an email, to its senders or, with `all`, to everyone it
+/// was sent to but the user
+///
+/// Replies go to the addresses of the `Reply-To` header instead of the
+/// senders when it has any. The text of the email is quoted below an
+/// attribution line, and the reply is threaded under it.
+pub(crate) fn reply(email: &EmailContent, own: &str, all: bool) -> Draft {
+    let senders = email
+        .reply_to
+        .as_ref()
+        .filter(|reply_to| !reply_to.is_empty())
+        .or(email.from.as_ref());
+    let mut listed = Vec::new();
+    let mut to = recipients(&[senders], own, &mut listed);
+    let cc = if all {
+        let others = recipients(&[email.to.as_ref()], own, &mut listed);
+        if to.is_empty() {
+            to = others;
+        } else if !others.is_empty() {
+            to = format!("{to}, {others}");
+        }
+        recipients(&[email.cc.as_ref()], own, &mut listed)
+    } else {
+        String::new()
+    };
+    // Replies to the user's own emails go to whoever they were sent to
+    if to.is_empty() {
+        to = recipients(&[email.to.as_ref()], own, &mut listed);
+    }
+    let sender = email
+        .from
+        .iter()
+        .flatten()
+        .find_map(recipient)
+        .unwrap_or_else(|| "someone".to_owned());
+    let quoted: Vec<String> = text(email)
+        .lines()
+        .map(|line| {
+            if line.starts_with('>') {
+                format!(">{line}")
+            } else {
+                format!("> {line}")
+            }
+        })
+        .collect();
+    let mut references = email.references.clone();
+    if let Some(message_id) = &email.message_id {
+        if !references.contains(message_id) {
+            references.push(message_id.clone());
+        }
+    }
+    Draft {
+        to,
+        cc,
+        subject: prefixed("Re:", email.subject.as_deref()),
+        body: format!(
+            "\n\nOn {}, {sender} wrote:\n{}\n",
+            date(email.date),
+            quoted.join("\n")
+        ),
+        in_reply_to: email.message_id.clone(),
+        references,
+        ..Draft::default()
+    }
+}
+
+/// Starts forwarding an email, with its headers and text below the user's own
+pub(crate) fn forward(email: &EmailContent) -> Draft {
+    let addresses = |list: Option<&Vec<StringAddress>>| {
+        list.into_iter()
+            .flatten()
+            .filter_map(recipient)
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    let mut header = format!(
+        "\n\n---------- Forwarded message ----------\nFrom: {}\nDate: \
+         {}\nSubject: {}\nTo: {}\n",
+        addresses(email.from.as_ref()),
+        date(email.date),
+        email.subject.as_deref().unwrap_or_default(),
+        addresses(email.to.as_ref()),
+    );
+    if email.cc.as_ref().is_some_and(|cc| !cc.is_empty()) {
+        writeln!(header, "Cc: {}", addresses(email.cc.as_ref()))
+            .expect("Writing to a String can't fail");
+    }
+    Draft {
+        subject: prefixed("Fwd:", email.subject.as_deref()),
+        body: format!("{header}\n{}\n", text(email)),
+        ..Draft::default()
+    }
+}
+
+/// Parses a comma separated list of addresses into the bare addresses and
+/// their header form
+///
+/// Returns `None` if the list can't be parsed.
+fn parse_addresses(list: &str) -> Option<Vec<(String, String)>> {
+    if list.trim().is_empty() {
+        return Some(Vec::new());
+    }
+    let parsed = mailparse::addrparse(list).ok()?;
+    let mut addresses = Vec::new();
+    for address in parsed.iter() {
+        let singles = match address {
+            MailAddr::Single(single) => std::slice::from_ref(single),
+            MailAddr::Group(group) => group.addrs.as_slice(),
+        };
+        for single in singles {
+            let formatted = match &single.display_name {
+                Some(name) if !name.trim().is_empty() => {
+                    format!("{} <{}>", encode_phrase(name), single.addr)
+                }
+                _ => single.addr.clone(),
+            };
+            addresses.push((single.addr.clone(), formatted));
+        }
+    }
+    Some(addresses)
+}
+
+/// Encodes bytes as base64
+pub(crate) fn base64(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk.first().copied().unwrap_or_default(),
+            chunk.get(1).copied().unwrap_or_default(),
+            chunk.get(2).copied().unwrap_or_default(),
+        ];
+        let indices = [
+            bytes[0] >> 2,
+            ((bytes[0] & 0b11) << 4) | (bytes[1] >> 4),
+            ((bytes[1] & 0b1111) << 2) | (bytes[2] >> 6),
+            bytes[2] & 0b11_1111,
+        ];
+        for (position, index) in indices.into_iter().enumerate() {
+            if position <= chunk.len() {
+                encoded.push(char::from(BASE64[usize::from(index)]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Encodes text for a header as RFC 2047 encoded words, unless it is plain
+/// ASCII
+fn encode_header_text(text: &str) -> String {
+    if text
+        .chars()
+        .all(|character| character.is_ascii() && !character.is_ascii_control())
+    {
+        return text.to_owned();
+    }
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for character in text.chars() {
+        if word.len() + character.len_utf8() > ENCODED_WORD_BYTES {
+            words.push(format!("=?UTF-8?B?{}?=", base64(word.as_bytes())));
+            word.clear();
+        }
+        word.push(character);
+    }
+    if !word.is_empty() {
+        words.push(format!("=?UTF-8?B?{}?=", base64(word.as_bytes())));
+    }
+    words.join("\r\n ")
+}
+
+/// Encodes the display name of an address, quoting it if it has special
+/// characters
+fn encode_phrase(name: &str) -> String {
+    let name = name.trim();
+    if !name.is_ascii() {
+        return encode_header_text(name);
+    }
+    if name.contains(NAME_SPECIALS) {
+        let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{escaped}\"")
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Writes a header whose value is a list, folding it between items so lines
+/// stay short
+fn write_list_header(
+    message: &mut String,
+    name: &str,
+    items: &[String],
+    separator: &str,
+) {
+    let mut line_length = name.len() + 2;
+    message.push_str(name);
+    message.push(':');
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            message.push_str(separator);
+            line_length += separator.len();
+            if line_length + item.len() + 1 > LINE_LENGTH {
+                message.push_str("\r\n");
+                line_length = 0;
+            }
+        }
+        message.push(' ');
+        message.push_str(item);
+        line_length += item.len() + 1;
+    }
+    message.push_str("\r\n");
+}
+
+/// Encodes text as quoted-printable with CRLF line endings
+pub(crate) fn quoted_printable(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for (index, line) in text.lines().enumerate() {
+        if index > 0 {
+            encoded.push_str("\r\n");
+        }
+        let bytes = line.as_bytes();
+        let mut line_length = 0;
+        for (position, byte) in bytes.iter().enumerate() {
+            let last = position + 1 == bytes.len();
+            let literal = matches!(byte, b'!'..=b'<' | b'>'..=b'~')
+                || (matches!(byte, b' ' | b'\t') && !last);
+            let width = if literal {
+                1
+            } else {
+                3
+            };
+            // Leave room for the `=` of a soft line break
+            if line_length + width > LINE_LENGTH - 1 {
+                encoded.push_str("=\r\n");
+                line_length = 0;
+            }
+            if literal {
+                encoded.push(char::from(*byte));
+            } else {
+                write!(encoded, "={byte:02X}")
+                    .expect("Writing to a String can't fail");
+            }
+            line_length += width;
+        }
+    }
+    encoded
+}
+
+/// Generates a unique hexadecimal string for the message IDs and MIME
+/// boundaries of a sender
+fn unique_id(from: &str) -> String {
+    let count = MESSAGE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let now = OffsetDateTime::now_utc().unix_timestamp_nanos();
+    let seed = format!("{now}.{count}.{}.{from}", process::id());
+    Sha256::digest(seed.as_bytes()).iter().take(12).fold(
+        String::new(),
+        |mut hash, byte| {
+            write!(hash, "{byte:02x}").expect("Writing to a String can't fail");
+            hash
+        },
+    )
+}
+
+/// Generates a unique message ID in the domain of the sender
+pub(crate) fn message_id(from: &str) -> String {
+    let domain =
+        from.rsplit_once('@').map_or("localhost", |(_, domain)| domain);
+    format!("<{}@{domain}>", unique_id(from))
+}
+
+/// Guesses the MIME type of a file from its extension
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default();
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map_or("application/octet-stream", |(_, mime_type)| mime_type)
+}
+
+/// Reads a file to attach to an email
+pub(crate) fn attachment(path: &Path) -> io::Result<Attachment> {
+    Ok(Attachment {
+        filename: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        mime_type: mime_type(path).to_owned(),
+        data: fs::read(path)?,
+    })
+}
+
+/// Encodes bytes as base64 in lines of 76 characters, each ending with CRLF
+fn base64_lines(data: &[u8]) -> String {
+    data.chunks(BASE64_LINE_BYTES).fold(String::new(), |mut lines, chunk| {
+        lines.push_str(&base64(chunk));
+        lines.push_str("\r\n");
+        lines
+    })
+}
+
+/// Formats the file name of an attachment as the `name` parameter of its
+/// `Content-Type` and the `filename` parameter of its `Content-Disposition`
+///
+/// Names that aren't plain ASCII are encoded as described in RFC 2231 for
+/// `filename`, continued over several lines if they are long. Older clients
+/// only read `name`, which gets an RFC 2047 encoded word instead.
+fn filename_parameters(name: &str) -> (String, String) {
+    let plain = name
+        .chars()
+        .all(|character| character.is_ascii() && !character.is_ascii_control())
+        && name.len() <= PARAMETER_SECTION_LENGTH;
+    if plain {
+        let quoted =
+            format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        return (format!("name={quoted}"), format!("filename={quoted}"));
+    }
+    let mut encoded = String::with_capacity(name.len() * 3);
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || ATTRIBUTE_CHARACTERS.contains(&byte)
+        {
+            encoded.push(char::from(byte));
+        } else {
+            write!(encoded, "%{byte:02X}")
+                .expect("Writing to a String can't fail");
+        }
+    }
+    let mut sections = Vec::new();
+    let mut rest = encoded.as_str();
+    while !rest.is_empty() {
+        let mut end = PARAMETER_SECTION_LENGTH.min(rest.len());
+        // Never split a percent-encoded byte between sections
+        if let Some(percent) = rest.get(end.saturating_sub(2)..end) {
+            if let Some(offset) = percent.find('%') {
+                end = end - 2 + offset;
+            }
+        }
+        let (section, remainder) = rest.split_at(end);
+        sections.push(section);
+        rest = remainder;
+    }
+    let filename = if let [section] = sections.as_slice() {
+        format!("filename*=UTF-8''{section}")
+    } else {
+        sections
+            .iter()
+            .enumerate()
+            .map(|(index, section)| {
+                if index == 0 {
+                    format!("filename*0*=UTF-8''{section}")
+                } else {
+                    format!("filename*{index}*={section}")
+                }
+            })
+            .collect::<Vec<String>>()
+            .join(";\r\n ")
+    };
+    (format!("name=\"{}\"", encode_header_text(name)), filename)
+}
+
+/// An email ready to be handed to the server
+#[derive(Debug)]
+pub(crate) struct Outgoing {
+    /// The bare addresses of every recipient, including blind copies
+    pub(crate) recipients: Vec<String>,
+    /// The RFC 5322 message
+    pub(crate) message: Vec<u8>,
+}
+
+/// Builds the message to send for a draft, with the files read for its
+/// attachments
+///
+/// Emails with attachments are sent as `multipart/mixed`, the text first and
+/// the files encoded as base64 after it. Returns `None` if the draft has no
+/// recipients or an address that can't be parsed.
+pub(crate) fn build(
+    draft: &Draft,
+    attachments: &[Attachment],
+    from: &str,
+    date: OffsetDateTime,
+) -> Option<Outgoing> {
+    let to = parse_addresses(&draft.to)?;
+    let cc = parse_addresses(&draft.cc)?;
+    let bcc = parse_addresses(&draft.bcc)?;
+    let recipients: Vec<String> = to
+        .iter()
+        .chain(&cc)
+        .chain(&bcc)
+        .map(|(bare, _)| bare.clone())
+        .collect();
+    if recipients.is_empty() {
+        return None;
+    }
+    let header_list = |addresses: &[(String, String)]| {
+        addresses
+            .iter()
+            .map(|(_, formatted)| formatted.clone())
+            .collect::<Vec<String>>()
+    };
+    let mut message = String::new();
+    let date = date.format(&Rfc2822).ok()?;
+    write!(message, "Date: {date}\r\nFrom: {from}\r\n")
+        .expect("Writing to a String can't fail");
+    if !to.is_empty() {
+        write_list_header(&mut message, "To", &header_list(&to), ",");
+    }
+    if !cc.is_empty() {
+        write_list_header(&mut message, "Cc", &header_list(&cc), ",");
+    }
+    write!(
+        message,
+        "Subject: {}\r\nMessage-ID: {}\r\n",
+        encode_header_text(draft.subject.trim()),
+        message_id(from)
+    )
+    .expect("Writing to a String can't fail");
+    if let Some(in_reply_to) = &draft.in_reply_to {
+        write!(message, "In-Reply-To: {in_reply_to}\r\n")
+            .expect("Writing to a String can't fail");
+    }
+    if !draft.references.is_empty() {
+        write_list_header(&mut message, "References", &draft.references, "");
+    }
+    message.push_str("MIME-Version: 1.0\r\n");
+    let text = format!(
+        "Content-Type: text/plain; \
+         charset=utf-8\r\nContent-Transfer-Encoding: \
+         quoted-printable\r\n\r\n{}\r\n",
+        quoted_printable(&draft.body)
+    );
+    if attachments.is_empty() {
+        message.push_str(&text);
+    } else {
+        // `=_` never appears in quoted-printable or base64, so the boundary
+        // can't occur in any part
+        let boundary = format!("=_weasel_{}", unique_id(from));
+        write!(
+            message,
+            "Content-Type: multipart/mixed; \
+             boundary=\"{boundary}\"\r\n\r\nThis is a multi-part message in \
+             MIME format.\r\n--{boundary}\r\n{text}"
+        )
+        .expect("Writing to a String can't fail");
+        for attachment in attachments {
+            let (name, filename) = filename_parameters(
+                attachment.filename.as_deref().unwrap_or("attachment"),
+            );
+            write!(
+                message,
+                "--{boundary}\r\nContent-Type: {};\r\n \
+                 {name}\r\nContent-Disposition: attachment;\r\n \
+                 {filename}\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+                attachment.mime_type,
+                base64_lines(&attachment.data)
+            )
+            .expect("Writing to a String can't fail");
+        }
+        write!(message, "--{boundary}--\r\n")
+            .expect("Writing to a String can't fail");
+    }
+    Some(Outgoing {
+        recipients,
+        message: message.into_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{base64, filename_parameters, quoted_printable, reply};
+    use crate::database::listing::EmailContent;
+
+    /// The test vectors of RFC 4648 section 10
+    #[test]
+    fn encodes_base64() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64(data.as_bytes()), encoded, "encoding {data:?}");
+        }
+    }
+
+    /// Long lines get soft breaks and no line is longer than 76 characters
+    #[test]
+    fn breaks_long_quoted_printable_lines() {
+        let encoded = quoted_printable(&"é".repeat(40));
+        let lines: Vec<&str> = encoded.split("\r\n").collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines.iter().all(|line| line.len() <= 76));
+        assert!(lines[..3].iter().all(|line| line.ends_with('=')));
+        // Soft breaks fall between encoded bytes, never inside one
+        assert_eq!(lines[0], format!("{}=C3=", "=C3=A9".repeat(12)));
+        let joined: String = lines
+            .iter()
+            .map(|line| line.strip_suffix('=').unwrap_or(line))
+            .collect();
+        assert_eq!(joined, "=C3=A9".repeat(40));
+    }
+
+    /// Spaces and tabs ending a line would be stripped in transit, so they
+    /// are encoded
+    #[test]
+    fn encodes_trailing_whitespace() {
+        assert_eq!(
+            quoted_printable("a b \nc\t\nd=e\n"),
+            "a b=20\r\nc=09\r\nd=3De"
+        );
+        assert_eq!(quoted_printable(&"x".repeat(75)), "x".repeat(75));
+        assert_eq!(
+            quoted_printable(&"x".repeat(76)),
+            format!("{}=\r\nx", "x".repeat(75))
+        );
+    }
+
+    /// Short ASCII file names are quoted as they are
+    #[test]
+    fn quotes_plain_file_names() {
+        assert_eq!(
+            filename_parameters("my \"notes\".txt"),
+            (
+                "name=\"my \\\"notes\\\".txt\"".to_owned(),
+                "filename=\"my \\\"notes\\\".txt\"".to_owned()
+            )
+        );
+    }
+
+    /// Long file names are split into RFC 2231 sections without cutting a
+    /// percent-encoded byte
+    #[test]
+    fn continues_long_file_names() {
+        let name = format!("{}é{}.pdf", "a".repeat(58), "b".repeat(60));
+        let (_, filename) = filename_parameters(&name);
+        let sections: Vec<&str> = filename.split(";\r\n ").collect();
+        assert_eq!(
+            sections,
+            [
+                format!("filename*0*=UTF-8''{}", "a".repeat(58)),
+                format!("filename*1*=%C3%A9{}", "b".repeat(54)),
+                "filename*2*=bbbbbb.pdf".to_owned(),
+            ]
+        );
+        let (_, filename) = filename_parameters("résumé.pdf");
+        assert_eq!(filename, "filename*=UTF-8''r%C3%A9sum%C3%A9.pdf");
+    }
+
+    /// An email sent by `sender`, with the given `Reply-To` addresses
+    fn email(reply_to: &serde_json::Value) -> EmailContent {
+        serde_json::from_value(json!({
+            "account": "me@example.com",
+            "mailbox": "INBOX",
+            "uid": 1,
+            "date": null,
+            "subject": "Plans",
+            "from": [{
+                "name": "Sender",
+                "adl": null,
+                "mailbox": "sender",
+                "host": "example.com",
+            }],
+            "to": [{
+                "name": null,
+                "adl": null,
+                "mailbox": "me",
+                "host": "example.com",
+            }],
+            "cc": null,
+            "reply_to": reply_to,
+            "flags": [],
+            "message_id": "<plans@example.com>",
+            "body_text": "See you\n> earlier",
+            "raw": null,
+        }))
+        .expect("The email should be complete")
+    }
+
+    /// Replies go to the `Reply-To` addresses when there are any, and quote
+    /// the email
+    #[test]
+    fn replies_to_reply_to() {
+        let draft = reply(
+            &email(&json!([{
+                "name": null,
+                "adl": null,
+                "mailbox": "list",
+                "host": "example.org",
+            }])),
+            "me@example.com",
+            false,
+        );
+        assert_eq!(draft.to, "list@example.org");
+        assert_eq!(draft.subject, "Re: Plans");
+        assert_eq!(draft.in_reply_to.as_deref(), Some("<plans@example.com>"));
+        assert!(draft.body.contains("> See you\n>> earlier"));
+
+        let draft = reply(&email(&json!([])), "me@example.com", false);
+        assert_eq!(draft.to, "Sender <sender@example.com>");
+        let draft = reply(&email(&json!(null)), "me@example.com", false);
+        assert_eq!(draft.to, "Sender <sender@example.com>");
+    }
+}
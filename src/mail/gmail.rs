@@ -0,0 +1,291 @@
+//! Support for Gmail's IMAP extensions
+//!
+//! Gmail presents labels as folders, so the same message shows up in
+//! `[Gmail]/All Mail` and in a folder for each of its labels, with a different
+//! UID in each. Servers advertising `X-GM-EXT-1` report a message ID that is
+//! the same in every folder, which lets the database keep a single record per
+//! message with its labels as tags. See
+//! <https://developers.google.com/gmail/imap/imap-extensions> for details.
+//!
+//! The IMAP parser doesn't know these `FETCH` items, so they are requested
+//! with a raw command and the response is read here.
+
+use std::collections::HashMap;
+
+use super::imap_toolbox::{Errors, ImapEmail, ImapSession};
+
+/// The capability advertised by servers supporting the extensions
+const CAPABILITY: &str = "X-GM-EXT-1";
+
+/// What Gmail knows about a message beyond standard IMAP
+#[derive(Debug, Clone)]
+pub(crate) struct GmailMetadata {
+    /// `X-GM-MSGID`, which is the same in every folder the message is in
+    pub(crate) message_id: u64,
+    /// `X-GM-THRID`, Gmail's own conversation ID
+    pub(crate) thread_id: u64,
+    /// `X-GM-LABELS`, e.g. `\Inbox`, `\Important` or `Receipts`
+    pub(crate) labels: Vec<String>,
+}
+
+/// A value in a `FETCH` response
+#[derive(Debug)]
+enum Value {
+    /// An atom, number or string
+    Text(String),
+    /// A parenthesized list
+    List(Vec<Value>),
+}
+
+/// Reads one value of a `FETCH` response, responding with it and the rest of
+/// the response
+fn read_value(input: &[u8]) -> Option<(Value, &[u8])> {
+    let input = input.trim_ascii_start();
+    match input.first()? {
+        b'(' => {
+            let mut items = Vec::new();
+            let mut rest = &input[1..];
+            loop {
+                rest = rest.trim_ascii_start();
+                if let Some(rest) = rest.strip_prefix(b")") {
+                    return Some((Value::List(items), rest));
+                }
+                let (item, after) = read_value(rest)?;
+                items.push(item);
+                rest = after;
+            }
+        }
+        b'"' => {
+            let mut text = Vec::new();
+            let mut escaped = false;
+            for (index, byte) in input.iter().enumerate().skip(1) {
+                match (escaped, byte) {
+                    (false, b'\\') => escaped = true,
+                    (false, b'"') => {
+                        let text = String::from_utf8_lossy(&text).into_owned();
+                        return Some((Value::Text(text), &input[index + 1..]));
+                    }
+                    _ => {
+                        text.push(*byte);
+                        escaped = false;
+                    }
+                }
+            }
+            None
+        }
+        b'{' => {
+            let close = input.iter().position(|byte| *byte == b'}')?;
+            let length: usize =
+                std::str::from_utf8(&input[1..close]).ok()?.parse().ok()?;
+            let rest = input[close + 1..].strip_prefix(b"\r\n")?;
+            let text =
+                String::from_utf8_lossy(rest.get(..length)?).into_owned();
+            Some((Value::Text(text), &rest[length..]))
+        }
+        _ => {
+            let end = input
+                .iter()
+                .position(|byte| b" ()\r\n".contains(byte))
+                .unwrap_or(input.len());
+            let text = String::from_utf8_lossy(&input[..end]).into_owned();
+            Some((Value::Text(text), &input[end..]))
+        }
+    }
+}
+
+/// Decodes a label from the modified UTF-7 IMAP uses for mailbox names
+///
+/// See [RFC 3501](https://datatracker.ietf.org/doc/html/rfc3501#section-5.1.3)
+/// for the encoding. Labels that aren't valid modified UTF-7 are kept as is.
+fn decode_label(label: &str) -> String {
+    /// Decodes the modified base64 between `&` and `-`
+    fn decode_shifted(shifted: &str) -> Option<String> {
+        let mut bits: u32 = 0;
+        let mut bit_count = 0;
+        let mut units = Vec::new();
+        for character in shifted.bytes() {
+            let value = match character {
+                b'A'..=b'Z' => character - b'A',
+                b'a'..=b'z' => character - b'a' + 26,
+                b'0'..=b'9' => character - b'0' + 52,
+                b'+' => 62,
+                b',' => 63,
+                _ => return None,
+            };
+            bits = (bits << 6) | u32::from(value);
+            bit_count += 6;
+            if bit_count >= 16 {
+                bit_count -= 16;
+                units.push(u16::try_from((bits >> bit_count) & 0xFFFF).ok()?);
+            }
+        }
+        String::from_utf16(&units).ok()
+    }
+
+    let mut decoded = String::with_capacity(label.len());
+    let mut rest = label;
+    while let Some((before, after)) = rest.split_once('&') {
+        decoded.push_str(before);
+        let Some((shifted, after)) = after.split_once('-') else {
+            return label.to_owned();
+        };
+        if shifted.is_empty() {
+            decoded.push('&');
+        } else {
+            let Some(shifted) = decode_shifted(shifted) else {
+                return label.to_owned();
+            };
+            decoded.push_str(&shifted);
+        }
+        rest = after;
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Reads the UID and Gmail metadata of every message in a `FETCH` response
+fn parse_fetches(response: &[u8]) -> HashMap<u32, GmailMetadata> {
+    let mut metadata = HashMap::new();
+    let mut rest = response;
+    while let Some(start) =
+        rest.windows(7).position(|window| window == b" FETCH ")
+    {
+        let Some((Value::List(items), after)) = read_value(&rest[start + 7..])
+        else {
+            break;
+        };
+        rest = after;
+        let mut uid = None;
+        let mut message_id = None;
+        let mut thread_id = None;
+        let mut labels = Vec::new();
+        for pair in items.chunks(2) {
+            match pair {
+                [Value::Text(name), Value::Text(value)] => {
+                    match name.to_ascii_uppercase().as_str() {
+                        "UID" => uid = value.parse().ok(),
+                        "X-GM-MSGID" => message_id = value.parse().ok(),
+                        "X-GM-THRID" => thread_id = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+                [Value::Text(name), Value::List(values)]
+                    if name.eq_ignore_ascii_case("X-GM-LABELS") =>
+                {
+                    labels = values
+                        .iter()
+                        .filter_map(|value| match value {
+                            Value::Text(label) => Some(decode_label(label)),
+                            Value::List(_) => None,
+                        })
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        if let (Some(uid), Some(message_id), Some(thread_id)) =
+            (uid, message_id, thread_id)
+        {
+            metadata.insert(
+                uid,
+                GmailMetadata {
+                    message_id,
+                    thread_id,
+                    labels,
+                },
+            );
+        }
+    }
+    metadata
+}
+
+/// Adds Gmail metadata to emails fetched from the selected mailbox, if the
+/// server supports the extensions
+///
+/// `uids` is the UID set the emails were fetched with.
+pub(super) fn add_metadata(
+    session: &mut ImapSession,
+    uids: &str,
+    emails: &mut [ImapEmail],
+) -> Result<(), Errors> {
+    let supported = session
+        .capabilities()
+        .is_ok_and(|capabilities| capabilities.has_str(CAPABILITY));
+    if !supported || emails.is_empty() {
+        return Ok(());
+    }
+    let items = "(UID X-GM-MSGID X-GM-THRID X-GM-LABELS)";
+    let Ok(response) = session
+        .run_command_and_read_response(format!("UID FETCH {uids} {items}"))
+    else {
+        return Err(Errors::Fetch);
+    };
+    let mut metadata = parse_fetches(&response);
+    for email in emails {
+        email.gmail = metadata.remove(&email.uid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_label, parse_fetches};
+
+    /// Reads the labels of the message with the given UID
+    fn labels(response: &[u8], uid: u32) -> Vec<String> {
+        parse_fetches(response)
+            .remove(&uid)
+            .expect("The message should have metadata")
+            .labels
+    }
+
+    /// Quoted labels have their escapes resolved, and literals are read
+    #[test]
+    fn reads_quoted_labels() {
+        let response = b"* 1 FETCH (X-GM-THRID 11 X-GM-MSGID 12 X-GM-LABELS \
+                         (\"Work \\\"Q1\\\"\" \"back\\\\slash\" Plain \
+                         {11}\r\nTwo (words)) UID 7)\r\nA1 OK Success\r\n";
+        assert_eq!(
+            labels(response, 7),
+            vec!["Work \"Q1\"", "back\\slash", "Plain", "Two (words)"]
+        );
+        let metadata = parse_fetches(response);
+        assert_eq!(metadata[&7].message_id, 12);
+        assert_eq!(metadata[&7].thread_id, 11);
+    }
+
+    /// System labels keep their backslash, whether sent as atoms or quoted
+    #[test]
+    fn reads_system_labels() {
+        let response = b"* 1 FETCH (UID 3 X-GM-MSGID 1 X-GM-THRID 1 \
+                         X-GM-LABELS (\\Inbox \"\\\\Important\" \\Starred))\r\n\
+                         * 2 FETCH (UID 4 X-GM-MSGID 2 X-GM-THRID 1 \
+                         X-GM-LABELS ())\r\n";
+        assert_eq!(
+            labels(response, 3),
+            vec!["\\Inbox", "\\Important", "\\Starred"]
+        );
+        assert!(labels(response, 4).is_empty());
+    }
+
+    /// Non-ASCII labels are decoded from modified UTF-7, and invalid ones
+    /// are kept as they are
+    #[test]
+    fn decodes_modified_utf7() {
+        let cases = [
+            ("Entw&APw-rfe", "Entwürfe"),
+            ("&ZeVnLIqe-", "日本語"),
+            ("&2D3eAA- Fun", "😀 Fun"),
+            ("Tom &- Jerry", "Tom & Jerry"),
+            ("[Gmail]/Sent Mail", "[Gmail]/Sent Mail"),
+            ("Broken &APw", "Broken &APw"),
+            ("Broken &A!w-", "Broken &A!w-"),
+        ];
+        for (label, expected) in cases {
+            assert_eq!(decode_label(label), expected, "{label}");
+        }
+        let response = b"* 5 FETCH (UID 9 X-GM-MSGID 3 X-GM-THRID 3 \
+                         X-GM-LABELS (\"Entw&APw-rfe\" &ZeVnLIqe-))\r\n";
+        assert_eq!(labels(response, 9), vec!["Entwürfe", "日本語"]);
+    }
+}
@@ -0,0 +1,746 @@
+//! Turns HTML bodies into styled text that is safe to display
+//!
+//! Nothing in an HTML body is ever run or loaded: scripts, styles, forms,
+//! frames and embedded objects are dropped with their contents, and images
+//! are replaced by their alternative text. Only links to `http`, `https` and
+//! `mailto` addresses are kept, cleaned of tracking. The parser is deliberately
+//! forgiving, since the HTML in emails is often far from valid.
+
+use super::{
+    styled::{is_safe_link, push, push_linked, Span, TextStyle},
+    tracking,
+};
+
+/// Elements that are dropped along with everything inside them
+const SKIPPED: [&str; 19] = [
+    "applet", "audio", "button", "canvas", "embed", "form", "frameset", "head",
+    "iframe", "math", "noscript", "object", "script", "select", "style", "svg",
+    "template", "textarea", "video",
+];
+
+/// Elements that are set apart from the text around them by a blank line
+const PARAGRAPHS: [&str; 15] = [
+    "blockquote",
+    "dl",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ol",
+    "p",
+    "pre",
+    "table",
+    "ul",
+    "hr",
+];
+
+/// Elements that start on a line of their own
+const LINES: [&str; 16] = [
+    "address",
+    "article",
+    "aside",
+    "caption",
+    "center",
+    "dd",
+    "div",
+    "dt",
+    "figcaption",
+    "footer",
+    "header",
+    "li",
+    "main",
+    "nav",
+    "section",
+    "tr",
+];
+
+/// Elements whose cells are separated by a space
+const CELLS: [&str; 2] = ["td", "th"];
+
+/// An HTML tag as found in the source
+struct Tag {
+    /// The name of the element, in lowercase
+    name: String,
+    /// Whether the tag closes the element
+    closing: bool,
+    /// Attributes by lowercase name, with their values decoded
+    attributes: Vec<(String, String)>,
+}
+
+impl Tag {
+    /// Gets the value of an attribute
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// An element that is open while rendering
+struct Element {
+    /// The name of the element, in lowercase
+    name: String,
+    /// How text inside the element is displayed
+    style: TextStyle,
+    /// Whether whitespace inside the element is kept as is, as in `<pre>`
+    preformatted: bool,
+}
+
+/// Gets the index right after the next occurrence of `needle` at or after
+/// `from`, or the end of `source` if there is none
+fn find_after(source: &str, from: usize, needle: &str) -> usize {
+    source
+        .get(from..)
+        .and_then(|rest| rest.find(needle))
+        .map_or(source.len(), |index| from + index + needle.len())
+}
+
+/// Parses the attribute value starting at `start`, quoted or not, returning it
+/// and the index right after it
+fn attribute_value(source: &str, start: usize) -> (&str, usize) {
+    let bytes = source.as_bytes();
+    if let Some(quote @ (b'"' | b'\'')) = bytes.get(start) {
+        let value_start = start + 1;
+        let end = source
+            .get(value_start..)
+            .and_then(|rest| rest.find(char::from(*quote)))
+            .map_or(source.len(), |end| value_start + end);
+        let value = source.get(value_start..end).unwrap_or_default();
+        return (value, (end + 1).min(source.len()));
+    }
+    let mut end = start;
+    while bytes
+        .get(end)
+        .is_some_and(|byte| !byte.is_ascii_whitespace() && *byte != b'>')
+    {
+        end += 1;
+    }
+    (source.get(start..end).unwrap_or_default(), end)
+}
+
+/// Parses the tag starting at `start`, which must be a `<`, returning it and
+/// the index right after it
+fn parse_tag(source: &str, start: usize) -> (Tag, usize) {
+    let bytes = source.as_bytes();
+    let is_space =
+        |index: usize| bytes.get(index).is_some_and(u8::is_ascii_whitespace);
+    let mut index = start + 1;
+    let closing = bytes.get(index) == Some(&b'/');
+    if closing {
+        index += 1;
+    }
+    let name_start = index;
+    while bytes.get(index).is_some_and(|byte| {
+        !byte.is_ascii_whitespace() && !b"/>".contains(byte)
+    }) {
+        index += 1;
+    }
+    let name =
+        source.get(name_start..index).unwrap_or_default().to_ascii_lowercase();
+    let mut attributes = Vec::new();
+    loop {
+        while is_space(index) || bytes.get(index) == Some(&b'/') {
+            index += 1;
+        }
+        match bytes.get(index) {
+            None => break,
+            Some(b'>') => {
+                index += 1;
+                break;
+            }
+            Some(_) => {}
+        }
+        let attribute_start = index;
+        while bytes.get(index).is_some_and(|byte| {
+            !byte.is_ascii_whitespace() && !b"=/>".contains(byte)
+        }) {
+            index += 1;
+        }
+        let attribute = source
+            .get(attribute_start..index)
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        while is_space(index) {
+            index += 1;
+        }
+        let mut value = "";
+        if bytes.get(index) == Some(&b'=') {
+            index += 1;
+            while is_space(index) {
+                index += 1;
+            }
+            (value, index) = attribute_value(source, index);
+        }
+        if !attribute.is_empty() {
+            attributes.push((attribute, decode_entities(value)));
+        }
+    }
+    (
+        Tag {
+            name,
+            closing,
+            attributes,
+        },
+        index,
+    )
+}
+
+/// Gets the character a named or numeric character reference stands for,
+/// given what is between its `&` and `;`
+fn entity_character(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code).filter(|character| *character != '\0');
+    }
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "shy" => '\u{ad}',
+        "zwnj" => '\u{200c}',
+        "zwj" => '\u{200d}',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "euro" => '€',
+        "pound" => '£',
+        "middot" => '·',
+        "bull" => '•',
+        "hellip" => '…',
+        "ndash" => '–',
+        "mdash" => '—',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        _ => return None,
+    })
+}
+
+/// Decodes the character references in a piece of HTML text
+///
+/// References that aren't known are kept as they are.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        let (before, reference) = rest.split_at(start);
+        decoded.push_str(before);
+        let (ampersand, after) = reference.split_at(1);
+        rest = after;
+        if let Some((character, after)) = after
+            .split_once(';')
+            .and_then(|(name, after)| Some((entity_character(name)?, after)))
+        {
+            decoded.push(character);
+            rest = after;
+        } else {
+            decoded.push_str(ampersand);
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Builds styled text from the tags and text of an HTML document
+#[derive(Default)]
+struct Renderer {
+    /// The text rendered so far
+    spans: Vec<Span>,
+    /// The elements that are open, innermost last
+    open: Vec<Element>,
+    /// Whether whitespace was skipped that should become a space before the
+    /// next word
+    pending_space: bool,
+    /// Whether remote images may be linked to
+    remote_content: bool,
+    /// How many remote images were left out
+    blocked: usize,
+    /// How many tracking pixels were left out
+    trackers: usize,
+}
+
+impl Renderer {
+    /// The style of text at the current position
+    fn style(&self) -> TextStyle {
+        self.open
+            .last()
+            .map(|element| element.style.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether whitespace is kept as is at the current position
+    fn preformatted(&self) -> bool {
+        self.open.iter().any(|element| element.preformatted)
+    }
+
+    /// Counts the line breaks at the end of the rendered text, up to two
+    fn trailing_newlines(&self) -> usize {
+        self.spans
+            .iter()
+            .rev()
+            .flat_map(|span| span.text.chars().rev())
+            .take(2)
+            .take_while(|character| *character == '\n')
+            .count()
+    }
+
+    /// Whether the rendered text is empty or ends with a space or line break
+    fn at_word_break(&self) -> bool {
+        self.spans.last().is_none_or(|span| span.text.ends_with([' ', '\n']))
+    }
+
+    /// The style of line breaks at the current position, which only keeps
+    /// the quote level
+    fn break_style(&self) -> TextStyle {
+        TextStyle {
+            quote_level: self.style().quote_level,
+            ..TextStyle::default()
+        }
+    }
+
+    /// Ends the current line, leaving `count` line breaks before the next text
+    /// so paragraphs can be set apart by a blank line
+    fn break_lines(&mut self, count: usize) {
+        self.pending_space = false;
+        if self.spans.is_empty() {
+            return;
+        }
+        let style = self.break_style();
+        let missing = count.saturating_sub(self.trailing_newlines());
+        push(&mut self.spans, &"\n".repeat(missing), &style);
+    }
+
+    /// Adds text in the style of the current position
+    fn text(&mut self, raw: &str) {
+        let style = self.style();
+        self.styled_text(raw, &style);
+    }
+
+    /// Adds text, collapsing its whitespace unless it is preformatted
+    fn styled_text(&mut self, raw: &str, style: &TextStyle) {
+        if raw.is_empty() {
+            return;
+        }
+        let text = decode_entities(raw);
+        if self.preformatted() {
+            push_linked(&mut self.spans, &text, style);
+            self.pending_space = false;
+            return;
+        }
+        let mut collapsed = String::with_capacity(text.len());
+        let mut space = self.pending_space
+            || text
+                .starts_with(|character: char| character.is_ascii_whitespace());
+        for word in text.split_ascii_whitespace() {
+            if space {
+                if collapsed.is_empty() {
+                    // A space left over from before the text is styled like
+                    // the text before it, but never part of a link
+                    if let Some(last) =
+                        self.spans.last().filter(|_| !self.at_word_break())
+                    {
+                        let before = TextStyle {
+                            link: None,
+                            ..last.style.clone()
+                        };
+                        push(&mut self.spans, " ", &before);
+                    }
+                } else {
+                    collapsed.push(' ');
+                }
+            }
+            collapsed.push_str(word);
+            space = true;
+        }
+        self.pending_space = if collapsed.is_empty() {
+            space
+        } else {
+            text.ends_with(|character: char| character.is_ascii_whitespace())
+        };
+        push_linked(&mut self.spans, &collapsed, style);
+    }
+
+    /// Opens an element with the given style
+    fn open(&mut self, tag: &Tag, style: TextStyle, preformatted: bool) {
+        self.open.push(Element {
+            name: tag.name.clone(),
+            style,
+            preformatted: preformatted || self.preformatted(),
+        });
+    }
+
+    /// Applies an opening tag
+    fn open_tag(&mut self, tag: &Tag) {
+        let name = tag.name.as_str();
+        if PARAGRAPHS.contains(&name) {
+            self.break_lines(2);
+        } else if LINES.contains(&name) {
+            self.break_lines(1);
+        } else if CELLS.contains(&name) {
+            self.pending_space = true;
+        }
+        let current = self.style();
+        let style = match name {
+            "b" | "strong" => TextStyle {
+                bold: true,
+                ..current
+            },
+            "i" | "em" | "cite" | "dfn" => TextStyle {
+                italic: true,
+                ..current
+            },
+            "code" | "kbd" | "samp" | "tt" | "pre" => TextStyle {
+                monospace: true,
+                ..current
+            },
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => TextStyle {
+                bold: true,
+                heading: name
+                    .trim_start_matches('h')
+                    .parse()
+                    .unwrap_or_default(),
+                ..current
+            },
+            "blockquote" => TextStyle {
+                quote_level: current.quote_level.saturating_add(1),
+                ..current
+            },
+            "a" => match tag
+                .attribute("href")
+                .map(|href| tracking::clean_link(href.trim()))
+            {
+                Some(href) if is_safe_link(&href) => TextStyle {
+                    link: Some(href),
+                    ..current
+                },
+                _ => current,
+            },
+            "br" => {
+                if !self.spans.is_empty() && self.trailing_newlines() < 2 {
+                    let style = self.break_style();
+                    push(&mut self.spans, "\n", &style);
+                }
+                self.pending_space = false;
+                return;
+            }
+            "img" => {
+                self.image(tag);
+                return;
+            }
+            "li" => {
+                push(&mut self.spans, "• ", &current);
+                current
+            }
+            "hr" => return,
+            _ => current,
+        };
+        self.open(tag, style, name == "pre");
+    }
+
+    /// Adds an image as its alternative text
+    ///
+    /// Tracking pixels are left out. Other remote images are left out too,
+    /// unless remote content is allowed, in which case they link to where
+    /// they would be loaded from.
+    fn image(&mut self, tag: &Tag) {
+        let source = tag.attribute("src").unwrap_or_default().trim();
+        let alt = tag.attribute("alt").map(str::trim).unwrap_or_default();
+        if !tracking::is_remote(source) {
+            if !alt.is_empty() {
+                self.text(&format!("[{alt}]"));
+            }
+            return;
+        }
+        if tracking::is_tracking_pixel(
+            source,
+            tag.attribute("width"),
+            tag.attribute("height"),
+            tag.attribute("style"),
+        ) {
+            self.trackers += 1;
+            return;
+        }
+        if !self.remote_content {
+            self.blocked += 1;
+            if !alt.is_empty() {
+                self.text(&format!("[{alt}]"));
+            }
+            return;
+        }
+        let address = match source.strip_prefix("//") {
+            Some(rest) => format!("https://{rest}"),
+            None => source.to_owned(),
+        };
+        let label = if alt.is_empty() {
+            "[Image]".to_owned()
+        } else {
+            format!("[Image: {alt}]")
+        };
+        let style = TextStyle {
+            link: Some(address),
+            ..self.style()
+        };
+        self.styled_text(&label, &style);
+    }
+
+    /// Applies a closing tag, closing every element opened since the one it
+    /// matches
+    fn close_tag(&mut self, tag: &Tag) {
+        let name = tag.name.as_str();
+        if let Some(index) =
+            self.open.iter().rposition(|element| element.name == name)
+        {
+            self.open.truncate(index);
+        }
+        if PARAGRAPHS.contains(&name) {
+            self.break_lines(2);
+        } else if LINES.contains(&name) {
+            self.break_lines(1);
+        } else if CELLS.contains(&name) {
+            self.pending_space = true;
+        }
+    }
+
+    /// Applies the markup starting with the `<` at `start`, returning the
+    /// index where text continues
+    fn markup(&mut self, source: &str, lowercase: &str, start: usize) -> usize {
+        let rest = lowercase.get(start..).unwrap_or_default();
+        if rest.starts_with("<!--") {
+            return find_after(lowercase, start + 4, "-->");
+        }
+        match rest.as_bytes().get(1) {
+            // Doctypes, CDATA sections and processing instructions
+            Some(b'!' | b'?') => find_after(lowercase, start, ">"),
+            Some(byte) if *byte == b'/' || byte.is_ascii_alphabetic() => {
+                let (tag, end) = parse_tag(source, start);
+                if tag.closing {
+                    self.close_tag(&tag);
+                    return end;
+                }
+                if SKIPPED.contains(&tag.name.as_str()) {
+                    // Drop the contents up to the closing tag. If there is
+                    // none, only the tag is dropped so the rest still shows.
+                    let closing = format!("</{}", tag.name);
+                    return lowercase
+                        .get(end..)
+                        .and_then(|rest| rest.find(&closing))
+                        .map_or(end, |index| {
+                            find_after(lowercase, end + index, ">")
+                        });
+                }
+                self.open_tag(&tag);
+                end
+            }
+            _ => {
+                self.text("<");
+                start + 1
+            }
+        }
+    }
+
+    /// Removes the whitespace at the end of the rendered text and returns it
+    fn finish(mut self) -> Rendered {
+        while let Some(last) = self.spans.last_mut() {
+            let length = last.text.trim_end().len();
+            last.text.truncate(length);
+            if !last.text.is_empty() {
+                break;
+            }
+            self.spans.pop();
+        }
+        Rendered {
+            spans: self.spans,
+            blocked: self.blocked,
+            trackers: self.trackers,
+        }
+    }
+}
+
+/// An HTML document rendered as styled text
+#[derive(Debug)]
+pub(crate) struct Rendered {
+    /// The styled text
+    pub(crate) spans: Vec<Span>,
+    /// How many remote images were left out because remote content isn't
+    /// allowed
+    pub(crate) blocked: usize,
+    /// How many tracking pixels were left out
+    pub(crate) trackers: usize,
+}
+
+/// Renders an HTML document as styled text, dropping anything that could run
+/// code, load remote content or submit data
+///
+/// Remote images are linked to if `remote_content` is allowed, but never
+/// loaded. Tracking pixels are always dropped.
+pub(crate) fn render(html: &str, remote_content: bool) -> Rendered {
+    // ASCII lowercasing keeps byte indices, so tags can be found in the
+    // lowercase copy and read from the original
+    let lowercase = html.to_ascii_lowercase();
+    let mut renderer = Renderer {
+        remote_content,
+        ..Renderer::default()
+    };
+    let mut position = 0;
+    while let Some(offset) =
+        html.get(position..).and_then(|rest| rest.find('<'))
+    {
+        let start = position + offset;
+        renderer.text(html.get(position..start).unwrap_or_default());
+        position = renderer.markup(html, &lowercase, start);
+    }
+    renderer.text(html.get(position..).unwrap_or_default());
+    renderer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, Rendered};
+
+    /// The text of a rendered document, without its styles
+    fn text(rendered: &Rendered) -> String {
+        rendered.spans.iter().map(|span| span.text.as_str()).collect()
+    }
+
+    /// The text rendered from a document, with remote content blocked
+    fn rendered_text(html: &str) -> String {
+        text(&render(html, false))
+    }
+
+    /// Where the span containing `label` links to
+    fn link<'a>(rendered: &'a Rendered, label: &str) -> Option<&'a str> {
+        rendered
+            .spans
+            .iter()
+            .find(|span| span.text.contains(label))
+            .expect("The label should be rendered")
+            .style
+            .link
+            .as_deref()
+    }
+
+    /// Scripts, styles, frames and forms are dropped with their contents,
+    /// whatever the case of their tags
+    #[test]
+    fn strips_active_content() {
+        assert_eq!(
+            rendered_text(
+                "<p>Hi<script>alert(1)</script><STYLE>p { color: red \
+                 }</STYLE><iframe src=\"https://example.com\">frame</iframe> \
+                 there</p><ScRiPt type=text/javascript>document.write('x')\
+                 </sCrIpT><form><input value=secret></form>"
+            ),
+            "Hi there"
+        );
+        assert_eq!(
+            rendered_text(
+                "<head><title>Title</title></head><body>Body<!-- hidden \
+                 --></body>"
+            ),
+            "Body"
+        );
+    }
+
+    /// Tags and comments left open never swallow text before them, and a
+    /// dropped element without a closing tag only drops the tag
+    #[test]
+    fn forgives_unterminated_markup() {
+        assert_eq!(rendered_text("a <b>bold"), "a bold");
+        assert!(render("a <b>bold", false)
+            .spans
+            .iter()
+            .any(|span| { span.text == "bold" && span.style.bold }));
+        assert_eq!(rendered_text("Hi <a href=\"https://example.com"), "Hi");
+        assert_eq!(rendered_text("text <p"), "text");
+        assert_eq!(rendered_text("kept <!-- never closed"), "kept");
+        assert_eq!(rendered_text("<script>alert(1)"), "alert(1)");
+        assert_eq!(rendered_text("1 < 2 and 3 <= 4"), "1 < 2 and 3 <= 4");
+    }
+
+    /// Only `http`, `https` and `mailto` links are kept, however their
+    /// scheme is written
+    #[test]
+    fn drops_unsafe_links() {
+        for href in [
+            "javascript:alert(1)",
+            " JavaScript:alert(1)",
+            "&#106;avascript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+            "vbscript:msgbox",
+        ] {
+            let rendered =
+                render(&format!("<a href='{href}'>click</a>"), false);
+            assert_eq!(text(&rendered), "click");
+            assert_eq!(link(&rendered, "click"), None, "for {href}");
+        }
+        let rendered = render(
+            "<a href=\"https://example.com/?a=1&amp;utm_source=mail\">safe</\
+             a> <a href=mailto:me@example.com>mail</a>",
+            false,
+        );
+        assert_eq!(link(&rendered, "safe"), Some("https://example.com/?a=1"));
+        assert_eq!(link(&rendered, "mail"), Some("mailto:me@example.com"));
+    }
+
+    /// Known character references are decoded once, and anything else is
+    /// kept as written
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(
+            rendered_text(
+                "&lt;b&gt;not bold&lt;/b&gt; &amp;amp; &copy; &#233; &#xE9; \
+                 &unknown; &#0; &#xD800; & alone"
+            ),
+            "<b>not bold</b> &amp; © é é &unknown; &#0; &#xD800; & alone"
+        );
+        assert!(render("<b>&lt;i&gt;</b>", false)
+            .spans
+            .iter()
+            .all(|span| !span.style.italic));
+    }
+
+    /// Multi-byte characters next to markup are never cut in half
+    #[test]
+    fn slices_non_ascii_text() {
+        assert_eq!(
+            rendered_text("<p>héllo <b>wörld</b> 日本語</p><p>ünïcode</p>"),
+            "héllo wörld 日本語\n\nünïcode"
+        );
+        assert_eq!(rendered_text("<İ>x</İ> <é"), "<İ>x <é");
+        assert_eq!(rendered_text("<img alt=\"日本"), "[日本]");
+        assert_eq!(rendered_text("<img alt=日本語>"), "[日本語]");
+        let rendered = render("<a href=https://例え.jp/ü>ü</a>", false);
+        assert_eq!(link(&rendered, "ü"), Some("https://例え.jp/ü"));
+    }
+
+    /// Tracking pixels are always dropped, and other remote images are only
+    /// linked to when remote content is allowed
+    #[test]
+    fn leaves_out_remote_images() {
+        let html = "<img src=https://example.com/t.gif width=1 height=1>\
+                    <img src=https://example.com/photo.jpg alt=Photo>";
+        let blocked = render(html, false);
+        assert_eq!(text(&blocked), "[Photo]");
+        assert_eq!((blocked.blocked, blocked.trackers), (1, 1));
+        let allowed = render(html, true);
+        assert_eq!(text(&allowed), "[Image: Photo]");
+        assert_eq!((allowed.blocked, allowed.trackers), (0, 1));
+        assert_eq!(
+            link(&allowed, "Photo"),
+            Some("https://example.com/photo.jpg")
+        );
+    }
+}
@@ -1,10 +1,28 @@
 //! Various tools for handling IMAP functionality
 
-use imap_proto::Address;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Write},
+};
+
+use imap_proto::{Address, BodyStructure, MessageSection, SectionPath};
 use serde::{Deserialize, Serialize};
-use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+use time::{
+    format_description::{well_known::Rfc2822, FormatItem},
+    macros::format_description,
+    OffsetDateTime,
+};
+
+use super::{gmail, gmail::GmailMetadata, mime};
+use crate::config::{Account, SyncPolicy};
 
-use crate::config::Account;
+/// How many message bodies are requested at once when a sync policy only
+/// allows some of them
+const BODY_CHUNK_SIZE: usize = 100;
+
+/// The date format of `SEARCH` criteria like `SINCE 1-Feb-1994`
+const SEARCH_DATE: &[FormatItem<'_>] =
+    format_description!("[day padding:none]-[month repr:short]-[year]");
 
 /// Represents an email retrieved through IMAP
 #[derive(Debug)]
@@ -13,6 +31,21 @@ pub(crate) struct ImapEmail {
     pub(crate) uid: u32,
     /// The envelope of the message
     pub(crate) envelope: Envelope,
+    /// The full RFC 822 source of the message
+    pub(crate) raw: Option<Vec<u8>>,
+    /// IMAP flags such as `\Seen`, formatted as the server sent them
+    pub(crate) flags: Vec<String>,
+    /// Message IDs from the `References` header, oldest first. Only available
+    /// if the message source or header was fetched.
+    pub(crate) references: Vec<String>,
+    /// Labels and IDs of messages fetched from Gmail
+    pub(crate) gmail: Option<GmailMetadata>,
+    /// Size of the full source in bytes, if it was fetched or reported by
+    /// the server
+    pub(crate) size: Option<u32>,
+    /// Whether `raw` leaves out attachments that are too large for the sync
+    /// policy
+    pub(crate) partial: bool,
 }
 
 /// See [RFC 2822](https://datatracker.ietf.org/doc/html/rfc2822#section-3.6) for more details.
@@ -24,6 +57,17 @@ pub(crate) struct Envelope {
     pub(crate) subject: Option<String>,
     /// The email sender(s)
     pub(crate) from: Option<Vec<StringAddress>>,
+    /// The primary recipient(s)
+    pub(crate) to: Option<Vec<StringAddress>>,
+    /// The carbon copy recipient(s)
+    pub(crate) cc: Option<Vec<StringAddress>>,
+    /// Where replies should go, if not to the sender(s). Servers fill this
+    /// in with the senders when the header is missing.
+    pub(crate) reply_to: Option<Vec<StringAddress>>,
+    /// The `Message-ID` header, including angle brackets
+    pub(crate) message_id: Option<String>,
+    /// The first message ID in the `In-Reply-To` header
+    pub(crate) in_reply_to: Option<String>,
 }
 
 /// Errors that can occur while interacting with IMAP
@@ -39,9 +83,74 @@ pub(crate) enum Errors {
     Select,
     /// The client can't fetch messages from the given inbox
     Fetch,
+    /// The server rejected a search of the given inbox
+    Search,
+    /// A mailbox name or search argument contains a line break or NUL, which
+    /// would end the command early and start another
+    Argument,
+    /// The server refused to list the account's mailboxes
+    List,
+    /// The server refused to copy messages to the destination mailbox
+    Copy,
+    /// The server refused to move messages to the destination mailbox
+    Move,
+    /// The account has no trash mailbox to move messages to, other than the
+    /// one they are in
+    NoTrash,
+    /// The server refused to change the flags of messages
+    Flag,
+    /// The server refused to expunge deleted messages
+    Expunge,
+    /// The server doesn't support IDLE
+    Idle,
+    /// The connection dropped while waiting for changes to a mailbox
+    Disconnected,
     /// The client failed to logout. I'm honestly not sure how this would
     /// happen, but it can.
     Logout,
+    /// The local copy of the mailbox could not be read or updated
+    Store(crate::database::Errors),
+    /// The database actor stopped before answering
+    Unreachable(actix::MailboxError),
+    /// The draft has no recipients or an address that can't be parsed
+    Compose,
+    /// A file attached to the draft could not be read
+    Attach,
+    /// The SMTP server refused the email or one of its recipients
+    Send,
+}
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Errors::Connect => write!(f, "failed to connect to the server"),
+            Errors::Login => write!(f, "the server refused the login"),
+            Errors::Select => write!(f, "failed to select the mailbox"),
+            Errors::Fetch => write!(f, "failed to fetch mail"),
+            Errors::Search => write!(f, "the server refused the search"),
+            Errors::Argument => {
+                write!(f, "argument contains a line break or NUL")
+            }
+            Errors::List => write!(f, "failed to list mailboxes"),
+            Errors::Copy => write!(f, "the server refused to copy mail"),
+            Errors::Move => write!(f, "the server refused to move mail"),
+            Errors::NoTrash => write!(f, "no trash mailbox to move mail to"),
+            Errors::Flag => write!(f, "the server refused to change flags"),
+            Errors::Expunge => write!(f, "the server refused to expunge"),
+            Errors::Idle => write!(f, "the server doesn't support IDLE"),
+            Errors::Disconnected => write!(f, "the connection dropped"),
+            Errors::Logout => write!(f, "failed to logout"),
+            Errors::Store(e) => {
+                write!(f, "failed to update the local copy: {e}")
+            }
+            Errors::Unreachable(e) => {
+                write!(f, "failed to reach the database: {e}")
+            }
+            Errors::Compose => write!(f, "the draft can't be sent as is"),
+            Errors::Attach => write!(f, "failed to read an attachment"),
+            Errors::Send => write!(f, "the server refused the email"),
+        }
+    }
 }
 
 /// Errors that can occur while parsing email headers
@@ -114,6 +223,12 @@ fn process_subject(envelope: &imap_proto::types::Envelope) -> Option<String> {
     Some(string)
 }
 
+/// Turn a message ID field into the first message ID it contains
+fn process_message_id(field: Option<&[u8]>) -> Option<String> {
+    let string = String::from_utf8(field?.to_vec()).ok()?;
+    mime::message_ids(&string).into_iter().next()
+}
+
 /// An email address that contains strings instead of &[u8]
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct StringAddress {
@@ -128,6 +243,20 @@ pub(crate) struct StringAddress {
 }
 
 impl StringAddress {
+    /// Gets the display name, e.g. `John Doe` in `John Doe <jdoe@example.com>`
+    pub(crate) fn name(&self) -> Option<&str> {
+        self.name.as_deref().filter(|name| !name.trim().is_empty())
+    }
+
+    /// Gets the bare address in lowercase, e.g. `jdoe@example.com`
+    ///
+    /// Returns `None` for group syntax entries, which have no host.
+    pub(crate) fn address(&self) -> Option<String> {
+        let mailbox = self.mailbox.as_deref()?;
+        let host = self.host.as_deref()?;
+        Some(format!("{mailbox}@{host}").to_lowercase())
+    }
+
     /// Creates a new string address
     fn new(
         name: Option<String>,
@@ -144,7 +273,7 @@ impl StringAddress {
     }
 }
 
-impl std::fmt::Display for StringAddress {
+impl Display for StringAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let name = self.name.clone().unwrap_or_default();
         let mailbox = self.mailbox.clone().unwrap_or_default();
@@ -176,10 +305,12 @@ fn process_addresses(
     Some(returned)
 }
 
+/// An authenticated connection to an IMAP server
+pub(super) type ImapSession =
+    imap::Session<native_tls::TlsStream<std::net::TcpStream>>;
+
 /// Creates an IMAP session with the given server
-fn create_session(
-    account: &Account,
-) -> Result<imap::Session<native_tls::TlsStream<std::net::TcpStream>>, Errors> {
+fn create_session(account: &Account) -> Result<ImapSession, Errors> {
     let tls = native_tls::TlsConnector::builder().build().expect(
         "Failed to build TLS Connector. The application will never work \
          without this.",
@@ -199,31 +330,30 @@ fn create_session(
     Ok(imap_session)
 }
 
-/// Fetch a mailbox for a given account
-pub(crate) fn fetch_mailbox(
-    account: &Account,
-    mailbox: &str,
-) -> Result<Vec<ImapEmail>, Errors> {
-    let mut imap_session = create_session(account)?;
-    if imap_session.select(mailbox).is_err() {
-        return Err(Errors::Select);
-    };
-    let Ok(messages) = imap_session.fetch("*", "(UID ENVELOPE)") else {
-        return Err(Errors::Fetch);
-    };
-
+/// Turns the messages returned by a `FETCH` command into emails
+fn process_fetches(messages: &[imap::types::Fetch]) -> Vec<ImapEmail> {
     let mut returned: Vec<ImapEmail> = Vec::new();
 
-    for m in &messages {
+    for m in messages {
         let mut date = None;
         let mut subject = None;
         let mut from = None;
+        let mut to = None;
+        let mut cc = None;
+        let mut reply_to = None;
+        let mut message_id = None;
+        let mut in_reply_to = None;
         // Process the message envelope
         if let Some(envelope) = m.envelope() {
             let envelope = envelope.to_owned();
             date = process_date(envelope);
             subject = process_subject(envelope);
             from = process_addresses(&envelope.from);
+            to = process_addresses(&envelope.to);
+            cc = process_addresses(&envelope.cc);
+            reply_to = process_addresses(&envelope.reply_to);
+            message_id = process_message_id(envelope.message_id);
+            in_reply_to = process_message_id(envelope.in_reply_to);
         }
 
         returned.push(ImapEmail {
@@ -232,11 +362,1108 @@ pub(crate) fn fetch_mailbox(
                 date,
                 subject,
                 from,
+                to,
+                cc,
+                reply_to,
+                message_id,
+                in_reply_to,
             },
+            raw: m.body().map(<[u8]>::to_vec),
+            flags: m.flags().iter().map(ToString::to_string).collect(),
+            references: m
+                .body()
+                .or_else(|| m.header())
+                .map(mime::references)
+                .unwrap_or_default(),
+            gmail: None,
+            size: m.size.or_else(|| {
+                m.body().and_then(|body| u32::try_from(body.len()).ok())
+            }),
+            partial: false,
+        });
+    }
+    returned
+}
+
+/// Picks the messages of the selected mailbox whose bodies a sync policy
+/// allows downloading
+///
+/// Responds with the messages that may be downloaded whole and those larger
+/// than the policy's `max_message_size`, of which only some parts may be.
+/// `headers` must have been fetched with `RFC822.SIZE` for the messages with
+/// the given UIDs.
+fn bodies_allowed(
+    session: &mut ImapSession,
+    policy: &SyncPolicy,
+    uids: &str,
+    headers: &[imap::types::Fetch],
+) -> Result<(Vec<u32>, Vec<u32>), Errors> {
+    if policy.headers_only {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let recent: Option<HashSet<u32>> = match policy.body_days {
+        Some(days) => {
+            let since = OffsetDateTime::now_utc()
+                - time::Duration::days(i64::from(days));
+            let Ok(since) = since.format(SEARCH_DATE) else {
+                return Err(Errors::Search);
+            };
+            let Ok(uids) =
+                session.uid_search(format!("UID {uids} SINCE {since}"))
+            else {
+                return Err(Errors::Search);
+            };
+            Some(uids)
+        }
+        None => None,
+    };
+    let mut whole = Vec::new();
+    let mut oversized = Vec::new();
+    for message in headers {
+        let Some(uid) = message.uid else {
+            continue;
+        };
+        if recent.as_ref().is_some_and(|recent| !recent.contains(&uid)) {
+            continue;
+        }
+        let fits = policy
+            .max_message_size
+            .is_none_or(|max| message.size.is_some_and(|size| size <= max));
+        if fits {
+            whole.push(uid);
+        } else {
+            oversized.push(uid);
+        }
+    }
+    Ok((whole, oversized))
+}
+
+/// A part of a message by its section path, e.g. `[1, 2]` for `BODY[1.2]`,
+/// with whether it is text to show and its size in bytes
+type Part = (Vec<u32>, bool, u32);
+
+/// Lists the parts of a message that aren't made of other parts
+fn leaf_parts(
+    structure: &BodyStructure<'_>,
+    path: &[u32],
+    parts: &mut Vec<Part>,
+) {
+    // The body of a message that isn't multipart is its part 1
+    let leaf_path = if path.is_empty() {
+        vec![1]
+    } else {
+        path.to_vec()
+    };
+    match structure {
+        BodyStructure::Multipart {
+            bodies,
+            ..
+        } => {
+            for (index, body) in (1..).zip(bodies) {
+                let mut child = path.to_vec();
+                child.push(index);
+                leaf_parts(body, &child, parts);
+            }
+        }
+        BodyStructure::Text {
+            common,
+            other,
+            ..
+        } => {
+            let attached =
+                common.disposition.as_ref().is_some_and(|disposition| {
+                    disposition.ty.eq_ignore_ascii_case("attachment")
+                });
+            parts.push((leaf_path, !attached, other.octets));
+        }
+        BodyStructure::Basic {
+            other,
+            ..
+        }
+        | BodyStructure::Message {
+            other,
+            ..
+        } => parts.push((leaf_path, false, other.octets)),
+    }
+}
+
+/// Picks the parts of a message too large for a sync policy to download
+///
+/// Text to show is always picked. Other parts, such as attachments, are
+/// picked smallest first as long as they fit into `max` bytes along with the
+/// text. Returns `None` if nothing would be left out.
+fn parts_within(
+    structure: &BodyStructure<'_>,
+    max: u32,
+) -> Option<Vec<Vec<u32>>> {
+    let mut parts = Vec::new();
+    leaf_parts(structure, &[], &mut parts);
+    parts.sort_by_key(|(_, text, size)| (!*text, *size));
+    let count = parts.len();
+    let mut total: u64 = 0;
+    let mut picked = Vec::new();
+    for (path, text, size) in parts {
+        if !text && total + u64::from(size) > u64::from(max) {
+            break;
+        }
+        total += u64::from(size);
+        picked.push(path);
+    }
+    (picked.len() < count).then_some(picked)
+}
+
+/// Formats a section path, e.g. `1.2`
+fn section(path: &[u32]) -> String {
+    path.iter().map(ToString::to_string).collect::<Vec<String>>().join(".")
+}
+
+/// Copies the fields of a message header, except those describing its
+/// content, and leaves out the empty line ending it
+fn without_content_fields(header: &[u8]) -> Vec<u8> {
+    let mut kept = Vec::with_capacity(header.len());
+    let mut keep = false;
+    for line in header.split_inclusive(|byte| *byte == b'\n') {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        // Folded lines continue the field before them
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+            let name =
+                line.split(|byte| *byte == b':').next().unwrap_or_default();
+            keep = ![
+                &b"content-type"[..],
+                b"content-transfer-encoding",
+                b"mime-version",
+            ]
+            .iter()
+            .any(|field| name.trim_ascii().eq_ignore_ascii_case(field));
+        }
+        if keep {
+            kept.extend_from_slice(line);
+        }
+    }
+    kept
+}
+
+/// Downloads the header and some parts of a message and puts them together
+/// as a `multipart/mixed` message of their own
+fn fetch_parts(
+    session: &mut ImapSession,
+    uid: u32,
+    parts: &[Vec<u32>],
+) -> Result<Option<Vec<u8>>, Errors> {
+    let mut items = String::new();
+    for path in parts {
+        let section = section(path);
+        write!(items, " BODY.PEEK[{section}.MIME] BODY.PEEK[{section}]")
+            .expect("Writing to a String can't fail");
+    }
+    let Ok(messages) = session
+        .uid_fetch(uid.to_string(), format!("(UID BODY.PEEK[HEADER]{items})"))
+    else {
+        return Err(Errors::Fetch);
+    };
+    let Some(message) = messages.iter().next() else {
+        return Ok(None);
+    };
+    let Some(header) = message.header() else {
+        return Ok(None);
+    };
+    // `=_` can't occur in base64 or quoted-printable encoded parts
+    let boundary = format!("=_weasel_partial_{uid}");
+    let mut raw = without_content_fields(header);
+    raw.extend_from_slice(
+        format!(
+            "MIME-Version: 1.0\r\nContent-Type: multipart/mixed; \
+             boundary=\"{boundary}\"\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    for path in parts {
+        let mime = message.section(&SectionPath::Part(
+            path.clone(),
+            Some(MessageSection::Mime),
+        ));
+        let body = message.section(&SectionPath::Part(path.clone(), None));
+        let (Some(mime), Some(body)) = (mime, body) else {
+            continue;
+        };
+        raw.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+        raw.extend_from_slice(mime);
+        raw.extend_from_slice(body);
+        raw.extend_from_slice(b"\r\n");
+    }
+    raw.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+    Ok(Some(raw))
+}
+
+/// Fetches the headers of the messages with the given UIDs in the selected
+/// mailbox, and the bodies a sync policy allows
+///
+/// Of messages larger than the policy allows, the text and the attachments
+/// that fit are downloaded, and the email is marked as partial.
+fn fetch_partially(
+    session: &mut ImapSession,
+    policy: &SyncPolicy,
+    uids: &str,
+) -> Result<Vec<ImapEmail>, Errors> {
+    let Ok(headers) = session
+        .uid_fetch(uids, "(UID FLAGS ENVELOPE RFC822.SIZE BODY.PEEK[HEADER])")
+    else {
+        return Err(Errors::Fetch);
+    };
+    let (mut whole, oversized) =
+        bodies_allowed(session, policy, uids, &headers)?;
+    let mut bodies: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut partial: HashSet<u32> = HashSet::new();
+    let max = policy.max_message_size.unwrap_or(u32::MAX);
+    for chunk in oversized.chunks(BODY_CHUNK_SIZE) {
+        let Ok(structures) =
+            session.uid_fetch(uid_set(chunk), "(UID BODYSTRUCTURE)")
+        else {
+            return Err(Errors::Fetch);
+        };
+        for message in &structures {
+            let (Some(uid), Some(structure)) =
+                (message.uid, message.bodystructure())
+            else {
+                continue;
+            };
+            match parts_within(structure, max) {
+                Some(parts) => {
+                    if let Some(raw) = fetch_parts(session, uid, &parts)? {
+                        bodies.insert(uid, raw);
+                        partial.insert(uid);
+                    }
+                }
+                None => whole.push(uid),
+            }
+        }
+    }
+    for chunk in whole.chunks(BODY_CHUNK_SIZE) {
+        let Ok(messages) =
+            session.uid_fetch(uid_set(chunk), "(UID BODY.PEEK[])")
+        else {
+            return Err(Errors::Fetch);
+        };
+        bodies.extend(messages.iter().filter_map(|message| {
+            Some((message.uid?, message.body()?.to_vec()))
+        }));
+    }
+    log::trace!(
+        "Downloaded {} of {} message bodies, {} of them partially",
+        bodies.len(),
+        headers.len(),
+        partial.len()
+    );
+    let mut returned = process_fetches(&headers);
+    for email in &mut returned {
+        email.raw = bodies.remove(&email.uid);
+        email.partial = partial.contains(&email.uid);
+    }
+    Ok(returned)
+}
+
+/// What was seen of a mailbox during a synchronization
+///
+/// See [RFC 3501](https://datatracker.ietf.org/doc/html/rfc3501#section-2.3.1.1)
+/// for UIDs and [RFC 7162](https://datatracker.ietf.org/doc/html/rfc7162) for
+/// modification sequences.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MailboxState {
+    /// The `UIDVALIDITY`, which changes when the server gives the messages
+    /// new UIDs
+    pub(crate) uid_validity: u32,
+    /// The `UIDNEXT`. Every message with a lower UID was downloaded.
+    pub(crate) uid_next: u32,
+    /// The `HIGHESTMODSEQ`, if the server supports CONDSTORE. Flags that
+    /// changed after it are refreshed.
+    pub(crate) highest_modseq: Option<u64>,
+}
+
+/// A selected mailbox whose messages are downloaded a chunk at a time
+///
+/// Only the messages that arrived since the last synchronization are
+/// downloaded. The session stays open between chunks, so each chunk can be
+/// stored before the next one is downloaded and the whole mailbox is never
+/// held in memory.
+pub(crate) struct MailboxSync {
+    /// The session with the mailbox selected
+    session: ImapSession,
+    /// Which bodies may be downloaded
+    policy: SyncPolicy,
+    /// UIDs of the messages left to download, in ascending order
+    pending: Vec<u32>,
+    /// What is seen of the mailbox now, to remember once every chunk was
+    /// stored
+    pub(crate) state: Option<MailboxState>,
+    /// Whether the server gave the messages new UIDs since the last
+    /// synchronization, so every email stored for the mailbox is stale
+    pub(crate) reset: bool,
+    /// UIDs of every message in the mailbox
+    pub(crate) uids: HashSet<u32>,
+    /// Flags of the messages downloaded before, by UID. Only the flags that
+    /// changed are included if the server supports CONDSTORE.
+    pub(crate) flags: HashMap<u32, Vec<String>>,
+}
+
+impl MailboxSync {
+    /// Selects a mailbox of an account and finds out what changed since it
+    /// was last seen
+    pub(crate) fn open(
+        account: &Account,
+        mailbox: &str,
+        policy: SyncPolicy,
+        known: Option<&MailboxState>,
+    ) -> Result<Self, Errors> {
+        let mut session = create_session(account)?;
+        // Asked before selecting, so changes made in between are only seen
+        // twice instead of never
+        let highest_modseq = highest_modseq(&mut session, mailbox)?;
+        let Ok(selected) = session.select(mailbox) else {
+            return Err(Errors::Select);
+        };
+        let uids: HashSet<u32> = if selected.exists == 0 {
+            HashSet::new()
+        } else {
+            search_uids(&mut session, "ALL")?.into_iter().collect()
+        };
+        // Servers that don't report `UIDNEXT` still hand out ascending UIDs
+        let uid_next = selected.uid_next.unwrap_or_else(|| {
+            uids.iter().max().and_then(|uid| uid.checked_add(1)).unwrap_or(1)
         });
+        let state = selected.uid_validity.map(|uid_validity| MailboxState {
+            uid_validity,
+            uid_next,
+            highest_modseq,
+        });
+        let reset = known.is_some_and(|known| {
+            state
+                .as_ref()
+                .is_none_or(|state| state.uid_validity != known.uid_validity)
+        });
+        let known = known.filter(|_| !reset);
+        let downloaded = known.map_or(1, |known| known.uid_next);
+        let mut pending: Vec<u32> =
+            uids.iter().copied().filter(|uid| *uid >= downloaded).collect();
+        pending.sort_unstable();
+        let flags = match known {
+            Some(known) if uids.iter().any(|uid| *uid < downloaded) => {
+                fetch_flags(&mut session, known, highest_modseq)?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Self {
+            session,
+            policy,
+            pending,
+            state,
+            reset,
+            uids,
+            flags,
+        })
+    }
+
+    /// Downloads up to `size` of the messages left, newest first
+    ///
+    /// Only the bodies the sync policy allows are downloaded. Everything else
+    /// is left to `fetch_messages`. Responds with `None` once every message
+    /// was downloaded.
+    pub(crate) fn next_chunk(
+        &mut self,
+        size: usize,
+    ) -> Result<Option<Vec<ImapEmail>>, Errors> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let chunk =
+            self.pending.split_off(self.pending.len().saturating_sub(size));
+        let set = uid_set(&chunk);
+        let mut returned = if self.policy.is_complete() {
+            let Ok(messages) = self.session.uid_fetch(
+                &set,
+                "(UID FLAGS ENVELOPE RFC822.SIZE BODY.PEEK[])",
+            ) else {
+                return Err(Errors::Fetch);
+            };
+            process_fetches(&messages)
+        } else {
+            fetch_partially(&mut self.session, &self.policy, &set)?
+        };
+        gmail::add_metadata(&mut self.session, &set, &mut returned)?;
+        Ok(Some(returned))
+    }
+
+    /// Logs out once every chunk was downloaded
+    pub(crate) fn finish(mut self) -> Result<(), Errors> {
+        if self.session.logout().is_err() {
+            return Err(Errors::Logout);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the `HIGHESTMODSEQ` of a mailbox, if the server supports CONDSTORE
+///
+/// Mailboxes that don't keep modification sequences have none.
+fn highest_modseq(
+    session: &mut ImapSession,
+    mailbox: &str,
+) -> Result<Option<u64>, Errors> {
+    let condstore = session
+        .capabilities()
+        .is_ok_and(|capabilities| capabilities.has_str("CONDSTORE"));
+    if !condstore {
+        return Ok(None);
+    }
+    let command = format!("STATUS {} (HIGHESTMODSEQ)", quote(mailbox)?);
+    Ok(session
+        .run_command_and_read_response(command)
+        .ok()
+        .and_then(|response| parse_highest_modseq(&response)))
+}
+
+/// Extracts the `HIGHESTMODSEQ` from the untagged response of a `STATUS`
+/// command
+fn parse_highest_modseq(response: &[u8]) -> Option<u64> {
+    let response = String::from_utf8_lossy(response);
+    let line = response.lines().find(|line| line.starts_with("* STATUS"))?;
+    // The attributes follow the mailbox name, which could contain anything
+    let (_, attributes) = line.rsplit_once('(')?;
+    let mut words = attributes
+        .split(|character: char| ") ".contains(character))
+        .filter(|word| !word.is_empty());
+    words.find(|word| word.eq_ignore_ascii_case("HIGHESTMODSEQ"))?;
+    words.next()?.parse().ok()
+}
+
+/// Fetches the flags of the messages downloaded during the last
+/// synchronization of the selected mailbox
+///
+/// If both synchronizations saw a `HIGHESTMODSEQ`, only the flags that changed
+/// since are fetched.
+fn fetch_flags(
+    session: &mut ImapSession,
+    known: &MailboxState,
+    highest_modseq: Option<u64>,
+) -> Result<HashMap<u32, Vec<String>>, Errors> {
+    let items = match (known.highest_modseq, highest_modseq) {
+        (Some(before), Some(now)) if before == now => return Ok(HashMap::new()),
+        (Some(before), Some(_)) => {
+            format!("(UID FLAGS) (CHANGEDSINCE {before})")
+        }
+        _ => "(UID FLAGS)".to_owned(),
+    };
+    let last = known.uid_next.saturating_sub(1);
+    let Ok(messages) = session.uid_fetch(format!("1:{last}"), items) else {
+        return Err(Errors::Fetch);
+    };
+    Ok(messages
+        .iter()
+        .filter_map(|message| {
+            let flags = message.flags().iter().map(ToString::to_string);
+            Some((message.uid?, flags.collect()))
+        })
+        .collect())
+}
+
+/// Formats UIDs as an IMAP sequence set, e.g. `1:3,5`
+fn uid_set(uids: &[u32]) -> String {
+    let mut sorted = uids.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+    let mut set = String::new();
+    let mut sorted = sorted.into_iter().peekable();
+    while let Some(start) = sorted.next() {
+        let mut end = start;
+        while let Some(next) =
+            sorted.next_if(|&next| end.checked_add(1) == Some(next))
+        {
+            end = next;
+        }
+        if !set.is_empty() {
+            set.push(',');
+        }
+        if start == end {
+            write!(set, "{start}")
+        } else {
+            write!(set, "{start}:{end}")
+        }
+        .expect("Writing to a String can't fail");
+    }
+    set
+}
+
+/// Fetches specific messages of a mailbox with the given `FETCH` items
+fn fetch_uids(
+    account: &Account,
+    mailbox: &str,
+    uids: &[u32],
+    items: &str,
+) -> Result<Vec<ImapEmail>, Errors> {
+    if uids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut imap_session = create_session(account)?;
+    if imap_session.examine(mailbox).is_err() {
+        return Err(Errors::Select);
+    }
+    let set = uid_set(uids);
+    let Ok(messages) = imap_session.uid_fetch(&set, items) else {
+        return Err(Errors::Fetch);
+    };
+    let mut returned = process_fetches(&messages);
+    gmail::add_metadata(&mut imap_session, &set, &mut returned)?;
+    if imap_session.logout().is_err() {
+        return Err(Errors::Logout);
+    }
+    Ok(returned)
+}
+
+/// Fetch only the envelopes and flags of specific messages in a mailbox
+pub(crate) fn fetch_envelopes(
+    account: &Account,
+    mailbox: &str,
+    uids: &[u32],
+) -> Result<Vec<ImapEmail>, Errors> {
+    fetch_uids(account, mailbox, uids, "(UID FLAGS ENVELOPE RFC822.SIZE)")
+}
+
+/// Fetch specific messages of a mailbox in full, regardless of the sync
+/// policy
+pub(crate) fn fetch_messages(
+    account: &Account,
+    mailbox: &str,
+    uids: &[u32],
+) -> Result<Vec<ImapEmail>, Errors> {
+    fetch_uids(
+        account,
+        mailbox,
+        uids,
+        "(UID FLAGS ENVELOPE RFC822.SIZE BODY.PEEK[])",
+    )
+}
+
+/// Parses an IMAP sequence set like `1:3,7` into the numbers it contains
+fn parse_sequence_set(set: &str) -> Vec<u32> {
+    let mut numbers = Vec::new();
+    for range in set.split(',') {
+        let mut bounds = range.split(':').filter_map(|n| n.parse::<u32>().ok());
+        match (bounds.next(), bounds.next()) {
+            (Some(start), Some(end)) => {
+                numbers.extend(start.min(end)..=start.max(end));
+            }
+            (Some(number), None) => numbers.push(number),
+            _ => log::warn!("Ignoring malformed sequence set \"{range}\""),
+        }
+    }
+    numbers
+}
+
+/// Extracts the UIDs from the untagged `ESEARCH` response of a
+/// `UID SEARCH RETURN (ALL)` command
+///
+/// See [RFC 4731](https://datatracker.ietf.org/doc/html/rfc4731) for the
+/// response format.
+fn parse_esearch(response: &[u8]) -> Vec<u32> {
+    let response = String::from_utf8_lossy(response);
+    let Some(line) =
+        response.lines().find(|line| line.starts_with("* ESEARCH"))
+    else {
+        return Vec::new();
+    };
+    let mut words = line.split_whitespace();
+    if words.any(|word| word.eq_ignore_ascii_case("ALL")) {
+        words.next().map(parse_sequence_set).unwrap_or_default()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Searches the selected mailbox for the UIDs of matching messages
+///
+/// Servers advertising `ESEARCH` are asked for a compact sequence set instead
+/// of a list of every UID, which matters for searches that match most of a
+/// large archive.
+fn search_uids(
+    session: &mut ImapSession,
+    criteria: &str,
+) -> Result<Vec<u32>, Errors> {
+    let esearch = session
+        .capabilities()
+        .is_ok_and(|capabilities| capabilities.has_str("ESEARCH"));
+    if esearch {
+        let Ok(response) = session.run_command_and_read_response(format!(
+            "UID SEARCH RETURN (ALL) {criteria}"
+        )) else {
+            return Err(Errors::Search);
+        };
+        Ok(parse_esearch(&response))
+    } else {
+        let Ok(uids) = session.uid_search(criteria) else {
+            return Err(Errors::Search);
+        };
+        Ok(uids.into_iter().collect())
+    }
+}
+
+/// Search a mailbox on the server and return the UIDs of matching messages
+///
+/// `criteria` are IMAP `SEARCH` criteria.
+pub(crate) fn search_mailbox(
+    account: &Account,
+    mailbox: &str,
+    criteria: &str,
+) -> Result<Vec<u32>, Errors> {
+    let criteria = check_argument(criteria)?;
+    let mut imap_session = create_session(account)?;
+    if imap_session.examine(mailbox).is_err() {
+        return Err(Errors::Select);
     }
+    let criteria = if criteria.is_ascii() {
+        criteria.to_owned()
+    } else {
+        format!("CHARSET UTF-8 {criteria}")
+    };
+    let uids = search_uids(&mut imap_session, &criteria)?;
     if imap_session.logout().is_err() {
         return Err(Errors::Logout);
+    }
+    Ok(uids)
+}
+
+/// Where messages ended up after being moved
+#[derive(Debug)]
+pub(crate) struct Moved {
+    /// The mailbox the messages were moved to
+    pub(crate) destination: String,
+    /// New UIDs in the destination by old UID. This is empty if the server
+    /// doesn't report them, which requires `UIDPLUS`.
+    pub(crate) uids: HashMap<u32, u32>,
+}
+
+/// Refuses text that can't be part of a command
+///
+/// A line break would end the command and let the rest of the text run as
+/// another one, and NUL can't be sent at all.
+fn check_argument(text: &str) -> Result<&str, Errors> {
+    if text.contains(['\r', '\n', '\0']) {
+        log::warn!("Refusing to send {text:?} to the server");
+        return Err(Errors::Argument);
+    }
+    Ok(text)
+}
+
+/// Quotes a mailbox name for use in a command
+fn quote(mailbox: &str) -> Result<String, Errors> {
+    let mailbox = check_argument(mailbox)?;
+    Ok(format!("\"{}\"", mailbox.replace('\\', "\\\\").replace('"', "\\\"")))
+}
+
+/// Extracts the mapping from old to new UIDs out of the `COPYUID` response
+/// code sent after a `MOVE`
+///
+/// See [RFC 4315](https://datatracker.ietf.org/doc/html/rfc4315#section-3)
+/// for the response format.
+fn parse_copyuid(response: &[u8]) -> HashMap<u32, u32> {
+    let response = String::from_utf8_lossy(response);
+    let Some((_, code)) = response.split_once("[COPYUID ") else {
+        return HashMap::new();
     };
-    Ok(returned)
+    let mut words = code.split([' ', ']']);
+    let (Some(_validity), Some(source), Some(destination)) =
+        (words.next(), words.next(), words.next())
+    else {
+        return HashMap::new();
+    };
+    parse_sequence_set(source)
+        .into_iter()
+        .zip(parse_sequence_set(destination))
+        .collect()
+}
+
+/// Checks that the given messages of the selected mailbox can be expunged
+/// without expunging any other, and tells whether the server supports
+/// `UIDPLUS`
+///
+/// Only the given messages are expunged if the server supports `UIDPLUS`.
+/// Otherwise a plain `EXPUNGE` would also remove any other message flagged
+/// `\Deleted`, e.g. by another client that means to undo it, so it is refused
+/// while there are any.
+fn check_expunge(
+    session: &mut ImapSession,
+    uids: &[u32],
+) -> Result<bool, Errors> {
+    let uidplus = session
+        .capabilities()
+        .is_ok_and(|capabilities| capabilities.has_str("UIDPLUS"));
+    if uidplus {
+        return Ok(true);
+    }
+    let Ok(deleted) = session.uid_search("DELETED") else {
+        return Err(Errors::Search);
+    };
+    if deleted.iter().any(|uid| !uids.contains(uid)) {
+        log::warn!(
+            "Server lacks UIDPLUS and the mailbox has other deleted messages, \
+             refusing to expunge"
+        );
+        return Err(Errors::Expunge);
+    }
+    Ok(false)
+}
+
+/// Permanently removes messages that are already in the selected mailbox
+///
+/// `uidplus` is whether the server supports `UIDPLUS`, as found by
+/// `check_expunge`, which must have been called first.
+fn expunge(
+    session: &mut ImapSession,
+    uids: &str,
+    uidplus: bool,
+) -> Result<(), Errors> {
+    if session.uid_store(uids, "+FLAGS.SILENT (\\Deleted)").is_err() {
+        return Err(Errors::Flag);
+    }
+    let expunged = if uidplus {
+        session.uid_expunge(uids).map(|_| ())
+    } else {
+        session.expunge().map(|_| ())
+    };
+    expunged.map_err(|e| {
+        log::warn!("Server refused to expunge: {e}");
+        Errors::Expunge
+    })
+}
+
+/// Moves messages out of the selected mailbox
+///
+/// Servers without the `MOVE` extension of
+/// [RFC 6851](https://datatracker.ietf.org/doc/html/rfc6851) get the
+/// equivalent `COPY`, `STORE \Deleted` and `EXPUNGE` instead.
+fn move_selected(
+    session: &mut ImapSession,
+    uids: &[u32],
+    destination: &str,
+) -> Result<Moved, Errors> {
+    let set = uid_set(uids);
+    let supports_move = session
+        .capabilities()
+        .is_ok_and(|capabilities| capabilities.has_str("MOVE"));
+    let new_uids = if supports_move {
+        let Ok(response) = session.run_command_and_read_response(format!(
+            "UID MOVE {set} {}",
+            quote(destination)?
+        )) else {
+            return Err(Errors::Move);
+        };
+        parse_copyuid(&response)
+    } else {
+        let uidplus = check_expunge(session, uids)?;
+        if session.uid_copy(&set, quote(destination)?).is_err() {
+            return Err(Errors::Copy);
+        }
+        expunge(session, &set, uidplus)?;
+        HashMap::new()
+    };
+    Ok(Moved {
+        destination: destination.to_owned(),
+        uids: new_uids,
+    })
+}
+
+/// Finds the account's trash mailbox
+///
+/// The mailbox marked `\Trash` as described in
+/// [RFC 6154](https://datatracker.ietf.org/doc/html/rfc6154) is preferred,
+/// then one named `Trash`.
+fn find_trash(session: &mut ImapSession) -> Result<Option<String>, Errors> {
+    let Ok(names) = session.list(Some(""), Some("*")) else {
+        return Err(Errors::List);
+    };
+    let special_use = names.iter().find(|name| {
+        name.attributes().iter().any(|attribute| {
+            matches!(
+                attribute,
+                imap::types::NameAttribute::Custom(custom)
+                    if custom.eq_ignore_ascii_case("\\Trash")
+            )
+        })
+    });
+    let by_name = || {
+        names.iter().find(|name| {
+            name.name()
+                .rsplit(name.delimiter().unwrap_or("/"))
+                .next()
+                .is_some_and(|leaf| leaf.eq_ignore_ascii_case("Trash"))
+        })
+    };
+    Ok(special_use.or_else(by_name).map(|name| name.name().to_owned()))
+}
+
+/// A connection running the actions queued for an account one after another
+///
+/// It connects once the first action runs, and consecutive actions on the
+/// same mailbox share its selection. A failed command may leave the session
+/// in any state, so the connection is dropped after it and the next action
+/// starts over on a new one.
+pub(crate) struct ActionSession {
+    /// The account the actions belong to
+    account: Account,
+    /// The connection, once one was made
+    session: Option<ImapSession>,
+    /// The mailbox currently selected
+    selected: Option<String>,
+}
+
+impl ActionSession {
+    /// Prepares to run actions for an account without connecting yet
+    pub(crate) fn new(account: Account) -> Self {
+        Self {
+            account,
+            session: None,
+            selected: None,
+        }
+    }
+
+    /// Gets the connection, making one if there is none
+    fn session(&mut self) -> Result<&mut ImapSession, Errors> {
+        if self.session.is_none() {
+            self.session = Some(create_session(&self.account)?);
+        }
+        self.session.as_mut().ok_or(Errors::Connect)
+    }
+
+    /// Runs commands in a mailbox, selecting it first unless it already is,
+    /// and drops the connection if they fail
+    fn attempt<T>(
+        &mut self,
+        mailbox: &str,
+        operation: impl FnOnce(&mut ImapSession) -> Result<T, Errors>,
+    ) -> Result<T, Errors> {
+        let result = self.select(mailbox).and_then(operation);
+        if result.is_err() {
+            self.session = None;
+            self.selected = None;
+        }
+        result
+    }
+
+    /// Selects a mailbox unless it already is
+    fn select(&mut self, mailbox: &str) -> Result<&mut ImapSession, Errors> {
+        let selected = self.selected.as_deref() == Some(mailbox);
+        let session = self.session()?;
+        if !selected {
+            if session.select(mailbox).is_err() {
+                return Err(Errors::Select);
+            }
+            self.selected = Some(mailbox.to_owned());
+        }
+        self.session()
+    }
+
+    /// Copy messages from one mailbox of the account to another
+    pub(crate) fn copy_messages(
+        &mut self,
+        mailbox: &str,
+        uids: &[u32],
+        destination: &str,
+    ) -> Result<(), Errors> {
+        self.attempt(mailbox, |session| {
+            if session.uid_copy(uid_set(uids), quote(destination)?).is_err() {
+                return Err(Errors::Copy);
+            }
+            Ok(())
+        })
+    }
+
+    /// Move messages from one mailbox of the account to another
+    pub(crate) fn move_messages(
+        &mut self,
+        mailbox: &str,
+        uids: &[u32],
+        destination: &str,
+    ) -> Result<Moved, Errors> {
+        self.attempt(mailbox, |session| {
+            move_selected(session, uids, destination)
+        })
+    }
+
+    /// Delete messages from a mailbox of the account
+    ///
+    /// Messages are expunged if `permanently` is set. Otherwise they are
+    /// moved to the trash mailbox and the new location is returned, which
+    /// fails for messages of accounts without one and for messages already in
+    /// it.
+    pub(crate) fn delete_messages(
+        &mut self,
+        mailbox: &str,
+        uids: &[u32],
+        permanently: bool,
+    ) -> Result<Option<Moved>, Errors> {
+        self.attempt(mailbox, |session| {
+            if permanently {
+                let uidplus = check_expunge(session, uids)?;
+                expunge(session, &uid_set(uids), uidplus)?;
+                return Ok(None);
+            }
+            let trash = find_trash(session)?
+                .filter(|trash| trash != mailbox)
+                .ok_or(Errors::NoTrash)?;
+            move_selected(session, uids, &trash).map(Some)
+        })
+    }
+
+    /// Add flags to or remove flags from messages in a mailbox of the account
+    ///
+    /// Responds with the UIDs of messages that no longer exist on the server,
+    /// e.g. because another client deleted them.
+    pub(crate) fn store_flags(
+        &mut self,
+        mailbox: &str,
+        uids: &[u32],
+        flags: &[String],
+        add: bool,
+    ) -> Result<Vec<u32>, Errors> {
+        self.attempt(mailbox, |session| {
+            let Ok(existing) =
+                session.uid_search(format!("UID {}", uid_set(uids)))
+            else {
+                return Err(Errors::Search);
+            };
+            let (present, vanished): (Vec<u32>, Vec<u32>) =
+                uids.iter().partition(|uid| existing.contains(uid));
+            if !present.is_empty() {
+                let query = format!(
+                    "{}FLAGS.SILENT ({})",
+                    if add {
+                        '+'
+                    } else {
+                        '-'
+                    },
+                    flags.join(" ")
+                );
+                if session.uid_store(uid_set(&present), query).is_err() {
+                    return Err(Errors::Flag);
+                }
+            }
+            Ok(vanished)
+        })
+    }
+
+    /// Whether a mailbox of the account exists and can be selected
+    ///
+    /// This tells a mailbox that was deleted or renamed from one the server
+    /// can't open for the moment.
+    pub(crate) fn mailbox_exists(
+        &mut self,
+        mailbox: &str,
+    ) -> Result<bool, Errors> {
+        let pattern = quote(mailbox)?;
+        let session = self.session()?;
+        let Ok(names) = session.list(Some(""), Some(&pattern)) else {
+            self.session = None;
+            self.selected = None;
+            return Err(Errors::List);
+        };
+        Ok(names.iter().any(|name| {
+            name.name() == mailbox
+                && !name
+                    .attributes()
+                    .contains(&imap::types::NameAttribute::NoSelect)
+        }))
+    }
+
+    /// Logs out once every action ran
+    pub(crate) fn finish(self) -> Result<(), Errors> {
+        let Some(mut session) = self.session else {
+            return Ok(());
+        };
+        if session.logout().is_err() {
+            return Err(Errors::Logout);
+        }
+        Ok(())
+    }
+}
+
+/// Watch a mailbox of an account for changes with IDLE
+///
+/// See [RFC 2177](https://datatracker.ietf.org/doc/html/rfc2177) for details.
+/// `changed` is called once the mailbox is being watched, so changes made
+/// before then aren't missed, and again whenever the server reports a change.
+/// It responds with whether to keep watching. This blocks until it responds
+/// `false` or the connection drops.
+pub(crate) fn idle(
+    account: &Account,
+    mailbox: &str,
+    mut changed: impl FnMut() -> bool,
+) -> Result<(), Errors> {
+    let mut imap_session = create_session(account)?;
+    let supports_idle = imap_session
+        .capabilities()
+        .is_ok_and(|capabilities| capabilities.has_str("IDLE"));
+    if !supports_idle {
+        return Err(Errors::Idle);
+    }
+    if imap_session.examine(mailbox).is_err() {
+        return Err(Errors::Select);
+    }
+    while changed() {
+        let Ok(handle) = imap_session.idle() else {
+            return Err(Errors::Disconnected);
+        };
+        if handle.wait_keepalive().is_err() {
+            return Err(Errors::Disconnected);
+        }
+    }
+    if imap_session.logout().is_err() {
+        return Err(Errors::Logout);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_highest_modseq, quote, uid_set, Errors};
+
+    /// Quotes and backslashes are escaped, and line breaks and NUL are
+    /// refused instead of ending the command
+    #[test]
+    fn quotes_arguments() {
+        assert_eq!(
+            quote("Work \"2024\"\\Q1").expect("The name can be quoted"),
+            "\"Work \\\"2024\\\"\\\\Q1\""
+        );
+        for name in ["INBOX\r\nA1 DELETE INBOX", "INBOX\n", "IN\0BOX"] {
+            assert!(
+                matches!(quote(name), Err(Errors::Argument)),
+                "for {name:?}"
+            );
+        }
+    }
+
+    /// Consecutive UIDs are joined into ranges, in any order and with
+    /// duplicates
+    #[test]
+    fn joins_uid_ranges() {
+        assert_eq!(uid_set(&[7, 1, 2, 3, 3, 5, 8, 9]), "1:3,5,7:9");
+        assert_eq!(uid_set(&[u32::MAX, 4]), "4,4294967295");
+        assert_eq!(uid_set(&[]), "");
+    }
+
+    /// The number after `HIGHESTMODSEQ` is read even if the mailbox name
+    /// looks like an attribute, and mailboxes without one have none
+    #[test]
+    fn reads_highest_modseq() {
+        let response = concat!(
+            "* STATUS \"HIGHESTMODSEQ (1)\" ",
+            "(HIGHESTMODSEQ 7011231777)\r\n"
+        );
+        assert_eq!(
+            parse_highest_modseq(response.as_bytes()),
+            Some(7_011_231_777)
+        );
+        let response = b"* STATUS INBOX (MESSAGES 3)\r\n";
+        assert_eq!(parse_highest_modseq(response), None);
+    }
 }
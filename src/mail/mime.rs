@@ -0,0 +1,197 @@
+//! Tools for taking apart the MIME tree of a raw RFC 822 message
+
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+
+/// A file attached to an email
+#[derive(Debug)]
+pub(crate) struct Attachment {
+    /// The file name suggested by the sender, if any
+    pub(crate) filename: Option<String>,
+    /// The MIME type of the attachment, e.g. `application/pdf`
+    pub(crate) mime_type: String,
+    /// The decoded contents of the attachment
+    pub(crate) data: Vec<u8>,
+}
+
+/// Gets the file name of a MIME part from its `Content-Disposition` or
+/// `Content-Type` parameters
+fn filename(part: &ParsedMail<'_>) -> Option<String> {
+    part.get_content_disposition()
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned()
+}
+
+/// The name to save an attachment under: the file name suggested by the
+/// sender without its directories, or `attachment` if there is none
+///
+/// The sender chooses the name, so it must never lead out of the directory
+/// the attachment is saved to.
+pub(crate) fn safe_filename(filename: Option<&str>) -> String {
+    let name: String = filename
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|character| !character.is_control())
+        .collect();
+    let name = name.trim().trim_start_matches('.');
+    if name.is_empty() {
+        "attachment".to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Whether a MIME part should be treated as an attachment rather than as part
+/// of the message body
+fn is_attachment(part: &ParsedMail<'_>) -> bool {
+    if !part.subparts.is_empty() {
+        return false;
+    }
+    part.get_content_disposition().disposition == DispositionType::Attachment
+        || filename(part).is_some()
+}
+
+/// Recursively collects the attachments below a MIME part
+fn collect_attachments(part: &ParsedMail<'_>, found: &mut Vec<Attachment>) {
+    if is_attachment(part) {
+        match part.get_body_raw() {
+            Ok(data) => found.push(Attachment {
+                filename: filename(part),
+                mime_type: part.ctype.mimetype.clone(),
+                data,
+            }),
+            Err(e) => log::warn!("Failed to decode attachment: {e}"),
+        }
+        return;
+    }
+    for subpart in &part.subparts {
+        collect_attachments(subpart, found);
+    }
+}
+
+/// Extracts all attachments from a raw RFC 822 message
+///
+/// Messages that can't be parsed are treated as having no attachments.
+pub(crate) fn attachments(raw: &[u8]) -> Vec<Attachment> {
+    let parsed = match mailparse::parse_mail(raw) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::warn!("Failed to parse message: {e}");
+            return Vec::new();
+        }
+    };
+    let mut found = Vec::new();
+    collect_attachments(&parsed, &mut found);
+    found
+}
+
+/// Recursively collects the decoded `text/plain` and `text/html` parts below a
+/// MIME part, skipping attachments
+fn collect_text(
+    part: &ParsedMail<'_>,
+    plain: &mut Vec<String>,
+    html: &mut Vec<String>,
+) {
+    if is_attachment(part) {
+        return;
+    }
+    if part.subparts.is_empty() {
+        let target = match part.ctype.mimetype.as_str() {
+            "text/plain" => plain,
+            "text/html" => html,
+            _ => return,
+        };
+        match part.get_body() {
+            Ok(body) => target.push(body),
+            Err(e) => log::warn!("Failed to decode message text: {e}"),
+        }
+        return;
+    }
+    for subpart in &part.subparts {
+        collect_text(subpart, plain, html);
+    }
+}
+
+/// Crudely removes the tags from an HTML document, leaving only its text
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for character in html.chars() {
+        match character {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(character),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// The text alternatives of a message body
+#[derive(Debug, Default)]
+pub(crate) struct Bodies {
+    /// The `text/plain` parts, joined
+    pub(crate) plain: Option<String>,
+    /// The `text/html` parts, joined
+    pub(crate) html: Option<String>,
+}
+
+/// Extracts the decoded `text/plain` and `text/html` bodies of a raw RFC 822
+/// message
+pub(crate) fn bodies(raw: &[u8]) -> Bodies {
+    let parsed = match mailparse::parse_mail(raw) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            log::warn!("Failed to parse message: {e}");
+            return Bodies::default();
+        }
+    };
+    let mut plain = Vec::new();
+    let mut html = Vec::new();
+    collect_text(&parsed, &mut plain, &mut html);
+    Bodies {
+        plain: (!plain.is_empty()).then(|| plain.join("\n")),
+        html: (!html.is_empty()).then(|| html.join("\n")),
+    }
+}
+
+/// Extracts the decoded text of a raw RFC 822 message
+///
+/// The `text/plain` parts are preferred. If there are none, the text of the
+/// `text/html` parts is used instead.
+pub(crate) fn text_body(raw: &[u8]) -> Option<String> {
+    let bodies = bodies(raw);
+    bodies.plain.or_else(|| bodies.html.map(|html| strip_tags(&html)))
+}
+
+/// Extracts every `<message-id>` from a header value like `References`
+pub(crate) fn message_ids(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>'))
+        .map(|(id, _)| format!("<{}>", id.trim()))
+        .collect()
+}
+
+/// Extracts the message IDs from the `References` header of a raw RFC 822
+/// message or of just its header section, oldest first
+pub(crate) fn references(raw: &[u8]) -> Vec<String> {
+    match mailparse::parse_headers(raw) {
+        Ok((headers, _)) => headers
+            .get_first_value("References")
+            .map(|references| message_ids(&references))
+            .unwrap_or_default(),
+        Err(e) => {
+            log::warn!("Failed to parse message headers: {e}");
+            Vec::new()
+        }
+    }
+}
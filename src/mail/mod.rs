@@ -1,7 +1,16 @@
 //! Contains and re-exports all mail-related functionality
 
 mod actor;
+pub(crate) mod compose;
+mod gmail;
+pub(crate) mod html;
 mod imap_toolbox;
+pub(crate) mod mime;
+mod scheduler;
+mod smtp;
+pub(crate) mod styled;
+mod tracking;
 
 pub(crate) use actor::*;
 pub(crate) use imap_toolbox::*;
+pub(crate) use scheduler::*;
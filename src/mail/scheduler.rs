@@ -0,0 +1,252 @@
+//! Contains the actor that decides when mailboxes are synchronized
+//!
+//! Every configured folder is fetched when the scheduler starts and then on
+//! the folder's interval. Folders watched with IDLE are fetched whenever the
+//! server reports a change instead, and polling only resumes if the watch
+//! ends.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use actix::prelude::*;
+
+use super::{
+    actor::{FetchMessage, MailActor},
+    imap_toolbox::{self, Errors},
+};
+use crate::config::Account;
+
+/// How long to wait before watching a mailbox again after the connection
+/// dropped
+const IDLE_RETRY: Duration = Duration::from_mins(5);
+
+/// A mailbox of an account, as account address and mailbox name
+type MailboxKey = (String, String);
+
+/// An actor that synchronizes the mailboxes of every account on schedule
+pub(crate) struct SyncSchedulerActor {
+    /// The accounts to synchronize
+    accounts: Vec<Account>,
+    /// Mail actors by the address of their account
+    mail_actors: HashMap<String, Addr<MailActor>>,
+    /// Mailboxes currently watched with IDLE
+    watched: HashSet<MailboxKey>,
+    /// Mailboxes with a fetch in progress
+    syncing: HashSet<MailboxKey>,
+}
+
+impl SyncSchedulerActor {
+    /// Creates a scheduler for the given accounts and their mail actors
+    pub(crate) fn new(
+        accounts: Vec<Account>,
+        mail_actors: HashMap<String, Addr<MailActor>>,
+    ) -> Self {
+        Self {
+            accounts,
+            mail_actors,
+            watched: HashSet::new(),
+            syncing: HashSet::new(),
+        }
+    }
+
+    /// Fetches a mailbox unless a fetch of it is already in progress
+    fn sync(&mut self, account: &str, mailbox: &str, ctx: &mut Context<Self>) {
+        let Some(mail_actor) = self.mail_actors.get(account) else {
+            log::warn!("Scheduler has no mail actor for {account}");
+            return;
+        };
+        let key = (account.to_owned(), mailbox.to_owned());
+        if !self.syncing.insert(key.clone()) {
+            log::trace!(
+                "Scheduler skipped {mailbox} of {account}, in progress"
+            );
+            return;
+        }
+        ctx.spawn(
+            mail_actor
+                .send(FetchMessage {
+                    mailbox: mailbox.to_owned(),
+                })
+                .into_actor(self)
+                .map(move |result, actor, _ctx| {
+                    match result {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => log::warn!(
+                            "Scheduler failed to sync {} of {}: {e}",
+                            key.1,
+                            key.0
+                        ),
+                        Err(e) => log::warn!(
+                            "Scheduler failed to reach the mail actor for {}: \
+                             {e}",
+                            key.0
+                        ),
+                    }
+                    actor.syncing.remove(&key);
+                }),
+        );
+    }
+
+    /// Watches a mailbox with IDLE on a thread of its own, since waiting for
+    /// the server blocks
+    fn watch(account: Account, mailbox: String, ctx: &Context<Self>) {
+        let scheduler = ctx.address();
+        std::thread::spawn(move || {
+            let result = imap_toolbox::idle(&account, &mailbox, || {
+                scheduler.do_send(MailboxChangedMessage {
+                    account: account.address.clone(),
+                    mailbox: mailbox.clone(),
+                });
+                scheduler.connected()
+            });
+            scheduler.do_send(WatchEndedMessage {
+                account: account.address,
+                mailbox,
+                result,
+            });
+        });
+    }
+}
+
+impl Actor for SyncSchedulerActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        log::trace!("Sync scheduler started");
+        for account in self.accounts.clone() {
+            for folder in &account.folders {
+                let key = (account.address.clone(), folder.mailbox.clone());
+                if folder.idle {
+                    // The watch fetches the mailbox once it is up
+                    Self::watch(account.clone(), folder.mailbox.clone(), ctx);
+                } else {
+                    self.sync(&key.0, &key.1, ctx);
+                }
+                ctx.run_interval(
+                    account.sync_interval(folder),
+                    move |actor, ctx| {
+                        if actor.watched.contains(&key) {
+                            return;
+                        }
+                        actor.sync(&key.0, &key.1, ctx);
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// A message to synchronize mailboxes right away, whether or not they are
+/// watched with IDLE
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub(crate) struct SyncNowMessage {
+    /// Only synchronize this account
+    pub(crate) account: Option<String>,
+    /// Only synchronize this mailbox. It doesn't have to be one of the
+    /// configured folders if an account is given as well.
+    pub(crate) mailbox: Option<String>,
+}
+
+impl Handler<SyncNowMessage> for SyncSchedulerActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SyncNowMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("Sync scheduler received {msg:?}");
+        let mailboxes: Vec<MailboxKey> = match (msg.account, msg.mailbox) {
+            (Some(account), Some(mailbox)) => vec![(account, mailbox)],
+            (account, mailbox) => self
+                .accounts
+                .iter()
+                .filter(|candidate| {
+                    account
+                        .as_ref()
+                        .is_none_or(|account| *account == candidate.address)
+                })
+                .flat_map(|account| {
+                    account.folders.iter().map(|folder| {
+                        (account.address.clone(), folder.mailbox.clone())
+                    })
+                })
+                .filter(|(_, candidate)| {
+                    mailbox.as_ref().is_none_or(|mailbox| mailbox == candidate)
+                })
+                .collect(),
+        };
+        for (account, mailbox) in mailboxes {
+            self.sync(&account, &mailbox, ctx);
+        }
+    }
+}
+
+/// A message from an IDLE watch that the server reported a change to a
+/// mailbox, or that the watch is up
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct MailboxChangedMessage {
+    /// The account the mailbox belongs to
+    account: String,
+    /// The mailbox that changed
+    mailbox: String,
+}
+
+impl Handler<MailboxChangedMessage> for SyncSchedulerActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: MailboxChangedMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("Sync scheduler received {msg:?}");
+        self.sync(&msg.account, &msg.mailbox, ctx);
+        self.watched.insert((msg.account, msg.mailbox));
+    }
+}
+
+/// A message from an IDLE watch that it ended
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+struct WatchEndedMessage {
+    /// The account the mailbox belongs to
+    account: String,
+    /// The mailbox that was watched
+    mailbox: String,
+    /// Why the watch ended
+    result: Result<(), Errors>,
+}
+
+impl Handler<WatchEndedMessage> for SyncSchedulerActor {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: WatchEndedMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::trace!("Sync scheduler received {msg:?}");
+        let key = (msg.account, msg.mailbox);
+        self.watched.remove(&key);
+        // Polling takes over, starting with whatever was missed
+        self.sync(&key.0, &key.1, ctx);
+        if matches!(msg.result, Err(Errors::Idle)) {
+            log::info!("{} doesn't support IDLE, polling {}", key.0, key.1);
+            return;
+        }
+        let Some(account) =
+            self.accounts.iter().find(|account| account.address == key.0)
+        else {
+            return;
+        };
+        let account = account.clone();
+        ctx.run_later(IDLE_RETRY, move |_actor, ctx| {
+            Self::watch(account, key.1, ctx);
+        });
+    }
+}
@@ -0,0 +1,353 @@
+//! A minimal SMTP client for sending email
+//!
+//! Only what is needed to hand a finished message to the account's submission
+//! server is implemented: implicit TLS on port 465 or `STARTTLS` on any other
+//! port, `AUTH PLAIN` with the account's credentials, and a single message per
+//! connection. See RFC 5321 for the protocol and RFC 4954 for authentication.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+};
+
+use super::{compose, imap_toolbox::Errors};
+use crate::config::Account;
+
+/// The port of SMTP submission over implicit TLS
+const IMPLICIT_TLS_PORT: u16 = 465;
+
+/// A connection to an SMTP server, over TLS or not yet
+struct Session<S: Read + Write> {
+    /// The connection, buffered to read replies line by line
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> Session<S> {
+    /// Starts a session over a connection
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Reads a reply, which may span several lines, and returns its code and
+    /// text
+    fn reply(&mut self) -> Result<(u16, String), Errors> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            match self.stream.read_line(&mut line) {
+                Ok(0) => return Err(Errors::Disconnected),
+                Ok(_) => {}
+                Err(e) => {
+                    log::warn!("Failed to read SMTP reply: {e}");
+                    return Err(Errors::Disconnected);
+                }
+            }
+            let code = line.get(..3).and_then(|code| code.parse().ok());
+            let Some(code) = code else {
+                log::warn!("Received malformed SMTP reply {line:?}");
+                return Err(Errors::Send);
+            };
+            text.push_str(line.get(4..).unwrap_or_default());
+            // `250-` continues the reply on the next line, `250 ` ends it
+            if line.get(3..4) != Some("-") {
+                return Ok((code, text));
+            }
+        }
+    }
+
+    /// Sends a command and checks that the reply has the expected code,
+    /// returning the reply's text
+    ///
+    /// Rejections are reported as `error`.
+    fn command(
+        &mut self,
+        command: &str,
+        expected: u16,
+        error: fn() -> Errors,
+    ) -> Result<String, Errors> {
+        self.write(format!("{command}\r\n").as_bytes())?;
+        self.expect(expected, error)
+    }
+
+    /// Reads a reply and checks that it has the expected code
+    fn expect(
+        &mut self,
+        expected: u16,
+        error: fn() -> Errors,
+    ) -> Result<String, Errors> {
+        let (code, text) = self.reply()?;
+        if code == expected {
+            Ok(text)
+        } else {
+            log::warn!("SMTP server replied {code} {}", text.trim_end());
+            Err(error())
+        }
+    }
+
+    /// Writes raw data to the server
+    fn write(&mut self, data: &[u8]) -> Result<(), Errors> {
+        self.stream.get_mut().write_all(data).map_err(|e| {
+            log::warn!("Failed to write to SMTP server: {e}");
+            Errors::Disconnected
+        })
+    }
+
+    /// Greets the server and returns the extensions it supports
+    fn hello(&mut self, domain: &str) -> Result<String, Errors> {
+        self.command(&format!("EHLO {domain}"), 250, || Errors::Connect)
+    }
+
+    /// Logs in and sends a message, then ends the session
+    fn send(
+        &mut self,
+        account: &Account,
+        recipients: &[String],
+        message: &[u8],
+    ) -> Result<(), Errors> {
+        let credentials =
+            format!("\0{}\0{}", account.address, account.smtp_password);
+        self.command(
+            &format!("AUTH PLAIN {}", compose::base64(credentials.as_bytes())),
+            235,
+            || Errors::Login,
+        )?;
+        self.command(&format!("MAIL FROM:<{}>", account.address), 250, || {
+            Errors::Send
+        })?;
+        for recipient in recipients {
+            self.write(format!("RCPT TO:<{recipient}>\r\n").as_bytes())?;
+            let (code, text) = self.reply()?;
+            // 251 means the server forwards to another address
+            if code != 250 && code != 251 {
+                log::warn!(
+                    "SMTP server refused {recipient}: {code} {}",
+                    text.trim_end()
+                );
+                return Err(Errors::Send);
+            }
+        }
+        self.command("DATA", 354, || Errors::Send)?;
+        self.write(&dot_stuff(message))?;
+        self.command(".", 250, || Errors::Send)?;
+        // The message was accepted, so a failed goodbye doesn't matter
+        if let Err(e) = self.command("QUIT", 221, || Errors::Logout) {
+            log::debug!("SMTP server didn't close the session cleanly: {e:?}");
+        }
+        Ok(())
+    }
+}
+
+/// Prepares a message for the `DATA` command, doubling the dots that start
+/// lines so none reads as the end of the data, and ending it with a line break
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(message.len() + 2);
+    let mut line_start = true;
+    for byte in message {
+        if line_start && *byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(*byte);
+        line_start = *byte == b'\n';
+    }
+    if !line_start {
+        stuffed.extend_from_slice(b"\r\n");
+    }
+    stuffed
+}
+
+/// Sends a message through the SMTP server of an account
+pub(crate) fn send(
+    account: &Account,
+    recipients: &[String],
+    message: &[u8],
+) -> Result<(), Errors> {
+    let tls = native_tls::TlsConnector::builder().build().expect(
+        "Failed to build TLS Connector. The application will never work \
+         without this.",
+    );
+    let host = &*account.smtp_address;
+    let domain = account
+        .address
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain);
+    let stream =
+        TcpStream::connect((host, account.smtp_port)).map_err(|e| {
+            log::warn!("Failed to connect to {host}: {e}");
+            Errors::Connect
+        })?;
+    let connect_tls = |stream| {
+        tls.connect(host, stream).map_err(|e| {
+            log::warn!("Failed to start TLS with {host}: {e}");
+            Errors::Connect
+        })
+    };
+    let mut session = if account.smtp_port == IMPLICIT_TLS_PORT {
+        Session::new(connect_tls(stream)?)
+    } else {
+        let mut plain = Session::new(stream);
+        plain.expect(220, || Errors::Connect)?;
+        let extensions = plain.hello(domain)?;
+        // Never send the password in the clear
+        if !extensions
+            .lines()
+            .any(|extension| extension.trim().eq_ignore_ascii_case("STARTTLS"))
+        {
+            log::warn!("{host} doesn't support STARTTLS");
+            return Err(Errors::Connect);
+        }
+        plain.command("STARTTLS", 220, || Errors::Connect)?;
+        Session::new(connect_tls(plain.stream.into_inner())?)
+    };
+    if account.smtp_port == IMPLICIT_TLS_PORT {
+        session.expect(220, || Errors::Connect)?;
+    }
+    session.hello(domain)?;
+    session.send(account, recipients, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+
+    use serde_json::json;
+
+    use super::{dot_stuff, Errors, Session};
+    use crate::config::Account;
+
+    /// A connection replaying what the server says and recording what the
+    /// client writes
+    struct Script {
+        /// The replies of the server
+        replies: Cursor<Vec<u8>>,
+        /// What the client wrote
+        written: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buffer)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.written.write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Starts a session with a server that replies with the given lines
+    fn session(replies: &str) -> Session<Script> {
+        Session::new(Script {
+            replies: Cursor::new(replies.as_bytes().to_vec()),
+            written: Vec::new(),
+        })
+    }
+
+    /// What the client wrote in a session
+    fn written(session: Session<Script>) -> String {
+        String::from_utf8(session.stream.into_inner().written)
+            .expect("The client writes text")
+    }
+
+    /// An account sending as `me@example.com`
+    fn account() -> Account {
+        serde_json::from_value(json!({
+            "address": "me@example.com",
+            "smtp_address": "smtp.example.com",
+            "smtp_password": "secret",
+            "smtp_port": 587,
+            "imap_address": "imap.example.com",
+            "imap_password": "secret",
+            "imap_port": 993,
+        }))
+        .expect("The account should be complete")
+    }
+
+    /// Replies over several lines are joined, each line without its code
+    #[test]
+    fn reads_multi_line_replies() {
+        let mut session = session(
+            "250-smtp.example.com\r\n250-STARTTLS\r\n250 SIZE 1000\r\n220 \
+             ready\r\n",
+        );
+        let (code, text) = session.reply().expect("The reply is complete");
+        assert_eq!(code, 250);
+        assert_eq!(text, "smtp.example.com\r\nSTARTTLS\r\nSIZE 1000\r\n");
+        assert_eq!(
+            session.reply().expect("The next reply is read on its own"),
+            (220, "ready\r\n".to_owned())
+        );
+    }
+
+    /// Replies without a code and replies cut short are errors
+    #[test]
+    fn rejects_broken_replies() {
+        assert!(matches!(session("hello\r\n").reply(), Err(Errors::Send)));
+        assert!(matches!(
+            session("250-first line\r\n").reply(),
+            Err(Errors::Disconnected)
+        ));
+    }
+
+    /// Replies with another code than expected report the given error
+    #[test]
+    fn reports_unexpected_codes() {
+        let mut session = session("554 no\r\n");
+        assert!(matches!(
+            session.command("DATA", 354, || Errors::Send),
+            Err(Errors::Send)
+        ));
+        assert_eq!(written(session), "DATA\r\n");
+    }
+
+    /// Dots starting a line are doubled, and the data ends with a line break
+    #[test]
+    fn stuffs_dots() {
+        assert_eq!(
+            dot_stuff(b".hidden\r\nmiddle.dot\r\n..\r\nlast"),
+            b"..hidden\r\nmiddle.dot\r\n...\r\nlast\r\n"
+        );
+        assert_eq!(dot_stuff(b"done\r\n"), b"done\r\n");
+        assert_eq!(dot_stuff(b"\r\n."), b"\r\n..\r\n");
+    }
+
+    /// A message is sent with the commands of RFC 5321, its lines stuffed
+    #[test]
+    fn sends_a_message() {
+        let mut session = session(
+            "235 ok\r\n250 ok\r\n250 ok\r\n251 forwarded\r\n354 go on\r\n250 \
+             queued\r\n221 bye\r\n",
+        );
+        session
+            .send(
+                &account(),
+                &["a@example.org".to_owned(), "b@example.org".to_owned()],
+                b"Subject: Hi\r\n\r\n.\r\n",
+            )
+            .expect("The server accepts the message");
+        assert_eq!(
+            written(session),
+            "AUTH PLAIN AG1lQGV4YW1wbGUuY29tAHNlY3JldA==\r\nMAIL \
+             FROM:<me@example.com>\r\nRCPT TO:<a@example.org>\r\nRCPT \
+             TO:<b@example.org>\r\nDATA\r\nSubject: \
+             Hi\r\n\r\n..\r\n.\r\nQUIT\r\n"
+        );
+    }
+
+    /// A refused recipient stops the message from being sent
+    #[test]
+    fn stops_at_a_refused_recipient() {
+        let mut session = session("235 ok\r\n250 ok\r\n550 no such user\r\n");
+        assert!(matches!(
+            session.send(&account(), &["a@example.org".to_owned()], b"Hi"),
+            Err(Errors::Send)
+        ));
+        assert!(!written(session).contains("DATA"));
+    }
+}
@@ -0,0 +1,143 @@
+//! Styled text for displaying message bodies
+//!
+//! Bodies are turned into runs of text with a style each, which the GUI
+//! renders. Plain text gets its quoted lines marked by quote level and its
+//! URLs turned into links; see the `html` module for HTML bodies.
+
+/// How a run of text is displayed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TextStyle {
+    /// Bold, e.g. `<b>` or `<strong>`
+    pub(crate) bold: bool,
+    /// Italic, e.g. `<i>` or `<em>`
+    pub(crate) italic: bool,
+    /// In a fixed-width font, e.g. `<pre>` or `<code>`
+    pub(crate) monospace: bool,
+    /// The level of an HTML heading, from 1 to 6, or 0 for body text
+    pub(crate) heading: u8,
+    /// How many levels deep the text is quoted
+    pub(crate) quote_level: u8,
+    /// Where the text links to, shown underlined. Only `http`, `https` and
+    /// `mailto` links are kept.
+    pub(crate) link: Option<String>,
+}
+
+/// A run of text with a single style
+#[derive(Debug, Clone)]
+pub(crate) struct Span {
+    /// The text
+    pub(crate) text: String,
+    /// How the text is displayed
+    pub(crate) style: TextStyle,
+}
+
+/// URL schemes that are turned into links
+const LINK_SCHEMES: [&str; 3] = ["https://", "http://", "mailto:"];
+
+/// Characters that end a URL found in text
+const URL_END: &[char] = &[' ', '\t', '\n', '\r', '<', '>', '"', '`'];
+
+/// Punctuation that is more likely to end a sentence than a URL
+const URL_TRAILING: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '\''];
+
+/// Whether a link is safe to open
+pub(crate) fn is_safe_link(url: &str) -> bool {
+    let lowercase = url.trim_start().to_lowercase();
+    LINK_SCHEMES.iter().any(|scheme| lowercase.starts_with(scheme))
+}
+
+/// Finds the first URL scheme in a piece of text, as its byte index and the
+/// scheme itself
+fn find_scheme(text: &str) -> Option<(usize, &'static str)> {
+    let lowercase = text.to_ascii_lowercase();
+    LINK_SCHEMES
+        .iter()
+        .chain(&["www."])
+        .filter_map(|scheme| Some((lowercase.find(scheme)?, *scheme)))
+        .min_by_key(|(start, _)| *start)
+}
+
+/// Appends text to a list of spans, merging it into the last span if it has
+/// the same style
+pub(crate) fn push(spans: &mut Vec<Span>, text: &str, style: &TextStyle) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.style == *style => last.text.push_str(text),
+        _ => spans.push(Span {
+            text: text.to_owned(),
+            style: style.clone(),
+        }),
+    }
+}
+
+/// Appends text to a list of spans, turning URLs in it into links unless the
+/// text is already a link
+pub(crate) fn push_linked(
+    spans: &mut Vec<Span>,
+    text: &str,
+    style: &TextStyle,
+) {
+    if style.link.is_some() {
+        push(spans, text, style);
+        return;
+    }
+    let mut rest = text;
+    while let Some((start, scheme)) = find_scheme(rest) {
+        let (before, candidate) = rest.split_at(start);
+        push(spans, before, style);
+        let length = candidate.find(URL_END).unwrap_or(candidate.len());
+        let url = candidate.split_at(length).0.trim_end_matches(URL_TRAILING);
+        // Only whole words, so e.g. `xhttp://` or `awww.` aren't links
+        let whole_word = before
+            .chars()
+            .next_back()
+            .is_none_or(|character| !character.is_alphanumeric());
+        if whole_word && url.len() > scheme.len() {
+            let address = if scheme == "www." {
+                format!("http://{url}")
+            } else {
+                url.to_owned()
+            };
+            let link = TextStyle {
+                link: Some(address),
+                ..style.clone()
+            };
+            push(spans, url, &link);
+            rest = candidate.split_at(url.len()).1;
+        } else {
+            let (shown, after) = candidate.split_at(scheme.len());
+            push(spans, shown, style);
+            rest = after;
+        }
+    }
+    push(spans, rest, style);
+}
+
+/// Counts the `>` quoting a line of plain text
+fn quote_level(line: &str) -> u8 {
+    let mut level: u8 = 0;
+    for character in line.chars() {
+        match character {
+            '>' => level = level.saturating_add(1),
+            ' ' => {}
+            _ => break,
+        }
+    }
+    level
+}
+
+/// Styles a plain text body, marking quoted lines by their quote level and
+/// turning URLs into links
+pub(crate) fn plain(text: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    for line in text.split_inclusive('\n') {
+        let style = TextStyle {
+            quote_level: quote_level(line),
+            ..TextStyle::default()
+        };
+        push_linked(&mut spans, line, &style);
+    }
+    spans
+}
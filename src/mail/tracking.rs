@@ -0,0 +1,297 @@
+//! Detection of the ways HTML emails report back to their senders
+//!
+//! Senders learn that an email was read through tiny remote images, the
+//! tracking pixels, and which links were followed through redirects that wrap
+//! the real address and through parameters added to it. Pixels are recognized
+//! by their size and by the addresses of known tracking services; wrapped
+//! links are unwrapped and their tracking parameters dropped.
+
+/// Parts of the addresses of known tracking pixels, in lowercase
+const TRACKER_PATTERNS: [&str; 16] = [
+    "/track/open",
+    "/wf/open",
+    "/open.php",
+    "/open.gif",
+    "/o.gif",
+    "/e/o/",
+    "/beacon",
+    "/pixel",
+    "pixel.gif",
+    "pixel.png",
+    "mailtrack.io",
+    "list-manage.com/track",
+    "mandrillapp.com/track",
+    "google-analytics.com/collect",
+    "hubspotemail.net",
+    "mixpanel.com/track",
+];
+
+/// Redirect services, as host and path prefix, and the query parameters they
+/// carry the real address in
+const REDIRECTS: [(&str, &str, &[&str]); 8] = [
+    ("google.com", "/url", &["q", "url"]),
+    ("l.facebook.com", "/l.php", &["u"]),
+    ("lm.facebook.com", "/l.php", &["u"]),
+    ("safelinks.protection.outlook.com", "/", &["url"]),
+    ("youtube.com", "/redirect", &["q"]),
+    ("out.reddit.com", "/", &["url"]),
+    ("slack-redir.net", "/link", &["url"]),
+    ("t.umblr.com", "/redirect", &["z"]),
+];
+
+/// Query parameters that only track where a link was followed from
+const TRACKING_PARAMETERS: [&str; 10] = [
+    "fbclid", "gclid", "dclid", "yclid", "igshid", "mc_cid", "mc_eid",
+    "_hsenc", "_hsmi", "mkt_tok",
+];
+
+/// Whether an image is a remote resource, which would be fetched from the
+/// sender's servers
+pub(crate) fn is_remote(source: &str) -> bool {
+    let source = source.trim_start().to_ascii_lowercase();
+    source.starts_with("http://")
+        || source.starts_with("https://")
+        || source.starts_with("//")
+}
+
+/// Parses a length in pixels from an HTML attribute, like `1` or `1px`
+fn pixels(length: &str) -> Option<f64> {
+    length.trim().trim_end_matches("px").trim().parse().ok()
+}
+
+/// Whether a piece of inline CSS hides an element or shrinks it to a pixel
+fn hides(style: &str) -> bool {
+    let style = style
+        .chars()
+        .filter(|character| !character.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    ["display:none", "visibility:hidden", "opacity:0;"]
+        .iter()
+        .any(|hidden| style.contains(hidden))
+        || style.ends_with("opacity:0")
+        || ["width:", "height:"].iter().any(|dimension| {
+            ["0;", "0px", "1px"].iter().any(|tiny| {
+                style.contains(&format!(";{dimension}{tiny}"))
+                    || style.starts_with(&format!("{dimension}{tiny}"))
+            })
+        })
+}
+
+/// Whether an image is a tracking pixel, given its address and its `width`,
+/// `height` and `style` attributes
+///
+/// Tracking pixels are at most a pixel wide or high, hidden, or served by
+/// known tracking services.
+pub(crate) fn is_tracking_pixel(
+    source: &str,
+    width: Option<&str>,
+    height: Option<&str>,
+    style: Option<&str>,
+) -> bool {
+    let tiny = |length: Option<&str>| {
+        length.and_then(pixels).is_some_and(|length| length <= 1.0)
+    };
+    let source = source.to_ascii_lowercase();
+    tiny(width)
+        || tiny(height)
+        || style.is_some_and(hides)
+        || TRACKER_PATTERNS.iter().any(|pattern| source.contains(pattern))
+}
+
+/// Decodes the percent-encoding of a URL component
+///
+/// Returns `None` if the decoded bytes aren't UTF-8.
+fn percent_decode(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while let Some(byte) = bytes.get(index) {
+        let escaped = (*byte == b'%')
+            .then(|| bytes.get(index + 1..index + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(escaped) = escaped {
+            decoded.push(escaped);
+            index += 3;
+        } else {
+            decoded.push(*byte);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Splits an `http` or `https` URL into the part before the query, the host,
+/// the path, the query and the fragment with its `#`
+fn split_url(url: &str) -> Option<(&str, &str, &str, &str, &str)> {
+    let (_scheme, rest) = url.split_once("://")?;
+    let (before_fragment, fragment) =
+        url.find('#').map_or((url, ""), |index| url.split_at(index));
+    let (base, query) =
+        before_fragment.split_once('?').unwrap_or((before_fragment, ""));
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, after) = rest.split_at(authority_end);
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let path_end = after.find(['?', '#']).unwrap_or(after.len());
+    let path = after.split_at(path_end).0;
+    Some((base, host, path, query, fragment))
+}
+
+/// Follows a link through known redirect services to the address it really
+/// points to
+fn unwrap_redirect(url: &str) -> Option<String> {
+    let (_base, host, path, query, _fragment) = split_url(url)?;
+    let host = host.to_ascii_lowercase();
+    let (_, _, parameters) =
+        REDIRECTS.iter().find(|(service, prefix, _)| {
+            (host == *service || host.ends_with(&format!(".{service}")))
+                && path.starts_with(prefix)
+        })?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(name, _)| parameters.contains(name))
+        .find_map(|(_, value)| percent_decode(value))
+        .filter(|target| split_url(target).is_some())
+}
+
+/// Whether a query parameter only tracks where a link was followed from
+fn is_tracking_parameter(pair: &str) -> bool {
+    let name = pair.split('=').next().unwrap_or(pair).to_ascii_lowercase();
+    name.starts_with("utm_") || TRACKING_PARAMETERS.contains(&name.as_str())
+}
+
+/// Cleans a link of the ways it reports being followed
+///
+/// Known redirect services are skipped by linking to the address they wrap,
+/// and tracking parameters are removed from the query. Links that aren't
+/// `http` or `https` are returned as they are.
+pub(crate) fn clean_link(url: &str) -> String {
+    let mut url = url.to_owned();
+    // Redirects are sometimes wrapped in redirects
+    for _ in 0..REDIRECTS.len() {
+        match unwrap_redirect(&url) {
+            Some(target) => url = target,
+            None => break,
+        }
+    }
+    let Some((base, _host, _path, query, fragment)) = split_url(&url) else {
+        return url;
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !is_tracking_parameter(pair))
+        .collect();
+    if kept.is_empty() {
+        format!("{base}{fragment}")
+    } else {
+        format!("{base}?{}{fragment}", kept.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clean_link, is_tracking_pixel};
+
+    /// Tracking parameters are dropped and the rest of the link is kept in
+    /// order
+    #[test]
+    fn drops_tracking_parameters() {
+        let cases = [
+            (
+                "https://example.com/a?utm_source=mail&id=3&fbclid=x#top",
+                "https://example.com/a?id=3#top",
+            ),
+            (
+                "https://example.com/?UTM_Medium=email&mc_eid=1",
+                "https://example.com/",
+            ),
+            ("https://example.com/?utmost=1", "https://example.com/?utmost=1"),
+            ("https://example.com/a?&&b=1", "https://example.com/a?b=1"),
+            (
+                "mailto:me@example.com?utm_source=x",
+                "mailto:me@example.com?utm_source=x",
+            ),
+            ("not a link", "not a link"),
+        ];
+        for (link, cleaned) in cases {
+            assert_eq!(clean_link(link), cleaned, "for {link}");
+        }
+    }
+
+    /// Known redirect services are skipped, even when nested
+    #[test]
+    fn unwraps_redirects() {
+        let cases = [
+            (
+                "https://www.google.com/url?sa=t&q=https%3A%2F%2Fexample.com%2F%3Fid%3D1%26utm_source%3Dx",
+                "https://example.com/?id=1",
+            ),
+            (
+                "https://eur01.safelinks.protection.outlook.com/?url=https%3A%2F%2Fl.facebook.com%2Fl.php%3Fu%3Dhttps%253A%252F%252Fexample.com%252Fpage&data=1",
+                "https://example.com/page",
+            ),
+            // Targets that aren't web addresses stay wrapped
+            (
+                "https://www.google.com/url?q=javascript%3Aalert(1)",
+                "https://www.google.com/url?q=javascript%3Aalert(1)",
+            ),
+            // Lookalike hosts aren't trusted
+            (
+                "https://notgoogle.com/url?q=https%3A%2F%2Fexample.com",
+                "https://notgoogle.com/url?q=https%3A%2F%2Fexample.com",
+            ),
+            // Invalid UTF-8 is left encoded
+            (
+                "https://www.google.com/url?q=https%3A%2F%2F%FF",
+                "https://www.google.com/url?q=https%3A%2F%2F%FF",
+            ),
+        ];
+        for (link, cleaned) in cases {
+            assert_eq!(clean_link(link), cleaned, "for {link}");
+        }
+    }
+
+    /// Tiny, hidden and known tracking images are pixels, other images
+    /// aren't
+    #[test]
+    fn recognizes_tracking_pixels() {
+        let photo = "https://example.com/photo.jpg";
+        assert!(is_tracking_pixel(photo, Some("1"), None, None));
+        assert!(is_tracking_pixel(photo, None, Some(" 0px "), None));
+        assert!(is_tracking_pixel(photo, None, None, Some("display: none")));
+        assert!(is_tracking_pixel(
+            photo,
+            None,
+            None,
+            Some("border:0;opacity:0")
+        ));
+        assert!(is_tracking_pixel(
+            photo,
+            None,
+            None,
+            Some("WIDTH: 1px; height: 1px")
+        ));
+        assert!(is_tracking_pixel(
+            "https://Example.list-manage.com/track/open.php?u=1",
+            Some("600"),
+            None,
+            None
+        ));
+        assert!(!is_tracking_pixel(photo, Some("600"), Some("auto"), None));
+        assert!(!is_tracking_pixel(
+            photo,
+            None,
+            None,
+            Some("width:100px; opacity:0.5")
+        ));
+        assert!(!is_tracking_pixel(
+            photo,
+            Some("10"),
+            Some("10px"),
+            Some("border:1px")
+        ));
+    }
+}
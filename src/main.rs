@@ -4,14 +4,16 @@ use std::collections::HashMap;
 
 use actix::prelude::*;
 
+mod carddav;
 mod config;
 mod database;
 mod gui;
 mod mail;
 
+use carddav::CardDavActor;
 use database::DatabaseActor;
-use gui::watchdog_actor::GuiWatchdogActor;
-use mail::{FetchMessage, MailActor};
+use gui::{bridge::GuiBridgeActor, watchdog_actor::GuiWatchdogActor};
+use mail::{MailActor, SyncSchedulerActor};
 
 use crate::gui::actor::{GuiActor, StartMessage};
 
@@ -30,8 +32,16 @@ fn main() {
         .get()
         .expect("Configuration has not been initialized");
 
-    let database_addr = system
-        .block_on(async { DatabaseActor::start(DatabaseActor::new().await) });
+    let database_addr = system.block_on(async {
+        DatabaseActor::start(
+            DatabaseActor::new(
+                config.get_blob_directory(),
+                config.get_state_directory(),
+            )
+            .await
+            .expect("Failed to start the database"),
+        )
+    });
 
     // Start mail actors for all accounts
     let mut mail_actors: HashMap<String, Addr<MailActor>> = HashMap::new();
@@ -42,20 +52,52 @@ fn main() {
                 database_addr.clone(),
             ))
         });
-        // This really shouldn't be in the main thread
-        addr.do_send(FetchMessage {
-            mailbox: ("INBOX".to_owned()),
-        });
         mail_actors.insert(user.address.clone(), addr);
     }
 
+    // The scheduler starts synchronizing every account as soon as it starts
+    let sync_scheduler_addr = system.block_on(async {
+        SyncSchedulerActor::start(SyncSchedulerActor::new(
+            config.get_accounts().clone(),
+            mail_actors.clone(),
+        ))
+    });
+
+    // Start CardDAV actors for all address book accounts, which synchronize
+    // as soon as they start
+    let mut carddav_actors: Vec<Addr<CardDavActor>> = Vec::new();
+    for account in config.get_carddav_accounts() {
+        let addr = system.block_on(async {
+            CardDavActor::start(CardDavActor::new(
+                account.clone(),
+                database_addr.clone(),
+            ))
+        });
+        carddav_actors.push(addr);
+    }
+
+    // The bridge runs with the other actors, since the GUI blocks its thread
+    let gui_bridge_addr = system.block_on(async {
+        GuiBridgeActor::start(GuiBridgeActor::new(
+            config.get_accounts().clone(),
+            database_addr.clone(),
+            mail_actors.clone(),
+            sync_scheduler_addr.clone(),
+        ))
+    });
+
     let gui_watchdog_addr = system
         .block_on(async { GuiWatchdogActor::start(GuiWatchdogActor::new()) });
 
     // Start the GUI
     let gui_arbiter = Arbiter::new();
     gui_arbiter.spawn(async move {
-        let gui_actor = GuiActor::start(GuiActor::new(gui_watchdog_addr));
+        let gui_actor = GuiActor::start(GuiActor::new(
+            gui_watchdog_addr,
+            gui_bridge_addr,
+            config.get_accounts().clone(),
+            config.get_keybindings().clone(),
+        ));
         gui_actor.send(StartMessage).await.expect("GUI actor panicked");
         System::current().stop();
     });